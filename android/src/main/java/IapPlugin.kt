@@ -1,6 +1,9 @@
 package app.tauri.iap
 
 import android.app.Activity
+import android.content.ActivityNotFoundException
+import android.content.Intent
+import android.net.Uri
 import android.util.Log
 import android.webkit.WebView
 import app.tauri.annotation.Command
@@ -47,17 +50,39 @@ class AcknowledgePurchaseArgs {
     var purchaseToken: String? = null
 }
 
+@InvokeArg
+class ConsumePurchaseArgs {
+    var purchaseToken: String? = null
+}
+
+@InvokeArg
+class RedeemOfferCodeArgs {
+    var code: String? = null
+}
+
+@InvokeArg
+class ShowManageSubscriptionsArgs {
+    var productId: String? = null
+}
+
 @InvokeArg
 class GetProductStatusArgs {
     var productId: String = ""
     var productType: String = "subs" // "subs" or "inapp"
 }
 
+@InvokeArg
+class GetProductsStatusArgs {
+    var productIds: List<String> = emptyList()
+    var productType: String = "subs" // "subs" or "inapp"
+}
+
 @TauriPlugin
 class IapPlugin(private val activity: Activity): Plugin(activity), PurchasesUpdatedListener, BillingClientStateListener {
     private lateinit var billingClient: BillingClient
     private val coroutineScope = CoroutineScope(Dispatchers.Main)
     private var pendingPurchaseInvoke: Invoke? = null
+    private var awaitingRedemption = false
     private val TAG = "IapPlugin"
     
     companion object {
@@ -95,12 +120,20 @@ class IapPlugin(private val activity: Activity): Plugin(activity), PurchasesUpda
                 if (billingResult.responseCode == BillingClient.BillingResponseCode.OK) {
                     invoke.resolve(JSObject().put("success", true))
                 } else {
-                    invoke.reject("Billing setup failed: ${billingResult.debugMessage}")
+                    // Only an unavailable service is worth connecting to again
+                    val code = when (billingResult.responseCode) {
+                        BillingClient.BillingResponseCode.SERVICE_UNAVAILABLE,
+                        BillingClient.BillingResponseCode.SERVICE_DISCONNECTED,
+                        BillingClient.BillingResponseCode.NETWORK_ERROR -> "serviceUnavailable"
+                        else -> "billingUnavailable"
+                    }
+                    invoke.reject("Billing setup failed: ${billingResult.debugMessage}", code)
                 }
             }
 
             override fun onBillingServiceDisconnected() {
                 Log.d(TAG, "Billing service disconnected")
+                trigger("billingConnectionChanged", JSObject().put("state", "disconnected"))
             }
         })
     }
@@ -110,7 +143,7 @@ class IapPlugin(private val activity: Activity): Plugin(activity), PurchasesUpda
         val args = invoke.parseArgs(GetProductsArgs::class.java)
         
         if (!billingClient.isReady) {
-            invoke.reject("Billing client not ready")
+            invoke.reject("Billing client not ready", "notConnected")
             return
         }
         
@@ -150,6 +183,8 @@ class IapPlugin(private val activity: Activity): Plugin(activity), PurchasesUpda
                                         put("offerToken", offer.offerToken)
                                         put("basePlanId", offer.basePlanId)
                                         put("offerId", offer.offerId)
+                                        // Play only returns offers the user is eligible for
+                                        put("isEligible", true)
                                         
                                         // Pricing phases
                                         val pricingPhases = offer.pricingPhases.pricingPhaseList.map { phase ->
@@ -179,6 +214,20 @@ class IapPlugin(private val activity: Activity): Plugin(activity), PurchasesUpda
                     }
                 }
                 products.put("products", JSONArray(productsArray))
+
+                // Report why requested products were not returned
+                val failureReasons = JSObject()
+                val invalidProductIds = productDetailsResult.unfetchedProductList.map { unfetched ->
+                    failureReasons.put(unfetched.productId, when (unfetched.statusCode) {
+                        // The product exists, but none of its offers is available to this user
+                        UnfetchedProduct.StatusCode.NO_ELIGIBLE_OFFER -> "notEligible"
+                        else -> "notFound"
+                    })
+                    unfetched.productId
+                }
+                products.put("invalidProductIds", JSONArray(invalidProductIds))
+                products.put("failureReasons", failureReasons)
+
                 invoke.resolve(products)
             } else {
                 invoke.reject("Failed to fetch products: ${billingResult.debugMessage}")
@@ -191,7 +240,7 @@ class IapPlugin(private val activity: Activity): Plugin(activity), PurchasesUpda
         val args = invoke.parseArgs(PurchaseArgs::class.java)
         
         if (!billingClient.isReady) {
-            invoke.reject("Billing client not ready")
+            invoke.reject("Billing client not ready", "notConnected")
             return
         }
         
@@ -219,13 +268,14 @@ class IapPlugin(private val activity: Activity): Plugin(activity), PurchasesUpda
             if (billingResult.responseCode == BillingClient.BillingResponseCode.OK && productDetailsResult.productDetailsList.isNotEmpty()) {
                 val productDetails = productDetailsResult.productDetailsList[0]
 
-                // Get offer token from args or from first available subscription offer
-                val offerToken = args.offerToken ?: 
-                    productDetails.subscriptionOfferDetails?.firstOrNull()?.offerToken
-                
                 val productDetailsParamsBuilder = BillingFlowParams.ProductDetailsParams.newBuilder()
                     .setProductDetails(productDetails)
                 
+                // The Rust side picks the best eligible offer when the caller did not choose one,
+                // a subscription without any falls back to its base plan
+                val offerToken = args.offerToken ?: productDetails.subscriptionOfferDetails?.let { offers ->
+                    (offers.firstOrNull { it.offerId == null } ?: offers.firstOrNull())?.offerToken
+                }
                 offerToken?.let { productDetailsParamsBuilder.setOfferToken(it) }
                 
                 val productDetailsParamsList = listOf(productDetailsParamsBuilder.build())
@@ -263,7 +313,7 @@ class IapPlugin(private val activity: Activity): Plugin(activity), PurchasesUpda
         val args = invoke.parseArgs(RestorePurchasesArgs::class.java)
         
         if (!billingClient.isReady) {
-            invoke.reject("Billing client not ready")
+            invoke.reject("Billing client not ready", "notConnected")
             return
         }
         
@@ -279,20 +329,7 @@ class IapPlugin(private val activity: Activity): Plugin(activity), PurchasesUpda
         
         billingClient.queryPurchasesAsync(params) { billingResult, purchases ->
             if (billingResult.responseCode == BillingClient.BillingResponseCode.OK) {
-                val purchasesArray = purchases.map { purchase ->
-                    JSObject().apply {
-                        put("orderId", purchase.orderId)
-                        put("packageName", purchase.packageName)
-                        put("productId", purchase.products.firstOrNull() ?: "")
-                        put("purchaseTime", purchase.purchaseTime)
-                        put("purchaseToken", purchase.purchaseToken)
-                        put("purchaseState", purchase.purchaseState)
-                        put("isAutoRenewing", purchase.isAutoRenewing)
-                        put("isAcknowledged", purchase.isAcknowledged)
-                        put("originalJson", purchase.originalJson)
-                        put("signature", purchase.signature)
-                    }
-                }
+                val purchasesArray = purchases.map { purchase -> purchaseToJson(purchase) }
                 
                 val result = JSObject()
                 result.put("purchases", JSONArray(purchasesArray))
@@ -318,7 +355,7 @@ class IapPlugin(private val activity: Activity): Plugin(activity), PurchasesUpda
         }
         
         if (!billingClient.isReady) {
-            invoke.reject("Billing client not ready")
+            invoke.reject("Billing client not ready", "notConnected")
             return
         }
         
@@ -335,12 +372,165 @@ class IapPlugin(private val activity: Activity): Plugin(activity), PurchasesUpda
         }
     }
     
+    @Command
+    fun consumePurchase(invoke: Invoke) {
+        val purchaseToken = invoke.parseArgs(ConsumePurchaseArgs::class.java).purchaseToken
+        
+        if (purchaseToken == null) {
+            invoke.reject("Purchase token is required")
+            return
+        }
+        
+        if (!billingClient.isReady) {
+            invoke.reject("Billing client not ready", "notConnected")
+            return
+        }
+        
+        val consumeParams = ConsumeParams.newBuilder()
+            .setPurchaseToken(purchaseToken)
+            .build()
+        
+        billingClient.consumeAsync(consumeParams) { billingResult, _ ->
+            if (billingResult.responseCode == BillingClient.BillingResponseCode.OK) {
+                invoke.resolve(JSObject().put("success", true))
+            } else {
+                invoke.reject("Failed to consume purchase: ${billingResult.debugMessage}")
+            }
+        }
+    }
+    
+    @Command
+    fun getStoreCapabilities(invoke: Invoke) {
+        if (!billingClient.isReady) {
+            invoke.reject("Billing client not ready", "notConnected")
+            return
+        }
+        
+        val features = JSONArray().put("consumables").put("offerCodes")
+        if (billingClient.isFeatureSupported(BillingClient.FeatureType.SUBSCRIPTIONS).responseCode == BillingClient.BillingResponseCode.OK) {
+            features.put("subscriptions")
+        }
+        if (billingClient.isFeatureSupported(BillingClient.FeatureType.SUBSCRIPTIONS_UPDATE).responseCode == BillingClient.BillingResponseCode.OK) {
+            features.put("replacement")
+        }
+        
+        billingClient.getBillingConfigAsync(GetBillingConfigParams.newBuilder().build()) { billingResult, billingConfig ->
+            val capabilities = JSObject().apply {
+                put("store", "play")
+                // Play has no payment restriction API, a ready billing client can take payments
+                put("canMakePayments", true)
+                put("features", features)
+                if (billingResult.responseCode == BillingClient.BillingResponseCode.OK && billingConfig != null) {
+                    put("storefront", billingConfig.countryCode)
+                }
+            }
+            invoke.resolve(capabilities)
+        }
+    }
+    
+    @Command
+    fun getUnfinishedPurchases(invoke: Invoke) {
+        if (!billingClient.isReady) {
+            invoke.reject("Billing client not ready", "notConnected")
+            return
+        }
+        
+        queryUnfinishedPurchases(
+            { unfinished -> invoke.resolve(JSObject().put("purchases", JSONArray(unfinished))) },
+            { message -> invoke.reject("Failed to query purchases: $message") }
+        )
+    }
+    
+    @Command
+    fun redeemOfferCode(invoke: Invoke) {
+        val args = invoke.parseArgs(RedeemOfferCodeArgs::class.java)
+        
+        // Play redeems promo codes in the Play Store app, the purchase is picked up on resume
+        var uri = "https://play.google.com/redeem"
+        args.code?.let { uri += "?code=" + Uri.encode(it) }
+        
+        try {
+            activity.startActivity(Intent(Intent.ACTION_VIEW, Uri.parse(uri)))
+            awaitingRedemption = true
+            invoke.resolve(JSObject().put("presented", true))
+        } catch (e: ActivityNotFoundException) {
+            invoke.reject("Play Store is not available", "notSupported")
+        }
+    }
+    
+    @Command
+    fun showManageSubscriptions(invoke: Invoke) {
+        val args = invoke.parseArgs(ShowManageSubscriptionsArgs::class.java)
+        
+        // Without a product the list of all subscriptions opens
+        var url = "https://play.google.com/store/account/subscriptions"
+        args.productId?.let {
+            url += "?sku=" + Uri.encode(it) + "&package=" + Uri.encode(activity.packageName)
+        }
+        
+        try {
+            activity.startActivity(Intent(Intent.ACTION_VIEW, Uri.parse(url)))
+            invoke.resolve(JSObject().put("presented", true).put("url", url))
+        } catch (e: ActivityNotFoundException) {
+            invoke.reject("No app can open the subscription page", "notSupported")
+        }
+    }
+    
+    @Command
+    fun beginRefundRequest(invoke: Invoke) {
+        // Play has no refund request flow, refunds go through the Play Store website or support
+        invoke.reject("Google Play has no in-app refund requests", "notSupported")
+    }
+    
+    override fun onResume() {
+        super.onResume()
+        
+        if (!awaitingRedemption || !billingClient.isReady) {
+            return
+        }
+        awaitingRedemption = false
+        
+        // Purchases made in the Play Store app are not reported to onPurchasesUpdated
+        queryUnfinishedPurchases(
+            { unfinished -> unfinished.forEach { trigger("purchaseUpdated", it) } },
+            { message -> Log.w(TAG, "Failed to query redeemed purchases: $message") }
+        )
+    }
+    
+    // Unacknowledged purchases of both types, including pending ones
+    private fun queryUnfinishedPurchases(onResult: (List<JSObject>) -> Unit, onError: (String) -> Unit) {
+        val unfinished = mutableListOf<JSObject>()
+        val productTypes = listOf(BillingClient.ProductType.INAPP, BillingClient.ProductType.SUBS)
+        
+        fun query(index: Int) {
+            if (index == productTypes.size) {
+                onResult(unfinished)
+                return
+            }
+            
+            val params = QueryPurchasesParams.newBuilder()
+                .setProductType(productTypes[index])
+                .build()
+            
+            billingClient.queryPurchasesAsync(params) { billingResult, purchases ->
+                if (billingResult.responseCode == BillingClient.BillingResponseCode.OK) {
+                    purchases.filter { !it.isAcknowledged }.forEach { unfinished.add(purchaseToJson(it)) }
+                    query(index + 1)
+                } else {
+                    onError(billingResult.debugMessage)
+                }
+            }
+        }
+        
+        query(0)
+    }
+    
     @Command
     fun getProductStatus(invoke: Invoke) {
         val args = invoke.parseArgs(GetProductStatusArgs::class.java)
         
         if (!billingClient.isReady) {
-            invoke.reject("Billing client not ready")
+            invoke.reject("Billing client not ready", "notConnected")
             return
         }
         
@@ -356,35 +546,66 @@ class IapPlugin(private val activity: Activity): Plugin(activity), PurchasesUpda
         
         billingClient.queryPurchasesAsync(params) { billingResult, purchases ->
             if (billingResult.responseCode == BillingClient.BillingResponseCode.OK) {
-                val productPurchase = purchases.find { purchase ->
-                    purchase.products.contains(args.productId)
-                }
-                
-                val statusResult = JSObject().apply {
-                    put("productId", args.productId)
-                    
-                    if (productPurchase != null) {
-                        put("isOwned", true)
-                        put("purchaseState", when(productPurchase.purchaseState) {
-                            Purchase.PurchaseState.PURCHASED -> PURCHASE_STATE_PURCHASED
-                            Purchase.PurchaseState.PENDING -> PURCHASE_STATE_PENDING
-                            else -> PURCHASE_STATE_CANCELED
-                        })
-                        put("purchaseTime", productPurchase.purchaseTime)
-                        put("isAutoRenewing", productPurchase.isAutoRenewing)
-                        put("isAcknowledged", productPurchase.isAcknowledged)
-                        put("purchaseToken", productPurchase.purchaseToken)
-                        
-                        // Note: Android doesn't provide expiration time directly for subscriptions
-                        // It would require additional Google Play Developer API calls
-                    } else {
-                        put("isOwned", false)
-                    }
+                invoke.resolve(productStatus(args.productId, purchases))
+            } else {
+                invoke.reject("Failed to get product status: ${billingResult.debugMessage}")
+            }
+        }
+    }
+    
+    @Command
+    fun getProductsStatus(invoke: Invoke) {
+        val args = invoke.parseArgs(GetProductsStatusArgs::class.java)
+        
+        if (!billingClient.isReady) {
+            invoke.reject("Billing client not ready", "notConnected")
+            return
+        }
+        
+        val productType = when (args.productType) {
+            "inapp" -> BillingClient.ProductType.INAPP
+            "subs" -> BillingClient.ProductType.SUBS
+            else -> BillingClient.ProductType.SUBS
+        }
+        
+        val params = QueryPurchasesParams.newBuilder()
+            .setProductType(productType)
+            .build()
+        
+        // A single purchases query serves every requested product
+        billingClient.queryPurchasesAsync(params) { billingResult, purchases ->
+            if (billingResult.responseCode == BillingClient.BillingResponseCode.OK) {
+                val statuses = JSObject()
+                for (productId in args.productIds) {
+                    statuses.put(productId, productStatus(productId, purchases))
                 }
+                invoke.resolve(JSObject().put("statuses", statuses))
+            } else {
+                invoke.reject("Failed to get products status: ${billingResult.debugMessage}")
+            }
+        }
+    }
+    
+    private fun productStatus(productId: String, purchases: List<Purchase>): JSObject {
+        val productPurchase = purchases.find { purchase ->
+            purchase.products.contains(productId)
+        }
+        
+        return JSObject().apply {
+            put("productId", productId)
+            
+            if (productPurchase != null) {
+                put("isOwned", true)
+                put("purchaseState", purchaseStateValue(productPurchase.purchaseState))
+                put("purchaseTime", productPurchase.purchaseTime)
+                put("isAutoRenewing", productPurchase.isAutoRenewing)
+                put("isAcknowledged", productPurchase.isAcknowledged)
+                put("purchaseToken", productPurchase.purchaseToken)
                 
-                invoke.resolve(statusResult)
+                // Note: Android doesn't provide expiration time directly for subscriptions
+                // It would require additional Google Play Developer API calls
             } else {
-                invoke.reject("Failed to get product status: ${billingResult.debugMessage}")
+                put("isOwned", false)
             }
         }
     }
@@ -399,7 +620,7 @@ class IapPlugin(private val activity: Activity): Plugin(activity), PurchasesUpda
                 }
             }
             BillingClient.BillingResponseCode.USER_CANCELED -> {
-                pendingPurchaseInvoke?.reject("Purchase cancelled by user")
+                pendingPurchaseInvoke?.reject("Purchase cancelled by user", "userCanceled")
                 pendingPurchaseInvoke = null
             }
             else -> {
@@ -409,20 +630,36 @@ class IapPlugin(private val activity: Activity): Plugin(activity), PurchasesUpda
         }
     }
     
+    private fun purchaseToJson(purchase: Purchase): JSObject {
+        return JSObject().apply {
+            put("orderId", purchase.orderId)
+            put("packageName", purchase.packageName)
+            put("productId", purchase.products.firstOrNull() ?: "")
+            put("purchaseTime", purchase.purchaseTime)
+            put("purchaseToken", purchase.purchaseToken)
+            put("purchaseState", purchaseStateValue(purchase.purchaseState))
+            put("isAutoRenewing", purchase.isAutoRenewing)
+            put("isAcknowledged", purchase.isAcknowledged)
+            put("originalJson", purchase.originalJson)
+            put("signature", purchase.signature)
+        }
+    }
+    
+    // Play Billing numbers its states differently from the plugin's PurchaseState
+    private fun purchaseStateValue(purchaseState: Int): Int {
+        return when (purchaseState) {
+            Purchase.PurchaseState.PURCHASED -> PURCHASE_STATE_PURCHASED
+            Purchase.PurchaseState.PENDING -> PURCHASE_STATE_PENDING
+            else -> PURCHASE_STATE_CANCELED
+        }
+    }
+    
     private fun handlePurchase(purchase: Purchase) {
-        if (purchase.purchaseState == Purchase.PurchaseState.PURCHASED) {
-            val purchaseData = JSObject().apply {
-                put("orderId", purchase.orderId)
-                put("packageName", purchase.packageName)
-                put("productId", purchase.products.firstOrNull() ?: "")
-                put("purchaseTime", purchase.purchaseTime)
-                put("purchaseToken", purchase.purchaseToken)
-                put("purchaseState", purchase.purchaseState)
-                put("isAutoRenewing", purchase.isAutoRenewing)
-                put("isAcknowledged", purchase.isAcknowledged)
-                put("originalJson", purchase.originalJson)
-                put("signature", purchase.signature)
-            }
+        // Pending purchases (e.g. cash payments) are reported too, the Rust side tracks them
+        // until Play reports them as purchased
+        if (purchase.purchaseState == Purchase.PurchaseState.PURCHASED ||
+            purchase.purchaseState == Purchase.PurchaseState.PENDING) {
+            val purchaseData = purchaseToJson(purchase)
             
             pendingPurchaseInvoke?.resolve(purchaseData)
             pendingPurchaseInvoke = null
@@ -440,5 +677,6 @@ class IapPlugin(private val activity: Activity): Plugin(activity), PurchasesUpda
     
     override fun onBillingServiceDisconnected() {
         Log.d(TAG, "Billing service disconnected")
+        trigger("billingConnectionChanged", JSObject().put("state", "disconnected"))
     }
 }
\ No newline at end of file
@@ -5,10 +5,31 @@ const COMMANDS: &[&str] = &[
     "initialize",
     "get_products",
     "purchase",
+    "purchase_and_fulfill",
     "restore_purchases",
     "get_purchase_history",
     "acknowledge_purchase",
+    "consume_purchase",
     "get_product_status",
+    "get_products_status",
+    "get_in_flight_purchases",
+    "cancel_purchase",
+    "get_pending_operations",
+    "retry_pending_operations",
+    "get_pending_purchases",
+    "subscribe_events",
+    "get_connection_state",
+    "get_store_capabilities",
+    "redeem_offer_code",
+    "begin_refund_request",
+    "show_manage_subscriptions",
+    "query_ledger",
+    "mark_fulfilled",
+    "get_currency_balance",
+    "spend_currency",
+    "get_currency_history",
+    "validate_purchase",
+    "verify_entitlement_token",
 ];
 
 fn main() {
@@ -20,6 +20,15 @@ export interface PricingPhase {
   recurrenceMode: number;
 }
 
+/**
+ * What the user pays during the first pricing phase of an offer
+ */
+export type OfferKind =
+  | "free_trial"
+  | "pay_as_you_go"
+  | "pay_up_front"
+  | "recurring";
+
 /**
  * Subscription offer details including pricing phases
  */
@@ -28,6 +37,8 @@ export interface SubscriptionOffer {
   basePlanId: string;
   offerId?: string;
   pricingPhases: PricingPhase[];
+  offerKind: OfferKind;
+  isEligible: boolean;
 }
 
 /**
@@ -44,11 +55,34 @@ export interface Product {
   subscriptionOfferDetails?: SubscriptionOffer[];
 }
 
+/**
+ * Why a requested product id was not returned by the store
+ */
+export type ProductFailureReason =
+  | "notFound"
+  | "wrongType"
+  | "regionUnavailable"
+  | "notEligible";
+
 /**
  * Response containing products fetched from the store
  */
 export interface GetProductsResponse {
   products: Product[];
+  /** Requested ids the store did not return, in request order */
+  invalidProductIds: string[];
+  /** Failure reason for each id in `invalidProductIds` */
+  failureReasons: Record<string, ProductFailureReason>;
+  /** Storefront country code the prices belong to, when the store reports it */
+  storefront?: string;
+}
+
+/**
+ * Optional parameters for product queries
+ */
+export interface GetProductsOptions {
+  /** Skip the product cache and query the store */
+  forceRefresh?: boolean;
 }
 
 /**
@@ -65,6 +99,172 @@ export interface Purchase {
   isAcknowledged: boolean;
   originalJson: string;
   signature: string;
+  /** When the store revoked the purchase, e.g. after a refund (macOS) */
+  revocationDate?: number;
+  revocationReason?: RevocationReason;
+  /** Whether the app received this purchase before. Unset without a token */
+  delivery?: Delivery;
+  /** Whether the app confirmed processing the purchase with `markFulfilled` */
+  fulfilled: boolean;
+}
+
+/**
+ * Whether a purchase reaches the app for the first time
+ */
+export type Delivery = "firstSeen" | "redelivery";
+
+/**
+ * Verdict of the validation server on a purchase
+ */
+export interface ValidationVerdict {
+  /** Whether the purchase is genuine and may be granted */
+  valid: boolean;
+  /** Why the purchase was rejected */
+  reason?: string;
+  /** When the entitlement ends, for subscriptions */
+  expiresAt?: number;
+  /** Signed token with the entitlements of the account, if the server issues them */
+  entitlementToken?: string;
+}
+
+/**
+ * Claims of an entitlement token. Times are in seconds since the Unix epoch.
+ */
+export interface EntitlementClaims {
+  /** The account the token was issued for */
+  sub: string;
+  /** Product ids the account owns */
+  entitlements: string[];
+  iat: number;
+  exp: number;
+  iss?: string;
+}
+
+/**
+ * Response from marking a purchase as fulfilled
+ */
+export interface MarkFulfilledResponse {
+  /** False when the plugin never delivered a purchase with this token */
+  success: boolean;
+}
+
+/**
+ * Why an entitlement was revoked
+ */
+export type RevocationReason = "developerIssue" | "other" | "noLongerOwned";
+
+/**
+ * An entitlement owned at the previous restore that has been revoked since
+ */
+export interface PurchaseRevocation {
+  productId: string;
+  productType: string;
+  purchaseToken: string;
+  reason: RevocationReason;
+  revokedAt: number;
+}
+
+/**
+ * Response from opening the subscription management UI
+ */
+export interface ShowManageSubscriptionsResponse {
+  presented: boolean;
+  /** The page that was opened, when the store has no in-app management UI */
+  url?: string;
+}
+
+/**
+ * Kind of ledger entry
+ */
+export type LedgerEventKind =
+  | "purchase"
+  | "restore"
+  | "acknowledgement"
+  | "consumption"
+  | "fulfillment"
+  | "refundRequest"
+  | "revocation";
+
+/**
+ * One entry of the local purchase ledger
+ */
+export interface LedgerEntry {
+  /** Schema version the entry was written with */
+  version: number;
+  recordedAt: number;
+  kind: LedgerEventKind;
+  productId?: string;
+  productType?: string;
+  purchaseToken?: string;
+  /** The purchase, restored purchases, revocation or refund status */
+  data?: unknown;
+}
+
+/**
+ * Filters for querying the ledger
+ */
+export interface QueryLedgerOptions {
+  productId?: string;
+  /** Inclusive lower bound on `recordedAt`, in milliseconds since the epoch */
+  from?: number;
+  /** Exclusive upper bound on `recordedAt` */
+  to?: number;
+  /** Only entries of these kinds */
+  kinds?: LedgerEventKind[];
+  /** Keep only the most recent entries */
+  limit?: number;
+}
+
+/**
+ * Response containing ledger entries, oldest first
+ */
+export interface QueryLedgerResponse {
+  entries: LedgerEntry[];
+}
+
+/**
+ * A change of a virtual currency balance
+ */
+export interface CurrencyTransaction {
+  currency: string;
+  /** Positive for credits, negative for spending */
+  amount: number;
+  balanceAfter: number;
+  reason?: string;
+  /** Purchase a credit came from */
+  purchaseToken?: string;
+  productId?: string;
+  createdAt: number;
+}
+
+/**
+ * Response containing the virtual currency balances
+ */
+export interface GetCurrencyBalanceResponse {
+  balances: Record<string, number>;
+}
+
+/**
+ * Filters for the virtual currency history
+ */
+export interface GetCurrencyHistoryOptions {
+  currency?: string;
+  /** Keep only the most recent transactions */
+  limit?: number;
+}
+
+/**
+ * Response containing virtual currency transactions, oldest first
+ */
+export interface GetCurrencyHistoryResponse {
+  transactions: CurrencyTransaction[];
+}
+
+/**
+ * Response from a refund request
+ */
+export interface BeginRefundRequestResponse {
+  status: "success" | "userCancelled";
 }
 
 /**
@@ -98,6 +298,126 @@ export interface GetPurchaseHistoryResponse {
  */
 export interface AcknowledgePurchaseResponse {
   success: boolean;
+  /** The store call failed and is retried in the background */
+  queued: boolean;
+}
+
+/**
+ * Response from consuming a purchase
+ */
+export interface ConsumePurchaseResponse {
+  success: boolean;
+  /** The store call failed and is retried in the background */
+  queued: boolean;
+}
+
+/**
+ * An acknowledge or consume call that failed and is waiting to be retried
+ */
+export interface PendingOperation {
+  kind: "acknowledge" | "consume";
+  purchaseToken: string;
+  /** Failed store calls so far */
+  attempts: number;
+  createdAt: number;
+  nextAttemptAt: number;
+  lastError?: string;
+}
+
+/**
+ * An unacknowledged purchase and when the store refunds it
+ */
+export interface AcknowledgementDeadline {
+  productId: string;
+  purchaseToken: string;
+  purchaseTime: number;
+  /** Time after which the store refunds the purchase */
+  deadline: number;
+}
+
+/**
+ * A purchase the store accepted but has not completed, e.g. a cash payment or Ask to Buy
+ */
+export interface PendingPurchase {
+  productId: string;
+  productType: string;
+  /** Empty when the store only assigns a token once the purchase completes (App Store) */
+  purchaseToken: string;
+  orderId?: string;
+  purchaseTime: number;
+}
+
+/**
+ * How a pending purchase ended
+ */
+export interface PendingPurchaseOutcome {
+  pending: PendingPurchase;
+  /** The completed or canceled purchase, if the store reported one */
+  purchase?: Purchase;
+}
+
+/**
+ * Response containing the pending purchases
+ */
+export interface GetPendingPurchasesResponse {
+  purchases: PendingPurchase[];
+}
+
+/**
+ * State of the connection to the store
+ */
+export type ConnectionState =
+  | "disconnected"
+  | "connecting"
+  | "connected"
+  | "failed";
+
+/**
+ * Connection to the store
+ */
+export interface ConnectionStatus {
+  state: ConnectionState;
+  lastError?: string;
+  updatedAt: number;
+}
+
+/**
+ * Store backing the plugin on this platform
+ */
+export type StoreKind =
+  | "play"
+  | "appStore"
+  | "microsoftStore"
+  | "steam"
+  | "stripe"
+  | "mock"
+  | "none";
+
+/**
+ * Optional store feature
+ */
+export type StoreFeature =
+  | "subscriptions"
+  | "replacement"
+  | "priceChangeConfirmation"
+  | "consumables"
+  | "offerCodes";
+
+/**
+ * What the active store supports
+ */
+export interface StoreCapabilities {
+  store: StoreKind;
+  canMakePayments: boolean;
+  storefront?: string;
+  features: StoreFeature[];
+}
+
+/**
+ * Response containing the queued acknowledge and consume calls
+ */
+export interface GetPendingOperationsResponse {
+  operations: PendingOperation[];
 }
 
 /**
@@ -123,11 +443,38 @@ export interface ProductStatus {
   purchaseToken?: string;
 }
 
+/**
+ * Status of several products, keyed by product id
+ */
+export interface GetProductsStatusResponse {
+  statuses: Record<string, ProductStatus>;
+}
+
+/**
+ * A purchase flow that has started and not yet returned
+ */
+export interface InFlightPurchase {
+  /** Pass to `cancelPurchase` to stop waiting for this flow */
+  requestId: string;
+  productId: string;
+  productType: string;
+  /** Obfuscated account id or app account token the purchase was started with */
+  accountId?: string;
+  startedAt: number;
+}
+
+/**
+ * Response containing the running purchase flows
+ */
+export interface GetInFlightPurchasesResponse {
+  purchases: InFlightPurchase[];
+}
+
 /**
  * Optional parameters for purchase requests
  */
 export interface PurchaseOptions {
-  /** Offer token for subscription products (Android) */
+  /** Offer token for subscription products (Android). Defaults to the best eligible offer */
   offerToken?: string;
   /** Obfuscated account identifier for fraud prevention (Android only) */
   obfuscatedAccountId?: string;
@@ -135,6 +482,62 @@ export interface PurchaseOptions {
   obfuscatedProfileId?: string;
   /** App account token - must be a valid UUID string (iOS only) */
   appAccountToken?: string;
+  /** Identifies the purchase for `cancelPurchase`. Generated when omitted. Reusing the id of a purchase that is still waiting rejects with `duplicateRequestId` */
+  requestId?: string;
+  /** Reject with a `timedOut` error if the store has not finished after this many milliseconds */
+  timeoutMs?: number;
+}
+
+/**
+ * Options for `purchaseAndFulfill`
+ */
+export interface PurchaseAndFulfillOptions extends PurchaseOptions {
+  /** Consume the purchase once granted instead of acknowledging it */
+  consume?: boolean;
+}
+
+/**
+ * Step of a purchase flow, in the order they run
+ */
+export type PurchaseFlowStage = "purchase" | "verify" | "grant" | "finish";
+
+/**
+ * How a purchase flow ended
+ */
+export type PurchaseFlowOutcome =
+  | { status: "granted"; purchase: Purchase }
+  /** Finishes later with a `purchaseFlowFinished` event */
+  | { status: "pending"; purchase: Purchase }
+  | { status: "canceled" }
+  | {
+      status: "failed";
+      stage: PurchaseFlowStage;
+      error: string;
+      purchase?: Purchase;
+    };
+
+/**
+ * A purchase flow that finished after its `purchaseAndFulfill` call returned
+ */
+export interface PurchaseFlowFinished {
+  /** The `requestId` of the flow */
+  flowId: string;
+  outcome: PurchaseFlowOutcome;
+}
+
+/**
+ * Response from cancelling a purchase
+ */
+export interface CancelPurchaseResponse {
+  /** False when no running purchase has the given request id */
+  success: boolean;
+}
+
+/**
+ * Response from opening the offer code redemption UI
+ */
+export interface RedeemOfferCodeResponse {
+  presented: boolean;
 }
 
 /**
@@ -156,9 +559,11 @@ export async function initialize(): Promise<InitializeResponse> {
 
 /**
  * Fetch product information from the app store.
+ * Results are cached according to the `productCache` plugin config.
  *
  * @param productIds - Array of product identifiers to fetch
  * @param productType - Type of products: "subs" for subscriptions, "inapp" for one-time purchases
+ * @param options - Optional query parameters
  * @returns Promise resolving to product information
  * @example
  * ```typescript
@@ -166,16 +571,23 @@ export async function initialize(): Promise<InitializeResponse> {
  *   ['com.example.premium', 'com.example.remove_ads'],
  *   'inapp'
  * );
+ *
+ * // Bypass the cache
+ * const fresh = await getProducts(['com.example.premium'], 'subs', {
+ *   forceRefresh: true
+ * });
  * ```
  */
 export async function getProducts(
   productIds: string[],
   productType: "subs" | "inapp" = "subs",
+  options?: GetProductsOptions,
 ): Promise<GetProductsResponse> {
   return await invoke<GetProductsResponse>("plugin:iap|get_products", {
     payload: {
       productIds,
       productType,
+      ...options,
     },
   });
 }
@@ -203,6 +615,11 @@ export async function getProducts(
  *   obfuscatedAccountId: 'user_account_id',
  *   obfuscatedProfileId: 'user_profile_id'
  * });
+ *
+ * // Give up waiting after two minutes
+ * const purchase = await purchase('com.example.premium', 'subs', {
+ *   timeoutMs: 120000
+ * });
  * ```
  */
 export async function purchase(
@@ -219,6 +636,42 @@ export async function purchase(
   });
 }
 
+/**
+ * Purchase a product and run it through the verify and grant steps registered
+ * in Rust, then acknowledge or consume it.
+ *
+ * The flow survives restarts: an interrupted flow resumes on the next
+ * `initialize` and reports its outcome through `onPurchaseFlowFinished`, as
+ * does a pending purchase once the store completes it.
+ *
+ * @param productId - The product ID to purchase
+ * @param productType - Type of product: "subs" or "inapp"
+ * @param options - Purchase options, plus `consume` for consumables
+ * @returns Promise resolving to the outcome of the flow
+ * @example
+ * ```typescript
+ * const outcome = await purchaseAndFulfill('com.example.coins', 'inapp', {
+ *   consume: true
+ * });
+ * if (outcome.status === 'failed') {
+ *   console.error(`Failed at ${outcome.stage}: ${outcome.error}`);
+ * }
+ * ```
+ */
+export async function purchaseAndFulfill(
+  productId: string,
+  productType: "subs" | "inapp" = "subs",
+  options?: PurchaseAndFulfillOptions,
+): Promise<PurchaseFlowOutcome> {
+  return await invoke<PurchaseFlowOutcome>("plugin:iap|purchase_and_fulfill", {
+    payload: {
+      productId,
+      productType,
+      ...options,
+    },
+  });
+}
+
 /**
  * Restore user's previous purchases.
  *
@@ -276,6 +729,8 @@ export async function getPurchaseHistory(): Promise<GetPurchaseHistoryResponse>
  * const result = await acknowledgePurchase(purchase.purchaseToken);
  * if (result.success) {
  *   console.log('Purchase acknowledged');
+ * } else if (result.queued) {
+ *   console.log('Acknowledgement will be retried');
  * }
  * ```
  */
@@ -292,6 +747,53 @@ export async function acknowledgePurchase(
   );
 }
 
+/**
+ * Consume a purchase so a consumable product can be bought again.
+ * If the store call fails it is queued and retried in the background.
+ *
+ * @param purchaseToken - Purchase token from the transaction
+ * @returns Promise resolving to consumption status
+ * @example
+ * ```typescript
+ * const result = await consumePurchase(purchase.purchaseToken);
+ * ```
+ */
+export async function consumePurchase(
+  purchaseToken: string,
+): Promise<ConsumePurchaseResponse> {
+  return await invoke<ConsumePurchaseResponse>("plugin:iap|consume_purchase", {
+    payload: {
+      purchaseToken,
+    },
+  });
+}
+
+/**
+ * Get the acknowledge and consume calls waiting to be retried, for diagnostics.
+ *
+ * @returns Promise resolving to the queued operations, oldest first
+ */
+export async function getPendingOperations(): Promise<GetPendingOperationsResponse> {
+  return await invoke<GetPendingOperationsResponse>(
+    "plugin:iap|get_pending_operations",
+  );
+}
+
+/**
+ * Retry every queued acknowledge and consume call now, e.g. when the device comes back online.
+ *
+ * @returns Promise resolving to the operations that are still queued
+ * @example
+ * ```typescript
+ * window.addEventListener('online', () => retryPendingOperations());
+ * ```
+ */
+export async function retryPendingOperations(): Promise<GetPendingOperationsResponse> {
+  return await invoke<GetPendingOperationsResponse>(
+    "plugin:iap|retry_pending_operations",
+  );
+}
+
 /**
  * Get the current status of a product for the user.
  * Checks if the product is owned, expired, or available for purchase.
@@ -322,6 +824,369 @@ export async function getProductStatus(
   });
 }
 
+/**
+ * Get the current status of several products with a single store query.
+ *
+ * @param productIds - Product identifiers to check, defaults to every catalog product of `productType` from the plugin config
+ * @param productType - Type of products: "subs" or "inapp"
+ * @returns Promise resolving to the status of each product, keyed by product id
+ * @example
+ * ```typescript
+ * const { statuses } = await getProductsStatus(['com.example.monthly', 'com.example.yearly']);
+ * const owned = Object.values(statuses).filter(status => status.isOwned);
+ * ```
+ */
+export async function getProductsStatus(
+  productIds?: string[],
+  productType: "subs" | "inapp" = "subs",
+): Promise<GetProductsStatusResponse> {
+  return await invoke<GetProductsStatusResponse>(
+    "plugin:iap|get_products_status",
+    {
+      payload: {
+        productIds,
+        productType,
+      },
+    },
+  );
+}
+
+/**
+ * Get the purchase flows that are currently running, e.g. to disable a buy button.
+ *
+ * @returns Promise resolving to the running purchase flows, oldest first
+ * @example
+ * ```typescript
+ * const { purchases } = await getInFlightPurchases();
+ * const busy = purchases.some(p => p.productId === 'com.example.premium');
+ * ```
+ */
+export async function getInFlightPurchases(): Promise<GetInFlightPurchasesResponse> {
+  return await invoke<GetInFlightPurchasesResponse>(
+    "plugin:iap|get_in_flight_purchases",
+  );
+}
+
+/**
+ * Stop waiting for a running purchase flow.
+ * The pending `purchase` call rejects with a `purchaseCanceled` error. If the store still
+ * completes the purchase afterwards, it is delivered through `onPurchaseUpdated`.
+ *
+ * @param requestId - The `requestId` the purchase was started with
+ * @returns Promise resolving to whether a running purchase was found
+ * @example
+ * ```typescript
+ * const pending = purchase('com.example.premium', 'subs', { requestId: 'checkout-1' });
+ * await cancelPurchase('checkout-1');
+ * ```
+ */
+export async function cancelPurchase(
+  requestId: string,
+): Promise<CancelPurchaseResponse> {
+  return await invoke<CancelPurchaseResponse>("plugin:iap|cancel_purchase", {
+    payload: {
+      requestId,
+    },
+  });
+}
+
+/**
+ * Get the purchases that are waiting for payment or approval.
+ *
+ * @returns Promise resolving to the pending purchases, oldest first
+ * @example
+ * ```typescript
+ * const { purchases } = await getPendingPurchases();
+ * ```
+ */
+export async function getPendingPurchases(): Promise<GetPendingPurchasesResponse> {
+  return await invoke<GetPendingPurchasesResponse>(
+    "plugin:iap|get_pending_purchases",
+  );
+}
+
+/**
+ * Get the state of the connection to the store.
+ *
+ * @returns Promise resolving to the connection status
+ * @example
+ * ```typescript
+ * const { state } = await getConnectionState();
+ * ```
+ */
+export async function getConnectionState(): Promise<ConnectionStatus> {
+  return await invoke<ConnectionStatus>("plugin:iap|get_connection_state");
+}
+
+/**
+ * Get the active store, whether it can take payments and the features it supports.
+ * Use it to hide purchase actions the store cannot handle.
+ *
+ * @returns Promise resolving to the store capabilities
+ * @example
+ * ```typescript
+ * const { canMakePayments, features } = await getStoreCapabilities();
+ * if (canMakePayments && features.includes("subscriptions")) {
+ *   showSubscriptionOffers();
+ * }
+ * ```
+ */
+export async function getStoreCapabilities(): Promise<StoreCapabilities> {
+  return await invoke<StoreCapabilities>("plugin:iap|get_store_capabilities");
+}
+
+/**
+ * Open the store's offer code or promo code redemption UI.
+ * Redeemed purchases are delivered through `onPurchaseUpdated`. Rejects with a
+ * `notSupported` error where the store has no redemption UI.
+ *
+ * @param code - Code to prefill, only used by Google Play
+ * @returns Promise resolving once the redemption UI is shown
+ * @example
+ * ```typescript
+ * onPurchaseUpdated((purchase) => grant(purchase));
+ * await redeemOfferCode();
+ * ```
+ */
+export async function redeemOfferCode(
+  code?: string,
+): Promise<RedeemOfferCodeResponse> {
+  return await invoke<RedeemOfferCodeResponse>("plugin:iap|redeem_offer_code", {
+    payload: {
+      code,
+    },
+  });
+}
+
+/**
+ * Open the store's subscription management UI, where users cancel or change
+ * their plan.
+ *
+ * @param productId - Subscription to open directly, only used by Google Play
+ * @returns Promise resolving to whether the UI was shown and the URL it opened
+ * @example
+ * ```typescript
+ * await showManageSubscriptions('com.example.premium');
+ * ```
+ */
+export async function showManageSubscriptions(
+  productId?: string,
+): Promise<ShowManageSubscriptionsResponse> {
+  return await invoke<ShowManageSubscriptionsResponse>(
+    "plugin:iap|show_manage_subscriptions",
+    {
+      payload: {
+        productId,
+      },
+    },
+  );
+}
+
+/**
+ * Show the store's refund request sheet for a transaction.
+ * Rejects with a `notSupported` error outside iOS and macOS. A granted refund
+ * is reported through `onPurchaseRevoked`.
+ *
+ * @param transactionId - The `purchaseToken` of the purchase to refund
+ * @returns Promise resolving to whether the request was submitted
+ * @example
+ * ```typescript
+ * const { status } = await beginRefundRequest(purchase.purchaseToken);
+ * ```
+ */
+export async function beginRefundRequest(
+  transactionId: string,
+): Promise<BeginRefundRequestResponse> {
+  return await invoke<BeginRefundRequestResponse>(
+    "plugin:iap|begin_refund_request",
+    {
+      payload: {
+        transactionId,
+      },
+    },
+  );
+}
+
+/**
+ * Ask the configured validation server whether a purchase is genuine.
+ * Requires the `remote-validator` feature and the `validation` config.
+ *
+ * @param purchase - The purchase to validate
+ * @returns Promise resolving to the verdict of the server
+ * @example
+ * ```typescript
+ * const { valid, reason } = await validatePurchase(purchase);
+ * if (!valid) {
+ *   console.warn(`Rejected: ${reason}`);
+ * }
+ * ```
+ */
+export async function validatePurchase(
+  purchase: Purchase,
+): Promise<ValidationVerdict> {
+  return await invoke<ValidationVerdict>("plugin:iap|validate_purchase", {
+    payload: {
+      purchase,
+    },
+  });
+}
+
+/**
+ * Check an entitlement token against the keys in the `entitlementTokens`
+ * config. Fails with `invalidToken`, or `tokenExpired` once it expired.
+ *
+ * @param token - A token issued by the verification server
+ * @returns Promise resolving to the claims of the token
+ * @example
+ * ```typescript
+ * const { entitlementToken } = await validatePurchase(purchase);
+ * if (entitlementToken) {
+ *   const { entitlements } = await verifyEntitlementToken(entitlementToken);
+ * }
+ * ```
+ */
+export async function verifyEntitlementToken(
+  token: string,
+): Promise<EntitlementClaims> {
+  return await invoke<EntitlementClaims>(
+    "plugin:iap|verify_entitlement_token",
+    {
+      payload: {
+        token,
+      },
+    },
+  );
+}
+
+/**
+ * Confirm that the app processed a purchase, e.g. credited the coins of a
+ * consumable. Later deliveries of the purchase have `fulfilled` set.
+ *
+ * @param purchaseToken - The purchase token of the processed purchase
+ * @returns Promise resolving to whether the purchase was known
+ * @example
+ * ```typescript
+ * onPurchaseUpdated(async (purchase) => {
+ *   if (!purchase.fulfilled) {
+ *     await creditCoins(purchase);
+ *     await markFulfilled(purchase.purchaseToken);
+ *   }
+ * });
+ * ```
+ */
+export async function markFulfilled(
+  purchaseToken: string,
+): Promise<MarkFulfilledResponse> {
+  return await invoke<MarkFulfilledResponse>("plugin:iap|mark_fulfilled", {
+    payload: {
+      purchaseToken,
+    },
+  });
+}
+
+/**
+ * Get the balance of every virtual currency credited so far.
+ *
+ * @returns Promise resolving to the balances by currency
+ * @example
+ * ```typescript
+ * const { balances } = await getCurrencyBalance();
+ * console.log(balances.coins ?? 0);
+ * ```
+ */
+export async function getCurrencyBalance(): Promise<GetCurrencyBalanceResponse> {
+  return await invoke<GetCurrencyBalanceResponse>(
+    "plugin:iap|get_currency_balance",
+  );
+}
+
+/**
+ * Spend virtual currency. Rejects with an `insufficientBalance` error if the
+ * balance is too low.
+ *
+ * @param currency - Currency to spend
+ * @param amount - Amount to deduct
+ * @param reason - What the currency was spent on, kept in the history
+ * @returns Promise resolving to the transaction
+ * @example
+ * ```typescript
+ * const { balanceAfter } = await spendCurrency('coins', 50, 'extra_life');
+ * ```
+ */
+export async function spendCurrency(
+  currency: string,
+  amount: number,
+  reason?: string,
+): Promise<CurrencyTransaction> {
+  return await invoke<CurrencyTransaction>("plugin:iap|spend_currency", {
+    payload: {
+      currency,
+      amount,
+      reason,
+    },
+  });
+}
+
+/**
+ * Get the credits and spending of the virtual currency wallet.
+ *
+ * @param options - Filter by currency and keep only the latest transactions
+ * @returns Promise resolving to the transactions, oldest first
+ */
+export async function getCurrencyHistory(
+  options: GetCurrencyHistoryOptions = {},
+): Promise<GetCurrencyHistoryResponse> {
+  return await invoke<GetCurrencyHistoryResponse>(
+    "plugin:iap|get_currency_history",
+    {
+      payload: options,
+    },
+  );
+}
+
+/**
+ * Read the local purchase ledger, e.g. to investigate a purchase a user says
+ * they did not receive.
+ *
+ * @param options - Filters by product, date range and entry kind
+ * @returns Promise resolving to the matching entries, oldest first
+ * @example
+ * ```typescript
+ * const { entries } = await queryLedger({
+ *   productId: 'com.example.coins',
+ *   from: Date.now() - 7 * 24 * 60 * 60 * 1000,
+ * });
+ * ```
+ */
+export async function queryLedger(
+  options: QueryLedgerOptions = {},
+): Promise<QueryLedgerResponse> {
+  return await invoke<QueryLedgerResponse>("plugin:iap|query_ledger", {
+    payload: options,
+  });
+}
+
+/**
+ * Listen to a plugin event and ask the plugin for the events it held back until now.
+ * The plugin holds the purchases replayed on `initialize` until the app listens for
+ * them, so none are lost between launch and listener registration.
+ */
+function listenToEvent<T>(
+  name: string,
+  callback: (payload: T) => void,
+): () => void {
+  const unlisten = listen<T>(name, (event) => {
+    callback(event.payload);
+  });
+  unlisten.then(() =>
+    invoke("plugin:iap|subscribe_events", { payload: { events: [name] } }),
+  );
+
+  return () => {
+    unlisten.then((fn: () => void) => fn());
+  };
+}
+
 /**
  * Listen for purchase updates.
  * This event is triggered when a purchase state changes.
@@ -344,11 +1209,131 @@ export async function getProductStatus(
 export function onPurchaseUpdated(
   callback: (purchase: Purchase) => void,
 ): () => void {
-  const unlisten = listen<Purchase>("purchaseUpdated", (event) => {
-    callback(event.payload);
-  });
+  return listenToEvent<Purchase>("purchaseUpdated", callback);
+}
 
-  return () => {
-    unlisten.then((fn: () => void) => fn());
-  };
+/**
+ * Listen for queued acknowledge and consume calls that finally went through.
+ *
+ * @param callback - Function to call with the completed operation
+ * @returns Cleanup function to stop listening
+ */
+export function onPendingOperationSucceeded(
+  callback: (operation: PendingOperation) => void,
+): () => void {
+  return listenToEvent<PendingOperation>("pendingOperationSucceeded", callback);
+}
+
+/**
+ * Listen for queued acknowledge and consume calls that ran out of retries.
+ *
+ * @param callback - Function to call with the dropped operation
+ * @returns Cleanup function to stop listening
+ */
+export function onPendingOperationFailed(
+  callback: (operation: PendingOperation) => void,
+): () => void {
+  return listenToEvent<PendingOperation>("pendingOperationFailed", callback);
+}
+
+/**
+ * Listen for completed purchases that are still unacknowledged close to the store's
+ * refund deadline (Android, 3 days after purchase).
+ *
+ * @param callback - Function to call with the purchase and its deadline
+ * @returns Cleanup function to stop listening
+ * @example
+ * ```typescript
+ * const unsubscribe = onAcknowledgementDeadlineApproaching(async (pending) => {
+ *   await acknowledgePurchase(pending.purchaseToken);
+ * });
+ * ```
+ */
+export function onAcknowledgementDeadlineApproaching(
+  callback: (deadline: AcknowledgementDeadline) => void,
+): () => void {
+  return listenToEvent<AcknowledgementDeadline>(
+    "acknowledgementDeadlineApproaching",
+    callback,
+  );
+}
+
+/**
+ * Listen for pending purchases that completed.
+ *
+ * @param callback - Function to call with the pending purchase and the completed purchase
+ * @returns Cleanup function to stop listening
+ */
+export function onPendingPurchaseResolved(
+  callback: (outcome: PendingPurchaseOutcome) => void,
+): () => void {
+  return listenToEvent<PendingPurchaseOutcome>(
+    "pendingPurchaseResolved",
+    callback,
+  );
+}
+
+/**
+ * Listen for pending purchases that were canceled or declined.
+ *
+ * @param callback - Function to call with the pending purchase
+ * @returns Cleanup function to stop listening
+ */
+export function onPendingPurchaseFailed(
+  callback: (outcome: PendingPurchaseOutcome) => void,
+): () => void {
+  return listenToEvent<PendingPurchaseOutcome>(
+    "pendingPurchaseFailed",
+    callback,
+  );
+}
+
+/**
+ * Listen for changes of the connection to the store.
+ *
+ * @param callback - Function to call with the new connection status
+ * @returns Cleanup function to stop listening
+ */
+export function onBillingConnectionChanged(
+  callback: (status: ConnectionStatus) => void,
+): () => void {
+  return listenToEvent<ConnectionStatus>("billingConnectionChanged", callback);
+}
+
+/**
+ * Listen for entitlements that were refunded or revoked since the previous
+ * `restorePurchases` call.
+ *
+ * @param callback - Function to call with the revoked entitlement
+ * @returns Cleanup function to stop listening
+ */
+export function onPurchaseRevoked(
+  callback: (revocation: PurchaseRevocation) => void,
+): () => void {
+  return listenToEvent<PurchaseRevocation>("purchaseRevoked", callback);
+}
+
+/**
+ * Listen for consumable purchases credited to the virtual currency wallet.
+ *
+ * @param callback - Function to call with the credit transaction
+ * @returns Cleanup function to stop listening
+ */
+export function onCurrencyCredited(
+  callback: (transaction: CurrencyTransaction) => void,
+): () => void {
+  return listenToEvent<CurrencyTransaction>("currencyCredited", callback);
+}
+
+/**
+ * Listen for purchase flows that finished after their `purchaseAndFulfill`
+ * call returned, e.g. after a pending payment or a restart.
+ *
+ * @param callback - Function to call with the flow id and outcome
+ * @returns Cleanup function to stop listening
+ */
+export function onPurchaseFlowFinished(
+  callback: (finished: PurchaseFlowFinished) => void,
+): () => void {
+  return listenToEvent<PurchaseFlowFinished>("purchaseFlowFinished", callback);
 }
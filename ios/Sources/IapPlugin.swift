@@ -28,11 +28,24 @@ class AcknowledgePurchaseArgs: Decodable {
     let purchaseToken: String
 }
 
+class ConsumePurchaseArgs: Decodable {
+    let purchaseToken: String
+}
+
+class BeginRefundRequestArgs: Decodable {
+    let transactionId: String
+}
+
 class GetProductStatusArgs: Decodable {
     let productId: String
     let productType: String?
 }
 
+class GetProductsStatusArgs: Decodable {
+    let productIds: [String]
+    let productType: String?
+}
+
 enum PurchaseStateValue: Int {
     case purchased = 0
     case canceled = 1
@@ -111,29 +124,128 @@ class IapPlugin: Plugin, SKProductsRequestDelegate, SKPaymentTransactionObserver
         invoke.resolve(["success": true])
     }
     
+    @objc public func consumePurchase(_ invoke: Invoke) throws {
+        // Consumable transactions are finished as soon as they are delivered
+        invoke.resolve(["success": true])
+    }
+    
+    @objc public func getStoreCapabilities(_ invoke: Invoke) throws {
+        let features = ["subscriptions", "priceChangeConfirmation", "consumables", "offerCodes"]
+        
+        var capabilities: [String: Any] = [
+            "store": "appStore",
+            "canMakePayments": SKPaymentQueue.canMakePayments(),
+            "features": features
+        ]
+        if let storefront = SKPaymentQueue.default().storefront {
+            capabilities["storefront"] = storefront.countryCode
+        }
+        invoke.resolve(capabilities)
+    }
+    
+    @objc public func redeemOfferCode(_ invoke: Invoke) throws {
+        // The sheet cannot be prefilled, a `code` argument is ignored and the user enters it.
+        // Redeemed transactions arrive through the payment queue observer as purchaseUpdated
+        DispatchQueue.main.async {
+            SKPaymentQueue.default().presentCodeRedemptionSheet()
+            invoke.resolve(["presented": true])
+        }
+    }
+    
+    @objc public func beginRefundRequest(_ invoke: Invoke) throws {
+        let args = try invoke.parseArgs(BeginRefundRequestArgs.self)
+        
+        guard let transactionId = UInt64(args.transactionId) else {
+            invoke.reject("Invalid transaction id: \(args.transactionId)")
+            return
+        }
+        
+        // StoreKit 1 has no refund API, the StoreKit 2 transaction id is the same number
+        Task { @MainActor in
+            guard let scene = UIApplication.shared.connectedScenes
+                .first(where: { $0.activationState == .foregroundActive }) as? UIWindowScene else {
+                invoke.reject("No active window to present the refund sheet")
+                return
+            }
+            
+            do {
+                let status = try await Transaction.beginRefundRequest(for: transactionId, in: scene)
+                invoke.resolve(["status": status == .success ? "success" : "userCancelled"])
+            } catch {
+                invoke.reject("Refund request failed: \(error.localizedDescription)")
+            }
+        }
+    }
+    
+    @objc public func showManageSubscriptions(_ invoke: Invoke) throws {
+        // The App Store sheet lists all of the app's subscriptions, a productId is ignored
+        Task { @MainActor in
+            guard let scene = UIApplication.shared.connectedScenes
+                .first(where: { $0.activationState == .foregroundActive }) as? UIWindowScene else {
+                invoke.reject("No active window to present the subscriptions sheet")
+                return
+            }
+            
+            do {
+                try await AppStore.showManageSubscriptions(in: scene)
+                invoke.resolve(["presented": true])
+            } catch {
+                invoke.reject("Failed to show subscriptions: \(error.localizedDescription)")
+            }
+        }
+    }
+    
+    @objc public func getUnfinishedPurchases(_ invoke: Invoke) throws {
+        // Transactions still in the payment queue were never finished, e.g. because the app
+        // was closed during the purchase
+        let purchases = SKPaymentQueue.default().transactions
+            .filter { $0.transactionState == .purchased || $0.transactionState == .deferred }
+            .map { self.createPurchaseObject(from: $0) }
+        invoke.resolve(["purchases": purchases])
+    }
+    
     @objc public func getProductStatus(_ invoke: Invoke) throws {
         let args = try invoke.parseArgs(GetProductStatusArgs.self)
+        invoke.resolve(self.productStatus(for: args.productId, hasReceipt: self.hasReceipt()))
+    }
+    
+    @objc public func getProductsStatus(_ invoke: Invoke) throws {
+        let args = try invoke.parseArgs(GetProductsStatusArgs.self)
         
+        // Read the receipt once for all requested products
+        let hasReceipt = self.hasReceipt()
+        var statuses: [String: Any] = [:]
+        for productId in args.productIds {
+            statuses[productId] = self.productStatus(for: productId, hasReceipt: hasReceipt)
+        }
+        invoke.resolve(["statuses": statuses])
+    }
+    
+    private func hasReceipt() -> Bool {
+        guard let receiptURL = Bundle.main.appStoreReceiptURL else {
+            return false
+        }
+        return (try? Data(contentsOf: receiptURL)) != nil
+    }
+    
+    private func productStatus(for productId: String, hasReceipt: Bool) -> [String: Any] {
         // Check if product is owned by checking receipt
-        let receiptURL = Bundle.main.appStoreReceiptURL
-        if let receiptData = try? Data(contentsOf: receiptURL!) {
+        if hasReceipt {
             // Parse receipt to check product ownership
             // This is a simplified version - in practice you'd parse the receipt properly
-            let statusResult: [String: Any] = [
-                "productId": args.productId,
+            return [
+                "productId": productId,
                 "isOwned": false, // Would be determined by receipt parsing
                 "isAcknowledged": true,
                 "purchaseState": PurchaseStateValue.purchased.rawValue
             ]
-            invoke.resolve(statusResult)
         } else {
-            let statusResult: [String: Any] = [
-                "productId": args.productId,
+            return [
+                "productId": productId,
                 "isOwned": false,
                 "isAcknowledged": true,
                 "purchaseState": PurchaseStateValue.canceled.rawValue
             ]
-            invoke.resolve(statusResult)
         }
     }
     
@@ -175,7 +287,20 @@ class IapPlugin: Plugin, SKProductsRequestDelegate, SKPaymentTransactionObserver
                 SKPaymentQueue.default().add(payment)
             } else {
                 // This is a getProducts request
-                invoke.resolve(["products": productsArray])
+                let invalidProductIds = response.invalidProductIdentifiers
+                var failureReasons: [String: String] = [:]
+                for productId in invalidProductIds {
+                    failureReasons[productId] = "notFound"
+                }
+                var result: [String: Any] = [
+                    "products": productsArray,
+                    "invalidProductIds": invalidProductIds,
+                    "failureReasons": failureReasons
+                ]
+                if let storefront = SKPaymentQueue.default().storefront {
+                    result["storefront"] = storefront.countryCode
+                }
+                invoke.resolve(result)
                 self.pendingInvoke = nil
                 self.isPurchaseRequest = false
                 self.currentAppAccountToken = nil
@@ -219,7 +344,7 @@ class IapPlugin: Plugin, SKProductsRequestDelegate, SKPaymentTransactionObserver
                     if let error = transaction.error as? SKError {
                         switch error.code {
                         case .paymentCancelled:
-                            invoke.reject("Purchase cancelled by user")
+                            invoke.reject("Purchase cancelled by user", code: "userCanceled")
                         default:
                             invoke.reject("Purchase failed: \(error.localizedDescription)")
                         }
@@ -229,6 +354,9 @@ class IapPlugin: Plugin, SKProductsRequestDelegate, SKPaymentTransactionObserver
                     self.pendingInvoke = nil
                     self.isPurchaseRequest = false
                     self.currentAppAccountToken = nil
+                } else {
+                    // A deferred purchase that was declined
+                    self.trigger("purchaseUpdated", data: self.createPurchaseObject(from: transaction) as! JSObject)
                 }
                 
                 // Finish the transaction
@@ -248,14 +376,19 @@ class IapPlugin: Plugin, SKProductsRequestDelegate, SKPaymentTransactionObserver
                 SKPaymentQueue.default().finishTransaction(transaction)
                 
             case .deferred:
-                // Handle deferred purchase (e.g., Ask to Buy)
+                // Handle deferred purchase (e.g., Ask to Buy). It is resolved as pending,
+                // the approved purchase arrives later as a purchased transaction
+                let purchase = self.createPurchaseObject(from: transaction)
                 if let invoke = self.pendingInvoke {
-                    invoke.reject("Purchase is pending")
+                    invoke.resolve(purchase)
                     self.pendingInvoke = nil
                     self.isPurchaseRequest = false
                     self.currentAppAccountToken = nil
                 }
                 
+                // Emit event
+                self.trigger("purchaseUpdated", data: purchase as! JSObject)
+                
             case .purchasing:
                 // Transaction is being processed
                 break
@@ -288,6 +421,17 @@ class IapPlugin: Plugin, SKProductsRequestDelegate, SKPaymentTransactionObserver
     
     // MARK: - Helper Methods
     
+    private func purchaseStateValue(for state: SKPaymentTransactionState) -> PurchaseStateValue {
+        switch state {
+        case .purchased, .restored:
+            return .purchased
+        case .deferred, .purchasing:
+            return .pending
+        default:
+            return .canceled
+        }
+    }
+    
     private func createPurchaseObject(from transaction: SKPaymentTransaction) -> [String: Any] {
         return [
             "orderId": transaction.transactionIdentifier ?? "",
@@ -295,7 +439,7 @@ class IapPlugin: Plugin, SKProductsRequestDelegate, SKPaymentTransactionObserver
             "productId": transaction.payment.productIdentifier,
             "purchaseTime": Int(transaction.transactionDate?.timeIntervalSince1970 ?? 0 * 1000),
             "purchaseToken": transaction.transactionIdentifier ?? "",
-            "purchaseState": transaction.transactionState == .purchased ? 0 : 1,
+            "purchaseState": self.purchaseStateValue(for: transaction.transactionState).rawValue,
             "isAutoRenewing": false, // StoreKit 1 doesn't provide this info directly
             "isAcknowledged": false,
             "originalJson": "", // Not available in StoreKit 1
@@ -1,3 +1,4 @@
+import AppKit
 import StoreKit
 
 /// Block the current thread until the async operation finishes.
@@ -73,17 +74,19 @@ func getProductsAsync(productIds: RustVec<RustString>, productType: RustString)
                     
                     // Add introductory offer if available
                     if let introOffer = subscription.introductoryOffer {
+                        let isEligible = await subscription.isEligibleForIntroOffer
                         let offer: [String: Any] = [
                             "offerToken": "",  // macOS doesn't use offer tokens
                             "basePlanId": "",
                             "offerId": introOffer.id ?? "",
+                            "isEligible": isEligible,
                             "pricingPhases": [[
                                 "formattedPrice": introOffer.displayPrice,
                                 "priceCurrencyCode": getCurrencyCode(for: product),
-                                "priceAmountMicros": 0,  // Not available in StoreKit 2
+                                "priceAmountMicros": priceMicros(introOffer.price),
                                 "billingPeriod": formatSubscriptionPeriod(introOffer.period),
                                 "billingCycleCount": introOffer.periodCount,
-                                "recurrenceMode": 0
+                                "recurrenceMode": recurrenceMode(for: introOffer.paymentMode)
                             ]]
                         ]
                         subscriptionOffers.append(offer)
@@ -94,10 +97,11 @@ func getProductsAsync(productIds: RustVec<RustString>, productType: RustString)
                         "offerToken": "",
                         "basePlanId": "",
                         "offerId": "",
+                        "isEligible": true,
                         "pricingPhases": [[
                             "formattedPrice": product.displayPrice,
                             "priceCurrencyCode": getCurrencyCode(for: product),
-                            "priceAmountMicros": 0,
+                            "priceAmountMicros": priceMicros(product.price),
                             "billingPeriod": formatSubscriptionPeriod(subscription.subscriptionPeriod),
                             "billingCycleCount": 0,
                             "recurrenceMode": 1
@@ -115,7 +119,10 @@ func getProductsAsync(productIds: RustVec<RustString>, productType: RustString)
             productsArray.append(productDict)
         }
         
-        let json: [String: Any] = ["products": productsArray]
+        var json: [String: Any] = ["products": productsArray]
+        if let storefront = await Storefront.current {
+            json["storefront"] = storefront.countryCode
+        }
         if let jsonString = serializeToJSON(json) {
             return .Ok(RustString(jsonString))
         } else {
@@ -166,7 +173,25 @@ func purchaseAsync(productId: RustString, productType: RustString, offerToken: O
             return .Err(RustString("Purchase cancelled by user"))
             
         case .pending:
-            return .Err(RustString("Purchase is pending"))
+            // Ask to Buy or a payment that needs action. There is no transaction yet, the
+            // purchase shows up in the current entitlements once it is approved
+            let purchase: [String: Any] = [
+                "orderId": NSNull(),
+                "packageName": Bundle.main.bundleIdentifier ?? "",
+                "productId": id,
+                "purchaseTime": Int(Date().timeIntervalSince1970 * 1000),
+                "purchaseToken": "",
+                "purchaseState": 2,  // pending
+                "isAutoRenewing": false,
+                "isAcknowledged": false,
+                "originalJson": "",
+                "signature": ""
+            ]
+            if let jsonString = serializeToJSON(purchase) {
+                return .Ok(RustString(jsonString))
+            } else {
+                return .Err(RustString("Failed to serialize purchase"))
+            }
             
         @unknown default:
             return .Err(RustString("Unknown purchase result"))
@@ -242,6 +267,111 @@ public func acknowledgePurchase(purchaseToken: RustString) -> FFIResult {
     }
 }
 
+public func getStoreCapabilities() -> FFIResult {
+    blockOn {
+        await getStoreCapabilitiesAsync()
+    }
+}
+
+@MainActor
+func getStoreCapabilitiesAsync() async -> FFIResult {
+    var json: [String: Any] = [
+        "store": "appStore",
+        "canMakePayments": AppStore.canMakePayments,
+        "features": ["subscriptions", "consumables"]
+    ]
+    if let storefront = await Storefront.current {
+        json["storefront"] = storefront.countryCode
+    }
+    if let jsonString = serializeToJSON(json) {
+        return .Ok(RustString(jsonString))
+    } else {
+        return .Err(RustString("Failed to serialize JSON"))
+    }
+}
+
+public func beginRefundRequest(transactionId: RustString) -> FFIResult {
+    blockOn {
+        await beginRefundRequestAsync(transactionId: transactionId.as_str().toString())
+    }
+}
+
+@MainActor
+func beginRefundRequestAsync(transactionId: String) async -> FFIResult {
+    guard let id = UInt64(transactionId) else {
+        return .Err(RustString("Invalid transaction id: \(transactionId)"))
+    }
+    guard let window = NSApplication.shared.keyWindow ?? NSApplication.shared.mainWindow else {
+        return .Err(RustString("No window to present the refund sheet"))
+    }
+    
+    do {
+        let status = try await Transaction.beginRefundRequest(for: id, in: window)
+        let json: [String: Any] = ["status": status == .success ? "success" : "userCancelled"]
+        if let jsonString = serializeToJSON(json) {
+            return .Ok(RustString(jsonString))
+        } else {
+            return .Err(RustString("Failed to serialize JSON"))
+        }
+    } catch {
+        return .Err(RustString("Refund request failed: \(error.localizedDescription)"))
+    }
+}
+
+public func showManageSubscriptions() -> FFIResult {
+    // StoreKit has no subscription management sheet on macOS, the App Store page opens instead
+    let url = "https://apps.apple.com/account/subscriptions"
+    let presented = NSWorkspace.shared.open(URL(string: url)!)
+    
+    let json: [String: Any] = ["presented": presented, "url": url]
+    if let jsonString = serializeToJSON(json) {
+        return .Ok(RustString(jsonString))
+    } else {
+        return .Err(RustString("Failed to serialize JSON"))
+    }
+}
+
+public func getUnfinishedPurchases() -> FFIResult {
+    blockOn {
+        await getUnfinishedPurchasesAsync()
+    }
+}
+
+@MainActor
+func getUnfinishedPurchasesAsync() async -> FFIResult {
+    var purchases: [[String: Any]] = []
+    
+    // Transactions that completed while the app was not running or mid-flow
+    for await result in Transaction.unfinished {
+        guard case .verified(let transaction) = result,
+              let product = try? await Product.products(for: [transaction.productID]).first else {
+            continue
+        }
+        
+        purchases.append(await createPurchaseObject(from: transaction, product: product))
+        
+        // Acknowledgement is a no-op on Apple platforms, so nothing else would finish it
+        await transaction.finish()
+    }
+    
+    let json: [String: Any] = ["purchases": purchases]
+    if let jsonString = serializeToJSON(json) {
+        return .Ok(RustString(jsonString))
+    } else {
+        return .Err(RustString("Failed to serialize purchases"))
+    }
+}
+
+public func consumePurchase(purchaseToken: RustString) -> FFIResult {
+    // StoreKit finishes consumable transactions when they are delivered
+    let json: [String: Any] = ["success": true]
+    if let jsonString = serializeToJSON(json) {
+        return .Ok(RustString(jsonString))
+    } else {
+        return .Err(RustString("Failed to serialize JSON"))
+    }
+}
+
 public func getProductStatus(productId: RustString, productType: RustString) -> FFIResult {
     blockOn {
         await getProductStatusAsync(productId: productId, productType: productType)
@@ -251,76 +381,117 @@ public func getProductStatus(productId: RustString, productType: RustString) ->
 @MainActor
 func getProductStatusAsync(productId: RustString, productType: RustString) async -> FFIResult {
     let id = productId.as_str().toString()
+    let transactions = await currentEntitlements(for: [id])
+    let product = try? await Product.products(for: [id]).first
+    let statusResult = await productStatus(for: id, transaction: transactions[id], product: product)
     
-    var statusResult: [String: Any] = [
-        "productId": id,
-        "isOwned": false
-    ]
+    if let jsonString = serializeToJSON(statusResult) {
+        return .Ok(RustString(jsonString))
+    } else {
+        return .Err(RustString("Failed to serialize status"))
+    }
+}
+
+public func getProductsStatus(productIds: RustVec<RustString>, productType: RustString) -> FFIResult {
+    blockOn {
+        await getProductsStatusAsync(productIds: productIds, productType: productType)
+    }
+}
+
+@MainActor
+func getProductsStatusAsync(productIds: RustVec<RustString>, productType: RustString) async -> FFIResult {
+    let ids: [String] = productIds.map { $0.as_str().toString() }
     
-    // Check current entitlements for the specific product
+    // Scan the entitlements and load the products once for all requested ids
+    let transactions = await currentEntitlements(for: Set(ids))
+    var products: [String: Product] = [:]
+    if let fetched = try? await Product.products(for: ids) {
+        for product in fetched {
+            products[product.id] = product
+        }
+    }
+    
+    var statuses: [String: Any] = [:]
+    for id in ids {
+        statuses[id] = await productStatus(for: id, transaction: transactions[id], product: products[id])
+    }
+    
+    let json: [String: Any] = ["statuses": statuses]
+    if let jsonString = serializeToJSON(json) {
+        return .Ok(RustString(jsonString))
+    } else {
+        return .Err(RustString("Failed to serialize statuses"))
+    }
+}
+
+/// First verified entitlement of each requested product.
+private func currentEntitlements(for ids: Set<String>) async -> [String: Transaction] {
+    var transactions: [String: Transaction] = [:]
     for await result in Transaction.currentEntitlements {
         switch result {
         case .verified(let transaction):
-            if transaction.productID == id {
-                statusResult["isOwned"] = true
-                statusResult["purchaseTime"] = Int(transaction.purchaseDate.timeIntervalSince1970 * 1000)
-                statusResult["purchaseToken"] = String(transaction.id)
-                statusResult["isAcknowledged"] = true  // Always true on macOS
-                
-                // Check if expired/revoked
-                if let revocationDate = transaction.revocationDate {
-                    statusResult["purchaseState"] = 1  // canceled
-                    statusResult["isOwned"] = false
-                    statusResult["expirationTime"] = Int(revocationDate.timeIntervalSince1970 * 1000)
-                } else if let expirationDate = transaction.expirationDate {
-                    if expirationDate < Date() {
-                        statusResult["purchaseState"] = 1  // canceled
-                        statusResult["isOwned"] = false
-                    } else {
-                        statusResult["purchaseState"] = 0  // purchased
-                    }
-                    statusResult["expirationTime"] = Int(expirationDate.timeIntervalSince1970 * 1000)
-                } else {
-                    statusResult["purchaseState"] = 0  // purchased
-                }
-
-                // Check subscription renewal status if it's a subscription
-                if let product = try? await Product.products(for: [id]).first {
-                    if product.type == .autoRenewable {
-                        // Check subscription status
-                        if let statuses = try? await product.subscription?.status {
-                            for status in statuses {
-                                if status.state == .subscribed {
-                                    statusResult["isAutoRenewing"] = true
-                                } else if status.state == .expired {
-                                    statusResult["isAutoRenewing"] = false
-                                    statusResult["purchaseState"] = 1  // canceled
-                                    statusResult["isOwned"] = false
-                                } else if status.state == .inGracePeriod {
-                                    statusResult["isAutoRenewing"] = true
-                                    statusResult["purchaseState"] = 0  // purchased
-                                } else {
-                                    statusResult["isAutoRenewing"] = false
-                                }
-                                break
-                            }
-                        }
-                    }
-                }
-                
-                break
+            if ids.contains(transaction.productID) && transactions[transaction.productID] == nil {
+                transactions[transaction.productID] = transaction
             }
         case .unverified(_, _):
             // Skip unverified transactions
             continue
         }
     }
+    return transactions
+}
+
+private func productStatus(for id: String, transaction: Transaction?, product: Product?) async -> [String: Any] {
+    var statusResult: [String: Any] = [
+        "productId": id,
+        "isOwned": false
+    ]
     
-    if let jsonString = serializeToJSON(statusResult) {
-        return .Ok(RustString(jsonString))
+    guard let transaction = transaction else {
+        return statusResult
+    }
+    
+    statusResult["isOwned"] = true
+    statusResult["purchaseTime"] = Int(transaction.purchaseDate.timeIntervalSince1970 * 1000)
+    statusResult["purchaseToken"] = String(transaction.id)
+    statusResult["isAcknowledged"] = true  // Always true on macOS
+    
+    // Check if expired/revoked
+    if let revocationDate = transaction.revocationDate {
+        statusResult["purchaseState"] = 1  // canceled
+        statusResult["isOwned"] = false
+        statusResult["expirationTime"] = Int(revocationDate.timeIntervalSince1970 * 1000)
+    } else if let expirationDate = transaction.expirationDate {
+        if expirationDate < Date() {
+            statusResult["purchaseState"] = 1  // canceled
+            statusResult["isOwned"] = false
+        } else {
+            statusResult["purchaseState"] = 0  // purchased
+        }
+        statusResult["expirationTime"] = Int(expirationDate.timeIntervalSince1970 * 1000)
     } else {
-        return .Err(RustString("Failed to serialize status"))
+        statusResult["purchaseState"] = 0  // purchased
+    }
+
+    // Check subscription renewal status if it's a subscription
+    if let product = product, product.type == .autoRenewable {
+        if let statuses = try? await product.subscription?.status, let status = statuses.first {
+            if status.state == .subscribed {
+                statusResult["isAutoRenewing"] = true
+            } else if status.state == .expired {
+                statusResult["isAutoRenewing"] = false
+                statusResult["purchaseState"] = 1  // canceled
+                statusResult["isOwned"] = false
+            } else if status.state == .inGracePeriod {
+                statusResult["isAutoRenewing"] = true
+                statusResult["purchaseState"] = 0  // purchased
+            } else {
+                statusResult["isAutoRenewing"] = false
+            }
+        }
     }
+    
+    return statusResult
 }
 
 // MARK: - Helper Functions
@@ -340,6 +511,20 @@ private func formatSubscriptionPeriod(_ period: Product.SubscriptionPeriod) -> S
     }
 }
 
+private func priceMicros(_ price: Decimal) -> Int64 {
+    return NSDecimalNumber(decimal: price * 1_000_000).int64Value
+}
+
+/// Map an introductory payment mode onto Google Play's recurrence modes, which the Rust side classifies.
+private func recurrenceMode(for paymentMode: Product.SubscriptionOffer.PaymentMode) -> Int {
+    switch paymentMode {
+    case .payUpFront:
+        return 3  // non-recurring
+    default:
+        return 2  // finite recurring (free trial or pay as you go)
+    }
+}
+
 private func getCurrencyCode(for product: Product) -> String {
     if #available(macOS 13.0, *) {
         return product.priceFormatStyle.locale.currency?.identifier ?? ""
@@ -365,7 +550,7 @@ private func createPurchaseObject(from transaction: Transaction, product: Produc
         }
     }
     
-    return [
+    var purchase: [String: Any] = [
         "orderId": String(transaction.id),
         "packageName": Bundle.main.bundleIdentifier ?? "",
         "productId": transaction.productID,
@@ -377,4 +562,9 @@ private func createPurchaseObject(from transaction: Transaction, product: Produc
         "originalJson": "",      // Not available in StoreKit 2
         "signature": ""          // Not available in StoreKit 2
     ]
+    if let revocationDate = transaction.revocationDate {
+        purchase["revocationDate"] = Int(revocationDate.timeIntervalSince1970 * 1000)
+        purchase["revocationReason"] = transaction.revocationReason == .developerIssue ? "developerIssue" : "other"
+    }
+    return purchase
 }
@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+use tauri::{AppHandle, Manager, Runtime};
+
+use crate::events::{self, IapEvent};
+use crate::models::*;
+use crate::{now_millis, AcknowledgementPolicy, Config};
+
+/// Google Play refunds purchases that are not acknowledged within three days. The other
+/// stores acknowledge on their own and have no deadline.
+const DEADLINE_MS: Option<i64> = if cfg!(target_os = "android") {
+    Some(3 * 24 * 60 * 60 * 1000)
+} else {
+    None
+};
+
+struct TrackedPurchase {
+    product_id: String,
+    purchase_time: i64,
+    deadline: Option<i64>,
+    /// When the deadline warning is due, once scheduled.
+    warn_at: Option<i64>,
+    warned: bool,
+}
+
+/// Completed purchases that have not been acknowledged yet, by purchase token.
+///
+/// Deadline warnings are emitted by a single timer thread, started with the first warning
+/// that is not due yet. It sleeps until the earliest scheduled warning.
+#[derive(Default)]
+pub(crate) struct AcknowledgementTracker {
+    purchases: Mutex<HashMap<String, TrackedPurchase>>,
+    /// Wakes the timer thread when a warning is scheduled.
+    scheduled: Condvar,
+    timer_started: AtomicBool,
+}
+
+impl AcknowledgementTracker {
+    /// Start tracking a purchase. Returns `false` if it is already tracked.
+    fn track(&self, purchase: &Purchase) -> bool {
+        let mut purchases = self.purchases.lock().unwrap_or_else(|e| e.into_inner());
+        if purchases.contains_key(&purchase.purchase_token) {
+            return false;
+        }
+
+        purchases.insert(
+            purchase.purchase_token.clone(),
+            TrackedPurchase {
+                product_id: purchase.product_id.clone(),
+                purchase_time: purchase.purchase_time,
+                deadline: DEADLINE_MS.map(|deadline| purchase.purchase_time + deadline),
+                warn_at: None,
+                warned: false,
+            },
+        );
+        true
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, TrackedPurchase>> {
+        self.purchases.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Schedule the deadline warning of a tracked purchase. Returns `false` if it is no
+    /// longer tracked.
+    fn schedule(&self, purchase_token: &str, warn_at: i64) -> bool {
+        let mut purchases = self.lock();
+        let Some(tracked) = purchases.get_mut(purchase_token) else {
+            return false;
+        };
+        tracked.warn_at = Some(warn_at);
+        self.scheduled.notify_all();
+        true
+    }
+}
+
+/// Mark the purchases whose warning is due as warned, returning their deadlines.
+fn take_due(
+    purchases: &mut HashMap<String, TrackedPurchase>,
+    now: i64,
+) -> Vec<AcknowledgementDeadline> {
+    purchases
+        .iter_mut()
+        .filter(|(_, tracked)| !tracked.warned && tracked.warn_at.is_some_and(|at| at <= now))
+        .filter_map(|(purchase_token, tracked)| {
+            tracked.warned = true;
+            Some(AcknowledgementDeadline {
+                product_id: tracked.product_id.clone(),
+                purchase_token: purchase_token.clone(),
+                purchase_time: tracked.purchase_time,
+                deadline: tracked.deadline?,
+            })
+        })
+        .collect()
+}
+
+/// When the earliest warning that is still to come is due.
+fn next_warning(purchases: &HashMap<String, TrackedPurchase>) -> Option<i64> {
+    purchases
+        .values()
+        .filter(|tracked| !tracked.warned)
+        .filter_map(|tracked| tracked.warn_at)
+        .min()
+}
+
+/// Apply the acknowledgement policy to purchases reported by the store.
+///
+/// Completed, unacknowledged purchases are acknowledged under [`AcknowledgementPolicy::Auto`]
+/// once they pass verification, except those of a purchase flow, which acknowledges them
+/// after granting. Those that stay unacknowledged get an
+/// `acknowledgementDeadlineApproaching` event ahead of the platform deadline, unless the
+/// policy is [`AcknowledgementPolicy::Never`].
+pub(crate) fn observe<R: Runtime>(app: &AppHandle<R>, purchases: &[Purchase]) {
+    let config = app.state::<Config>().acknowledgement.clone();
+    if config.policy == AcknowledgementPolicy::Never {
+        return;
+    }
+
+    let tracker = app.state::<AcknowledgementTracker>();
+    for purchase in purchases {
+        if purchase.is_acknowledged
+            || purchase.purchase_state != PurchaseStateValue::Purchased as i32
+            || !tracker.track(purchase)
+        {
+            continue;
+        }
+
+        if config.policy == AcknowledgementPolicy::Auto
+            && !crate::flow::handles(app, purchase)
+            && crate::flow::verify(app, purchase).is_ok()
+        {
+            // A failed call is queued for retry, the deadline keeps being tracked meanwhile
+            let acknowledged =
+                crate::retry_queue::acknowledge(app, purchase.purchase_token.clone())
+                    .is_ok_and(|response| response.success);
+            if acknowledged {
+                continue;
+            }
+        }
+
+        if let Some(deadline) = DEADLINE_MS.map(|deadline| purchase.purchase_time + deadline) {
+            let warn_at = deadline - config.warn_before_secs as i64 * 1000;
+            schedule_warning(app, purchase.purchase_token.clone(), warn_at);
+        }
+    }
+}
+
+/// Stop tracking a purchase once it has been acknowledged or consumed.
+pub(crate) fn forget<R: Runtime>(app: &AppHandle<R>, purchase_token: &str) {
+    app.state::<AcknowledgementTracker>()
+        .purchases
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(purchase_token);
+}
+
+fn schedule_warning<R: Runtime>(app: &AppHandle<R>, purchase_token: String, warn_at: i64) {
+    let tracker = app.state::<AcknowledgementTracker>();
+    if !tracker.schedule(&purchase_token, warn_at) {
+        return;
+    }
+    if warn_at <= now_millis() {
+        emit_due(app);
+        return;
+    }
+
+    if !tracker.timer_started.swap(true, Ordering::AcqRel) {
+        let app = app.clone();
+        std::thread::spawn(move || run_timer(&app));
+    }
+}
+
+fn emit_due<R: Runtime>(app: &AppHandle<R>) {
+    let due = take_due(
+        &mut app.state::<AcknowledgementTracker>().lock(),
+        now_millis(),
+    );
+    for deadline in due {
+        events::emit(app, IapEvent::AcknowledgementDeadlineApproaching(deadline));
+    }
+}
+
+/// Emit the warnings as they come due, for as long as the app runs.
+fn run_timer<R: Runtime>(app: &AppHandle<R>) {
+    let tracker = app.state::<AcknowledgementTracker>();
+    loop {
+        emit_due(app);
+
+        let purchases = tracker.lock();
+        let wait = next_warning(&purchases).map(|at| at - now_millis());
+        // Woken early when a warning is scheduled, the loop then looks again
+        match wait {
+            Some(wait) if wait <= 0 => {}
+            Some(wait) => drop(
+                tracker
+                    .scheduled
+                    .wait_timeout(purchases, Duration::from_millis(wait as u64)),
+            ),
+            None => drop(tracker.scheduled.wait(purchases)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tauri::test::{mock_app, MockRuntime};
+    use tauri::{App, Listener};
+
+    use super::*;
+    use crate::events::EventBuffer;
+    use crate::flow::{PurchaseFlowSteps, PurchaseFlows};
+
+    struct Rejecting;
+
+    impl PurchaseFlowSteps for Rejecting {
+        fn verify(&self, _purchase: &Purchase) -> std::result::Result<(), String> {
+            Err("Bad signature".to_string())
+        }
+    }
+
+    /// An app without a store, so acknowledging a purchase would panic.
+    fn app(steps: Option<Arc<dyn PurchaseFlowSteps>>) -> App<MockRuntime> {
+        let app = mock_app();
+        let mut config = Config::default();
+        config.acknowledgement.policy = AcknowledgementPolicy::Auto;
+        app.manage(config);
+        app.manage(AcknowledgementTracker::default());
+        #[cfg(feature = "remote-validator")]
+        app.manage(crate::validator::Validation(None));
+        app.manage(PurchaseFlows::with_path(None, steps));
+        app
+    }
+
+    fn purchase() -> Purchase {
+        serde_json::from_value(serde_json::json!({
+            "orderId": "order",
+            "packageName": "app",
+            "productId": "coins",
+            "purchaseTime": now_millis(),
+            "purchaseToken": "token",
+            "purchaseState": 0,
+            "isAutoRenewing": false,
+            "isAcknowledged": false,
+            "originalJson": "",
+            "signature": "",
+        }))
+        .unwrap()
+    }
+
+    fn is_tracked(app: &App<MockRuntime>, purchase_token: &str) -> bool {
+        app.state::<AcknowledgementTracker>()
+            .purchases
+            .lock()
+            .unwrap()
+            .contains_key(purchase_token)
+    }
+
+    #[test]
+    fn auto_does_not_acknowledge_a_purchase_that_failed_verification() {
+        let app = app(Some(Arc::new(Rejecting)));
+        observe(app.handle(), &[purchase()]);
+        assert!(is_tracked(&app, "token"));
+    }
+
+    #[test]
+    fn auto_leaves_the_purchase_of_a_flow_to_the_flow() {
+        let app = app(None);
+        let request = serde_json::from_value(serde_json::json!({ "productId": "coins" })).unwrap();
+        app.state::<PurchaseFlows>()
+            .start("flow".to_string(), request, false);
+        observe(app.handle(), &[purchase()]);
+        assert!(is_tracked(&app, "token"));
+    }
+
+    /// A tracker with purchases whose deadline is an hour away, and the tokens of the warnings
+    /// it emits.
+    fn tracking(tokens: &[&str]) -> (App<MockRuntime>, Arc<Mutex<Vec<String>>>) {
+        let app = mock_app();
+        app.manage(EventBuffer::default());
+        let tracker = AcknowledgementTracker::default();
+        for token in tokens {
+            tracker.lock().insert(
+                token.to_string(),
+                TrackedPurchase {
+                    product_id: "coins".to_string(),
+                    purchase_time: now_millis(),
+                    deadline: Some(now_millis() + 60 * 60 * 1000),
+                    warn_at: None,
+                    warned: false,
+                },
+            );
+        }
+        app.manage(tracker);
+
+        let warned = Arc::new(Mutex::new(Vec::new()));
+        let listener = warned.clone();
+        app.listen_any("acknowledgementDeadlineApproaching", move |event| {
+            let deadline: AcknowledgementDeadline = serde_json::from_str(event.payload()).unwrap();
+            listener.lock().unwrap().push(deadline.purchase_token);
+        });
+        (app, warned)
+    }
+
+    #[test]
+    fn one_timer_emits_every_warning_when_due() {
+        let (app, warned) = tracking(&["late", "early", "due"]);
+        let now = now_millis();
+        schedule_warning(app.handle(), "late".to_string(), now + 300);
+        schedule_warning(app.handle(), "early".to_string(), now + 100);
+        schedule_warning(app.handle(), "due".to_string(), now - 1);
+        // Due warnings are emitted right away
+        assert_eq!(*warned.lock().unwrap(), vec!["due"]);
+
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(*warned.lock().unwrap(), vec!["due", "early"]);
+        std::thread::sleep(Duration::from_millis(250));
+        assert_eq!(*warned.lock().unwrap(), vec!["due", "early", "late"]);
+        assert!(app
+            .state::<AcknowledgementTracker>()
+            .timer_started
+            .load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn acknowledged_purchases_are_not_warned() {
+        let (app, warned) = tracking(&["acknowledged", "open"]);
+        let now = now_millis();
+        schedule_warning(app.handle(), "acknowledged".to_string(), now + 100);
+        schedule_warning(app.handle(), "open".to_string(), now + 100);
+        forget(app.handle(), "acknowledged");
+        // Not tracked, so nothing is scheduled
+        schedule_warning(app.handle(), "unknown".to_string(), now - 1);
+
+        std::thread::sleep(Duration::from_millis(300));
+        assert_eq!(*warned.lock().unwrap(), vec!["open"]);
+    }
+}
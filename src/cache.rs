@@ -0,0 +1,470 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Manager, Runtime};
+
+use crate::models::*;
+use crate::single_flight::SingleFlight;
+use crate::{now_millis, IapExt, ProductCacheConfig};
+
+const CACHE_FILE: &str = "iap-product-cache.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CacheKey {
+    product_id: String,
+    product_type: String,
+    storefront: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CacheEntry {
+    fetched_at: i64,
+    result: CachedProduct,
+}
+
+/// Invalid ids are cached too, so a typo does not hit the store on every call.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum CachedProduct {
+    Found(Product),
+    Invalid(ProductFailureReason),
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    /// Storefront of the most recent store response; entries of other storefronts are dropped.
+    storefront: Option<String>,
+    entries: HashMap<CacheKey, CacheEntry>,
+}
+
+/// On-disk form of [`CacheState`], since JSON maps only take string keys.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PersistedCache {
+    storefront: Option<String>,
+    entries: Vec<(CacheKey, CacheEntry)>,
+}
+
+enum Lookup {
+    Fresh(GetProductsResponse),
+    Stale(GetProductsResponse),
+    Miss,
+}
+
+/// Product cache behind the `get_products` command.
+pub(crate) struct ProductCache {
+    config: ProductCacheConfig,
+    path: Option<PathBuf>,
+    state: Mutex<CacheState>,
+    fetches: SingleFlight<(Vec<String>, String), GetProductsResponse>,
+}
+
+impl ProductCache {
+    pub fn new<R: Runtime>(app: &AppHandle<R>, config: ProductCacheConfig) -> Self {
+        let path = config
+            .persist
+            .then(|| app.path().app_cache_dir().ok())
+            .flatten()
+            .map(|dir| dir.join(CACHE_FILE));
+        Self::with_path(config, path)
+    }
+
+    pub(crate) fn with_path(config: ProductCacheConfig, path: Option<PathBuf>) -> Self {
+        // A missing or unreadable cache file just means starting cold
+        let state = path
+            .as_ref()
+            .and_then(|path| std::fs::read(path).ok())
+            .and_then(|bytes| serde_json::from_slice::<PersistedCache>(&bytes).ok())
+            .map(|persisted| CacheState {
+                storefront: persisted.storefront,
+                entries: persisted.entries.into_iter().collect(),
+            })
+            .unwrap_or_default();
+
+        Self {
+            config,
+            path,
+            state: Mutex::new(state),
+            fetches: SingleFlight::default(),
+        }
+    }
+
+    fn lookup(&self, product_ids: &[String], product_type: &str) -> Lookup {
+        let ttl = self.config.ttl_secs as i64 * 1000;
+        let max_age = ttl + self.config.stale_while_revalidate_secs as i64 * 1000;
+        let now = now_millis();
+
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let mut response = GetProductsResponse {
+            products: Vec::new(),
+            invalid_product_ids: Vec::new(),
+            failure_reasons: HashMap::new(),
+            storefront: state.storefront.clone(),
+        };
+        let mut is_stale = false;
+        let mut seen = HashSet::new();
+
+        for product_id in product_ids.iter().filter(|id| seen.insert(id.as_str())) {
+            let key = CacheKey {
+                product_id: product_id.clone(),
+                product_type: product_type.to_string(),
+                storefront: state.storefront.clone(),
+            };
+            let Some(entry) = state.entries.get(&key) else {
+                return Lookup::Miss;
+            };
+
+            let age = now - entry.fetched_at;
+            if age > max_age {
+                return Lookup::Miss;
+            }
+            is_stale |= age > ttl;
+
+            match &entry.result {
+                CachedProduct::Found(product) => response.products.push(product.clone()),
+                CachedProduct::Invalid(reason) => {
+                    response.invalid_product_ids.push(product_id.clone());
+                    response.failure_reasons.insert(product_id.clone(), *reason);
+                }
+            }
+        }
+
+        if is_stale {
+            Lookup::Stale(response)
+        } else {
+            Lookup::Fresh(response)
+        }
+    }
+
+    /// Query the store, sharing the call with identical requests already in flight.
+    fn fetch<R: Runtime>(
+        &self,
+        app: &AppHandle<R>,
+        product_ids: Vec<String>,
+        product_type: String,
+    ) -> crate::Result<GetProductsResponse> {
+        self.fetch_with(product_ids, product_type, |product_ids, product_type| {
+            app.iap().get_products(product_ids, product_type)
+        })
+    }
+
+    fn fetch_with(
+        &self,
+        product_ids: Vec<String>,
+        product_type: String,
+        query: impl FnOnce(Vec<String>, String) -> crate::Result<GetProductsResponse>,
+    ) -> crate::Result<GetProductsResponse> {
+        let mut request_key = product_ids.clone();
+        request_key.sort();
+        request_key.dedup();
+
+        self.fetches.run((request_key, product_type.clone()), || {
+            let response = query(product_ids, product_type.clone())?;
+            if self.config.ttl_secs > 0 {
+                self.store(&product_type, &response);
+            }
+            Ok(response)
+        })
+    }
+
+    fn store(&self, product_type: &str, response: &GetProductsResponse) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if response.storefront.is_some() && response.storefront != state.storefront {
+            state.storefront = response.storefront.clone();
+            state.entries.clear();
+        }
+
+        let fetched_at = now_millis();
+        let storefront = state.storefront.clone();
+        let key = |product_id: &str| CacheKey {
+            product_id: product_id.to_string(),
+            product_type: product_type.to_string(),
+            storefront: storefront.clone(),
+        };
+
+        for product in &response.products {
+            state.entries.insert(
+                key(&product.product_id),
+                CacheEntry {
+                    fetched_at,
+                    result: CachedProduct::Found(without_eligibility(product)),
+                },
+            );
+        }
+        // Eligibility belongs to the user asking, so it is never served from the cache
+        for (product_id, reason) in response
+            .failure_reasons
+            .iter()
+            .filter(|(_, reason)| **reason != ProductFailureReason::NotEligible)
+        {
+            state.entries.insert(
+                key(product_id),
+                CacheEntry {
+                    fetched_at,
+                    result: CachedProduct::Invalid(*reason),
+                },
+            );
+        }
+
+        self.persist(&state);
+    }
+
+    fn persist(&self, state: &CacheState) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        let persisted = PersistedCache {
+            storefront: state.storefront.clone(),
+            entries: state
+                .entries
+                .iter()
+                .map(|(key, entry)| (key.clone(), entry.clone()))
+                .collect(),
+        };
+
+        // The cache is an optimization, so failing to write it is not an error
+        if let Ok(json) = serde_json::to_vec(&persisted) {
+            if let Some(dir) = path.parent() {
+                let _ = std::fs::create_dir_all(dir);
+            }
+            let _ = std::fs::write(path, json);
+        }
+    }
+}
+
+/// A copy of `product` that claims every offer is available, since offer eligibility is
+/// specific to the user the store answered for.
+fn without_eligibility(product: &Product) -> Product {
+    let mut product = product.clone();
+    for offer in product.subscription_offer_details.iter_mut().flatten() {
+        offer.is_eligible = true;
+    }
+    product
+}
+
+/// `get_products` through the product cache.
+///
+/// Fresh entries are returned without asking the store. Stale entries within the
+/// stale-while-revalidate window are returned as well, while a background refresh updates them.
+pub(crate) fn get_products<R: Runtime>(
+    app: &AppHandle<R>,
+    product_ids: Vec<String>,
+    product_type: String,
+    force_refresh: bool,
+) -> crate::Result<GetProductsResponse> {
+    let cache = app.state::<ProductCache>();
+
+    if !force_refresh && cache.config.ttl_secs > 0 {
+        match cache.lookup(&product_ids, &product_type) {
+            Lookup::Fresh(response) => return Ok(response),
+            Lookup::Stale(response) => {
+                let app = app.clone();
+                tauri::async_runtime::spawn_blocking(move || {
+                    let _ = app
+                        .state::<ProductCache>()
+                        .fetch(&app, product_ids, product_type);
+                });
+                return Ok(response);
+            }
+            Lookup::Miss => {}
+        }
+    }
+
+    cache.fetch(app, product_ids, product_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    const TTL_SECS: u64 = 60;
+    const STALE_SECS: u64 = 600;
+
+    fn cache(path: Option<PathBuf>) -> ProductCache {
+        ProductCache::with_path(
+            ProductCacheConfig {
+                ttl_secs: TTL_SECS,
+                stale_while_revalidate_secs: STALE_SECS,
+                persist: path.is_some(),
+            },
+            path,
+        )
+    }
+
+    fn product(product_id: &str) -> Product {
+        Product {
+            product_id: product_id.to_string(),
+            title: String::new(),
+            description: String::new(),
+            product_type: "subs".to_string(),
+            formatted_price: None,
+            price_currency_code: None,
+            price_amount_micros: None,
+            subscription_offer_details: Some(vec![SubscriptionOffer {
+                offer_token: "trial".to_string(),
+                base_plan_id: "monthly".to_string(),
+                offer_id: Some("trial".to_string()),
+                pricing_phases: Vec::new(),
+                offer_kind: OfferKind::FreeTrial,
+                is_eligible: false,
+            }]),
+        }
+    }
+
+    fn response(storefront: Option<&str>, products: &[&str]) -> GetProductsResponse {
+        GetProductsResponse {
+            products: products.iter().map(|id| product(id)).collect(),
+            invalid_product_ids: Vec::new(),
+            failure_reasons: HashMap::new(),
+            storefront: storefront.map(str::to_string),
+        }
+    }
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    /// Pretend every entry was fetched `secs` seconds ago.
+    fn age(cache: &ProductCache, secs: u64) {
+        let mut state = cache.state.lock().unwrap();
+        for entry in state.entries.values_mut() {
+            entry.fetched_at = now_millis() - secs as i64 * 1000;
+        }
+    }
+
+    fn product_ids(lookup: Lookup) -> Option<(bool, Vec<String>)> {
+        let (is_stale, response) = match lookup {
+            Lookup::Fresh(response) => (false, response),
+            Lookup::Stale(response) => (true, response),
+            Lookup::Miss => return None,
+        };
+        let ids = response
+            .products
+            .into_iter()
+            .map(|product| product.product_id)
+            .collect();
+        Some((is_stale, ids))
+    }
+
+    #[test]
+    fn entries_are_fresh_until_the_ttl_expires() {
+        let cache = cache(None);
+        cache.store("subs", &response(None, &["monthly"]));
+
+        assert_eq!(
+            product_ids(cache.lookup(&ids(&["monthly"]), "subs")),
+            Some((false, ids(&["monthly"])))
+        );
+        assert!(product_ids(cache.lookup(&ids(&["monthly"]), "inapp")).is_none());
+        assert!(product_ids(cache.lookup(&ids(&["monthly", "yearly"]), "subs")).is_none());
+
+        age(&cache, TTL_SECS + 1);
+        assert_eq!(
+            product_ids(cache.lookup(&ids(&["monthly"]), "subs")),
+            Some((true, ids(&["monthly"])))
+        );
+    }
+
+    #[test]
+    fn stale_entries_are_served_until_the_revalidate_window_ends() {
+        let cache = cache(None);
+        cache.store("subs", &response(None, &["monthly"]));
+
+        age(&cache, TTL_SECS + STALE_SECS - 1);
+        assert_eq!(
+            product_ids(cache.lookup(&ids(&["monthly"]), "subs")),
+            Some((true, ids(&["monthly"])))
+        );
+
+        age(&cache, TTL_SECS + STALE_SECS + 1);
+        assert!(product_ids(cache.lookup(&ids(&["monthly"]), "subs")).is_none());
+    }
+
+    #[test]
+    fn a_new_storefront_drops_the_entries_of_the_old_one() {
+        let cache = cache(None);
+        cache.store("subs", &response(Some("USA"), &["monthly"]));
+        cache.store("subs", &response(None, &["yearly"]));
+
+        let lookup = cache.lookup(&ids(&["monthly", "yearly"]), "subs");
+        assert!(
+            matches!(&lookup, Lookup::Fresh(response) if response.storefront.as_deref() == Some("USA"))
+        );
+
+        cache.store("subs", &response(Some("DEU"), &["yearly"]));
+        assert!(product_ids(cache.lookup(&ids(&["monthly"]), "subs")).is_none());
+        assert_eq!(
+            product_ids(cache.lookup(&ids(&["yearly"]), "subs")),
+            Some((false, ids(&["yearly"])))
+        );
+    }
+
+    #[test]
+    fn invalid_ids_are_cached_but_ineligible_ones_are_not() {
+        let cache = cache(None);
+        let queries = AtomicUsize::new(0);
+        let query = |_: Vec<String>, _: String| {
+            queries.fetch_add(1, Ordering::SeqCst);
+            let mut response = response(None, &[]);
+            response
+                .failure_reasons
+                .insert("typo".to_string(), ProductFailureReason::NotFound);
+            response
+                .failure_reasons
+                .insert("intro".to_string(), ProductFailureReason::NotEligible);
+            Ok(response)
+        };
+        cache
+            .fetch_with(ids(&["typo", "intro"]), "subs".to_string(), query)
+            .unwrap();
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+
+        let Lookup::Fresh(response) = cache.lookup(&ids(&["typo"]), "subs") else {
+            panic!("the invalid id was not cached");
+        };
+        assert_eq!(response.invalid_product_ids, ids(&["typo"]));
+        assert_eq!(
+            response.failure_reasons.get("typo"),
+            Some(&ProductFailureReason::NotFound)
+        );
+        assert!(product_ids(cache.lookup(&ids(&["intro"]), "subs")).is_none());
+    }
+
+    #[test]
+    fn cached_products_do_not_carry_eligibility_and_ids_are_deduplicated() {
+        let cache = cache(None);
+        cache.store("subs", &response(None, &["monthly"]));
+
+        let Lookup::Fresh(response) = cache.lookup(&ids(&["monthly", "monthly"]), "subs") else {
+            panic!("the product was not cached");
+        };
+        assert_eq!(response.products.len(), 1);
+        let offers = response.products[0]
+            .subscription_offer_details
+            .as_ref()
+            .unwrap();
+        assert!(offers.iter().all(|offer| offer.is_eligible));
+    }
+
+    #[test]
+    fn the_cache_survives_a_restart() {
+        let path = std::env::temp_dir().join(format!("iap-product-cache-{}.json", now_millis()));
+        cache(Some(path.clone())).store("subs", &response(Some("USA"), &["monthly"]));
+
+        let restarted = cache(Some(path.clone()));
+        let lookup = restarted.lookup(&ids(&["monthly"]), "subs");
+        assert!(
+            matches!(&lookup, Lookup::Fresh(response) if response.storefront.as_deref() == Some("USA"))
+        );
+        assert_eq!(product_ids(lookup), Some((false, ids(&["monthly"]))));
+
+        let _ = std::fs::remove_file(path);
+    }
+}
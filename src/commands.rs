@@ -1,11 +1,16 @@
-use tauri::{command, AppHandle, Runtime};
+use tauri::{command, AppHandle, Manager, Runtime};
 
+use crate::connection::with_reconnect;
 use crate::models::*;
-use crate::{IapExt, Result};
+use crate::{Config, IapExt, Result};
 
 #[command]
 pub(crate) async fn initialize<R: Runtime>(app: AppHandle<R>) -> Result<InitializeResponse> {
-    app.iap().initialize()
+    // Connecting also retries the acknowledge and consume calls queued in earlier sessions
+    let response = crate::connection::connect(&app)?;
+    let startup_app = app.clone();
+    tauri::async_runtime::spawn_blocking(move || crate::updates::replay_unfinished(&startup_app));
+    Ok(response)
 }
 
 #[command]
@@ -13,8 +18,14 @@ pub(crate) async fn get_products<R: Runtime>(
     app: AppHandle<R>,
     payload: GetProductsRequest,
 ) -> Result<GetProductsResponse> {
-    app.iap()
-        .get_products(payload.product_ids, payload.product_type)
+    with_reconnect(&app, || {
+        crate::cache::get_products(
+            &app,
+            payload.product_ids.clone(),
+            payload.product_type.clone(),
+            payload.force_refresh,
+        )
+    })
 }
 
 #[command]
@@ -22,8 +33,15 @@ pub(crate) async fn purchase<R: Runtime>(
     app: AppHandle<R>,
     payload: PurchaseRequest,
 ) -> Result<Purchase> {
-    app.iap()
-        .purchase(payload.product_id, payload.product_type, payload.options)
+    crate::flow::purchase(&app, payload)
+}
+
+#[command]
+pub(crate) async fn purchase_and_fulfill<R: Runtime>(
+    app: AppHandle<R>,
+    payload: PurchaseAndFulfillRequest,
+) -> Result<PurchaseFlowOutcome> {
+    Ok(crate::flow::purchase_and_fulfill(&app, payload))
 }
 
 #[command]
@@ -31,7 +49,16 @@ pub(crate) async fn restore_purchases<R: Runtime>(
     app: AppHandle<R>,
     payload: RestorePurchasesRequest,
 ) -> Result<RestorePurchasesResponse> {
-    app.iap().restore_purchases(payload.product_type)
+    let mut response = with_reconnect(&app, || {
+        app.iap().restore_purchases(payload.product_type.clone())
+    })?;
+    crate::ledger::record_restore(&app, &payload.product_type, &response.purchases);
+    crate::pending::reconcile(&app, &payload.product_type, &response.purchases);
+    crate::acknowledgement::observe(&app, &response.purchases);
+    crate::revocation::check(&app, &payload.product_type, &response.purchases);
+    crate::currency::reconcile(&app, &response.purchases);
+    crate::delivery::stamp(&app, &mut response.purchases);
+    Ok(response)
 }
 
 #[command]
@@ -39,7 +66,15 @@ pub(crate) async fn acknowledge_purchase<R: Runtime>(
     app: AppHandle<R>,
     payload: AcknowledgePurchaseRequest,
 ) -> Result<AcknowledgePurchaseResponse> {
-    app.iap().acknowledge_purchase(payload.purchase_token)
+    crate::retry_queue::acknowledge(&app, payload.purchase_token)
+}
+
+#[command]
+pub(crate) async fn consume_purchase<R: Runtime>(
+    app: AppHandle<R>,
+    payload: ConsumePurchaseRequest,
+) -> Result<ConsumePurchaseResponse> {
+    crate::retry_queue::consume(&app, payload.purchase_token)
 }
 
 #[command]
@@ -47,6 +82,209 @@ pub(crate) async fn get_product_status<R: Runtime>(
     app: AppHandle<R>,
     payload: GetProductStatusRequest,
 ) -> Result<ProductStatus> {
-    app.iap()
-        .get_product_status(payload.product_id, payload.product_type)
+    with_reconnect(&app, || {
+        app.iap()
+            .get_product_status(payload.product_id.clone(), payload.product_type.clone())
+    })
+}
+
+#[command]
+pub(crate) async fn get_products_status<R: Runtime>(
+    app: AppHandle<R>,
+    payload: GetProductsStatusRequest,
+) -> Result<GetProductsStatusResponse> {
+    let product_ids = payload
+        .product_ids
+        .unwrap_or_else(|| app.state::<Config>().catalog_ids(&payload.product_type));
+    with_reconnect(&app, || {
+        app.iap()
+            .get_products_status(product_ids.clone(), payload.product_type.clone())
+    })
+}
+
+#[command]
+pub(crate) async fn get_in_flight_purchases<R: Runtime>(
+    app: AppHandle<R>,
+) -> Result<GetInFlightPurchasesResponse> {
+    Ok(GetInFlightPurchasesResponse {
+        purchases: app.state::<crate::in_flight::InFlightPurchases>().list(),
+    })
+}
+
+#[command]
+pub(crate) async fn cancel_purchase<R: Runtime>(
+    app: AppHandle<R>,
+    payload: CancelPurchaseRequest,
+) -> Result<CancelPurchaseResponse> {
+    Ok(CancelPurchaseResponse {
+        success: app
+            .state::<crate::in_flight::InFlightPurchases>()
+            .cancel(&payload.request_id),
+    })
+}
+
+#[command]
+pub(crate) async fn get_pending_operations<R: Runtime>(
+    app: AppHandle<R>,
+) -> Result<GetPendingOperationsResponse> {
+    Ok(GetPendingOperationsResponse {
+        operations: app.state::<crate::retry_queue::RetryQueue>().list(),
+    })
+}
+
+#[command]
+pub(crate) async fn retry_pending_operations<R: Runtime>(
+    app: AppHandle<R>,
+) -> Result<GetPendingOperationsResponse> {
+    crate::retry_queue::retry(&app, false);
+    Ok(GetPendingOperationsResponse {
+        operations: app.state::<crate::retry_queue::RetryQueue>().list(),
+    })
+}
+
+#[command]
+pub(crate) async fn get_pending_purchases<R: Runtime>(
+    app: AppHandle<R>,
+) -> Result<GetPendingPurchasesResponse> {
+    Ok(GetPendingPurchasesResponse {
+        purchases: app.state::<crate::pending::PendingPurchases>().list(),
+    })
+}
+
+#[command]
+pub(crate) async fn subscribe_events<R: Runtime>(
+    app: AppHandle<R>,
+    payload: SubscribeEventsRequest,
+) -> Result<()> {
+    crate::events::subscribe(&app, payload.events);
+    Ok(())
+}
+
+#[command]
+pub(crate) async fn get_connection_state<R: Runtime>(
+    app: AppHandle<R>,
+) -> Result<ConnectionStatus> {
+    Ok(app.state::<crate::connection::Connection>().status())
+}
+
+#[command]
+pub(crate) async fn redeem_offer_code<R: Runtime>(
+    app: AppHandle<R>,
+    payload: RedeemOfferCodeRequest,
+) -> Result<RedeemOfferCodeResponse> {
+    with_reconnect(&app, || app.iap().redeem_offer_code(payload.code.clone()))
+}
+
+#[command]
+pub(crate) async fn begin_refund_request<R: Runtime>(
+    app: AppHandle<R>,
+    payload: BeginRefundRequestRequest,
+) -> Result<BeginRefundRequestResponse> {
+    let response = app
+        .iap()
+        .begin_refund_request(payload.transaction_id.clone())?;
+    crate::ledger::record_refund_request(&app, &payload.transaction_id, &response);
+    Ok(response)
+}
+
+#[command]
+pub(crate) async fn show_manage_subscriptions<R: Runtime>(
+    app: AppHandle<R>,
+    payload: ShowManageSubscriptionsRequest,
+) -> Result<ShowManageSubscriptionsResponse> {
+    app.iap().show_manage_subscriptions(payload.product_id)
+}
+
+#[command]
+pub(crate) async fn get_store_capabilities<R: Runtime>(
+    app: AppHandle<R>,
+) -> Result<StoreCapabilities> {
+    with_reconnect(&app, || app.iap().get_store_capabilities())
+}
+
+#[command]
+pub(crate) async fn query_ledger<R: Runtime>(
+    app: AppHandle<R>,
+    payload: QueryLedgerRequest,
+) -> Result<QueryLedgerResponse> {
+    Ok(QueryLedgerResponse {
+        entries: app.state::<crate::ledger::Ledger>().query(&payload),
+    })
+}
+
+#[command]
+pub(crate) async fn mark_fulfilled<R: Runtime>(
+    app: AppHandle<R>,
+    payload: MarkFulfilledRequest,
+) -> Result<MarkFulfilledResponse> {
+    Ok(MarkFulfilledResponse {
+        success: crate::delivery::mark_fulfilled(&app, &payload.purchase_token),
+    })
+}
+
+#[command]
+pub(crate) async fn get_currency_balance<R: Runtime>(
+    app: AppHandle<R>,
+) -> Result<GetCurrencyBalanceResponse> {
+    Ok(GetCurrencyBalanceResponse {
+        balances: app.state::<crate::currency::Wallet>().balances(),
+    })
+}
+
+#[command]
+pub(crate) async fn spend_currency<R: Runtime>(
+    app: AppHandle<R>,
+    payload: SpendCurrencyRequest,
+) -> Result<CurrencyTransaction> {
+    crate::currency::spend(&app, payload)
+}
+
+#[command]
+pub(crate) async fn get_currency_history<R: Runtime>(
+    app: AppHandle<R>,
+    payload: GetCurrencyHistoryRequest,
+) -> Result<GetCurrencyHistoryResponse> {
+    Ok(GetCurrencyHistoryResponse {
+        transactions: app.state::<crate::currency::Wallet>().history(&payload),
+    })
+}
+
+#[command]
+pub(crate) async fn validate_purchase<R: Runtime>(
+    app: AppHandle<R>,
+    payload: ValidatePurchaseRequest,
+) -> Result<ValidationVerdict> {
+    #[cfg(feature = "remote-validator")]
+    let verdict = crate::validator::validate(&app, &payload.purchase);
+    #[cfg(not(feature = "remote-validator"))]
+    let verdict = {
+        let _ = (app, payload);
+        None
+    };
+    verdict.unwrap_or_else(|| {
+        Err(crate::Error::rejected(
+            "notConfigured",
+            "No validation server is configured",
+        ))
+    })
+}
+
+#[command]
+pub(crate) async fn verify_entitlement_token<R: Runtime>(
+    app: AppHandle<R>,
+    payload: VerifyEntitlementTokenRequest,
+) -> Result<EntitlementClaims> {
+    #[cfg(feature = "entitlement-tokens")]
+    let claims = crate::entitlement_token::verify(&app, &payload.token);
+    #[cfg(not(feature = "entitlement-tokens"))]
+    let claims = {
+        let _ = (app, payload);
+        None
+    };
+    claims.unwrap_or_else(|| {
+        Err(crate::Error::rejected(
+            "notConfigured",
+            "No entitlement token keys are configured",
+        ))
+    })
 }
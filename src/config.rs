@@ -0,0 +1,389 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Plugin configuration, read from `plugins.iap` in `tauri.conf.json`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Config {
+    /// Products the app sells. Commands that accept an optional list of product ids fall
+    /// back to these.
+    #[serde(default)]
+    pub catalog: Vec<CatalogProduct>,
+    #[serde(default)]
+    pub product_cache: ProductCacheConfig,
+    /// What a purchase of a product that is already being purchased for the same account does.
+    #[serde(default)]
+    pub duplicate_purchase: DuplicatePurchasePolicy,
+    #[serde(default)]
+    pub acknowledgement: AcknowledgementConfig,
+    #[serde(default)]
+    pub currency: CurrencyConfig,
+    /// Server that validates purchases before `purchase_and_fulfill` grants them. Needs the
+    /// `remote-validator` feature.
+    #[serde(default)]
+    pub validation: Option<ValidationConfig>,
+    /// Keys of the entitlement tokens the verification server issues. Needs the
+    /// `entitlement-tokens` feature.
+    #[serde(default)]
+    pub entitlement_tokens: Option<EntitlementTokenConfig>,
+    /// Steam microtransactions on Linux. Needs the `steam` feature.
+    #[serde(default)]
+    pub steam: Option<SteamConfig>,
+    /// Stripe Checkout on Linux builds distributed outside the stores. Needs the `stripe`
+    /// feature.
+    #[serde(default)]
+    pub stripe: Option<StripeConfig>,
+    /// The in-memory store of the `mock` feature, for development and tests.
+    #[serde(default)]
+    pub mock: Option<MockConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CatalogProduct {
+    pub product_id: String,
+    #[serde(default = "default_product_type")]
+    pub product_type: String,
+}
+
+fn default_product_type() -> String {
+    "subs".to_string()
+}
+
+impl Config {
+    /// Ids of the catalog products of the given type.
+    pub fn catalog_ids(&self, product_type: &str) -> Vec<String> {
+        self.catalog
+            .iter()
+            .filter(|product| product.product_type == product_type)
+            .map(|product| product.product_id.clone())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DuplicatePurchasePolicy {
+    /// Wait for the running purchase flow and return its result.
+    #[default]
+    Join,
+    /// Fail right away with a `purchaseInProgress` error.
+    Reject,
+}
+
+/// What happens to completed purchases that the store still needs acknowledged.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AcknowledgementConfig {
+    #[serde(default)]
+    pub policy: AcknowledgementPolicy,
+    /// Seconds before the platform deadline at which `acknowledgementDeadlineApproaching` is
+    /// emitted for a purchase that is still unacknowledged.
+    #[serde(default = "default_deadline_warning")]
+    pub warn_before_secs: u64,
+}
+
+impl Default for AcknowledgementConfig {
+    fn default() -> Self {
+        Self {
+            policy: AcknowledgementPolicy::default(),
+            warn_before_secs: default_deadline_warning(),
+        }
+    }
+}
+
+fn default_deadline_warning() -> u64 {
+    24 * 60 * 60
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AcknowledgementPolicy {
+    /// Acknowledge every purchase the store reports as completed once it passed the validator
+    /// and the [`PurchaseFlowSteps::verify`](crate::PurchaseFlowSteps::verify) step, if any.
+    /// Purchases of a purchase flow are acknowledged by the flow after granting.
+    ///
+    /// Without a validator and without a `verify` step, purchases are acknowledged as the
+    /// store reports them, without any verification.
+    Auto,
+    /// Leave acknowledgement to the app and do not track deadlines.
+    Never,
+    /// Leave acknowledgement to the app, but warn before a purchase would be refunded.
+    #[default]
+    Manual,
+}
+
+/// Virtual currency credited for consumable purchases.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CurrencyConfig {
+    /// Consumable product ids and what a purchase of them credits. Purchases of these
+    /// products are credited once per purchase token and then consumed.
+    #[serde(default)]
+    pub catalog: HashMap<String, CurrencyGrant>,
+    /// Key the wallet files are signed with (HMAC-SHA256). A wallet whose signature does not
+    /// match is set aside and the balances start over, without crediting any purchase again.
+    #[serde(default)]
+    pub hmac_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CurrencyGrant {
+    pub currency: String,
+    pub amount: u64,
+}
+
+/// Where and how purchases are sent for validation.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ValidationConfig {
+    /// URL the purchases are POSTed to.
+    pub url: String,
+    /// Headers sent with every request, e.g. `Authorization`.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Attempts after the first one on network errors, `429` and `5xx` responses.
+    #[serde(default = "default_validation_retries")]
+    pub retries: u32,
+    #[serde(default = "default_validation_timeout")]
+    pub timeout_secs: u64,
+}
+
+fn default_validation_retries() -> u32 {
+    2
+}
+
+fn default_validation_timeout() -> u64 {
+    10
+}
+
+/// Keys entitlement tokens are signed and verified with.
+///
+/// To rotate keys, add the new public key to `keys` everywhere tokens are verified, then
+/// sign with the new key, and drop the old public key once its last tokens expired.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EntitlementTokenConfig {
+    /// Key new tokens are signed with. Belongs in the config of the server only, never in
+    /// `tauri.conf.json`.
+    #[serde(default)]
+    pub signing_key: Option<EntitlementSigningKey>,
+    /// Keys tokens are accepted from.
+    #[serde(default)]
+    pub keys: Vec<EntitlementPublicKey>,
+    /// `iss` claim of issued tokens. When set, tokens from other issuers are rejected.
+    #[serde(default)]
+    pub issuer: Option<String>,
+    /// Seconds a token is valid for at most.
+    #[serde(default = "default_entitlement_token_ttl")]
+    pub ttl_secs: u64,
+}
+
+fn default_entitlement_token_ttl() -> u64 {
+    60 * 60
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EntitlementSigningKey {
+    pub kid: String,
+    /// Standard base64 of the PKCS#8 document of an Ed25519 private key.
+    pub private_key: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EntitlementPublicKey {
+    pub kid: String,
+    /// Unpadded base64url of the 32 byte Ed25519 public key.
+    pub public_key: String,
+}
+
+/// The Steam app and the items it sells through the ISteamMicroTxn Web API.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SteamConfig {
+    pub app_id: u32,
+    /// Base URL of the Web API: a proxy of the app's backend that adds the publisher key, or
+    /// a local stand-in in tests.
+    pub base_url: String,
+    /// Publisher Web API key. Only for the proxy, the app refuses to start with it.
+    #[serde(default)]
+    pub web_api_key: Option<String>,
+    /// Use `ISteamMicroTxnSandbox`, which charges nothing.
+    #[serde(default)]
+    pub sandbox: bool,
+    /// Item definitions by product id.
+    #[serde(default)]
+    pub items: HashMap<String, SteamItem>,
+    /// Currency of the prices when the user's wallet currency is unknown.
+    #[serde(default = "default_steam_currency")]
+    pub currency: String,
+    /// ISO 639-1 language of the item descriptions.
+    #[serde(default = "default_steam_language")]
+    pub language: String,
+    /// Seconds a purchase waits for the user to authorize it in the Steam overlay.
+    #[serde(default = "default_steam_authorization_timeout")]
+    pub authorization_timeout_secs: u64,
+}
+
+/// An item the app sells on Steam.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SteamItem {
+    /// The app's own id of the item, `itemid` in the Web API.
+    pub item_id: u32,
+    /// Shown in the Steam purchase dialog.
+    pub description: String,
+    #[serde(default)]
+    pub category: Option<String>,
+    /// Price in cents by currency, e.g. `{ "USD": 199 }`.
+    pub prices: HashMap<String, u64>,
+}
+
+fn default_steam_currency() -> String {
+    "USD".to_string()
+}
+
+fn default_steam_language() -> String {
+    "en".to_string()
+}
+
+fn default_steam_authorization_timeout() -> u64 {
+    5 * 60
+}
+
+/// The Stripe account and the prices the app sells through Stripe Checkout.
+///
+/// The app and its backend share this config: the app talks to the backend at
+/// `backend_url`, and the backend to Stripe with the `secret_key` through a
+/// `stripe::StripeCheckout`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StripeConfig {
+    /// URL of the app's backend that serves the routes of `stripe::StripeCheckout`. Only in
+    /// the app's config.
+    #[serde(default)]
+    pub backend_url: Option<String>,
+    /// Headers sent to the backend with every request, e.g. an API key of the app.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Base URL of the Stripe API the backend calls, e.g. stripe-mock in tests.
+    #[serde(default = "default_stripe_api_base")]
+    pub api_base: String,
+    /// Secret or restricted key. Belongs in the config of the backend only, never in
+    /// `tauri.conf.json`.
+    #[serde(default)]
+    pub secret_key: Option<String>,
+    /// Prices by product id.
+    #[serde(default)]
+    pub products: HashMap<String, StripeProduct>,
+    /// URL Checkout returns to, e.g. `myapp://checkout`, which the app's deep link handler
+    /// passes on to the plugin. Without it, Checkout returns to a loopback HTTP server.
+    #[serde(default)]
+    pub deep_link: Option<String>,
+    /// Let users enter promotion codes in Checkout. Read by the backend.
+    #[serde(default)]
+    pub allow_promotion_codes: bool,
+    /// Seconds a purchase waits for Checkout to return.
+    #[serde(default = "default_stripe_checkout_timeout")]
+    pub checkout_timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StripeProduct {
+    /// A one-time price for `inapp` products, a recurring one for `subs`. Only the backend
+    /// needs it.
+    #[serde(default)]
+    pub price_id: String,
+    #[serde(default = "default_product_type")]
+    pub product_type: String,
+}
+
+fn default_stripe_api_base() -> String {
+    "https://api.stripe.com".to_string()
+}
+
+fn default_stripe_checkout_timeout() -> u64 {
+    15 * 60
+}
+
+/// What the in-memory store of the `mock` feature sells.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MockConfig {
+    /// Products by product id. Other ids are reported as `notFound`.
+    #[serde(default)]
+    pub products: HashMap<String, MockProduct>,
+    /// Listed products reported as `notFound`, e.g. one that was removed from the store.
+    #[serde(default)]
+    pub invalid_product_ids: Vec<String>,
+    /// Listed products reported as `regionUnavailable`, as if not sold in the storefront.
+    #[serde(default)]
+    pub region_unavailable_ids: Vec<String>,
+    /// Storefront reported with the products, e.g. `USA`.
+    #[serde(default)]
+    pub storefront: Option<String>,
+    /// Offer codes by code, each redeeming the product it maps to once.
+    #[serde(default)]
+    pub offer_codes: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MockProduct {
+    #[serde(default = "default_product_type")]
+    pub product_type: String,
+    #[serde(default)]
+    pub title: String,
+    /// Price in millionths of `currency`, e.g. `1990000` for 1.99.
+    #[serde(default)]
+    pub price_amount_micros: i64,
+    #[serde(default = "default_mock_currency")]
+    pub currency: String,
+}
+
+fn default_mock_currency() -> String {
+    "USD".to_string()
+}
+
+/// How long `get_products` results are reused before the store is asked again.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProductCacheConfig {
+    /// Seconds a cached product is served as is. `0` disables the cache.
+    #[serde(default = "default_product_cache_ttl")]
+    pub ttl_secs: u64,
+    /// Seconds past the TTL during which a stale product is still served while it is
+    /// refreshed in the background.
+    #[serde(default = "default_stale_while_revalidate")]
+    pub stale_while_revalidate_secs: u64,
+    /// Keep the cache in the app cache directory so it survives restarts.
+    #[serde(default = "default_true")]
+    pub persist: bool,
+}
+
+impl Default for ProductCacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: default_product_cache_ttl(),
+            stale_while_revalidate_secs: default_stale_while_revalidate(),
+            persist: true,
+        }
+    }
+}
+
+fn default_product_cache_ttl() -> u64 {
+    5 * 60
+}
+
+fn default_stale_while_revalidate() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_true() -> bool {
+    true
+}
@@ -0,0 +1,355 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tauri::{AppHandle, Manager, Runtime};
+
+use crate::events::{self, IapEvent};
+use crate::models::*;
+use crate::{now_millis, IapExt};
+
+/// Connection attempts before the state turns [`ConnectionState::Failed`].
+const CONNECT_ATTEMPTS: u32 = 4;
+const BASE_DELAY_MS: u64 = 1_000;
+
+/// Error code the native plugins reject with when the store connection is not ready.
+const NOT_CONNECTED: &str = "notConnected";
+
+/// Error codes of connection attempts that may succeed when tried again. Other errors, e.g.
+/// a device without the store or the desktop stub, fail right away.
+const TRANSIENT_ERROR_CODES: &[&str] = &[NOT_CONNECTED, "serviceUnavailable", "networkError"];
+
+/// Connection to the store.
+///
+/// Only Google Play Billing has a connection that can drop. The other stores connect on the
+/// first `initialize` and stay connected.
+#[derive(Default)]
+pub(crate) struct Connection {
+    status: Mutex<ConnectionStatus>,
+    /// Held while connecting. Commands that waited for it find the store connected and
+    /// share the reconnect instead of connecting again.
+    connecting: Mutex<()>,
+}
+
+impl Connection {
+    pub fn status(&self) -> ConnectionStatus {
+        self.status
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Move to a new state. Returns the new status if the state changed.
+    fn transition(
+        &self,
+        state: ConnectionState,
+        error: Option<String>,
+    ) -> Option<ConnectionStatus> {
+        let mut status = self.status.lock().unwrap_or_else(|e| e.into_inner());
+        if status.state == state && status.last_error == error {
+            return None;
+        }
+
+        *status = ConnectionStatus {
+            state,
+            last_error: error,
+            updated_at: now_millis(),
+        };
+        Some(status.clone())
+    }
+}
+
+fn set_state<R: Runtime>(app: &AppHandle<R>, state: ConnectionState, error: Option<String>) {
+    if let Some(status) = app.state::<Connection>().transition(state, error) {
+        events::emit(app, IapEvent::BillingConnectionChanged(status));
+    }
+}
+
+/// Connect to the store, retrying transient errors with backoff (1, 2, 4 seconds) before
+/// giving up.
+///
+/// Operations queued while disconnected are retried once connected.
+pub(crate) fn connect<R: Runtime>(app: &AppHandle<R>) -> crate::Result<InitializeResponse> {
+    let response = {
+        let connection = app.state::<Connection>();
+        let _connecting = connection
+            .connecting
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        // Another command reconnected while this one waited
+        if connection.status().state == ConnectionState::Connected {
+            return Ok(InitializeResponse { success: true });
+        }
+        connect_with(app, || app.iap().initialize(), std::thread::sleep)?
+    };
+
+    let retry_app = app.clone();
+    tauri::async_runtime::spawn_blocking(move || crate::retry_queue::retry(&retry_app, false));
+    Ok(response)
+}
+
+/// The attempts of [`connect`], with the store call and the wait between attempts passed in.
+fn connect_with<R: Runtime>(
+    app: &AppHandle<R>,
+    mut initialize: impl FnMut() -> crate::Result<InitializeResponse>,
+    sleep: impl Fn(Duration),
+) -> crate::Result<InitializeResponse> {
+    set_state(app, ConnectionState::Connecting, None);
+    let mut attempt = 0;
+    loop {
+        match initialize() {
+            Ok(response) => {
+                set_state(app, ConnectionState::Connected, None);
+                return Ok(response);
+            }
+            Err(err) if attempt + 1 < CONNECT_ATTEMPTS && is_transient(&err) => {
+                sleep(Duration::from_millis(BASE_DELAY_MS << attempt));
+                attempt += 1;
+            }
+            Err(err) => {
+                set_state(app, ConnectionState::Failed, Some(err.to_string()));
+                return Err(err);
+            }
+        }
+    }
+}
+
+fn is_transient(err: &crate::Error) -> bool {
+    err.code()
+        .is_some_and(|code| TRANSIENT_ERROR_CODES.contains(&code))
+}
+
+/// Record that the store dropped the connection and reconnect in the background.
+#[cfg(mobile)]
+pub(crate) fn disconnected<R: Runtime>(app: &AppHandle<R>) {
+    set_state(app, ConnectionState::Disconnected, None);
+
+    let app = app.clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        let _ = connect(&app);
+    });
+}
+
+/// Run a store operation, reconnecting and running it once more if it failed because the
+/// connection was not ready.
+pub(crate) fn with_reconnect<R: Runtime, T>(
+    app: &AppHandle<R>,
+    operation: impl Fn() -> crate::Result<T>,
+) -> crate::Result<T> {
+    reconnecting(app, operation, || connect(app).map(drop))
+}
+
+/// [`with_reconnect`] with the reconnect passed in.
+fn reconnecting<R: Runtime, T>(
+    app: &AppHandle<R>,
+    operation: impl Fn() -> crate::Result<T>,
+    reconnect: impl FnOnce() -> crate::Result<()>,
+) -> crate::Result<T> {
+    match operation() {
+        Err(err) if err.code() == Some(NOT_CONNECTED) => {
+            set_state(app, ConnectionState::Disconnected, None);
+            reconnect()?;
+            operation()
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::sync::Arc;
+
+    use tauri::test::{mock_app, MockRuntime};
+    use tauri::{App, Listener};
+
+    use super::*;
+    use crate::events::EventBuffer;
+
+    fn app() -> App<MockRuntime> {
+        let app = mock_app();
+        app.manage(Connection::default());
+        app.manage(EventBuffer::default());
+        app
+    }
+
+    fn unavailable() -> crate::Error {
+        crate::Error::rejected("serviceUnavailable", "The store is not reachable")
+    }
+
+    #[test]
+    fn connect_backs_off_between_attempts() {
+        let app = app();
+        let calls = Cell::new(0);
+        let waits = RefCell::new(Vec::new());
+
+        let response = connect_with(
+            app.handle(),
+            || {
+                calls.set(calls.get() + 1);
+                if calls.get() < 3 {
+                    Err(unavailable())
+                } else {
+                    Ok(InitializeResponse { success: true })
+                }
+            },
+            |wait| waits.borrow_mut().push(wait.as_millis()),
+        );
+
+        assert!(response.is_ok());
+        assert_eq!(waits.into_inner(), vec![1_000, 2_000]);
+        let status = app.state::<Connection>().status();
+        assert_eq!(status.state, ConnectionState::Connected);
+        assert_eq!(status.last_error, None);
+    }
+
+    #[test]
+    fn connect_fails_after_four_attempts() {
+        let app = app();
+        let calls = Cell::new(0);
+        let waits = RefCell::new(Vec::new());
+
+        let err = connect_with(
+            app.handle(),
+            || {
+                calls.set(calls.get() + 1);
+                Err(unavailable())
+            },
+            |wait| waits.borrow_mut().push(wait.as_millis()),
+        )
+        .unwrap_err();
+
+        assert_eq!(err.code(), Some("serviceUnavailable"));
+        assert_eq!(calls.get(), 4);
+        assert_eq!(waits.into_inner(), vec![1_000, 2_000, 4_000]);
+        let status = app.state::<Connection>().status();
+        assert_eq!(status.state, ConnectionState::Failed);
+        assert!(status.last_error.is_some());
+    }
+
+    #[test]
+    fn permanent_errors_fail_without_retrying() {
+        let app = app();
+        let calls = Cell::new(0);
+
+        let err = connect_with(
+            app.handle(),
+            || {
+                calls.set(calls.get() + 1);
+                Err(crate::Error::rejected(
+                    "billingUnavailable",
+                    "Google Play is not installed",
+                ))
+            },
+            |_| panic!("waited before giving up on a permanent error"),
+        )
+        .unwrap_err();
+
+        assert_eq!(err.code(), Some("billingUnavailable"));
+        assert_eq!(calls.get(), 1);
+        assert_eq!(
+            app.state::<Connection>().status().state,
+            ConnectionState::Failed
+        );
+    }
+
+    #[test]
+    fn errors_without_a_code_fail_without_retrying() {
+        let app = app();
+        let calls = Cell::new(0);
+
+        let result = connect_with(
+            app.handle(),
+            || {
+                calls.set(calls.get() + 1);
+                Err(std::io::Error::other("IAP is not supported on this platform").into())
+            },
+            |_| panic!("waited before giving up on the desktop stub"),
+        );
+
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn retries_do_not_emit_a_change_per_attempt() {
+        let app = app();
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let listener = states.clone();
+        app.listen_any("billingConnectionChanged", move |event| {
+            let status: ConnectionStatus = serde_json::from_str(event.payload()).unwrap();
+            listener.lock().unwrap().push(status.state);
+        });
+
+        let _ = connect_with(app.handle(), || Err(unavailable()), |_| {});
+
+        assert_eq!(
+            *states.lock().unwrap(),
+            vec![ConnectionState::Connecting, ConnectionState::Failed]
+        );
+    }
+
+    #[test]
+    fn a_not_connected_error_reconnects_and_runs_once_more() {
+        let app = app();
+        let calls = Cell::new(0);
+        let reconnected = Cell::new(false);
+
+        let result = reconnecting(
+            app.handle(),
+            || {
+                calls.set(calls.get() + 1);
+                if reconnected.get() {
+                    Ok(calls.get())
+                } else {
+                    Err(crate::Error::rejected(NOT_CONNECTED, "Not connected"))
+                }
+            },
+            || {
+                reconnected.set(true);
+                Ok(())
+            },
+        );
+
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(
+            app.state::<Connection>().status().state,
+            ConnectionState::Disconnected
+        );
+    }
+
+    #[test]
+    fn other_errors_do_not_reconnect() {
+        let app = app();
+        let calls = Cell::new(0);
+
+        let result: crate::Result<()> = reconnecting(
+            app.handle(),
+            || {
+                calls.set(calls.get() + 1);
+                Err(unavailable())
+            },
+            || panic!("reconnected on an error other than notConnected"),
+        );
+
+        assert_eq!(result.unwrap_err().code(), Some("serviceUnavailable"));
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn a_failed_reconnect_does_not_run_the_operation_again() {
+        let app = app();
+        let calls = Cell::new(0);
+
+        let result: crate::Result<()> = reconnecting(
+            app.handle(),
+            || {
+                calls.set(calls.get() + 1);
+                Err(crate::Error::rejected(NOT_CONNECTED, "Not connected"))
+            },
+            || Err(unavailable()),
+        );
+
+        assert_eq!(result.unwrap_err().code(), Some("serviceUnavailable"));
+        assert_eq!(calls.get(), 1);
+    }
+}
@@ -0,0 +1,537 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Manager, Runtime};
+
+use crate::events::{self, IapEvent};
+use crate::hmac_sha256;
+use crate::models::*;
+use crate::retry_queue::RetryQueue;
+use crate::{now_millis, Config, CurrencyConfig, CurrencyGrant};
+
+const WALLET_FILE: &str = "iap-wallet.json";
+const CREDITS_FILE: &str = "iap-wallet-credits.json";
+
+/// History entries beyond this are dropped, oldest first. Credited purchase tokens are kept
+/// regardless, so dropping history never allows a second credit.
+const MAX_HISTORY: usize = 1000;
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WalletState {
+    balances: BTreeMap<String, u64>,
+    history: Vec<CurrencyTransaction>,
+}
+
+/// A wallet file as stored on disk: its JSON and, with an HMAC key configured, the signature
+/// of that JSON.
+#[derive(Deserialize, Serialize)]
+struct WalletFile {
+    wallet: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mac: Option<String>,
+}
+
+/// Virtual currency balances credited from consumable purchases, kept in the app data
+/// directory.
+///
+/// The credited purchase tokens live in a file of their own, so balances that start over
+/// after the wallet was edited or deleted never credit a purchase again.
+pub(crate) struct Wallet {
+    path: Option<PathBuf>,
+    credits_path: Option<PathBuf>,
+    key: Option<Vec<u8>>,
+    state: Mutex<WalletState>,
+    /// Purchase tokens that were credited.
+    credited: Mutex<BTreeSet<String>>,
+}
+
+impl Wallet {
+    pub fn new<R: Runtime>(app: &AppHandle<R>, config: &CurrencyConfig) -> Self {
+        let dir = app.path().app_data_dir().ok();
+        let wallet = Self::with_paths(
+            dir.as_ref().map(|dir| dir.join(WALLET_FILE)),
+            dir.as_ref().map(|dir| dir.join(CREDITS_FILE)),
+            config,
+        );
+
+        wallet.recover_credits(app, config);
+        wallet
+    }
+
+    pub(crate) fn with_paths(
+        path: Option<PathBuf>,
+        credits_path: Option<PathBuf>,
+        config: &CurrencyConfig,
+    ) -> Self {
+        let key = config.hmac_key.as_ref().map(|key| key.as_bytes().to_vec());
+        let state = path
+            .as_deref()
+            .and_then(|path| load(path, key.as_deref()))
+            .unwrap_or_default();
+        let credited = credits_path
+            .as_deref()
+            .and_then(|path| load(path, key.as_deref()))
+            .unwrap_or_default();
+
+        Self {
+            path,
+            credits_path,
+            key,
+            state: Mutex::new(state),
+            credited: Mutex::new(credited),
+        }
+    }
+
+    pub fn balances(&self) -> BTreeMap<String, u64> {
+        self.state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .balances
+            .clone()
+    }
+
+    /// Transactions matching the request, oldest first.
+    pub fn history(&self, request: &GetCurrencyHistoryRequest) -> Vec<CurrencyTransaction> {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let mut transactions: Vec<CurrencyTransaction> = state
+            .history
+            .iter()
+            .filter(|transaction| {
+                request
+                    .currency
+                    .as_ref()
+                    .map_or(true, |currency| &transaction.currency == currency)
+            })
+            .cloned()
+            .collect();
+
+        if let Some(limit) = request.limit {
+            let skip = transactions.len().saturating_sub(limit);
+            transactions.drain(..skip);
+        }
+        transactions
+    }
+
+    /// Rebuild credits that are gone or were edited from the ledger. Every completed purchase
+    /// of a currency product the ledger recorded counts as credited: losing the file must not
+    /// credit a purchase twice, at the cost of never crediting one that was still waiting for
+    /// the validator.
+    fn recover_credits<R: Runtime>(&self, app: &AppHandle<R>, config: &CurrencyConfig) {
+        let mut credited = self.credited.lock().unwrap_or_else(|e| e.into_inner());
+        if !credited.is_empty() {
+            return;
+        }
+
+        let tokens = ledger_credits(app, config);
+        if !tokens.is_empty() {
+            credited.extend(tokens);
+            self.persist_credits(&credited);
+        }
+    }
+
+    fn is_credited(&self, purchase_token: &str) -> bool {
+        self.credited
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains(purchase_token)
+    }
+
+    /// Credit a purchase. Returns `None` if its token was credited before.
+    fn credit(&self, purchase: &Purchase, grant: &CurrencyGrant) -> Option<CurrencyTransaction> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        {
+            let mut credited = self.credited.lock().unwrap_or_else(|e| e.into_inner());
+            if !credited.insert(purchase.purchase_token.clone()) {
+                return None;
+            }
+            // Saved before the balance, a crash in between loses the credit rather than
+            // crediting twice
+            self.persist_credits(&credited);
+        }
+
+        let balance = state.balances.entry(grant.currency.clone()).or_default();
+        *balance = balance.saturating_add(grant.amount);
+        let transaction = CurrencyTransaction {
+            currency: grant.currency.clone(),
+            amount: i64::try_from(grant.amount).unwrap_or(i64::MAX),
+            balance_after: *balance,
+            reason: None,
+            purchase_token: Some(purchase.purchase_token.clone()),
+            product_id: Some(purchase.product_id.clone()),
+            created_at: now_millis(),
+        };
+        self.record(&mut state, transaction.clone());
+        Some(transaction)
+    }
+
+    fn spend(
+        &self,
+        currency: &str,
+        amount: u64,
+        reason: Option<String>,
+    ) -> crate::Result<CurrencyTransaction> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let balance = state.balances.get(currency).copied().unwrap_or(0);
+        if amount > balance {
+            return Err(crate::Error::rejected(
+                "insufficientBalance",
+                format!("Cannot spend {amount} {currency}, the balance is {balance}"),
+            ));
+        }
+
+        state
+            .balances
+            .insert(currency.to_string(), balance - amount);
+        let transaction = CurrencyTransaction {
+            currency: currency.to_string(),
+            amount: -i64::try_from(amount).unwrap_or(i64::MAX),
+            balance_after: balance - amount,
+            reason,
+            purchase_token: None,
+            product_id: None,
+            created_at: now_millis(),
+        };
+        self.record(&mut state, transaction.clone());
+        Ok(transaction)
+    }
+
+    fn record(&self, state: &mut WalletState, transaction: CurrencyTransaction) {
+        if state.history.len() == MAX_HISTORY {
+            state.history.remove(0);
+        }
+        state.history.push(transaction);
+        self.persist(state);
+    }
+
+    fn persist(&self, state: &WalletState) {
+        if let Some(path) = &self.path {
+            save(path, self.key.as_deref(), state);
+        }
+    }
+
+    fn persist_credits(&self, credited: &BTreeSet<String>) {
+        if let Some(path) = &self.credits_path {
+            save(path, self.key.as_deref(), credited);
+        }
+    }
+}
+
+/// Tokens of the completed purchases of currency products in the ledger.
+fn ledger_credits<R: Runtime>(app: &AppHandle<R>, config: &CurrencyConfig) -> Vec<String> {
+    let request = QueryLedgerRequest {
+        kinds: vec![LedgerEventKind::Purchase],
+        ..Default::default()
+    };
+    app.state::<crate::ledger::Ledger>()
+        .query(&request)
+        .into_iter()
+        .filter(|entry| {
+            entry
+                .product_id
+                .as_ref()
+                .is_some_and(|product_id| config.catalog.contains_key(product_id))
+                && entry.data["purchaseState"] == PurchaseStateValue::Purchased as i32
+        })
+        .filter_map(|entry| entry.purchase_token)
+        .filter(|token| !token.is_empty())
+        .collect()
+}
+
+fn save(path: &Path, key: Option<&[u8]>, value: &impl Serialize) {
+    let Ok(wallet) = serde_json::to_string(value) else {
+        return;
+    };
+
+    let mac = key.map(|key| hmac_sha256::hex(key, wallet.as_bytes()));
+    if let Ok(json) = serde_json::to_vec(&WalletFile { wallet, mac }) {
+        if let Some(dir) = path.parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+        let _ = std::fs::write(path, json);
+    }
+}
+
+/// Read a wallet file, checking its signature when a key is configured. A file that fails
+/// the check is moved aside, e.g. to `iap-wallet.json.tampered`, for support to look at.
+fn load<T: serde::de::DeserializeOwned>(path: &Path, key: Option<&[u8]>) -> Option<T> {
+    let bytes = std::fs::read(path).ok()?;
+    let file: WalletFile = serde_json::from_slice(&bytes).ok()?;
+
+    if let Some(key) = key {
+        let valid = file
+            .mac
+            .as_deref()
+            .is_some_and(|mac| hmac_sha256::verify(key, file.wallet.as_bytes(), mac));
+        if !valid {
+            let _ = std::fs::rename(path, path.with_extension("json.tampered"));
+            return None;
+        }
+    }
+
+    serde_json::from_str(&file.wallet).ok()
+}
+
+/// The catalog entry of a completed purchase, if it credits currency.
+fn grant<'a>(config: &'a Config, purchase: &Purchase) -> Option<&'a CurrencyGrant> {
+    if purchase.purchase_state != PurchaseStateValue::Purchased as i32
+        || purchase.purchase_token.is_empty()
+    {
+        return None;
+    }
+    config.currency.catalog.get(&purchase.product_id)
+}
+
+/// Credit completed purchases of currency products once they pass verification, and consume
+/// them.
+///
+/// This runs as the store reports a purchase, before a purchase flow verifies it, so purchases
+/// of a purchase flow are left to the flow, which credits them through [`credit`] after its
+/// verify step. The others are checked with the validator and the
+/// [`PurchaseFlowSteps::verify`](crate::PurchaseFlowSteps::verify) step here, like
+/// [`AcknowledgementPolicy::Auto`](crate::AcknowledgementPolicy::Auto) does. A purchase that
+/// fails verification is not credited; one the validator could not check is credited by a
+/// later `restore_purchases` once it passes.
+pub(crate) fn observe<R: Runtime>(app: &AppHandle<R>, purchases: &[Purchase]) {
+    for purchase in purchases {
+        let is_currency = grant(&app.state::<Config>(), purchase).is_some();
+        if is_currency
+            && !crate::flow::handles(app, purchase)
+            && crate::flow::verify(app, purchase).is_ok()
+        {
+            let _ = credit(app, purchase);
+        }
+    }
+}
+
+/// Credit a verified purchase of a currency product, unless it was credited before, and
+/// consume it. A failed consume is queued for retry, the credit stands either way.
+pub(crate) fn credit<R: Runtime>(app: &AppHandle<R>, purchase: &Purchase) -> crate::Result<()> {
+    let config = app.state::<Config>();
+    let Some(grant) = grant(&config, purchase) else {
+        return Ok(());
+    };
+    let Some(transaction) = app.state::<Wallet>().credit(purchase, grant) else {
+        return Ok(());
+    };
+
+    events::emit(app, IapEvent::CurrencyCredited(transaction));
+    crate::retry_queue::consume(app, purchase.purchase_token.clone()).map(drop)
+}
+
+/// Credit currency purchases from a `restore_purchases` listing, and consume the ones that
+/// were credited before but are still owned because their consume call never went through.
+pub(crate) fn reconcile<R: Runtime>(app: &AppHandle<R>, purchases: &[Purchase]) {
+    {
+        let config = app.state::<Config>();
+        let wallet = app.state::<Wallet>();
+        let queued = app.state::<RetryQueue>().list();
+        for purchase in purchases {
+            if grant(&config, purchase).is_none() || !wallet.is_credited(&purchase.purchase_token) {
+                continue;
+            }
+            let is_queued = queued.iter().any(|operation| {
+                operation.kind == PendingOperationKind::Consume
+                    && operation.purchase_token == purchase.purchase_token
+            });
+            if !is_queued {
+                let _ = crate::retry_queue::consume(app, purchase.purchase_token.clone());
+            }
+        }
+    }
+
+    observe(app, purchases);
+}
+
+pub(crate) fn spend<R: Runtime>(
+    app: &AppHandle<R>,
+    request: SpendCurrencyRequest,
+) -> crate::Result<CurrencyTransaction> {
+    app.state::<Wallet>()
+        .spend(&request.currency, request.amount, request.reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tauri::test::{mock_app, MockRuntime};
+    use tauri::{App, Listener};
+
+    use super::*;
+    use crate::events::EventBuffer;
+    use crate::flow::{PurchaseFlowSteps, PurchaseFlows};
+    use crate::ledger::Ledger;
+
+    fn config(hmac_key: Option<&str>) -> CurrencyConfig {
+        serde_json::from_value(serde_json::json!({
+            "catalog": { "coins_100": { "currency": "coins", "amount": 100 } },
+            "hmacKey": hmac_key,
+        }))
+        .unwrap()
+    }
+
+    fn purchase(token: &str) -> Purchase {
+        serde_json::from_value(serde_json::json!({
+            "orderId": "order",
+            "packageName": "app",
+            "productId": "coins_100",
+            "purchaseTime": now_millis(),
+            "purchaseToken": token,
+            "purchaseState": PurchaseStateValue::Purchased as i32,
+            "isAutoRenewing": false,
+            "isAcknowledged": false,
+            "originalJson": "",
+            "signature": "",
+        }))
+        .unwrap()
+    }
+
+    struct Dir(PathBuf);
+
+    impl Dir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("iap-{name}-{}", now_millis()));
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn wallet(&self, config: &CurrencyConfig) -> Wallet {
+            Wallet::with_paths(
+                Some(self.0.join(WALLET_FILE)),
+                Some(self.0.join(CREDITS_FILE)),
+                config,
+            )
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn a_purchase_is_credited_once() {
+        let config = config(None);
+        let grant = &config.catalog["coins_100"];
+        let dir = Dir::new("credit-once");
+        let wallet = dir.wallet(&config);
+
+        let credit = wallet.credit(&purchase("token"), grant).unwrap();
+        assert_eq!(credit.balance_after, 100);
+        assert!(wallet.credit(&purchase("token"), grant).is_none());
+        assert_eq!(wallet.balances()["coins"], 100);
+    }
+
+    #[test]
+    fn a_deleted_wallet_does_not_credit_again() {
+        let config = config(None);
+        let grant = &config.catalog["coins_100"];
+        let dir = Dir::new("deleted-wallet");
+        dir.wallet(&config).credit(&purchase("token"), grant);
+
+        std::fs::remove_file(dir.0.join(WALLET_FILE)).unwrap();
+        let wallet = dir.wallet(&config);
+        assert!(wallet.balances().is_empty());
+        assert!(wallet.credit(&purchase("token"), grant).is_none());
+    }
+
+    #[test]
+    fn a_tampered_wallet_starts_over_without_crediting_again() {
+        let config = config(Some("secret"));
+        let grant = &config.catalog["coins_100"];
+        let dir = Dir::new("tampered-wallet");
+        dir.wallet(&config).credit(&purchase("token"), grant);
+
+        let path = dir.0.join(WALLET_FILE);
+        let edited = std::fs::read_to_string(&path)
+            .unwrap()
+            .replace("100", "9999");
+        std::fs::write(&path, edited).unwrap();
+
+        let wallet = dir.wallet(&config);
+        assert!(wallet.balances().is_empty());
+        assert!(dir.0.join("iap-wallet.json.tampered").exists());
+        assert!(wallet.credit(&purchase("token"), grant).is_none());
+    }
+
+    #[test]
+    fn lost_credits_are_recovered_from_the_ledger() {
+        let config = config(Some("secret"));
+        let grant = &config.catalog["coins_100"];
+        let dir = Dir::new("lost-credits");
+        let app = mock_app();
+        app.manage(Ledger::with_path(Some(dir.0.join("iap-ledger.jsonl"))));
+        crate::ledger::record_purchases(app.handle(), Some("inapp"), &[purchase("token")]);
+        dir.wallet(&config).credit(&purchase("token"), grant);
+
+        std::fs::remove_file(dir.0.join(CREDITS_FILE)).unwrap();
+        let wallet = dir.wallet(&config);
+        wallet.recover_credits(app.handle(), &config);
+        assert!(wallet.credit(&purchase("token"), grant).is_none());
+        assert!(wallet.credit(&purchase("other"), grant).is_some());
+    }
+
+    struct Rejecting;
+
+    impl PurchaseFlowSteps for Rejecting {
+        fn verify(&self, _purchase: &Purchase) -> std::result::Result<(), String> {
+            Err("Bad signature".to_string())
+        }
+    }
+
+    /// An app without a store, so consuming a purchase would panic, and the number of
+    /// `currencyCredited` events it emits.
+    fn app(steps: Option<Arc<dyn PurchaseFlowSteps>>) -> (App<MockRuntime>, Arc<Mutex<usize>>) {
+        let app = mock_app();
+        let app_config = Config {
+            currency: config(None),
+            ..Config::default()
+        };
+        app.manage(Wallet::with_paths(None, None, &app_config.currency));
+        app.manage(app_config);
+        app.manage(EventBuffer::default());
+        app.manage(RetryQueue::with_path(None));
+        #[cfg(feature = "remote-validator")]
+        app.manage(crate::validator::Validation(None));
+        app.manage(PurchaseFlows::with_path(None, steps));
+
+        let credited = Arc::new(Mutex::new(0));
+        let listener = credited.clone();
+        app.listen_any("currencyCredited", move |_| *listener.lock().unwrap() += 1);
+        (app, credited)
+    }
+
+    #[test]
+    fn a_purchase_that_failed_verification_is_not_credited() {
+        let (app, credited) = app(Some(Arc::new(Rejecting)));
+
+        observe(app.handle(), &[purchase("token")]);
+        reconcile(app.handle(), &[purchase("token")]);
+
+        assert!(app.state::<Wallet>().balances().is_empty());
+        assert!(!app.state::<Wallet>().is_credited("token"));
+        assert_eq!(*credited.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn a_purchase_of_a_flow_is_left_to_the_flow() {
+        let (app, credited) = app(None);
+        let request: PurchaseRequest = serde_json::from_value(serde_json::json!({
+            "productId": "coins_100",
+            "productType": "inapp",
+        }))
+        .unwrap();
+        app.state::<PurchaseFlows>()
+            .start("flow".to_string(), request, true);
+
+        // Reported by the store before the flow verified it
+        observe(app.handle(), &[purchase("token")]);
+
+        assert!(app.state::<Wallet>().balances().is_empty());
+        assert_eq!(*credited.lock().unwrap(), 0);
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Manager, Runtime};
+
+use crate::events::{self, IapEvent};
+use crate::models::*;
+
+const DELIVERIES_FILE: &str = "iap-deliveries.json";
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DeliveryRecord {
+    purchase_state: i32,
+    fulfilled: bool,
+}
+
+#[derive(Default)]
+struct DeliveryState {
+    /// Purchases delivered to the app so far, by purchase token.
+    records: HashMap<String, DeliveryRecord>,
+    /// Token and state of every purchase delivered in this session.
+    delivered: HashSet<(String, i32)>,
+}
+
+/// Purchases delivered to the app, kept in the app data directory so a purchase that
+/// reaches the app through several paths or launches is recognized.
+pub(crate) struct Deliveries {
+    path: Option<PathBuf>,
+    state: Mutex<DeliveryState>,
+}
+
+impl Deliveries {
+    pub fn new<R: Runtime>(app: &AppHandle<R>) -> Self {
+        let path = app
+            .path()
+            .app_data_dir()
+            .ok()
+            .map(|dir| dir.join(DELIVERIES_FILE));
+        Self::with_path(path)
+    }
+
+    pub(crate) fn with_path(path: Option<PathBuf>) -> Self {
+        let records = path
+            .as_ref()
+            .and_then(|path| std::fs::read(path).ok())
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+
+        Self {
+            path,
+            state: Mutex::new(DeliveryState {
+                records,
+                delivered: HashSet::new(),
+            }),
+        }
+    }
+
+    /// Mark a purchase as first seen or redelivered. Returns `false` if this session already
+    /// delivered it in the same state.
+    ///
+    /// A purchase whose state changed, e.g. from pending to purchased, counts as first seen.
+    /// Purchases without a token cannot be told apart and are left unmarked.
+    fn stamp(&self, purchase: &mut Purchase) -> bool {
+        if purchase.purchase_token.is_empty() {
+            return true;
+        }
+
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let token = purchase.purchase_token.clone();
+        let (delivery, fulfilled) = match state.records.get(&token) {
+            Some(record) if record.purchase_state == purchase.purchase_state => {
+                (Delivery::Redelivery, record.fulfilled)
+            }
+            _ => {
+                state.records.insert(
+                    token.clone(),
+                    DeliveryRecord {
+                        purchase_state: purchase.purchase_state,
+                        fulfilled: false,
+                    },
+                );
+                self.persist(&state.records);
+                (Delivery::FirstSeen, false)
+            }
+        };
+
+        purchase.delivery = Some(delivery);
+        purchase.fulfilled = fulfilled;
+        state.delivered.insert((token, purchase.purchase_state))
+    }
+
+    /// Returns `false` if the purchase was never delivered.
+    fn mark_fulfilled(&self, purchase_token: &str) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(record) = state.records.get_mut(purchase_token) else {
+            return false;
+        };
+
+        if !record.fulfilled {
+            record.fulfilled = true;
+            self.persist(&state.records);
+        }
+        true
+    }
+
+    fn is_fulfilled(&self, purchase_token: &str) -> bool {
+        self.state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .records
+            .get(purchase_token)
+            .is_some_and(|record| record.fulfilled)
+    }
+
+    fn persist(&self, records: &HashMap<String, DeliveryRecord>) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        if let Ok(json) = serde_json::to_vec(records) {
+            if let Some(dir) = path.parent() {
+                let _ = std::fs::create_dir_all(dir);
+            }
+            let _ = std::fs::write(path, json);
+        }
+    }
+}
+
+/// Mark purchases returned by a command as first seen or redelivered.
+pub(crate) fn stamp<R: Runtime>(app: &AppHandle<R>, purchases: &mut [Purchase]) {
+    let deliveries = app.state::<Deliveries>();
+    for purchase in purchases {
+        deliveries.stamp(purchase);
+    }
+}
+
+/// Emit a `purchaseUpdated` event, unless this session already delivered the purchase in
+/// the same state, e.g. as the return value of `purchase`.
+pub(crate) fn emit<R: Runtime>(app: &AppHandle<R>, mut purchase: Purchase) {
+    if app.state::<Deliveries>().stamp(&mut purchase) {
+        events::emit(app, IapEvent::PurchaseUpdated(purchase));
+    }
+}
+
+pub(crate) fn is_fulfilled<R: Runtime>(app: &AppHandle<R>, purchase_token: &str) -> bool {
+    app.state::<Deliveries>().is_fulfilled(purchase_token)
+}
+
+/// Record that the app processed a purchase. Returns `false` for unknown tokens.
+pub(crate) fn mark_fulfilled<R: Runtime>(app: &AppHandle<R>, purchase_token: &str) -> bool {
+    let known = app.state::<Deliveries>().mark_fulfilled(purchase_token);
+    if known {
+        crate::ledger::record_fulfillment(app, purchase_token);
+    }
+    known
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::now_millis;
+
+    fn purchase(token: &str, state: PurchaseStateValue) -> Purchase {
+        serde_json::from_value(serde_json::json!({
+            "orderId": null,
+            "packageName": "app",
+            "productId": "premium",
+            "purchaseTime": 1_000,
+            "purchaseToken": token,
+            "purchaseState": state as i32,
+            "isAutoRenewing": false,
+            "isAcknowledged": false,
+            "originalJson": "",
+            "signature": "",
+        }))
+        .unwrap()
+    }
+
+    fn deliveries_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("iap-deliveries-{name}-{}.json", now_millis()))
+    }
+
+    #[test]
+    fn stamp_tells_first_deliveries_from_redeliveries() {
+        let deliveries = Deliveries::with_path(None);
+
+        let mut pending = purchase("token", PurchaseStateValue::Pending);
+        assert!(deliveries.stamp(&mut pending));
+        assert_eq!(pending.delivery, Some(Delivery::FirstSeen));
+
+        let mut again = purchase("token", PurchaseStateValue::Pending);
+        assert!(!deliveries.stamp(&mut again));
+        assert_eq!(again.delivery, Some(Delivery::Redelivery));
+
+        // A new state is news to the app again
+        let mut purchased = purchase("token", PurchaseStateValue::Purchased);
+        assert!(deliveries.stamp(&mut purchased));
+        assert_eq!(purchased.delivery, Some(Delivery::FirstSeen));
+        assert!(!purchased.fulfilled);
+
+        let mut untracked = purchase("", PurchaseStateValue::Purchased);
+        assert!(deliveries.stamp(&mut untracked));
+        assert!(deliveries.stamp(&mut untracked));
+        assert_eq!(untracked.delivery, None);
+    }
+
+    #[test]
+    fn mark_fulfilled_only_knows_delivered_tokens() {
+        let deliveries = Deliveries::with_path(None);
+        assert!(!deliveries.mark_fulfilled("unknown"));
+        assert!(!deliveries.is_fulfilled("unknown"));
+
+        deliveries.stamp(&mut purchase("token", PurchaseStateValue::Purchased));
+        assert!(!deliveries.is_fulfilled("token"));
+        assert!(deliveries.mark_fulfilled("token"));
+        assert!(deliveries.mark_fulfilled("token"));
+        assert!(deliveries.is_fulfilled("token"));
+
+        let mut redelivered = purchase("token", PurchaseStateValue::Purchased);
+        deliveries.stamp(&mut redelivered);
+        assert_eq!(redelivered.delivery, Some(Delivery::Redelivery));
+        assert!(redelivered.fulfilled);
+    }
+
+    #[test]
+    fn deliveries_survive_a_restart() {
+        let path = deliveries_path("restart");
+        let first = Deliveries::with_path(Some(path.clone()));
+        first.stamp(&mut purchase("fulfilled", PurchaseStateValue::Purchased));
+        first.stamp(&mut purchase("open", PurchaseStateValue::Purchased));
+        first.mark_fulfilled("fulfilled");
+
+        let restarted = Deliveries::with_path(Some(path.clone()));
+        assert!(restarted.is_fulfilled("fulfilled"));
+        assert!(!restarted.is_fulfilled("open"));
+
+        // Known from the file, but not yet delivered in this session
+        let mut open = purchase("open", PurchaseStateValue::Purchased);
+        assert!(restarted.stamp(&mut open));
+        assert_eq!(open.delivery, Some(Delivery::Redelivery));
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn an_unreadable_deliveries_file_starts_empty() {
+        let path = deliveries_path("unreadable");
+        std::fs::write(&path, "not json").unwrap();
+
+        let deliveries = Deliveries::with_path(Some(path.clone()));
+        let mut purchase = purchase("token", PurchaseStateValue::Purchased);
+        assert!(deliveries.stamp(&mut purchase));
+        assert_eq!(purchase.delivery, Some(Delivery::FirstSeen));
+
+        let _ = std::fs::remove_file(path);
+    }
+}
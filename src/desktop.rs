@@ -56,6 +56,51 @@ impl<R: Runtime> Iap<R> {
         )))
     }
 
+    pub fn get_unfinished_purchases(&self) -> crate::Result<GetUnfinishedPurchasesResponse> {
+        Err(crate::Error::from(std::io::Error::other(
+            "IAP is not supported on this platform",
+        )))
+    }
+
+    pub fn get_store_capabilities(&self) -> crate::Result<StoreCapabilities> {
+        Ok(StoreCapabilities {
+            store: StoreKind::None,
+            can_make_payments: false,
+            storefront: None,
+            features: Vec::new(),
+        })
+    }
+
+    pub fn redeem_offer_code(
+        &self,
+        _code: Option<String>,
+    ) -> crate::Result<RedeemOfferCodeResponse> {
+        Err(crate::Error::rejected(
+            "notSupported",
+            "Offer codes are not supported on this platform",
+        ))
+    }
+
+    pub fn begin_refund_request(
+        &self,
+        _transaction_id: String,
+    ) -> crate::Result<BeginRefundRequestResponse> {
+        Err(crate::Error::rejected(
+            "notSupported",
+            "Refund requests are not supported on this platform",
+        ))
+    }
+
+    pub fn show_manage_subscriptions(
+        &self,
+        _product_id: Option<String>,
+    ) -> crate::Result<ShowManageSubscriptionsResponse> {
+        Err(crate::Error::rejected(
+            "notSupported",
+            "Subscriptions are not supported on this platform",
+        ))
+    }
+
     pub fn acknowledge_purchase(
         &self,
         _purchase_token: String,
@@ -65,6 +110,15 @@ impl<R: Runtime> Iap<R> {
         )))
     }
 
+    pub fn consume_purchase(
+        &self,
+        _purchase_token: String,
+    ) -> crate::Result<ConsumePurchaseResponse> {
+        Err(crate::Error::from(std::io::Error::other(
+            "IAP is not supported on this platform",
+        )))
+    }
+
     pub fn get_product_status(
         &self,
         _product_id: String,
@@ -74,4 +128,51 @@ impl<R: Runtime> Iap<R> {
             "IAP is not supported on this platform",
         )))
     }
+
+    pub fn get_products_status(
+        &self,
+        _product_ids: Vec<String>,
+        _product_type: String,
+    ) -> crate::Result<GetProductsStatusResponse> {
+        Err(crate::Error::from(std::io::Error::other(
+            "IAP is not supported on this platform",
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tauri::test::mock_app;
+
+    use super::*;
+
+    #[test]
+    fn capabilities_report_no_store() {
+        let app = mock_app();
+        let capabilities = Iap(app.handle().clone()).get_store_capabilities().unwrap();
+
+        assert_eq!(capabilities.store, StoreKind::None);
+        assert!(!capabilities.can_make_payments);
+        assert!(capabilities.features.is_empty());
+    }
+
+    #[test]
+    fn offer_codes_are_not_supported() {
+        let app = mock_app();
+        let err = Iap(app.handle().clone())
+            .redeem_offer_code(Some("SPRING".to_string()))
+            .unwrap_err();
+
+        assert_eq!(err.code(), Some("notSupported"));
+    }
+
+    #[test]
+    fn managing_subscriptions_is_not_supported() {
+        let app = mock_app();
+        let err = Iap(app.handle().clone())
+            .show_manage_subscriptions(None)
+            .unwrap_err();
+
+        assert_eq!(err.code(), Some("notSupported"));
+    }
 }
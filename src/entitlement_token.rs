@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+#[cfg(feature = "plugin")]
+use std::sync::Arc;
+
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::Deserialize;
+use serde_json::json;
+#[cfg(feature = "plugin")]
+use tauri::{AppHandle, Manager, Runtime};
+
+use crate::jwt::{self, Jws};
+use crate::models::EntitlementClaims;
+use crate::{now_millis, EntitlementPublicKey, EntitlementSigningKey, EntitlementTokenConfig};
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+    kid: String,
+}
+
+/// A new Ed25519 key pair for signing entitlement tokens, as it goes into the config.
+pub fn generate_signing_key(
+    kid: impl Into<String>,
+) -> crate::Result<(EntitlementSigningKey, EntitlementPublicKey)> {
+    let kid = kid.into();
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+        .map_err(|_| crate::Error::rejected("invalidKey", "Generating the key failed"))?;
+    let key_pair = signing_key_pair(pkcs8.as_ref())?;
+
+    Ok((
+        EntitlementSigningKey {
+            kid: kid.clone(),
+            private_key: STANDARD.encode(pkcs8.as_ref()),
+        },
+        EntitlementPublicKey {
+            kid,
+            public_key: URL_SAFE_NO_PAD.encode(key_pair.public_key().as_ref()),
+        },
+    ))
+}
+
+fn signing_key_pair(pkcs8: &[u8]) -> crate::Result<Ed25519KeyPair> {
+    Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8).map_err(|err| {
+        crate::Error::rejected(
+            "invalidKey",
+            format!("Invalid entitlement signing key: {err}"),
+        )
+    })
+}
+
+/// Issues entitlement tokens: JWTs signed with Ed25519 (`alg` `EdDSA`), with the id of the
+/// signing key as `kid`.
+pub struct EntitlementTokenIssuer {
+    kid: String,
+    key_pair: Ed25519KeyPair,
+    ttl_secs: u64,
+    issuer: Option<String>,
+}
+
+impl EntitlementTokenIssuer {
+    /// Fails with `invalidKey` when the config has no usable `signing_key`.
+    pub fn from_config(config: &EntitlementTokenConfig) -> crate::Result<Self> {
+        let signing_key = config.signing_key.as_ref().ok_or_else(|| {
+            crate::Error::rejected("invalidKey", "No entitlement signing key is configured")
+        })?;
+        let pkcs8 = STANDARD.decode(&signing_key.private_key).map_err(|err| {
+            crate::Error::rejected(
+                "invalidKey",
+                format!("Invalid entitlement signing key: {err}"),
+            )
+        })?;
+
+        Ok(Self {
+            kid: signing_key.kid.clone(),
+            key_pair: signing_key_pair(&pkcs8)?,
+            ttl_secs: config.ttl_secs,
+            issuer: config.issuer.clone(),
+        })
+    }
+
+    /// A token for the account's entitlements. It expires after the configured TTL, or
+    /// earlier at `not_after` (milliseconds), e.g. when the first subscription among the
+    /// entitlements ends.
+    pub fn issue(
+        &self,
+        account_id: &str,
+        entitlements: Vec<String>,
+        not_after: Option<i64>,
+    ) -> crate::Result<(String, EntitlementClaims)> {
+        let issued_at = now_millis() / 1000;
+        let expires_at = not_after.map_or(i64::MAX, |not_after| not_after / 1000);
+        let ttl = i64::try_from(self.ttl_secs).unwrap_or(i64::MAX);
+        let claims = EntitlementClaims {
+            account_id: account_id.to_string(),
+            entitlements,
+            issued_at,
+            expires_at: expires_at.min(issued_at.saturating_add(ttl)),
+            issuer: self.issuer.clone(),
+        };
+
+        let token = jwt::encode(
+            &json!({ "alg": "EdDSA", "typ": "JWT", "kid": self.kid }),
+            &serde_json::to_value(&claims).unwrap_or_default(),
+            |input| Ok(self.key_pair.sign(input).as_ref().to_vec()),
+        )?;
+        Ok((token, claims))
+    }
+}
+
+/// Checks entitlement tokens against every configured public key, so tokens signed with a
+/// key that is being rotated out stay valid until they expire.
+pub struct EntitlementTokenVerifier {
+    keys: HashMap<String, Vec<u8>>,
+    issuer: Option<String>,
+}
+
+impl EntitlementTokenVerifier {
+    pub fn from_config(config: &EntitlementTokenConfig) -> crate::Result<Self> {
+        let keys = config
+            .keys
+            .iter()
+            .map(|key| {
+                URL_SAFE_NO_PAD
+                    .decode(&key.public_key)
+                    .map(|public_key| (key.kid.clone(), public_key))
+                    .map_err(|err| {
+                        crate::Error::rejected(
+                            "invalidKey",
+                            format!("Invalid entitlement public key {}: {err}", key.kid),
+                        )
+                    })
+            })
+            .collect::<crate::Result<_>>()?;
+
+        Ok(Self {
+            keys,
+            issuer: config.issuer.clone(),
+        })
+    }
+
+    /// The claims of a token with a valid signature that has not expired. Fails with
+    /// `tokenExpired` for expired tokens and `invalidToken` for anything else.
+    pub fn verify(&self, token: &str) -> crate::Result<EntitlementClaims> {
+        let invalid = |reason: &str| crate::Error::rejected("invalidToken", reason.to_string());
+
+        let jws = Jws::parse(token).ok_or_else(|| invalid("The token is malformed"))?;
+        let header: Header = jws
+            .header()
+            .ok_or_else(|| invalid("The token header is malformed"))?;
+        if header.alg != "EdDSA" {
+            return Err(invalid("The token is not signed with EdDSA"));
+        }
+        let public_key = self
+            .keys
+            .get(&header.kid)
+            .ok_or_else(|| invalid("The token is signed with an unknown key"))?;
+        let signature = jws
+            .signature()
+            .ok_or_else(|| invalid("The token signature is malformed"))?;
+        UnparsedPublicKey::new(&ED25519, public_key)
+            .verify(jws.signing_input.as_bytes(), &signature)
+            .map_err(|_| invalid("The token signature does not match"))?;
+
+        let claims: EntitlementClaims = jws
+            .payload()
+            .ok_or_else(|| invalid("The token claims are malformed"))?;
+        if self.issuer.is_some() && claims.issuer != self.issuer {
+            return Err(invalid("The token is from another issuer"));
+        }
+        if claims.expires_at <= now_millis() / 1000 {
+            return Err(crate::Error::rejected(
+                "tokenExpired",
+                "The entitlement token expired",
+            ));
+        }
+        Ok(claims)
+    }
+}
+
+/// Verifier for the `verify_entitlement_token` command, from the `entitlementTokens` config.
+#[cfg(feature = "plugin")]
+pub(crate) struct Verification(pub Option<Arc<EntitlementTokenVerifier>>);
+
+/// Checks a token with the configured keys, or `None` if no keys are configured.
+#[cfg(feature = "plugin")]
+pub(crate) fn verify<R: Runtime>(
+    app: &AppHandle<R>,
+    token: &str,
+) -> Option<crate::Result<EntitlementClaims>> {
+    let verifier = app.state::<Verification>().0.clone()?;
+    Some(verifier.verify(token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(
+        signing_key: EntitlementSigningKey,
+        keys: Vec<EntitlementPublicKey>,
+    ) -> EntitlementTokenConfig {
+        EntitlementTokenConfig {
+            signing_key: Some(signing_key),
+            keys,
+            issuer: Some("https://api.example.com".into()),
+            ttl_secs: 60,
+        }
+    }
+
+    fn issue(config: &EntitlementTokenConfig, not_after: Option<i64>) -> String {
+        EntitlementTokenIssuer::from_config(config)
+            .unwrap()
+            .issue("user-1", vec!["premium".into()], not_after)
+            .unwrap()
+            .0
+    }
+
+    fn verify(config: &EntitlementTokenConfig, token: &str) -> crate::Result<EntitlementClaims> {
+        EntitlementTokenVerifier::from_config(config)
+            .unwrap()
+            .verify(token)
+    }
+
+    /// A token with its header or claims replaced, keeping the original signature.
+    fn tampered(
+        token: &str,
+        header: Option<serde_json::Value>,
+        claims: serde_json::Value,
+    ) -> String {
+        let parts: Vec<&str> = token.split('.').collect();
+        let header = header.map_or(parts[0].to_string(), |header| {
+            URL_SAFE_NO_PAD.encode(header.to_string())
+        });
+        format!(
+            "{header}.{}.{}",
+            URL_SAFE_NO_PAD.encode(claims.to_string()),
+            parts[2]
+        )
+    }
+
+    #[test]
+    fn verifies_issued_tokens() {
+        let (signing_key, public_key) = generate_signing_key("k1").unwrap();
+        let config = config(signing_key, vec![public_key]);
+
+        let claims = verify(&config, &issue(&config, None)).unwrap();
+
+        assert_eq!(claims.account_id, "user-1");
+        assert_eq!(claims.entitlements, vec!["premium".to_string()]);
+        assert_eq!(claims.issuer.as_deref(), Some("https://api.example.com"));
+        assert_eq!(claims.expires_at, claims.issued_at + 60);
+    }
+
+    #[test]
+    fn a_huge_ttl_does_not_overflow() {
+        let (signing_key, public_key) = generate_signing_key("k1").unwrap();
+        let config = EntitlementTokenConfig {
+            ttl_secs: u64::MAX,
+            ..config(signing_key, vec![public_key])
+        };
+
+        let claims = verify(&config, &issue(&config, None)).unwrap();
+
+        assert_eq!(claims.expires_at, i64::MAX);
+    }
+
+    #[test]
+    fn rejects_other_algorithms() {
+        let (signing_key, public_key) = generate_signing_key("k1").unwrap();
+        let config = config(signing_key, vec![public_key]);
+        let token = issue(&config, None);
+        let claims: serde_json::Value = Jws::parse(&token).unwrap().payload().unwrap();
+
+        let token = tampered(
+            &token,
+            Some(json!({ "alg": "none", "typ": "JWT", "kid": "k1" })),
+            claims,
+        );
+
+        let err = verify(&config, &token).unwrap_err();
+        assert_eq!(err.code(), Some("invalidToken"));
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let (signing_key, _) = generate_signing_key("k1").unwrap();
+        let (_, other_key) = generate_signing_key("k2").unwrap();
+        let config = config(signing_key, vec![other_key]);
+
+        let err = verify(&config, &issue(&config, None)).unwrap_err();
+
+        assert_eq!(err.code(), Some("invalidToken"));
+    }
+
+    #[test]
+    fn rejects_tampered_claims() {
+        let (signing_key, public_key) = generate_signing_key("k1").unwrap();
+        let config = config(signing_key, vec![public_key]);
+        let token = issue(&config, None);
+        let mut claims: serde_json::Value = Jws::parse(&token).unwrap().payload().unwrap();
+        claims["entitlements"] = json!(["premium", "lifetime"]);
+
+        let err = verify(&config, &tampered(&token, None, claims)).unwrap_err();
+
+        assert_eq!(err.code(), Some("invalidToken"));
+    }
+
+    #[test]
+    fn rejects_expired_tokens() {
+        let (signing_key, public_key) = generate_signing_key("k1").unwrap();
+        let config = config(signing_key, vec![public_key]);
+
+        let token = issue(&config, Some(now_millis() - 1000));
+
+        let err = verify(&config, &token).unwrap_err();
+        assert_eq!(err.code(), Some("tokenExpired"));
+    }
+
+    #[test]
+    fn rejects_other_issuers() {
+        let (signing_key, public_key) = generate_signing_key("k1").unwrap();
+        let issuing = EntitlementTokenConfig {
+            issuer: Some("https://other.example.com".into()),
+            ..config(signing_key, vec![public_key])
+        };
+        let verifying = EntitlementTokenConfig {
+            issuer: Some("https://api.example.com".into()),
+            ..issuing.clone()
+        };
+
+        let err = verify(&verifying, &issue(&issuing, None)).unwrap_err();
+
+        assert_eq!(err.code(), Some("invalidToken"));
+    }
+
+    #[test]
+    fn accepts_tokens_of_a_rotated_key() {
+        let (old_signing_key, old_key) = generate_signing_key("k1").unwrap();
+        let (new_signing_key, new_key) = generate_signing_key("k2").unwrap();
+        let before = config(old_signing_key, vec![old_key.clone()]);
+        let after = config(new_signing_key, vec![old_key, new_key]);
+
+        let old_token = issue(&before, None);
+        let new_token = issue(&after, None);
+
+        assert_eq!(verify(&after, &old_token).unwrap().account_id, "user-1");
+        assert_eq!(verify(&after, &new_token).unwrap().account_id, "user-1");
+        assert!(verify(&before, &new_token).is_err());
+    }
+}
@@ -61,6 +61,58 @@ pub enum Error {
     WindowsApi(#[from] windows::core::Error),
 }
 
+impl Error {
+    /// Build a rejection with a store-style error code such as `purchaseInProgress`, for
+    /// failures detected by the plugin itself rather than by the store.
+    // Builds without the plugin use only some of the helpers, depending on their features
+    #[cfg_attr(not(feature = "plugin"), allow(dead_code))]
+    pub(crate) fn rejected(code: &str, message: impl Into<String>) -> Self {
+        #[cfg(mobile)]
+        use tauri::plugin::mobile::{ErrorResponse, PluginInvokeError};
+
+        PluginInvokeError::InvokeRejected(ErrorResponse {
+            code: Some(code.to_string()),
+            message: Some(message.into()),
+            data: (),
+        })
+        .into()
+    }
+
+    /// Error code of a rejection, e.g. `notConnected` from the native plugin.
+    #[cfg_attr(not(feature = "plugin"), allow(dead_code))]
+    pub(crate) fn code(&self) -> Option<&str> {
+        #[cfg(mobile)]
+        use tauri::plugin::mobile::PluginInvokeError;
+
+        match self {
+            Self::PluginInvoke(PluginInvokeError::InvokeRejected(response)) => {
+                response.code.as_deref()
+            }
+            _ => None,
+        }
+    }
+
+    /// A copy for another caller of a shared call. Rejections keep their code, other errors
+    /// become their message.
+    #[cfg(feature = "plugin")]
+    pub(crate) fn share(&self) -> Self {
+        #[cfg(mobile)]
+        use tauri::plugin::mobile::{ErrorResponse, PluginInvokeError};
+
+        match self {
+            Self::PluginInvoke(PluginInvokeError::InvokeRejected(response)) => {
+                PluginInvokeError::InvokeRejected(ErrorResponse {
+                    code: response.code.clone(),
+                    message: response.message.clone(),
+                    data: (),
+                })
+                .into()
+            }
+            other => std::io::Error::other(other.to_string()).into(),
+        }
+    }
+}
+
 impl Serialize for Error {
     fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
     where
@@ -0,0 +1,220 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use serde::Serialize;
+use tauri::{AppHandle, Emitter, Manager, Runtime};
+
+use crate::models::*;
+
+/// Events the plugin emits to the frontend. The payload is the wrapped value.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum IapEvent {
+    /// A purchase completed or changed state outside of a `purchase` call's return value.
+    PurchaseUpdated(Purchase),
+    /// A queued acknowledge or consume call went through.
+    PendingOperationSucceeded(PendingOperation),
+    /// A queued acknowledge or consume call ran out of attempts and was dropped.
+    PendingOperationFailed(PendingOperation),
+    /// A completed purchase is still unacknowledged close to the store's refund deadline.
+    AcknowledgementDeadlineApproaching(AcknowledgementDeadline),
+    /// A pending purchase completed.
+    PendingPurchaseResolved(PendingPurchaseOutcome),
+    /// A pending purchase was canceled or declined.
+    PendingPurchaseFailed(PendingPurchaseOutcome),
+    /// The connection to the store changed state.
+    BillingConnectionChanged(ConnectionStatus),
+    /// An entitlement owned at the previous restore was refunded or revoked.
+    PurchaseRevoked(PurchaseRevocation),
+    /// A purchase of a currency product was credited to the wallet.
+    CurrencyCredited(CurrencyTransaction),
+    /// A purchase flow finished after its `purchase_and_fulfill` call returned.
+    PurchaseFlowFinished(PurchaseFlowFinished),
+}
+
+impl IapEvent {
+    /// Event name the frontend listens to.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::PurchaseUpdated(_) => "purchaseUpdated",
+            Self::PendingOperationSucceeded(_) => "pendingOperationSucceeded",
+            Self::PendingOperationFailed(_) => "pendingOperationFailed",
+            Self::AcknowledgementDeadlineApproaching(_) => "acknowledgementDeadlineApproaching",
+            Self::PendingPurchaseResolved(_) => "pendingPurchaseResolved",
+            Self::PendingPurchaseFailed(_) => "pendingPurchaseFailed",
+            Self::BillingConnectionChanged(_) => "billingConnectionChanged",
+            Self::PurchaseRevoked(_) => "purchaseRevoked",
+            Self::CurrencyCredited(_) => "currencyCredited",
+            Self::PurchaseFlowFinished(_) => "purchaseFlowFinished",
+        }
+    }
+}
+
+/// Events of the launch replay held back per event name until the frontend listens to that
+/// name.
+///
+/// Purchases replayed on `initialize` would otherwise be emitted before the app had a chance
+/// to register its listeners. Every other event is emitted right away, so Rust listeners and
+/// the raw `listen` receive it too.
+#[derive(Default)]
+pub(crate) struct EventBuffer {
+    state: Mutex<BufferState>,
+}
+
+#[derive(Default)]
+struct BufferState {
+    subscribed: HashSet<String>,
+    /// Replays running now.
+    replaying: usize,
+    held: Vec<IapEvent>,
+}
+
+/// Events held beyond this are dropped, oldest first, for apps that never subscribe.
+const MAX_HELD_EVENTS: usize = 256;
+
+pub(crate) fn emit<R: Runtime>(app: &AppHandle<R>, event: IapEvent) {
+    {
+        let buffer = app.state::<EventBuffer>();
+        let mut state = buffer.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.replaying > 0 && !state.subscribed.contains(event.name()) {
+            // The same purchase can be reported by the native observer and the replay
+            if !state.held.iter().any(|held| is_same_event(held, &event)) {
+                if state.held.len() == MAX_HELD_EVENTS {
+                    state.held.remove(0);
+                }
+                state.held.push(event);
+            }
+            return;
+        }
+    }
+
+    // Nobody listening is not an error
+    let _ = app.emit(event.name(), &event);
+}
+
+/// Run a replay, holding the events it emits for names nobody subscribed to yet.
+pub(crate) fn replay<R: Runtime, T>(app: &AppHandle<R>, f: impl FnOnce() -> T) -> T {
+    let buffer = app.state::<EventBuffer>();
+    buffer
+        .state
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .replaying += 1;
+    let result = f();
+    buffer
+        .state
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .replaying -= 1;
+    result
+}
+
+/// Start emitting the given event names right away and deliver what was held back for them.
+pub(crate) fn subscribe<R: Runtime>(app: &AppHandle<R>, names: Vec<String>) {
+    let released: Vec<IapEvent> = {
+        let buffer = app.state::<EventBuffer>();
+        let mut state = buffer.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.subscribed.extend(names);
+
+        let (released, held) = std::mem::take(&mut state.held)
+            .into_iter()
+            .partition(|event| state.subscribed.contains(event.name()));
+        state.held = held;
+        released
+    };
+
+    for event in released {
+        let _ = app.emit(event.name(), &event);
+    }
+}
+
+fn is_same_event(a: &IapEvent, b: &IapEvent) -> bool {
+    match (a, b) {
+        (IapEvent::PurchaseUpdated(a), IapEvent::PurchaseUpdated(b)) => {
+            !a.purchase_token.is_empty()
+                && a.purchase_token == b.purchase_token
+                && a.purchase_state == b.purchase_state
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tauri::test::{mock_app, MockRuntime};
+    use tauri::{App, Listener};
+
+    use super::*;
+
+    fn app() -> (App<MockRuntime>, Arc<Mutex<Vec<String>>>) {
+        let app = mock_app();
+        app.manage(EventBuffer::default());
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let listener = received.clone();
+        app.listen_any("purchaseUpdated", move |event| {
+            listener.lock().unwrap().push(event.payload().to_string());
+        });
+        (app, received)
+    }
+
+    fn purchase_updated(token: &str) -> IapEvent {
+        IapEvent::PurchaseUpdated(
+            serde_json::from_value(serde_json::json!({
+                "orderId": "order",
+                "packageName": "app",
+                "productId": "coins",
+                "purchaseTime": 0,
+                "purchaseToken": token,
+                "purchaseState": 0,
+                "isAutoRenewing": false,
+                "isAcknowledged": false,
+                "originalJson": "",
+                "signature": "",
+            }))
+            .unwrap(),
+        )
+    }
+
+    fn held(app: &App<MockRuntime>) -> usize {
+        app.state::<EventBuffer>().state.lock().unwrap().held.len()
+    }
+
+    #[test]
+    fn events_outside_a_replay_reach_listeners_without_a_subscription() {
+        let (app, received) = app();
+        emit(app.handle(), purchase_updated("token"));
+
+        assert_eq!(received.lock().unwrap().len(), 1);
+        assert_eq!(held(&app), 0);
+    }
+
+    #[test]
+    fn replayed_events_are_held_until_subscribed() {
+        let (app, received) = app();
+        replay(app.handle(), || {
+            emit(app.handle(), purchase_updated("token"));
+            // The native observer reported the same purchase
+            emit(app.handle(), purchase_updated("token"));
+        });
+        assert!(received.lock().unwrap().is_empty());
+        assert_eq!(held(&app), 1);
+
+        subscribe(app.handle(), vec!["purchaseUpdated".to_string()]);
+        assert_eq!(received.lock().unwrap().len(), 1);
+        assert_eq!(held(&app), 0);
+    }
+
+    #[test]
+    fn replayed_events_of_subscribed_names_are_emitted() {
+        let (app, received) = app();
+        subscribe(app.handle(), vec!["purchaseUpdated".to_string()]);
+        replay(app.handle(), || {
+            emit(app.handle(), purchase_updated("token"))
+        });
+
+        assert_eq!(received.lock().unwrap().len(), 1);
+        assert_eq!(held(&app), 0);
+    }
+}
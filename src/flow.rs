@@ -0,0 +1,1033 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Manager, Runtime};
+
+use crate::connection::with_reconnect;
+use crate::events::{self, IapEvent};
+use crate::models::*;
+use crate::{now_millis, Config};
+
+const FLOWS_FILE: &str = "iap-purchase-flows.json";
+
+/// Error codes of a purchase the user or the app backed out of.
+const CANCELED_CODES: &[&str] = &["userCanceled", "purchaseCanceled", "purchaseNotCompleted"];
+
+/// Error codes of a `purchase` call that stopped waiting while the store flow keeps running.
+const STOPPED_WAITING_CODES: &[&str] = &["timedOut", "purchaseCanceled"];
+
+/// How long after a flow started a purchase of its product may still be the one it waits for.
+const MATCH_WINDOW_MS: i64 = 60 * 60 * 1000;
+
+/// Allowance for a store clock that is behind the device clock.
+const CLOCK_SKEW_MS: i64 = 5 * 60 * 1000;
+
+/// App-specific steps of [`purchase_and_fulfill`].
+///
+/// Register an implementation with
+/// [`Builder::purchase_flow_steps`](crate::Builder::purchase_flow_steps). Both steps default
+/// to doing nothing. They run on a blocking thread and may run again for the same purchase
+/// when a flow is resumed after a crash, so they should be idempotent.
+pub trait PurchaseFlowSteps: Send + Sync + 'static {
+    /// Check that the purchase is genuine, e.g. its signature or a server-side receipt
+    /// validation. An error fails the flow without granting or finishing the purchase.
+    fn verify(&self, purchase: &Purchase) -> std::result::Result<(), String> {
+        let _ = purchase;
+        Ok(())
+    }
+
+    /// Give the user what they paid for. Skipped for purchases already marked fulfilled.
+    fn grant(&self, purchase: &Purchase) -> std::result::Result<(), String> {
+        let _ = purchase;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct FlowRecord {
+    id: String,
+    request: PurchaseRequest,
+    consume: bool,
+    stage: PurchaseFlowStage,
+    /// The purchase once the store reported it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    purchase: Option<Purchase>,
+    /// When the record was last saved. Records without a purchase are only saved when the
+    /// flow starts.
+    updated_at: i64,
+}
+
+/// Purchase flows that have not finished, kept in the app data directory so a flow
+/// interrupted by a crash or a pending payment continues where it stopped.
+pub(crate) struct PurchaseFlows {
+    path: Option<PathBuf>,
+    steps: Option<Arc<dyn PurchaseFlowSteps>>,
+    records: Mutex<Vec<FlowRecord>>,
+    /// Flows a thread of this session is working on.
+    running: Mutex<HashSet<String>>,
+}
+
+impl PurchaseFlows {
+    pub fn new<R: Runtime>(app: &AppHandle<R>, steps: Option<Arc<dyn PurchaseFlowSteps>>) -> Self {
+        let path = app
+            .path()
+            .app_data_dir()
+            .ok()
+            .map(|dir| dir.join(FLOWS_FILE));
+        Self::with_path(path, steps)
+    }
+
+    pub(crate) fn with_path(
+        path: Option<PathBuf>,
+        steps: Option<Arc<dyn PurchaseFlowSteps>>,
+    ) -> Self {
+        let records = path
+            .as_ref()
+            .and_then(|path| std::fs::read(path).ok())
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+
+        Self {
+            path,
+            steps,
+            records: Mutex::new(records),
+            running: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Save a new flow at the purchase stage.
+    pub(crate) fn start(&self, id: String, request: PurchaseRequest, consume: bool) -> FlowRecord {
+        let mut record = FlowRecord {
+            id,
+            request,
+            consume,
+            stage: PurchaseFlowStage::Purchase,
+            purchase: None,
+            updated_at: 0,
+        };
+        self.save(&mut record);
+        record
+    }
+
+    /// Save a record, replacing the one with the same id.
+    fn save(&self, record: &mut FlowRecord) {
+        record.updated_at = now_millis();
+        let mut records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        match records.iter_mut().find(|saved| saved.id == record.id) {
+            Some(saved) => *saved = record.clone(),
+            None => records.push(record.clone()),
+        }
+        self.persist(&records);
+    }
+
+    fn remove(&self, id: &str) {
+        let mut records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        let before = records.len();
+        records.retain(|record| record.id != id);
+        if records.len() != before {
+            self.persist(&records);
+        }
+    }
+
+    /// Claim a flow for the calling thread. Returns `false` if another thread runs it.
+    fn claim(&self, id: &str) -> bool {
+        self.running
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(id.to_string())
+    }
+
+    fn release(&self, id: &str) {
+        self.running
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(id);
+    }
+
+    /// Attach a purchase reported by the store to the idle flow waiting for it, returning the
+    /// updated record. Flows that can continue are claimed for the caller.
+    fn attach(&self, purchase: &Purchase) -> Option<FlowRecord> {
+        let mut records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        let mut running = self.running.lock().unwrap_or_else(|e| e.into_inner());
+        let record = records.iter_mut().find(|record| {
+            !running.contains(&record.id)
+                && record.stage == PurchaseFlowStage::Purchase
+                && is_same_purchase(record, purchase)
+        })?;
+
+        if record.purchase.as_ref().map(|known| known.purchase_state)
+            == Some(purchase.purchase_state)
+        {
+            return None;
+        }
+        record.purchase = Some(purchase.clone());
+        record.updated_at = now_millis();
+        if purchase.purchase_state != PurchaseStateValue::Pending as i32 {
+            running.insert(record.id.clone());
+        }
+
+        let attached = record.clone();
+        self.persist(&records);
+        Some(attached)
+    }
+
+    /// Remove the idle flows still waiting for the store to complete a purchase of this
+    /// product, returning them.
+    fn abandon(&self, product_id: &str) -> Vec<FlowRecord> {
+        let mut records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        let running = self.running.lock().unwrap_or_else(|e| e.into_inner());
+        let (abandoned, kept): (Vec<FlowRecord>, Vec<FlowRecord>) = std::mem::take(&mut *records)
+            .into_iter()
+            .partition(|record| {
+                !running.contains(&record.id)
+                    && record.stage == PurchaseFlowStage::Purchase
+                    && record.purchase.is_none()
+                    && record.request.product_id == product_id
+            });
+        *records = kept;
+        if !abandoned.is_empty() {
+            self.persist(&records);
+        }
+        abandoned
+    }
+
+    /// Claim every flow no thread is working on.
+    fn claim_idle(&self) -> Vec<FlowRecord> {
+        let records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        let mut running = self.running.lock().unwrap_or_else(|e| e.into_inner());
+        records
+            .iter()
+            .filter(|record| running.insert(record.id.clone()))
+            .cloned()
+            .collect()
+    }
+
+    fn persist(&self, records: &[FlowRecord]) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        if let Ok(json) = serde_json::to_vec(records) {
+            if let Some(dir) = path.parent() {
+                let _ = std::fs::create_dir_all(dir);
+            }
+            let _ = std::fs::write(path, json);
+        }
+    }
+}
+
+/// Whether a purchase is the one a flow waits for or works on.
+///
+/// Once the flow knows its purchase, the token decides. Before that, a purchase of the
+/// product counts only if it was made within [`MATCH_WINDOW_MS`] of the flow starting and,
+/// when both name one, for the same account. A flow left behind by a purchase that never
+/// completed then does not claim later purchases of its product.
+fn is_same_purchase(record: &FlowRecord, purchase: &Purchase) -> bool {
+    if let Some(known) = record
+        .purchase
+        .as_ref()
+        .filter(|known| !known.purchase_token.is_empty())
+    {
+        return known.purchase_token == purchase.purchase_token;
+    }
+    if record.request.product_id != purchase.product_id {
+        return false;
+    }
+
+    let started_at = record.updated_at;
+    let in_window = purchase.purchase_time >= started_at - CLOCK_SKEW_MS
+        && purchase.purchase_time <= started_at + MATCH_WINDOW_MS;
+    let requested_account = record.request.options.as_ref().and_then(|options| {
+        options
+            .obfuscated_account_id
+            .as_deref()
+            .or(options.app_account_token.as_deref())
+    });
+    let same_account = match (requested_account, purchase_account(purchase)) {
+        (Some(requested), Some(account)) => requested == account,
+        _ => true,
+    };
+    in_window && same_account
+}
+
+/// The account a purchase was made for, from the `obfuscatedAccountId` (Google Play) or
+/// `appAccountToken` (App Store) of its original JSON.
+fn purchase_account(purchase: &Purchase) -> Option<String> {
+    let json: serde_json::Value = serde_json::from_str(&purchase.original_json).ok()?;
+    ["obfuscatedAccountId", "appAccountToken"]
+        .iter()
+        .find_map(|key| json[key].as_str().map(str::to_string))
+}
+
+fn is_canceled(error: &crate::Error) -> bool {
+    error
+        .code()
+        .is_some_and(|code| CANCELED_CODES.contains(&code))
+}
+
+fn stopped_waiting(error: &crate::Error) -> bool {
+    error
+        .code()
+        .is_some_and(|code| STOPPED_WAITING_CODES.contains(&code))
+}
+
+/// The outcome of a flow whose `purchase` call failed.
+///
+/// A flow that only stopped waiting stays at the purchase stage, so a purchase the store
+/// still completes continues it.
+fn purchase_failed(flows: &PurchaseFlows, id: &str, err: crate::Error) -> PurchaseFlowOutcome {
+    if !stopped_waiting(&err) {
+        flows.remove(id);
+    }
+    if is_canceled(&err) {
+        PurchaseFlowOutcome::Canceled
+    } else {
+        PurchaseFlowOutcome::Failed {
+            stage: PurchaseFlowStage::Purchase,
+            error: err.to_string(),
+            purchase: None,
+        }
+    }
+}
+
+/// `purchase` as the command runs it: with the `before_purchase` and `on_purchase_failed`
+/// hooks, a reconnect on a dropped connection and the purchase marked as first seen or
+/// redelivered.
+pub(crate) fn purchase<R: Runtime>(
+    app: &AppHandle<R>,
+    request: PurchaseRequest,
+) -> crate::Result<Purchase> {
+    crate::hooks::before_purchase(app, &request)?;
+    let result = with_reconnect(app, || crate::in_flight::purchase(app, request.clone()));
+    if let Err(err) = &result {
+        crate::hooks::purchase_failed(app, &request, err);
+    }
+
+    let mut purchase = result?;
+    crate::delivery::stamp(app, std::slice::from_mut(&mut purchase));
+    Ok(purchase)
+}
+
+/// Purchase a product and run it through verification, granting and acknowledging or
+/// consuming, as one flow.
+///
+/// The flow is saved before every stage. A flow interrupted by a crash resumes on the next
+/// `initialize`, and one waiting on a pending payment continues when the store completes it.
+/// A flow whose `purchase` call timed out or was canceled keeps waiting for the store flow
+/// it started. Their outcome is emitted as a `purchaseFlowFinished` event.
+pub fn purchase_and_fulfill<R: Runtime>(
+    app: &AppHandle<R>,
+    request: PurchaseAndFulfillRequest,
+) -> PurchaseFlowOutcome {
+    let flows = app.state::<PurchaseFlows>();
+    let PurchaseAndFulfillRequest {
+        purchase: mut request,
+        consume,
+    } = request;
+    let id = request
+        .request_id
+        .get_or_insert_with(|| format!("flow-{}-{}", request.product_id, now_millis()))
+        .clone();
+
+    if !flows.claim(&id) {
+        return PurchaseFlowOutcome::Failed {
+            stage: PurchaseFlowStage::Purchase,
+            error: format!("Purchase flow {id} is already running"),
+            purchase: None,
+        };
+    }
+
+    let record = flows.start(id.clone(), request.clone(), consume);
+
+    let outcome = match purchase(app, request) {
+        Ok(purchase) => advance(app, &flows, record, purchase),
+        Err(err) => purchase_failed(&flows, &id, err),
+    };
+    flows.release(&id);
+    outcome
+}
+
+/// Why a purchase did not pass verification.
+pub(crate) struct VerifyFailure {
+    pub error: String,
+    /// The validation server could not be reached, verifying again later may pass.
+    pub retry: bool,
+}
+
+/// Check a purchase with the validator and the `verify` step of the registered
+/// [`PurchaseFlowSteps`], each only if there is one.
+pub(crate) fn verify<R: Runtime>(
+    app: &AppHandle<R>,
+    purchase: &Purchase,
+) -> std::result::Result<(), VerifyFailure> {
+    #[cfg(feature = "remote-validator")]
+    match crate::validator::validate(app, purchase) {
+        Some(Ok(verdict)) if !verdict.valid => {
+            return Err(VerifyFailure {
+                error: verdict
+                    .reason
+                    .unwrap_or_else(|| "The validation server rejected the purchase".to_string()),
+                retry: false,
+            });
+        }
+        Some(Err(err)) => {
+            return Err(VerifyFailure {
+                retry: err.code() == Some("validationUnavailable"),
+                error: err.to_string(),
+            });
+        }
+        _ => {}
+    }
+
+    let steps = app.state::<PurchaseFlows>().steps.clone();
+    match steps.map(|steps| steps.verify(purchase)) {
+        Some(Err(error)) => Err(VerifyFailure {
+            error,
+            retry: false,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Whether a purchase flow waits for or works on this purchase, which it then acknowledges or
+/// consumes itself.
+pub(crate) fn handles<R: Runtime>(app: &AppHandle<R>, purchase: &Purchase) -> bool {
+    app.state::<PurchaseFlows>()
+        .records
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .any(|record| is_same_purchase(record, purchase))
+}
+
+/// Run the stages that follow the store purchase, starting at the stage of the record.
+fn advance<R: Runtime>(
+    app: &AppHandle<R>,
+    flows: &PurchaseFlows,
+    mut record: FlowRecord,
+    mut purchase: Purchase,
+) -> PurchaseFlowOutcome {
+    record.purchase = Some(purchase.clone());
+    if purchase.purchase_state == PurchaseStateValue::Pending as i32 {
+        flows.save(&mut record);
+        return PurchaseFlowOutcome::Pending { purchase };
+    }
+    if purchase.purchase_state == PurchaseStateValue::Canceled as i32 {
+        flows.remove(&record.id);
+        return PurchaseFlowOutcome::Canceled;
+    }
+
+    let id = record.id.clone();
+    let failed = |stage, error: String, purchase: Purchase| {
+        flows.remove(&id);
+        PurchaseFlowOutcome::Failed {
+            stage,
+            error,
+            purchase: Some(purchase),
+        }
+    };
+
+    if record.stage == PurchaseFlowStage::Purchase {
+        record.stage = PurchaseFlowStage::Verify;
+        flows.save(&mut record);
+    }
+
+    if record.stage == PurchaseFlowStage::Verify {
+        if let Err(failure) = verify(app, &purchase) {
+            // Kept at this stage, so the flow is verified again when it resumes
+            if !failure.retry {
+                flows.remove(&id);
+            }
+            return PurchaseFlowOutcome::Failed {
+                stage: PurchaseFlowStage::Verify,
+                error: failure.error,
+                purchase: Some(purchase),
+            };
+        }
+        crate::hooks::purchase_verified(app, &purchase);
+        record.stage = PurchaseFlowStage::Grant;
+        flows.save(&mut record);
+    }
+
+    if record.stage == PurchaseFlowStage::Grant {
+        // Checked again rather than trusting the saved purchase, a crash may have come between
+        // granting and saving the next stage
+        purchase.fulfilled = crate::delivery::is_fulfilled(app, &purchase.purchase_token);
+        if !purchase.fulfilled {
+            if let Some(Err(error)) = flows.steps.as_ref().map(|steps| steps.grant(&purchase)) {
+                return failed(PurchaseFlowStage::Grant, error, purchase);
+            }
+            purchase.fulfilled = crate::delivery::mark_fulfilled(app, &purchase.purchase_token);
+        }
+        record.stage = PurchaseFlowStage::Finish;
+        flows.save(&mut record);
+    }
+
+    // Currency products are credited to the wallet, which consumes them
+    let credited_by_wallet = app
+        .state::<Config>()
+        .currency
+        .catalog
+        .contains_key(&purchase.product_id);
+    // A failed call is queued for retry and does not fail the flow
+    let finished = if credited_by_wallet {
+        crate::currency::credit(app, &purchase)
+    } else if record.consume {
+        crate::retry_queue::consume(app, purchase.purchase_token.clone()).map(drop)
+    } else if !purchase.is_acknowledged {
+        crate::retry_queue::acknowledge(app, purchase.purchase_token.clone()).map(drop)
+    } else {
+        Ok(())
+    };
+    if let Err(err) = finished {
+        return failed(PurchaseFlowStage::Finish, err.to_string(), purchase);
+    }
+
+    flows.remove(&record.id);
+    PurchaseFlowOutcome::Granted { purchase }
+}
+
+fn finished<R: Runtime>(app: &AppHandle<R>, flow_id: String, outcome: PurchaseFlowOutcome) {
+    events::emit(
+        app,
+        IapEvent::PurchaseFlowFinished(PurchaseFlowFinished { flow_id, outcome }),
+    );
+}
+
+/// Continue flows waiting on the store when it reports their purchase, e.g. a pending
+/// payment that completed.
+pub(crate) fn observe<R: Runtime>(app: &AppHandle<R>, purchases: &[Purchase]) {
+    let flows = app.state::<PurchaseFlows>();
+    for purchase in purchases {
+        let Some(record) = flows.attach(purchase) else {
+            continue;
+        };
+        if purchase.purchase_state == PurchaseStateValue::Pending as i32 {
+            continue;
+        }
+
+        // Leave the thread that reported the purchase to its own work
+        let flow_app = app.clone();
+        let purchase = purchase.clone();
+        std::thread::spawn(move || {
+            let flows = flow_app.state::<PurchaseFlows>();
+            let id = record.id.clone();
+            let outcome = advance(&flow_app, &flows, record, purchase);
+            flows.release(&id);
+            finished(&flow_app, id, outcome);
+        });
+    }
+}
+
+/// End the flows that stopped waiting on a store flow which then failed, e.g. because the
+/// user dismissed the store UI after the `purchase` call timed out.
+pub(crate) fn store_flow_failed<R: Runtime>(
+    app: &AppHandle<R>,
+    product_id: &str,
+    error: &crate::Error,
+) {
+    for record in app.state::<PurchaseFlows>().abandon(product_id) {
+        let outcome = if is_canceled(error) {
+            PurchaseFlowOutcome::Canceled
+        } else {
+            PurchaseFlowOutcome::Failed {
+                stage: PurchaseFlowStage::Purchase,
+                error: error.to_string(),
+                purchase: None,
+            }
+        };
+        finished(app, record.id, outcome);
+    }
+}
+
+/// Continue the flows of earlier sessions, once the unfinished purchases were replayed.
+///
+/// A flow the store never reported a purchase for is dropped: its store flow ended with the
+/// app and the purchase did not complete.
+pub(crate) fn resume<R: Runtime>(app: &AppHandle<R>) {
+    let flows = app.state::<PurchaseFlows>();
+    for record in flows.claim_idle() {
+        let id = record.id.clone();
+        let outcome = match record.purchase.clone() {
+            // Still pending, `observe` continues it
+            Some(purchase) if purchase.purchase_state == PurchaseStateValue::Pending as i32 => {
+                flows.release(&id);
+                continue;
+            }
+            Some(purchase) => advance(app, &flows, record, purchase),
+            None => {
+                flows.remove(&id);
+                PurchaseFlowOutcome::Failed {
+                    stage: PurchaseFlowStage::Purchase,
+                    error: "The purchase flow was interrupted before the store completed it"
+                        .to_string(),
+                    purchase: None,
+                }
+            }
+        };
+        flows.release(&id);
+        finished(app, id, outcome);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tauri::test::{mock_app, MockRuntime};
+    use tauri::App;
+
+    use super::*;
+    use crate::delivery::Deliveries;
+    use crate::events::EventBuffer;
+    use crate::hooks::{Hooks, IapHooks};
+    use crate::ledger::Ledger;
+
+    #[derive(Default)]
+    struct Steps {
+        verified: AtomicUsize,
+        granted: AtomicUsize,
+        rejection: Option<String>,
+    }
+
+    impl PurchaseFlowSteps for Steps {
+        fn verify(&self, _purchase: &Purchase) -> std::result::Result<(), String> {
+            self.verified.fetch_add(1, Ordering::SeqCst);
+            self.rejection.clone().map_or(Ok(()), Err)
+        }
+
+        fn grant(&self, _purchase: &Purchase) -> std::result::Result<(), String> {
+            self.granted.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    fn app(steps: &Arc<Steps>) -> App<MockRuntime> {
+        app_with(steps.clone(), None)
+    }
+
+    fn app_with(
+        steps: Arc<dyn PurchaseFlowSteps>,
+        hooks: Option<Arc<dyn IapHooks>>,
+    ) -> App<MockRuntime> {
+        let app = mock_app();
+        app.manage(Config::default());
+        app.manage(EventBuffer::default());
+        app.manage(Deliveries::with_path(None));
+        app.manage(Ledger::with_path(None));
+        app.manage(Hooks::new(hooks));
+        #[cfg(feature = "remote-validator")]
+        app.manage(crate::validator::Validation(None));
+        app.manage(PurchaseFlows::with_path(None, Some(steps)));
+        app
+    }
+
+    /// Hooks and flow steps that log their calls in order.
+    #[derive(Default)]
+    struct Recording {
+        calls: Mutex<Vec<&'static str>>,
+        rejection: Option<String>,
+    }
+
+    impl Recording {
+        fn log(&self, call: &'static str) {
+            self.calls.lock().unwrap().push(call);
+        }
+    }
+
+    impl IapHooks for Arc<Recording> {
+        fn on_purchase_completed(&self, _purchase: &Purchase) {
+            self.log("completed");
+        }
+
+        fn on_purchase_verified(&self, _purchase: &Purchase) {
+            self.log("verified");
+        }
+    }
+
+    impl PurchaseFlowSteps for Arc<Recording> {
+        fn verify(&self, _purchase: &Purchase) -> std::result::Result<(), String> {
+            self.log("verify");
+            self.rejection.clone().map_or(Ok(()), Err)
+        }
+
+        fn grant(&self, _purchase: &Purchase) -> std::result::Result<(), String> {
+            self.log("grant");
+            Ok(())
+        }
+    }
+
+    /// Report a purchase the way `purchase` does, then run its flow.
+    fn report_and_advance(recording: &Arc<Recording>) -> PurchaseFlowOutcome {
+        let app = app_with(
+            Arc::new(recording.clone()),
+            Some(Arc::new(recording.clone())),
+        );
+
+        let purchase = purchase(PurchaseStateValue::Purchased);
+        crate::hooks::observe(app.handle(), std::slice::from_ref(&purchase));
+        let flows = app.state::<PurchaseFlows>();
+        advance(
+            app.handle(),
+            &flows,
+            record("flow", PurchaseFlowStage::Purchase, None),
+            purchase,
+        )
+    }
+
+    fn record(id: &str, stage: PurchaseFlowStage, purchase: Option<Purchase>) -> FlowRecord {
+        FlowRecord {
+            id: id.to_string(),
+            request: serde_json::from_value(serde_json::json!({
+                "productId": "coins",
+                "productType": "inapp",
+                "requestId": id,
+            }))
+            .unwrap(),
+            consume: false,
+            stage,
+            purchase,
+            updated_at: 0,
+        }
+    }
+
+    /// An acknowledged purchase, so finishing a flow does not call the store.
+    fn purchase(state: PurchaseStateValue) -> Purchase {
+        serde_json::from_value(serde_json::json!({
+            "orderId": "order",
+            "packageName": "app",
+            "productId": "coins",
+            "purchaseTime": now_millis(),
+            "purchaseToken": "token",
+            "purchaseState": state as i32,
+            "isAutoRenewing": false,
+            "isAcknowledged": true,
+            "originalJson": "",
+            "signature": "",
+        }))
+        .unwrap()
+    }
+
+    fn stages(app: &App<MockRuntime>) -> Vec<PurchaseFlowStage> {
+        app.state::<PurchaseFlows>()
+            .records
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|record| record.stage)
+            .collect()
+    }
+
+    #[test]
+    fn a_completed_purchase_runs_every_stage() {
+        let steps = Arc::new(Steps::default());
+        let app = app(&steps);
+        let flows = app.state::<PurchaseFlows>();
+        let mut purchase = purchase(PurchaseStateValue::Purchased);
+        crate::delivery::stamp(app.handle(), std::slice::from_mut(&mut purchase));
+
+        let mut record = record("flow", PurchaseFlowStage::Purchase, None);
+        flows.save(&mut record);
+        let outcome = advance(app.handle(), &flows, record, purchase);
+
+        let PurchaseFlowOutcome::Granted { purchase } = outcome else {
+            panic!("the flow did not grant the purchase");
+        };
+        assert!(purchase.fulfilled);
+        assert_eq!(steps.verified.load(Ordering::SeqCst), 1);
+        assert_eq!(steps.granted.load(Ordering::SeqCst), 1);
+        assert!(stages(&app).is_empty());
+    }
+
+    #[test]
+    fn a_rejected_purchase_is_not_granted() {
+        let steps = Arc::new(Steps {
+            rejection: Some("Bad signature".to_string()),
+            ..Steps::default()
+        });
+        let app = app(&steps);
+        let flows = app.state::<PurchaseFlows>();
+
+        let record = record("flow", PurchaseFlowStage::Purchase, None);
+        let outcome = advance(
+            app.handle(),
+            &flows,
+            record,
+            purchase(PurchaseStateValue::Purchased),
+        );
+
+        assert!(matches!(
+            outcome,
+            PurchaseFlowOutcome::Failed {
+                stage: PurchaseFlowStage::Verify,
+                ..
+            }
+        ));
+        assert_eq!(steps.granted.load(Ordering::SeqCst), 0);
+        assert!(stages(&app).is_empty());
+    }
+
+    #[test]
+    fn hooks_see_a_purchase_before_and_after_verification() {
+        let recording = Arc::new(Recording::default());
+
+        let outcome = report_and_advance(&recording);
+
+        assert!(matches!(outcome, PurchaseFlowOutcome::Granted { .. }));
+        assert_eq!(
+            *recording.calls.lock().unwrap(),
+            vec!["completed", "verify", "verified", "grant"]
+        );
+    }
+
+    #[test]
+    fn a_rejected_purchase_is_not_reported_as_verified() {
+        let recording = Arc::new(Recording {
+            rejection: Some("Bad signature".to_string()),
+            ..Recording::default()
+        });
+
+        let outcome = report_and_advance(&recording);
+
+        assert!(matches!(outcome, PurchaseFlowOutcome::Failed { .. }));
+        assert_eq!(
+            *recording.calls.lock().unwrap(),
+            vec!["completed", "verify"]
+        );
+    }
+
+    #[test]
+    fn a_stale_flow_does_not_claim_a_later_purchase_of_its_product() {
+        let steps = Arc::new(Steps::default());
+        let app = app(&steps);
+        let flows = app.state::<PurchaseFlows>();
+        // Started two hours ago by a purchase the store never completed
+        flows.records.lock().unwrap().push(FlowRecord {
+            updated_at: now_millis() - 2 * 60 * 60 * 1000,
+            ..record("stale", PurchaseFlowStage::Purchase, None)
+        });
+
+        let purchase = purchase(PurchaseStateValue::Purchased);
+        assert!(!handles(app.handle(), &purchase));
+        assert!(flows.attach(&purchase).is_none());
+
+        let mut current = record("current", PurchaseFlowStage::Purchase, None);
+        flows.save(&mut current);
+        assert!(handles(app.handle(), &purchase));
+        let attached = flows.attach(&purchase).expect("the flow was not attached");
+        assert_eq!(attached.id, "current");
+    }
+
+    #[test]
+    fn a_flow_does_not_claim_a_purchase_of_another_account() {
+        let steps = Arc::new(Steps::default());
+        let app = app(&steps);
+        let flows = app.state::<PurchaseFlows>();
+        let mut flow = record("flow", PurchaseFlowStage::Purchase, None);
+        flow.request.options = Some(
+            serde_json::from_value(serde_json::json!({ "obfuscatedAccountId": "alice" })).unwrap(),
+        );
+        flows.save(&mut flow);
+
+        let mut other = purchase(PurchaseStateValue::Purchased);
+        other.original_json = r#"{"obfuscatedAccountId":"bob"}"#.to_string();
+        assert!(!handles(app.handle(), &other));
+
+        let mut own = purchase(PurchaseStateValue::Purchased);
+        own.original_json = r#"{"obfuscatedAccountId":"alice"}"#.to_string();
+        assert!(handles(app.handle(), &own));
+        // Stores that do not report the account match by product and time alone
+        assert!(handles(
+            app.handle(),
+            &purchase(PurchaseStateValue::Purchased)
+        ));
+    }
+
+    #[test]
+    fn a_pending_purchase_waits_at_the_purchase_stage_until_it_completes() {
+        let steps = Arc::new(Steps::default());
+        let app = app(&steps);
+        let flows = app.state::<PurchaseFlows>();
+
+        let record = record("flow", PurchaseFlowStage::Purchase, None);
+        let outcome = advance(
+            app.handle(),
+            &flows,
+            record,
+            purchase(PurchaseStateValue::Pending),
+        );
+        assert!(matches!(outcome, PurchaseFlowOutcome::Pending { .. }));
+        assert_eq!(stages(&app), vec![PurchaseFlowStage::Purchase]);
+        assert_eq!(steps.verified.load(Ordering::SeqCst), 0);
+
+        // Reported again while still pending
+        assert!(flows
+            .attach(&purchase(PurchaseStateValue::Pending))
+            .is_none());
+
+        let completed = purchase(PurchaseStateValue::Purchased);
+        let record = flows.attach(&completed).expect("the flow was not attached");
+        assert!(!flows.claim("flow"));
+        let outcome = advance(app.handle(), &flows, record, completed);
+        assert!(matches!(outcome, PurchaseFlowOutcome::Granted { .. }));
+        assert!(stages(&app).is_empty());
+    }
+
+    #[test]
+    fn a_canceled_purchase_ends_the_flow() {
+        let steps = Arc::new(Steps::default());
+        let app = app(&steps);
+        let flows = app.state::<PurchaseFlows>();
+
+        let mut record = record("flow", PurchaseFlowStage::Purchase, None);
+        flows.save(&mut record);
+        let outcome = advance(
+            app.handle(),
+            &flows,
+            record,
+            purchase(PurchaseStateValue::Canceled),
+        );
+
+        assert!(matches!(outcome, PurchaseFlowOutcome::Canceled));
+        assert!(stages(&app).is_empty());
+    }
+
+    #[test]
+    fn a_flow_that_stopped_waiting_continues_with_the_late_purchase() {
+        for code in ["timedOut", "purchaseCanceled"] {
+            let steps = Arc::new(Steps::default());
+            let app = app(&steps);
+            let flows = app.state::<PurchaseFlows>();
+
+            let mut record = record("flow", PurchaseFlowStage::Purchase, None);
+            flows.save(&mut record);
+            assert!(flows.claim("flow"));
+            purchase_failed(
+                &flows,
+                "flow",
+                crate::Error::rejected(code, "Stopped waiting"),
+            );
+            flows.release("flow");
+            assert_eq!(stages(&app), vec![PurchaseFlowStage::Purchase], "{code}");
+
+            let completed = purchase(PurchaseStateValue::Purchased);
+            let record = flows.attach(&completed).expect("the flow was not attached");
+            let outcome = advance(app.handle(), &flows, record, completed);
+            assert!(
+                matches!(outcome, PurchaseFlowOutcome::Granted { .. }),
+                "{code}"
+            );
+        }
+    }
+
+    #[test]
+    fn a_store_cancel_ends_the_flow() {
+        let steps = Arc::new(Steps::default());
+        let app = app(&steps);
+        let flows = app.state::<PurchaseFlows>();
+
+        let mut record = record("flow", PurchaseFlowStage::Purchase, None);
+        flows.save(&mut record);
+        let outcome = purchase_failed(
+            &flows,
+            "flow",
+            crate::Error::rejected("userCanceled", "Canceled"),
+        );
+
+        assert!(matches!(outcome, PurchaseFlowOutcome::Canceled));
+        assert!(stages(&app).is_empty());
+    }
+
+    #[test]
+    fn a_failed_store_flow_ends_the_flows_waiting_for_it() {
+        let steps = Arc::new(Steps::default());
+        let app = app(&steps);
+        let flows = app.state::<PurchaseFlows>();
+
+        let mut waiting = record("waiting", PurchaseFlowStage::Purchase, None);
+        flows.save(&mut waiting);
+        let mut running = record("running", PurchaseFlowStage::Purchase, None);
+        flows.save(&mut running);
+        assert!(flows.claim("running"));
+
+        store_flow_failed(
+            app.handle(),
+            "coins",
+            &crate::Error::rejected("userCanceled", "Canceled"),
+        );
+
+        let ids: Vec<String> = flows
+            .records
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|record| record.id.clone())
+            .collect();
+        assert_eq!(ids, vec!["running".to_string()]);
+    }
+
+    #[test]
+    fn resume_continues_at_the_saved_stage() {
+        let steps = Arc::new(Steps::default());
+        let app = app(&steps);
+        let flows = app.state::<PurchaseFlows>();
+        let mut purchase = purchase(PurchaseStateValue::Purchased);
+        crate::delivery::stamp(app.handle(), std::slice::from_mut(&mut purchase));
+
+        let mut granting = record("granting", PurchaseFlowStage::Grant, Some(purchase));
+        flows.save(&mut granting);
+        let mut pending = record(
+            "pending",
+            PurchaseFlowStage::Purchase,
+            Some(self::purchase(PurchaseStateValue::Pending)),
+        );
+        flows.save(&mut pending);
+        let mut interrupted = record("interrupted", PurchaseFlowStage::Purchase, None);
+        flows.save(&mut interrupted);
+
+        resume(app.handle());
+
+        // Verified before the crash, only the grant runs again
+        assert_eq!(steps.verified.load(Ordering::SeqCst), 0);
+        assert_eq!(steps.granted.load(Ordering::SeqCst), 1);
+        assert_eq!(stages(&app), vec![PurchaseFlowStage::Purchase]);
+        assert!(flows.claim("pending"));
+    }
+
+    #[test]
+    fn resume_does_not_grant_a_fulfilled_purchase_again() {
+        let steps = Arc::new(Steps::default());
+        let app = app(&steps);
+        let flows = app.state::<PurchaseFlows>();
+        let mut purchase = purchase(PurchaseStateValue::Purchased);
+        crate::delivery::stamp(app.handle(), std::slice::from_mut(&mut purchase));
+        crate::delivery::mark_fulfilled(app.handle(), &purchase.purchase_token);
+
+        let mut record = record("flow", PurchaseFlowStage::Grant, Some(purchase));
+        flows.save(&mut record);
+        resume(app.handle());
+
+        assert_eq!(steps.granted.load(Ordering::SeqCst), 0);
+        assert!(stages(&app).is_empty());
+    }
+
+    #[test]
+    fn flows_are_saved_to_disk() {
+        let path = std::env::temp_dir().join(format!("iap-flows-{}.json", now_millis()));
+        let flows = PurchaseFlows::with_path(Some(path.clone()), None);
+        let mut record = record("flow", PurchaseFlowStage::Verify, None);
+        flows.save(&mut record);
+
+        let reloaded = PurchaseFlows::with_path(Some(path.clone()), None);
+        let stages: Vec<PurchaseFlowStage> = reloaded
+            .claim_idle()
+            .iter()
+            .map(|record| record.stage)
+            .collect();
+        assert_eq!(stages, vec![PurchaseFlowStage::Verify]);
+        let _ = std::fs::remove_file(path);
+    }
+}
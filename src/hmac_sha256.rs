@@ -0,0 +1,98 @@
+//! HMAC-SHA256 with hex encoded signatures, for the wallet file and Stripe webhooks.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn mac(key: &[u8], message: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac
+}
+
+/// The HMAC-SHA256 of a message, hex encoded.
+#[cfg(any(feature = "plugin", test))]
+pub(crate) fn hex(key: &[u8], message: &[u8]) -> String {
+    to_hex(&mac(key, message).finalize().into_bytes())
+}
+
+/// Whether `signature` is the hex encoded HMAC-SHA256 of the message, compared in constant
+/// time.
+pub(crate) fn verify(key: &[u8], message: &[u8], signature: &str) -> bool {
+    from_hex(signature).is_some_and(|bytes| mac(key, message).verify_slice(&bytes).is_ok())
+}
+
+#[cfg(any(feature = "plugin", test))]
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_rfc_4231() {
+        let cases: [(Vec<u8>, Vec<u8>, &str); 6] = [
+            (
+                vec![0x0b; 20],
+                b"Hi There".to_vec(),
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            ),
+            (
+                b"Jefe".to_vec(),
+                b"what do ya want for nothing?".to_vec(),
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+            (
+                vec![0xaa; 20],
+                vec![0xdd; 50],
+                "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe",
+            ),
+            (
+                (0x01..=0x19).collect(),
+                vec![0xcd; 50],
+                "82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b",
+            ),
+            // Keys longer than the block size are hashed first
+            (
+                vec![0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First".to_vec(),
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            ),
+            (
+                vec![0xaa; 131],
+                b"This is a test using a larger than block-size key and a larger than block-size data. The key needs to be hashed before being used by the HMAC algorithm.".to_vec(),
+                "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2",
+            ),
+        ];
+
+        for (key, message, expected) in cases {
+            assert_eq!(hex(&key, &message), expected);
+            assert!(verify(&key, &message, expected));
+        }
+    }
+
+    #[test]
+    fn verify_rejects_other_and_malformed_signatures() {
+        let signature = hex(b"key", b"message");
+        assert!(verify(b"key", b"message", &signature));
+        assert!(!verify(b"key", b"massage", &signature));
+        assert!(!verify(b"other", b"message", &signature));
+        assert!(!verify(b"key", b"message", &signature[..62]));
+        assert!(!verify(b"key", b"message", &signature[..63]));
+        assert!(!verify(b"key", b"message", &signature.replace('a', "g")));
+        assert!(!verify(b"key", b"message", ""));
+    }
+}
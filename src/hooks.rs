@@ -0,0 +1,199 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use tauri::{AppHandle, Manager, Runtime};
+
+use crate::models::*;
+
+/// Callbacks for Rust code that reacts to purchases without going through JS listeners.
+///
+/// Register an implementation with [`Builder::hooks`](crate::Builder::hooks). Every method
+/// has an empty default. Hooks run on the thread of the command or event that triggered
+/// them, so long work should be moved to a thread of its own.
+pub trait IapHooks: Send + Sync + 'static {
+    /// Called before a purchase flow starts. Returning an error vetoes the purchase, which
+    /// then fails with a `purchaseVetoed` error carrying the message.
+    fn before_purchase(&self, request: &PurchaseRequest) -> std::result::Result<(), String> {
+        let _ = request;
+        Ok(())
+    }
+
+    /// Called once per session for every purchase the store reports as completed through
+    /// `purchase`, a purchase event or the replay of unfinished purchases.
+    ///
+    /// It runs before any verification, so grant content from
+    /// [`on_purchase_verified`](Self::on_purchase_verified) or
+    /// [`PurchaseFlowSteps::grant`](crate::PurchaseFlowSteps::grant) instead. Purchases
+    /// returned by `restore_purchases` never trigger it.
+    fn on_purchase_completed(&self, purchase: &Purchase) {
+        let _ = purchase;
+    }
+
+    /// Called when a purchase of `purchase_and_fulfill` passed the validator and the
+    /// [`PurchaseFlowSteps::verify`](crate::PurchaseFlowSteps::verify) step, right before
+    /// it is granted. A flow resumed after a crash may call it again for the same purchase.
+    fn on_purchase_verified(&self, purchase: &Purchase) {
+        let _ = purchase;
+    }
+
+    /// Called when a `purchase` call fails, including timeouts and cancellations.
+    fn on_purchase_failed(&self, request: &PurchaseRequest, error: &crate::Error) {
+        let _ = (request, error);
+    }
+
+    /// Called when `restore_purchases` returns a different set of owned products than the
+    /// previous restore of the same product type, with everything that is owned now.
+    fn on_entitlement_changed(&self, product_type: &str, entitlements: &[Purchase]) {
+        let _ = (product_type, entitlements);
+    }
+
+    /// Called when an entitlement was refunded or revoked.
+    fn on_refund(&self, revocation: &PurchaseRevocation) {
+        let _ = revocation;
+    }
+}
+
+/// The registered hooks, if any.
+pub(crate) struct Hooks {
+    hooks: Option<Arc<dyn IapHooks>>,
+    /// Tokens `on_purchase_completed` was called for in this session.
+    completed: Mutex<HashSet<String>>,
+}
+
+impl Hooks {
+    pub fn new(hooks: Option<Arc<dyn IapHooks>>) -> Self {
+        Self {
+            hooks,
+            completed: Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+fn registered<R: Runtime>(app: &AppHandle<R>) -> Option<Arc<dyn IapHooks>> {
+    app.state::<Hooks>().hooks.clone()
+}
+
+pub(crate) fn before_purchase<R: Runtime>(
+    app: &AppHandle<R>,
+    request: &PurchaseRequest,
+) -> crate::Result<()> {
+    let Some(hooks) = registered(app) else {
+        return Ok(());
+    };
+    hooks
+        .before_purchase(request)
+        .map_err(|reason| crate::Error::rejected("purchaseVetoed", reason))
+}
+
+pub(crate) fn purchase_failed<R: Runtime>(
+    app: &AppHandle<R>,
+    request: &PurchaseRequest,
+    error: &crate::Error,
+) {
+    if let Some(hooks) = registered(app) {
+        hooks.on_purchase_failed(request, error);
+    }
+}
+
+/// Call `on_purchase_completed` for completed purchases not seen in this session yet.
+pub(crate) fn observe<R: Runtime>(app: &AppHandle<R>, purchases: &[Purchase]) {
+    let Some(hooks) = registered(app) else {
+        return;
+    };
+
+    let state = app.state::<Hooks>();
+    for purchase in purchases {
+        if purchase.purchase_state != PurchaseStateValue::Purchased as i32 {
+            continue;
+        }
+        let first = purchase.purchase_token.is_empty()
+            || state
+                .completed
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(purchase.purchase_token.clone());
+        if first {
+            hooks.on_purchase_completed(purchase);
+        }
+    }
+}
+
+pub(crate) fn purchase_verified<R: Runtime>(app: &AppHandle<R>, purchase: &Purchase) {
+    if let Some(hooks) = registered(app) {
+        hooks.on_purchase_verified(purchase);
+    }
+}
+
+pub(crate) fn entitlement_changed<R: Runtime>(
+    app: &AppHandle<R>,
+    product_type: &str,
+    entitlements: &[Purchase],
+) {
+    if let Some(hooks) = registered(app) {
+        hooks.on_entitlement_changed(product_type, entitlements);
+    }
+}
+
+pub(crate) fn refund<R: Runtime>(app: &AppHandle<R>, revocation: &PurchaseRevocation) {
+    if let Some(hooks) = registered(app) {
+        hooks.on_refund(revocation);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tauri::test::mock_app;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Counting(AtomicUsize);
+
+    impl IapHooks for Arc<Counting> {
+        fn on_purchase_completed(&self, _purchase: &Purchase) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn purchase(token: &str, state: PurchaseStateValue) -> Purchase {
+        serde_json::from_value(serde_json::json!({
+            "orderId": "order",
+            "packageName": "app",
+            "productId": "coins",
+            "purchaseTime": 0,
+            "purchaseToken": token,
+            "purchaseState": state as i32,
+            "isAutoRenewing": false,
+            "isAcknowledged": false,
+            "originalJson": "",
+            "signature": "",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn completed_purchases_are_reported_once_per_session() {
+        let counting = Arc::new(Counting::default());
+        let app = mock_app();
+        app.manage(Hooks::new(Some(Arc::new(counting.clone()))));
+
+        observe(
+            app.handle(),
+            &[
+                purchase("pending", PurchaseStateValue::Pending),
+                purchase("a", PurchaseStateValue::Purchased),
+            ],
+        );
+        observe(
+            app.handle(),
+            &[
+                purchase("a", PurchaseStateValue::Purchased),
+                purchase("b", PurchaseStateValue::Purchased),
+            ],
+        );
+
+        assert_eq!(counting.0.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,536 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use tauri::{AppHandle, Manager, Runtime};
+
+use crate::models::*;
+use crate::updates;
+use crate::{now_millis, Config, DuplicatePurchasePolicy, IapExt};
+
+/// Product id and account a purchase flow runs for.
+type PurchaseKey = (String, Option<String>);
+
+/// One store purchase flow, shared by every request waiting for it.
+struct Flight {
+    purchase: InFlightPurchase,
+    state: Mutex<FlightState>,
+    done: Condvar,
+}
+
+#[derive(Default)]
+struct FlightState {
+    result: Option<crate::Result<Purchase>>,
+    /// Requests still waiting for the result.
+    waiting: usize,
+    /// Waiting requests that were canceled but have not noticed yet.
+    canceled: HashSet<String>,
+}
+
+/// Purchase flows currently waiting on the store.
+#[derive(Default)]
+pub(crate) struct InFlightPurchases {
+    /// Registered from the start of the store flow until the store reports back, even when
+    /// every request stopped waiting for it earlier.
+    flights: Mutex<HashMap<PurchaseKey, Arc<Flight>>>,
+    /// Waiting requests by request id.
+    waiters: Mutex<HashMap<String, Arc<Flight>>>,
+    next_request: AtomicU64,
+}
+
+impl InFlightPurchases {
+    /// The running purchase flows, oldest first.
+    pub fn list(&self) -> Vec<InFlightPurchase> {
+        let mut purchases: Vec<InFlightPurchase> = self
+            .flights
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .map(|flight| flight.purchase.clone())
+            .collect();
+        purchases.sort_by_key(|purchase| purchase.started_at);
+        purchases
+    }
+
+    /// Stop waiting for the purchase flow with this request id.
+    ///
+    /// The store UI may still complete the purchase, in which case it is delivered as a
+    /// `purchaseUpdated` event. Returns `false` if no such request is waiting.
+    pub fn cancel(&self, request_id: &str) -> bool {
+        let Some(flight) = self
+            .waiters
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(request_id)
+            .cloned()
+        else {
+            return false;
+        };
+
+        let mut state = flight.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.result.is_some() {
+            return false;
+        }
+        state.canceled.insert(request_id.to_string());
+        flight.done.notify_all();
+        true
+    }
+
+    fn next_request_id(&self, product_id: &str) -> String {
+        let sequence = self.next_request.fetch_add(1, Ordering::Relaxed);
+        format!("{product_id}-{}-{sequence}", now_millis())
+    }
+
+    /// Join the flight running for `key`, or register a new one, and register the request as
+    /// its waiter. Returns the flight and whether this request has to start it.
+    fn enter(
+        &self,
+        key: PurchaseKey,
+        purchase: InFlightPurchase,
+        policy: DuplicatePurchasePolicy,
+    ) -> crate::Result<(Arc<Flight>, bool)> {
+        let mut flights = self.flights.lock().unwrap_or_else(|e| e.into_inner());
+        let mut waiters = self.waiters.lock().unwrap_or_else(|e| e.into_inner());
+        let request_id = purchase.request_id.clone();
+        if waiters.contains_key(&request_id) {
+            return Err(crate::Error::rejected(
+                "duplicateRequestId",
+                format!("A purchase with the request id {request_id} is already running"),
+            ));
+        }
+
+        let (flight, started) = match flights.get(&key) {
+            Some(flight) if policy == DuplicatePurchasePolicy::Join => (flight.clone(), false),
+            Some(_) => {
+                return Err(crate::Error::rejected(
+                    "purchaseInProgress",
+                    format!(
+                        "A purchase of {} is already in progress",
+                        purchase.product_id
+                    ),
+                ))
+            }
+            None => {
+                let flight = Arc::new(Flight {
+                    purchase,
+                    state: Mutex::new(FlightState::default()),
+                    done: Condvar::new(),
+                });
+                flights.insert(key, flight.clone());
+                (flight, true)
+            }
+        };
+        // Counted before the store can report back, so the result is never lost in between,
+        // and registered before anyone can cancel it
+        flight
+            .state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .waiting += 1;
+        waiters.insert(request_id, flight.clone());
+        Ok((flight, started))
+    }
+
+    /// Wait for the flight's result, until this request is canceled or its timeout elapses.
+    fn wait(
+        &self,
+        flight: &Flight,
+        request_id: &str,
+        timeout_ms: Option<u64>,
+    ) -> crate::Result<Purchase> {
+        let deadline =
+            timeout_ms.map(|timeout_ms| Instant::now() + Duration::from_millis(timeout_ms));
+
+        let mut state = flight.state.lock().unwrap_or_else(|e| e.into_inner());
+        let result = loop {
+            if let Some(result) = &state.result {
+                break match result {
+                    Ok(purchase) => Ok(purchase.clone()),
+                    Err(err) => Err(err.share()),
+                };
+            }
+            if state.canceled.remove(request_id) {
+                break Err(crate::Error::rejected(
+                    "purchaseCanceled",
+                    format!("Purchase request {request_id} was canceled"),
+                ));
+            }
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break Err(crate::Error::rejected(
+                            "timedOut",
+                            format!(
+                                "Purchase request {request_id} timed out after {}ms",
+                                timeout_ms.unwrap_or_default()
+                            ),
+                        ));
+                    }
+                    flight
+                        .done
+                        .wait_timeout(state, deadline - now)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+                None => flight.done.wait(state).unwrap_or_else(|e| e.into_inner()),
+            };
+        };
+        state.waiting -= 1;
+        result
+    }
+
+    /// Record the store's result and unregister the flight. Returns whether every request
+    /// had stopped waiting, so nobody received the result.
+    fn land(
+        &self,
+        key: &PurchaseKey,
+        flight: &Arc<Flight>,
+        result: crate::Result<Purchase>,
+    ) -> bool {
+        {
+            let mut flights = self.flights.lock().unwrap_or_else(|e| e.into_inner());
+            if flights
+                .get(key)
+                .is_some_and(|registered| Arc::ptr_eq(registered, flight))
+            {
+                flights.remove(key);
+            }
+        }
+
+        let mut state = flight.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.result = Some(result);
+        flight.done.notify_all();
+        state.waiting == 0
+    }
+}
+
+/// `purchase` with at most one flow per product and account, an optional timeout and
+/// cancellation through `cancel_purchase`.
+///
+/// A second request for the same product and account joins the running flow or fails with
+/// `purchaseInProgress`, depending on [`Config::duplicate_purchase`]. The flow stays
+/// registered until the store reports back, also when every request stopped waiting for it.
+pub(crate) fn purchase<R: Runtime>(
+    app: &AppHandle<R>,
+    request: PurchaseRequest,
+) -> crate::Result<Purchase> {
+    let in_flight = app.state::<InFlightPurchases>();
+    let PurchaseRequest {
+        product_id,
+        product_type,
+        options,
+        request_id,
+        timeout_ms,
+    } = request;
+
+    let account_id = options.as_ref().and_then(|opts| {
+        opts.obfuscated_account_id
+            .clone()
+            .or_else(|| opts.app_account_token.clone())
+    });
+    let key = (product_id.clone(), account_id.clone());
+    let request_id = request_id.unwrap_or_else(|| in_flight.next_request_id(&product_id));
+
+    let (flight, started) = in_flight.enter(
+        key.clone(),
+        InFlightPurchase {
+            request_id: request_id.clone(),
+            product_id: product_id.clone(),
+            product_type: product_type.clone(),
+            account_id,
+            started_at: now_millis(),
+        },
+        app.state::<Config>().duplicate_purchase,
+    )?;
+    if started {
+        start_store_flow(app, key, flight.clone(), product_id, product_type, options);
+    }
+
+    let result = in_flight.wait(&flight, &request_id, timeout_ms);
+    in_flight
+        .waiters
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(&request_id);
+    result
+}
+
+/// Run the store purchase on its own thread and land its result on the flight.
+///
+/// A purchase that completes after every request stopped waiting is emitted as a
+/// `purchaseUpdated` event instead of being lost, and a failure ends the purchase flows
+/// still waiting for it.
+fn start_store_flow<R: Runtime>(
+    app: &AppHandle<R>,
+    key: PurchaseKey,
+    flight: Arc<Flight>,
+    product_id: String,
+    product_type: String,
+    options: Option<PurchaseOptions>,
+) {
+    let store_app = app.clone();
+    std::thread::spawn(move || {
+        let result = store_app
+            .iap()
+            .purchase(product_id, product_type.clone(), options);
+        let purchase = result.as_ref().ok().cloned();
+        let error = result.as_ref().err().map(crate::Error::share);
+        if let Some(purchase) = &purchase {
+            updates::observe(
+                &store_app,
+                Some(&product_type),
+                std::slice::from_ref(purchase),
+            );
+        }
+
+        let unclaimed = store_app
+            .state::<InFlightPurchases>()
+            .land(&key, &flight, result);
+        // The mobile plugins already emitted it themselves
+        if let Some(purchase) = purchase.filter(|_| unclaimed && !updates::NATIVE_PURCHASE_EVENTS) {
+            crate::delivery::emit(&store_app, purchase);
+        }
+        if let Some(error) = error {
+            crate::flow::store_flow_failed(&store_app, &key.0, &error);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn key() -> PurchaseKey {
+        ("coins".to_string(), Some("account".to_string()))
+    }
+
+    fn in_flight_purchase(request_id: &str) -> InFlightPurchase {
+        InFlightPurchase {
+            request_id: request_id.to_string(),
+            product_id: "coins".to_string(),
+            product_type: "inapp".to_string(),
+            account_id: Some("account".to_string()),
+            started_at: now_millis(),
+        }
+    }
+
+    fn purchase() -> Purchase {
+        serde_json::from_value(serde_json::json!({
+            "orderId": "order",
+            "packageName": "app",
+            "productId": "coins",
+            "purchaseTime": 0,
+            "purchaseToken": "token",
+            "purchaseState": 0,
+            "isAutoRenewing": false,
+            "isAcknowledged": false,
+            "originalJson": "",
+            "signature": "",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn the_key_stays_registered_after_a_timeout() {
+        let in_flight = InFlightPurchases::default();
+        let (flight, started) = in_flight
+            .enter(
+                key(),
+                in_flight_purchase("a"),
+                DuplicatePurchasePolicy::Reject,
+            )
+            .unwrap();
+        assert!(started);
+
+        let err = in_flight.wait(&flight, "a", Some(10)).unwrap_err();
+        assert_eq!(err.code(), Some("timedOut"));
+
+        // The store flow is still running, so a second purchase must not start another one
+        let err = in_flight
+            .enter(
+                key(),
+                in_flight_purchase("b"),
+                DuplicatePurchasePolicy::Reject,
+            )
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), Some("purchaseInProgress"));
+        assert_eq!(in_flight.list().len(), 1);
+
+        assert!(in_flight.land(&key(), &flight, Ok(purchase())));
+        assert!(in_flight.list().is_empty());
+        let (_, started) = in_flight
+            .enter(
+                key(),
+                in_flight_purchase("c"),
+                DuplicatePurchasePolicy::Reject,
+            )
+            .unwrap();
+        assert!(started);
+    }
+
+    #[test]
+    fn the_key_stays_registered_after_a_cancel() {
+        let in_flight = Arc::new(InFlightPurchases::default());
+        let (flight, _) = in_flight
+            .enter(
+                key(),
+                in_flight_purchase("a"),
+                DuplicatePurchasePolicy::Join,
+            )
+            .unwrap();
+
+        let waiter = {
+            let in_flight = in_flight.clone();
+            let flight = flight.clone();
+            thread::spawn(move || in_flight.wait(&flight, "a", None))
+        };
+        assert!(in_flight.cancel("a"));
+        assert_eq!(
+            waiter.join().unwrap().unwrap_err().code(),
+            Some("purchaseCanceled")
+        );
+
+        let (joined, started) = in_flight
+            .enter(
+                key(),
+                in_flight_purchase("b"),
+                DuplicatePurchasePolicy::Join,
+            )
+            .unwrap();
+        assert!(!started);
+        assert!(Arc::ptr_eq(&joined, &flight));
+    }
+
+    #[test]
+    fn joiners_have_their_own_timeout_and_cancel() {
+        let in_flight = Arc::new(InFlightPurchases::default());
+        let (flight, _) = in_flight
+            .enter(
+                key(),
+                in_flight_purchase("leader"),
+                DuplicatePurchasePolicy::Join,
+            )
+            .unwrap();
+        let leader = {
+            let in_flight = in_flight.clone();
+            let flight = flight.clone();
+            thread::spawn(move || in_flight.wait(&flight, "leader", None))
+        };
+
+        let (joined, started) = in_flight
+            .enter(
+                key(),
+                in_flight_purchase("quick"),
+                DuplicatePurchasePolicy::Join,
+            )
+            .unwrap();
+        assert!(!started);
+        let err = in_flight.wait(&joined, "quick", Some(10)).unwrap_err();
+        assert_eq!(err.code(), Some("timedOut"));
+
+        let (joined, _) = in_flight
+            .enter(
+                key(),
+                in_flight_purchase("canceled"),
+                DuplicatePurchasePolicy::Join,
+            )
+            .unwrap();
+        let canceled = {
+            let in_flight = in_flight.clone();
+            thread::spawn(move || in_flight.wait(&joined, "canceled", None))
+        };
+        assert!(in_flight.cancel("canceled"));
+        assert_eq!(
+            canceled.join().unwrap().unwrap_err().code(),
+            Some("purchaseCanceled")
+        );
+
+        // The leader keeps waiting and receives the purchase
+        assert!(!in_flight.land(&key(), &flight, Ok(purchase())));
+        assert_eq!(leader.join().unwrap().unwrap().purchase_token, "token");
+    }
+
+    #[test]
+    fn waiters_share_errors_with_their_code() {
+        let in_flight = Arc::new(InFlightPurchases::default());
+        let (flight, _) = in_flight
+            .enter(
+                key(),
+                in_flight_purchase("a"),
+                DuplicatePurchasePolicy::Join,
+            )
+            .unwrap();
+        let (joined, _) = in_flight
+            .enter(
+                key(),
+                in_flight_purchase("b"),
+                DuplicatePurchasePolicy::Join,
+            )
+            .unwrap();
+
+        in_flight.land(
+            &key(),
+            &flight,
+            Err(crate::Error::rejected("userCanceled", "Canceled")),
+        );
+
+        for (flight, request_id) in [(flight, "a"), (joined, "b")] {
+            let err = in_flight.wait(&flight, request_id, None).unwrap_err();
+            assert_eq!(err.code(), Some("userCanceled"));
+        }
+    }
+
+    #[test]
+    fn a_request_can_be_canceled_as_soon_as_it_entered() {
+        let in_flight = InFlightPurchases::default();
+        let (flight, _) = in_flight
+            .enter(
+                key(),
+                in_flight_purchase("a"),
+                DuplicatePurchasePolicy::Join,
+            )
+            .unwrap();
+
+        assert!(in_flight.cancel("a"));
+        let err = in_flight.wait(&flight, "a", None).unwrap_err();
+        assert_eq!(err.code(), Some("purchaseCanceled"));
+    }
+
+    #[test]
+    fn a_running_request_id_is_not_reused() {
+        let in_flight = InFlightPurchases::default();
+        let (flight, _) = in_flight
+            .enter(
+                key(),
+                in_flight_purchase("a"),
+                DuplicatePurchasePolicy::Join,
+            )
+            .unwrap();
+
+        let other_product = ("gems".to_string(), None);
+        let err = in_flight
+            .enter(
+                other_product,
+                in_flight_purchase("a"),
+                DuplicatePurchasePolicy::Join,
+            )
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), Some("duplicateRequestId"));
+        assert_eq!(in_flight.list().len(), 1);
+
+        // The first request keeps its registration
+        assert!(in_flight.cancel("a"));
+        assert!(in_flight.wait(&flight, "a", None).is_err());
+    }
+
+    #[test]
+    fn cancel_of_an_unknown_request_is_false() {
+        assert!(!InFlightPurchases::default().cancel("missing"));
+    }
+}
@@ -0,0 +1,59 @@
+//! Compact JWS encoding, shared by the verification server and entitlement tokens.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::de::DeserializeOwned;
+
+/// A JWS signed by `sign`, which gets the signing input and returns the raw signature.
+pub(crate) fn encode(
+    header: &serde_json::Value,
+    claims: &serde_json::Value,
+    sign: impl FnOnce(&[u8]) -> crate::Result<Vec<u8>>,
+) -> crate::Result<String> {
+    let input = format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(header.to_string()),
+        URL_SAFE_NO_PAD.encode(claims.to_string())
+    );
+    let signature = sign(input.as_bytes())?;
+    Ok(format!("{input}.{}", URL_SAFE_NO_PAD.encode(signature)))
+}
+
+/// The parts of a JWS. Nothing is checked until the signature is verified against
+/// [`Jws::signing_input`].
+pub(crate) struct Jws<'a> {
+    pub signing_input: &'a str,
+    header: &'a str,
+    payload: &'a str,
+    signature: &'a str,
+}
+
+impl<'a> Jws<'a> {
+    pub fn parse(token: &'a str) -> Option<Self> {
+        let (signing_input, signature) = token.rsplit_once('.')?;
+        let (header, payload) = signing_input.split_once('.')?;
+        Some(Self {
+            signing_input,
+            header,
+            payload,
+            signature,
+        })
+    }
+
+    pub fn header<T: DeserializeOwned>(&self) -> Option<T> {
+        decode(self.header)
+    }
+
+    pub fn payload<T: DeserializeOwned>(&self) -> Option<T> {
+        decode(self.payload)
+    }
+
+    pub fn signature(&self) -> Option<Vec<u8>> {
+        URL_SAFE_NO_PAD.decode(self.signature).ok()
+    }
+}
+
+fn decode<T: DeserializeOwned>(part: &str) -> Option<T> {
+    let bytes = URL_SAFE_NO_PAD.decode(part).ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
@@ -0,0 +1,440 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde_json::{json, Value};
+use tauri::{AppHandle, Manager, Runtime};
+
+use crate::models::*;
+use crate::now_millis;
+
+const LEDGER_FILE: &str = "iap-ledger.jsonl";
+
+/// Schema version new entries are written with.
+const LEDGER_VERSION: u32 = 1;
+
+/// The ledger is compacted on startup once it grows past this size.
+const COMPACT_AT_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Append-only JSONL record of everything the store reported or the plugin did with a
+/// purchase, kept in the app data directory as a local audit trail.
+pub(crate) struct Ledger {
+    path: Option<PathBuf>,
+    /// Held while writing, so appends do not interleave with a compaction.
+    writing: Mutex<()>,
+}
+
+impl Ledger {
+    pub fn new<R: Runtime>(app: &AppHandle<R>) -> Self {
+        let path = app
+            .path()
+            .app_data_dir()
+            .ok()
+            .map(|dir| dir.join(LEDGER_FILE));
+        Self::with_path(path)
+    }
+
+    pub(crate) fn with_path(path: Option<PathBuf>) -> Self {
+        let ledger = Self {
+            path,
+            writing: Mutex::new(()),
+        };
+        let size = ledger
+            .path
+            .as_ref()
+            .and_then(|path| std::fs::metadata(path).ok())
+            .map_or(0, |metadata| metadata.len());
+        if size > COMPACT_AT_BYTES {
+            ledger.compact();
+        }
+        ledger
+    }
+
+    fn append(&self, entry: &LedgerEntry) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let Ok(mut line) = serde_json::to_vec(entry) else {
+            return;
+        };
+        line.push(b'\n');
+
+        let _writing = self.writing.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(dir) = path.parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+        // Recording is best effort, a failed write must not fail the purchase flow
+        if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+            let _ = file.write_all(&line);
+        }
+    }
+
+    /// Entries matching the request, oldest first.
+    pub fn query(&self, request: &QueryLedgerRequest) -> Vec<LedgerEntry> {
+        let mut entries: Vec<LedgerEntry> = {
+            let _writing = self.writing.lock().unwrap_or_else(|e| e.into_inner());
+            self.path.as_deref().map(read_entries).unwrap_or_default()
+        };
+        entries.retain(|entry| matches(entry, request));
+
+        if let Some(limit) = request.limit {
+            let skip = entries.len().saturating_sub(limit);
+            entries.drain(..skip);
+        }
+        entries
+    }
+
+    /// Rewrite the ledger in the current schema, dropping restore entries that repeat the
+    /// previous restore of the same product type.
+    ///
+    /// Lines this version cannot read, e.g. entries written by a newer plugin version, are
+    /// copied through unchanged, so downgrading the plugin does not lose them.
+    fn compact(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        let _writing = self.writing.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(lines) = read_lines(path) else {
+            return;
+        };
+        let mut last_restore: HashMap<Option<String>, Value> = HashMap::new();
+        let mut compacted = Vec::new();
+        for line in lines {
+            let Some(entry) = parse(&line) else {
+                compacted.extend(line.into_bytes());
+                compacted.push(b'\n');
+                continue;
+            };
+            if entry.kind == LedgerEventKind::Restore {
+                if last_restore.get(&entry.product_type) == Some(&entry.data) {
+                    continue;
+                }
+                last_restore.insert(entry.product_type.clone(), entry.data.clone());
+            }
+
+            match serde_json::to_vec(&entry) {
+                Ok(rewritten) => compacted.extend(rewritten),
+                Err(_) => compacted.extend(line.into_bytes()),
+            }
+            compacted.push(b'\n');
+        }
+
+        // Write next to the ledger and swap, so a crash never leaves a truncated ledger
+        let staged = path.with_extension("jsonl.tmp");
+        if std::fs::write(&staged, compacted).is_ok() {
+            let _ = std::fs::rename(&staged, path);
+        }
+    }
+}
+
+/// The non-empty lines of the ledger, `None` if it cannot be read.
+fn read_lines(path: &Path) -> Option<Vec<String>> {
+    let file = File::open(path).ok()?;
+    Some(
+        BufReader::new(file)
+            .lines()
+            .map_while(Result::ok)
+            .filter(|line| !line.trim().is_empty())
+            .collect(),
+    )
+}
+
+/// Entries in the current schema. Unreadable lines and entries written by a newer plugin
+/// version are skipped.
+fn read_entries(path: &Path) -> Vec<LedgerEntry> {
+    read_lines(path)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|line| parse(line))
+        .collect()
+}
+
+fn parse(line: &str) -> Option<LedgerEntry> {
+    serde_json::from_str(line).ok().and_then(migrate)
+}
+
+/// Bring an entry written by an older plugin version up to [`LEDGER_VERSION`].
+///
+/// Each schema change adds a step here, e.g. `if version < 2 { ... }`, that rewrites the raw
+/// JSON of older entries. The file itself is only rewritten by compaction.
+fn migrate(mut value: Value) -> Option<LedgerEntry> {
+    let version = value.get("version").and_then(Value::as_u64)?;
+    if version > u64::from(LEDGER_VERSION) {
+        return None;
+    }
+
+    value["version"] = LEDGER_VERSION.into();
+    serde_json::from_value(value).ok()
+}
+
+fn matches(entry: &LedgerEntry, request: &QueryLedgerRequest) -> bool {
+    if !request.kinds.is_empty() && !request.kinds.contains(&entry.kind) {
+        return false;
+    }
+    if request.from.is_some_and(|from| entry.recorded_at < from)
+        || request.to.is_some_and(|to| entry.recorded_at >= to)
+    {
+        return false;
+    }
+
+    let Some(product_id) = &request.product_id else {
+        return true;
+    };
+    if entry.product_id.as_ref() == Some(product_id) {
+        return true;
+    }
+    // Restore entries list the purchases of every product they returned
+    entry.data["purchases"].as_array().is_some_and(|purchases| {
+        purchases
+            .iter()
+            .any(|purchase| purchase["productId"] == product_id.as_str())
+    })
+}
+
+fn entry(kind: LedgerEventKind) -> LedgerEntry {
+    LedgerEntry {
+        version: LEDGER_VERSION,
+        recorded_at: now_millis(),
+        kind,
+        product_id: None,
+        product_type: None,
+        purchase_token: None,
+        data: Value::Null,
+    }
+}
+
+/// Record purchases reported by the store, one entry each.
+pub(crate) fn record_purchases<R: Runtime>(
+    app: &AppHandle<R>,
+    product_type: Option<&str>,
+    purchases: &[Purchase],
+) {
+    let ledger = app.state::<Ledger>();
+    for purchase in purchases {
+        ledger.append(&LedgerEntry {
+            product_id: Some(purchase.product_id.clone()),
+            product_type: product_type.map(str::to_string),
+            purchase_token: Some(purchase.purchase_token.clone()),
+            data: serde_json::to_value(purchase).unwrap_or_default(),
+            ..entry(LedgerEventKind::Purchase)
+        });
+    }
+}
+
+/// Record the result of a `restore_purchases` call as a single entry.
+pub(crate) fn record_restore<R: Runtime>(
+    app: &AppHandle<R>,
+    product_type: &str,
+    purchases: &[Purchase],
+) {
+    app.state::<Ledger>().append(&LedgerEntry {
+        product_type: Some(product_type.to_string()),
+        data: json!({ "purchases": purchases }),
+        ..entry(LedgerEventKind::Restore)
+    });
+}
+
+/// Record an acknowledge or consume call the store accepted.
+pub(crate) fn record_completed<R: Runtime>(
+    app: &AppHandle<R>,
+    kind: PendingOperationKind,
+    purchase_token: &str,
+) {
+    let kind = match kind {
+        PendingOperationKind::Acknowledge => LedgerEventKind::Acknowledgement,
+        PendingOperationKind::Consume => LedgerEventKind::Consumption,
+    };
+    app.state::<Ledger>().append(&LedgerEntry {
+        purchase_token: Some(purchase_token.to_string()),
+        ..entry(kind)
+    });
+}
+
+/// Record that the app confirmed it processed a purchase.
+pub(crate) fn record_fulfillment<R: Runtime>(app: &AppHandle<R>, purchase_token: &str) {
+    app.state::<Ledger>().append(&LedgerEntry {
+        purchase_token: Some(purchase_token.to_string()),
+        ..entry(LedgerEventKind::Fulfillment)
+    });
+}
+
+pub(crate) fn record_refund_request<R: Runtime>(
+    app: &AppHandle<R>,
+    transaction_id: &str,
+    response: &BeginRefundRequestResponse,
+) {
+    app.state::<Ledger>().append(&LedgerEntry {
+        purchase_token: Some(transaction_id.to_string()),
+        data: serde_json::to_value(response).unwrap_or_default(),
+        ..entry(LedgerEventKind::RefundRequest)
+    });
+}
+
+pub(crate) fn record_revocation<R: Runtime>(app: &AppHandle<R>, revocation: &PurchaseRevocation) {
+    app.state::<Ledger>().append(&LedgerEntry {
+        product_id: Some(revocation.product_id.clone()),
+        product_type: Some(revocation.product_type.clone()),
+        purchase_token: Some(revocation.purchase_token.clone()),
+        data: serde_json::to_value(revocation).unwrap_or_default(),
+        ..entry(LedgerEventKind::Revocation)
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path() -> PathBuf {
+        static NEXT: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+        let n = NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        std::env::temp_dir().join(format!("iap-ledger-{}-{n}.jsonl", now_millis()))
+    }
+
+    fn at(kind: LedgerEventKind, recorded_at: i64, product_id: Option<&str>) -> LedgerEntry {
+        LedgerEntry {
+            recorded_at,
+            product_id: product_id.map(str::to_string),
+            ..entry(kind)
+        }
+    }
+
+    fn restore(recorded_at: i64, product_ids: &[&str]) -> LedgerEntry {
+        let purchases: Vec<Value> = product_ids
+            .iter()
+            .map(|product_id| json!({ "productId": product_id }))
+            .collect();
+        LedgerEntry {
+            product_type: Some("subs".to_string()),
+            data: json!({ "purchases": purchases }),
+            ..at(LedgerEventKind::Restore, recorded_at, None)
+        }
+    }
+
+    fn kinds(entries: &[LedgerEntry]) -> Vec<(LedgerEventKind, i64)> {
+        entries
+            .iter()
+            .map(|entry| (entry.kind, entry.recorded_at))
+            .collect()
+    }
+
+    #[test]
+    fn migrate_reads_the_current_version_and_skips_newer_ones() {
+        let current = json!({ "version": 1, "recordedAt": 5, "kind": "purchase" });
+        let entry = migrate(current).expect("the current version was not read");
+        assert_eq!(entry.version, LEDGER_VERSION);
+        assert_eq!(entry.kind, LedgerEventKind::Purchase);
+
+        assert!(migrate(json!({ "version": 2, "recordedAt": 5, "kind": "purchase" })).is_none());
+        assert!(migrate(json!({ "recordedAt": 5, "kind": "purchase" })).is_none());
+        assert!(migrate(json!({ "version": 1, "recordedAt": 5, "kind": "gift" })).is_none());
+    }
+
+    #[test]
+    fn compaction_drops_repeated_restores_and_keeps_unreadable_lines() {
+        let path = temp_path();
+        let ledger = Ledger::with_path(Some(path.clone()));
+        ledger.append(&restore(1, &["monthly"]));
+        ledger.append(&restore(2, &["monthly"]));
+        ledger.append(&at(LedgerEventKind::Purchase, 3, Some("yearly")));
+        ledger.append(&restore(4, &["monthly", "yearly"]));
+        ledger.append(&restore(5, &["monthly", "yearly"]));
+        let newer = r#"{"version":2,"recordedAt":6,"kind":"gift","recipient":"friend"}"#;
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        writeln!(file, "{newer}").unwrap();
+        writeln!(file, "{{\"version\":1,\"recordedAt\":7,\"ki").unwrap();
+        drop(file);
+        ledger.append(&at(LedgerEventKind::Fulfillment, 8, None));
+
+        ledger.compact();
+
+        let all = ledger.query(&QueryLedgerRequest::default());
+        assert_eq!(
+            kinds(&all),
+            vec![
+                (LedgerEventKind::Restore, 1),
+                (LedgerEventKind::Purchase, 3),
+                (LedgerEventKind::Restore, 4),
+                (LedgerEventKind::Fulfillment, 8),
+            ]
+        );
+        let lines = read_lines(&path).unwrap();
+        assert_eq!(lines.len(), 6);
+        // Copied through byte for byte, in place
+        assert_eq!(lines[3], newer);
+        assert!(lines[4].ends_with("\"ki"));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn a_large_ledger_is_compacted_when_opened() {
+        let path = temp_path();
+        let ledger = Ledger::with_path(Some(path.clone()));
+        let padding = "x".repeat(64 * 1024);
+        for recorded_at in 0..70 {
+            ledger.append(&LedgerEntry {
+                data: json!({ "purchases": [], "padding": padding }),
+                product_type: Some("subs".to_string()),
+                ..at(LedgerEventKind::Restore, recorded_at, None)
+            });
+        }
+        assert!(std::fs::metadata(&path).unwrap().len() > COMPACT_AT_BYTES);
+
+        let reopened = Ledger::with_path(Some(path.clone()));
+
+        assert_eq!(reopened.query(&QueryLedgerRequest::default()).len(), 1);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn queries_filter_by_kind_time_and_product() {
+        let path = temp_path();
+        let ledger = Ledger::with_path(Some(path.clone()));
+        ledger.append(&at(LedgerEventKind::Purchase, 10, Some("monthly")));
+        ledger.append(&restore(20, &["monthly", "yearly"]));
+        ledger.append(&at(LedgerEventKind::Purchase, 30, Some("yearly")));
+        ledger.append(&at(LedgerEventKind::Acknowledgement, 40, None));
+
+        let query = |request: serde_json::Value| {
+            kinds(&ledger.query(&serde_json::from_value(request).unwrap()))
+        };
+
+        assert_eq!(
+            query(json!({ "kinds": ["purchase"] })),
+            vec![
+                (LedgerEventKind::Purchase, 10),
+                (LedgerEventKind::Purchase, 30)
+            ]
+        );
+        // `from` is inclusive and `to` exclusive
+        assert_eq!(
+            query(json!({ "from": 20, "to": 40 })),
+            vec![
+                (LedgerEventKind::Restore, 20),
+                (LedgerEventKind::Purchase, 30)
+            ]
+        );
+        // Restores match the products they listed
+        assert_eq!(
+            query(json!({ "productId": "yearly" })),
+            vec![
+                (LedgerEventKind::Restore, 20),
+                (LedgerEventKind::Purchase, 30)
+            ]
+        );
+        // The limit keeps the most recent entries
+        assert_eq!(
+            query(json!({ "limit": 2 })),
+            vec![
+                (LedgerEventKind::Purchase, 30),
+                (LedgerEventKind::Acknowledgement, 40)
+            ]
+        );
+        assert!(Ledger::with_path(None)
+            .query(&QueryLedgerRequest::default())
+            .is_empty());
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -1,45 +1,168 @@
+#[cfg(feature = "plugin")]
+use std::sync::Arc;
+
+#[cfg(feature = "plugin")]
 use tauri::{
-    plugin::{Builder, TauriPlugin},
+    plugin::{Builder as PluginBuilder, TauriPlugin},
     Manager, Runtime,
 };
 
+pub use config::*;
+#[cfg(feature = "entitlement-tokens")]
+pub use entitlement_token::{
+    generate_signing_key, EntitlementTokenIssuer, EntitlementTokenVerifier,
+};
+#[cfg(feature = "plugin")]
+pub use events::IapEvent;
+#[cfg(feature = "plugin")]
+pub use flow::{purchase_and_fulfill, PurchaseFlowSteps};
+#[cfg(feature = "plugin")]
+pub use hooks::IapHooks;
 pub use models::*;
+#[cfg(feature = "remote-validator")]
+pub use validator::RemoteValidator;
 
-#[cfg(any(
-    target_os = "linux",
-    all(target_os = "macos", not(feature = "unstable"))
+#[cfg(all(
+    feature = "plugin",
+    not(feature = "mock"),
+    any(
+        all(target_os = "linux", not(feature = "steam"), not(feature = "stripe")),
+        all(target_os = "macos", not(feature = "unstable"))
+    )
 ))]
 mod desktop;
 #[cfg(all(target_os = "macos", feature = "unstable"))]
 mod macos;
-#[cfg(mobile)]
+#[cfg(all(mobile, feature = "plugin"))]
 mod mobile;
-#[cfg(target_os = "windows")]
+#[cfg(all(
+    feature = "mock",
+    any(
+        all(target_os = "linux", not(feature = "steam"), not(feature = "stripe")),
+        all(target_os = "macos", not(feature = "unstable"))
+    )
+))]
+mod mock;
+#[cfg(all(target_os = "linux", feature = "steam"))]
+mod steam;
+#[cfg(feature = "stripe")]
+pub mod stripe;
+#[cfg(all(target_os = "windows", feature = "plugin"))]
 mod windows;
 
+#[cfg(feature = "plugin")]
+mod acknowledgement;
+#[cfg(feature = "plugin")]
+mod cache;
+#[cfg(feature = "plugin")]
 mod commands;
+mod config;
+#[cfg(feature = "plugin")]
+mod connection;
+#[cfg(feature = "plugin")]
+mod currency;
+#[cfg(feature = "plugin")]
+mod delivery;
+#[cfg(feature = "entitlement-tokens")]
+mod entitlement_token;
 mod error;
+#[cfg(feature = "plugin")]
+mod events;
+#[cfg(feature = "plugin")]
+mod flow;
+#[cfg(any(feature = "plugin", feature = "stripe"))]
+mod hmac_sha256;
+#[cfg(feature = "plugin")]
+mod hooks;
+#[cfg(feature = "plugin")]
+mod in_flight;
+#[cfg(feature = "entitlement-tokens")]
+mod jwt;
+#[cfg(feature = "plugin")]
+mod ledger;
 mod models;
+pub mod offers;
+#[cfg(feature = "plugin")]
+mod pending;
+#[cfg(all(
+    feature = "plugin",
+    any(
+        mobile,
+        target_os = "windows",
+        all(target_os = "macos", feature = "unstable"),
+        all(target_os = "linux", feature = "steam"),
+        all(target_os = "linux", feature = "stripe"),
+        feature = "mock"
+    )
+))]
+mod products;
+#[cfg(feature = "plugin")]
+mod retry_queue;
+#[cfg(feature = "plugin")]
+mod revocation;
+#[cfg(any(feature = "server", all(target_os = "linux", feature = "steam")))]
+mod rfc3339;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "plugin")]
+mod single_flight;
+#[cfg(all(
+    test,
+    any(
+        feature = "remote-validator",
+        feature = "server",
+        feature = "stripe",
+        all(target_os = "linux", feature = "steam")
+    )
+))]
+mod stand_in;
+#[cfg(feature = "plugin")]
+mod updates;
+#[cfg(feature = "remote-validator")]
+mod validator;
 
 pub use error::{Error, Result};
 
-#[cfg(any(
-    target_os = "linux",
-    all(target_os = "macos", not(feature = "unstable"))
+#[cfg(all(
+    feature = "plugin",
+    not(feature = "mock"),
+    any(
+        all(target_os = "linux", not(feature = "steam"), not(feature = "stripe")),
+        all(target_os = "macos", not(feature = "unstable"))
+    )
 ))]
 use desktop::Iap;
 #[cfg(all(target_os = "macos", feature = "unstable"))]
 use macos::Iap;
-#[cfg(mobile)]
+#[cfg(all(mobile, feature = "plugin"))]
 use mobile::Iap;
-#[cfg(target_os = "windows")]
+#[cfg(all(
+    feature = "mock",
+    any(
+        all(target_os = "linux", not(feature = "steam"), not(feature = "stripe")),
+        all(target_os = "macos", not(feature = "unstable"))
+    )
+))]
+use mock::Iap;
+#[cfg(all(target_os = "linux", feature = "steam"))]
+use steam::Iap;
+#[cfg(all(
+    target_os = "linux",
+    feature = "plugin",
+    feature = "stripe",
+    not(feature = "steam")
+))]
+use stripe::Iap;
+#[cfg(all(target_os = "windows", feature = "plugin"))]
 use windows::Iap;
 
 /// Extensions to [`tauri::App`], [`tauri::AppHandle`] and [`tauri::Window`] to access the iap APIs.
+#[cfg(feature = "plugin")]
 pub trait IapExt<R: Runtime> {
     fn iap(&self) -> &Iap<R>;
 }
 
+#[cfg(feature = "plugin")]
 impl<R: Runtime, T: Manager<R>> crate::IapExt<R> for T {
     fn iap(&self) -> &Iap<R> {
         self.state::<Iap<R>>().inner()
@@ -47,30 +170,227 @@ impl<R: Runtime, T: Manager<R>> crate::IapExt<R> for T {
 }
 
 /// Initializes the plugin.
-pub fn init<R: Runtime>() -> TauriPlugin<R> {
-    Builder::new("iap")
-        .invoke_handler(tauri::generate_handler![
-            commands::initialize,
-            commands::get_products,
-            commands::purchase,
-            commands::restore_purchases,
-            commands::acknowledge_purchase,
-            commands::get_product_status,
-        ])
-        .setup(|app, api| {
-            #[cfg(all(target_os = "macos", feature = "unstable"))]
-            let iap = macos::init(app, api)?;
-            #[cfg(mobile)]
-            let iap = mobile::init(app, api)?;
-            #[cfg(target_os = "windows")]
-            let iap = windows::init(app, api)?;
-            #[cfg(any(
-                target_os = "linux",
-                all(target_os = "macos", not(feature = "unstable"))
-            ))]
-            let iap = desktop::init(app, api)?;
-            app.manage(iap);
-            Ok(())
-        })
-        .build()
+#[cfg(feature = "plugin")]
+pub fn init<R: Runtime>() -> TauriPlugin<R, Option<Config>> {
+    Builder::new().build()
+}
+
+/// Builds the plugin with Rust-side options that cannot live in `tauri.conf.json`.
+#[cfg(feature = "plugin")]
+#[derive(Default)]
+pub struct Builder {
+    hooks: Option<Arc<dyn IapHooks>>,
+    purchase_flow_steps: Option<Arc<dyn PurchaseFlowSteps>>,
+    #[cfg(feature = "remote-validator")]
+    remote_validator: Option<RemoteValidator>,
+}
+
+#[cfg(feature = "plugin")]
+impl Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register callbacks for the purchase lifecycle.
+    pub fn hooks(mut self, hooks: impl IapHooks) -> Self {
+        self.hooks = Some(Arc::new(hooks));
+        self
+    }
+
+    /// Register the verify and grant steps of `purchase_and_fulfill`.
+    pub fn purchase_flow_steps(mut self, steps: impl PurchaseFlowSteps) -> Self {
+        self.purchase_flow_steps = Some(Arc::new(steps));
+        self
+    }
+
+    /// Validate purchases with this validator instead of the one from the `validation`
+    /// config, e.g. to send a session token that is only known at runtime.
+    #[cfg(feature = "remote-validator")]
+    pub fn remote_validator(mut self, validator: RemoteValidator) -> Self {
+        self.remote_validator = Some(validator);
+        self
+    }
+
+    pub fn build<R: Runtime>(self) -> TauriPlugin<R, Option<Config>> {
+        let hooks = self.hooks;
+        let purchase_flow_steps = self.purchase_flow_steps;
+        #[cfg(feature = "remote-validator")]
+        let remote_validator = self.remote_validator;
+        PluginBuilder::<R, Option<Config>>::new("iap")
+            .invoke_handler(tauri::generate_handler![
+                commands::initialize,
+                commands::get_products,
+                commands::purchase,
+                commands::purchase_and_fulfill,
+                commands::restore_purchases,
+                commands::acknowledge_purchase,
+                commands::consume_purchase,
+                commands::get_product_status,
+                commands::get_products_status,
+                commands::get_in_flight_purchases,
+                commands::cancel_purchase,
+                commands::get_pending_operations,
+                commands::retry_pending_operations,
+                commands::get_pending_purchases,
+                commands::subscribe_events,
+                commands::get_connection_state,
+                commands::get_store_capabilities,
+                commands::redeem_offer_code,
+                commands::begin_refund_request,
+                commands::show_manage_subscriptions,
+                commands::query_ledger,
+                commands::mark_fulfilled,
+                commands::get_currency_balance,
+                commands::spend_currency,
+                commands::get_currency_history,
+                commands::validate_purchase,
+                commands::verify_entitlement_token,
+            ])
+            .setup(move |app, api| {
+                let config = api.config().clone().unwrap_or_default();
+
+                #[cfg(all(target_os = "macos", feature = "unstable"))]
+                let iap = macos::init(app, api)?;
+                #[cfg(mobile)]
+                let iap = mobile::init(app, api)?;
+                #[cfg(target_os = "windows")]
+                let iap = windows::init(app, api)?;
+                #[cfg(all(
+                    not(feature = "mock"),
+                    any(
+                        all(target_os = "linux", not(feature = "steam"), not(feature = "stripe")),
+                        all(target_os = "macos", not(feature = "unstable"))
+                    )
+                ))]
+                let iap = desktop::init(app, api)?;
+                #[cfg(all(
+                    feature = "mock",
+                    any(
+                        all(target_os = "linux", not(feature = "steam"), not(feature = "stripe")),
+                        all(target_os = "macos", not(feature = "unstable"))
+                    )
+                ))]
+                let iap = mock::init(app, config.mock.clone())?;
+                #[cfg(all(target_os = "linux", feature = "steam"))]
+                let iap = steam::init(app, config.steam.clone())?;
+                #[cfg(all(target_os = "linux", feature = "stripe", not(feature = "steam")))]
+                let iap = stripe::init(app, config.stripe.clone())?;
+                app.manage(iap);
+                app.manage(cache::ProductCache::new(app, config.product_cache.clone()));
+                app.manage(in_flight::InFlightPurchases::default());
+                app.manage(retry_queue::RetryQueue::new(app));
+                app.manage(acknowledgement::AcknowledgementTracker::default());
+                app.manage(pending::PendingPurchases::new(app));
+                app.manage(events::EventBuffer::default());
+                app.manage(updates::Replay::default());
+                app.manage(connection::Connection::default());
+                app.manage(revocation::Entitlements::new(app));
+                app.manage(ledger::Ledger::new(app));
+                app.manage(delivery::Deliveries::new(app));
+                app.manage(currency::Wallet::new(app, &config.currency));
+                app.manage(hooks::Hooks::new(hooks));
+                app.manage(flow::PurchaseFlows::new(app, purchase_flow_steps));
+                #[cfg(feature = "remote-validator")]
+                app.manage(validator::Validation(
+                    remote_validator
+                        .or_else(|| config.validation.as_ref().map(RemoteValidator::from_config))
+                        .map(Arc::new),
+                ));
+                #[cfg(not(feature = "remote-validator"))]
+                if config.validation.is_some() {
+                    return Err(
+                        "the `validation` config needs the `remote-validator` feature".into(),
+                    );
+                }
+                if let Some(tokens) = &config.entitlement_tokens {
+                    if tokens.signing_key.is_some() {
+                        return Err("the entitlement signing key must not ship with the app".into());
+                    }
+                }
+                #[cfg(feature = "entitlement-tokens")]
+                app.manage(entitlement_token::Verification(
+                    match &config.entitlement_tokens {
+                        Some(tokens) if !tokens.keys.is_empty() => {
+                            Some(Arc::new(EntitlementTokenVerifier::from_config(tokens)?))
+                        }
+                        _ => None,
+                    },
+                ));
+                #[cfg(not(feature = "entitlement-tokens"))]
+                if config.entitlement_tokens.is_some() {
+                    return Err(
+                        "the `entitlementTokens` config needs the `entitlement-tokens` feature"
+                            .into(),
+                    );
+                }
+                if let Some(steam) = &config.steam {
+                    if steam.web_api_key.is_some() {
+                        return Err("the Steam Web API key must not ship with the app".into());
+                    }
+                }
+                if let Some(stripe) = &config.stripe {
+                    if stripe.secret_key.is_some() {
+                        return Err("the Stripe secret key must not ship with the app".into());
+                    }
+                }
+                #[cfg(not(feature = "steam"))]
+                if config.steam.is_some() {
+                    return Err("the `steam` config needs the `steam` feature".into());
+                }
+                #[cfg(not(feature = "stripe"))]
+                if config.stripe.is_some() {
+                    return Err("the `stripe` config needs the `stripe` feature".into());
+                }
+                #[cfg(all(target_os = "linux", feature = "steam", feature = "stripe"))]
+                if config.stripe.is_some() {
+                    return Err(
+                        "the `stripe` config is unused on Linux, where the `steam` feature selects Steam"
+                            .into(),
+                    );
+                }
+                #[cfg(not(all(
+                    feature = "mock",
+                    any(
+                        all(target_os = "linux", not(feature = "steam"), not(feature = "stripe")),
+                        all(target_os = "macos", not(feature = "unstable"))
+                    )
+                )))]
+                if config.mock.is_some() {
+                    return Err(
+                        "the `mock` config needs the `mock` feature on a platform without a store backend"
+                            .into(),
+                    );
+                }
+                app.manage(config);
+
+                #[cfg(mobile)]
+                mobile::register_listeners(app)?;
+                Ok(())
+            })
+            .build()
+    }
+}
+
+/// Current time in milliseconds since the Unix epoch, the unit of every timestamp in the models.
+#[cfg_attr(not(feature = "plugin"), allow(dead_code))]
+pub(crate) fn now_millis() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+/// A value escaped to be one segment of a URL path.
+#[cfg(any(feature = "server", feature = "stripe"))]
+pub(crate) fn path_segment(value: &str) -> String {
+    let mut segment = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                segment.push(byte as char)
+            }
+            _ => segment.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    segment
 }
@@ -3,6 +3,9 @@ use tauri::{plugin::PluginApi, AppHandle, Runtime};
 
 use crate::models::*;
 
+/// Error the Swift side returns when the user dismisses the payment sheet.
+const USER_CANCELED_MESSAGE: &str = "Purchase cancelled by user";
+
 mod codesign {
     use objc2_security::{
         kSecCSCheckAllArchitectures, kSecCSCheckNestedCode, kSecCSStrictValidate, SecCSFlags,
@@ -64,8 +67,14 @@ mod ffi {
             offerToken: Option<String>,
         ) -> FFIResult;
         fn restorePurchases(productType: String) -> FFIResult;
+        fn getUnfinishedPurchases() -> FFIResult;
+        fn getStoreCapabilities() -> FFIResult;
+        fn beginRefundRequest(transactionId: String) -> FFIResult;
+        fn showManageSubscriptions() -> FFIResult;
         fn acknowledgePurchase(purchaseToken: String) -> FFIResult;
+        fn consumePurchase(purchaseToken: String) -> FFIResult;
         fn getProductStatus(productId: String, productType: String) -> FFIResult;
+        fn getProductsStatus(productIds: Vec<String>, productType: String) -> FFIResult;
     }
 }
 
@@ -112,7 +121,16 @@ impl<R: Runtime> Iap<R> {
     ) -> crate::Result<GetProductsResponse> {
         codesign::is_signature_valid()?;
 
-        Self::to_result(ffi::getProducts(product_ids, product_type))
+        let mut response: GetProductsResponse =
+            Self::to_result(ffi::getProducts(product_ids.clone(), product_type.clone()))?;
+        crate::products::report_invalid_products(
+            &product_ids,
+            &product_type,
+            &mut response,
+            |ids, other_type| Self::to_result(ffi::getProducts(ids, other_type)),
+        );
+        crate::offers::annotate(&mut response.products);
+        Ok(response)
     }
 
     pub fn purchase(
@@ -124,7 +142,14 @@ impl<R: Runtime> Iap<R> {
         codesign::is_signature_valid()?;
 
         let offer_token = options.and_then(|opts| opts.offer_token);
-        Self::to_result(ffi::purchase(product_id, product_type, offer_token))
+        Self::to_result(ffi::purchase(product_id, product_type, offer_token)).map_err(|err| {
+            // The bridge only carries a message, give the cancel the code the other stores use
+            if err.to_string() == USER_CANCELED_MESSAGE {
+                crate::Error::rejected("userCanceled", USER_CANCELED_MESSAGE)
+            } else {
+                err
+            }
+        })
     }
 
     pub fn restore_purchases(
@@ -136,6 +161,46 @@ impl<R: Runtime> Iap<R> {
         Self::to_result(ffi::restorePurchases(product_type))
     }
 
+    pub fn get_unfinished_purchases(&self) -> crate::Result<GetUnfinishedPurchasesResponse> {
+        codesign::is_signature_valid()?;
+
+        Self::to_result(ffi::getUnfinishedPurchases())
+    }
+
+    pub fn get_store_capabilities(&self) -> crate::Result<StoreCapabilities> {
+        codesign::is_signature_valid()?;
+
+        Self::to_result(ffi::getStoreCapabilities())
+    }
+
+    pub fn redeem_offer_code(
+        &self,
+        _code: Option<String>,
+    ) -> crate::Result<RedeemOfferCodeResponse> {
+        Err(crate::Error::rejected(
+            "notSupported",
+            "StoreKit has no offer code redemption sheet on macOS",
+        ))
+    }
+
+    pub fn begin_refund_request(
+        &self,
+        transaction_id: String,
+    ) -> crate::Result<BeginRefundRequestResponse> {
+        codesign::is_signature_valid()?;
+
+        Self::to_result(ffi::beginRefundRequest(transaction_id))
+    }
+
+    pub fn show_manage_subscriptions(
+        &self,
+        _product_id: Option<String>,
+    ) -> crate::Result<ShowManageSubscriptionsResponse> {
+        codesign::is_signature_valid()?;
+
+        Self::to_result(ffi::showManageSubscriptions())
+    }
+
     pub fn acknowledge_purchase(
         &self,
         purchase_token: String,
@@ -145,6 +210,15 @@ impl<R: Runtime> Iap<R> {
         Self::to_result(ffi::acknowledgePurchase(purchase_token))
     }
 
+    pub fn consume_purchase(
+        &self,
+        purchase_token: String,
+    ) -> crate::Result<ConsumePurchaseResponse> {
+        codesign::is_signature_valid()?;
+
+        Self::to_result(ffi::consumePurchase(purchase_token))
+    }
+
     pub fn get_product_status(
         &self,
         product_id: String,
@@ -154,4 +228,14 @@ impl<R: Runtime> Iap<R> {
 
         Self::to_result(ffi::getProductStatus(product_id, product_type))
     }
+
+    pub fn get_products_status(
+        &self,
+        product_ids: Vec<String>,
+        product_type: String,
+    ) -> crate::Result<GetProductsStatusResponse> {
+        codesign::is_signature_valid()?;
+
+        Self::to_result(ffi::getProductsStatus(product_ids, product_type))
+    }
 }
@@ -1,10 +1,12 @@
 use serde::de::DeserializeOwned;
 use tauri::{
+    ipc::{Channel, InvokeResponseBody},
     plugin::{PluginApi, PluginHandle},
     AppHandle, Runtime,
 };
 
 use crate::models::*;
+use crate::IapExt;
 
 #[cfg(target_os = "android")]
 const PLUGIN_IDENTIFIER: &str = "app.tauri.iap";
@@ -25,6 +27,42 @@ pub fn init<R: Runtime, C: DeserializeOwned>(
     Ok(Iap(handle))
 }
 
+/// Forward what the native plugin reports on its own, e.g. a pending purchase that completed
+/// or a dropped store connection, to the Rust side. Runs once all plugin state is managed.
+pub fn register_listeners<R: Runtime>(app: &AppHandle<R>) -> crate::Result<()> {
+    let listener_app = app.clone();
+    let on_purchase_updated = Channel::new(move |body| {
+        if let InvokeResponseBody::Json(json) = body {
+            if let Ok(purchase) = serde_json::from_str::<Purchase>(&json) {
+                crate::updates::purchase_updated(&listener_app, purchase);
+            }
+        }
+        Ok(())
+    });
+    app.iap().0.run_mobile_plugin::<serde_json::Value>(
+        "registerListener",
+        serde_json::json!({
+            "event": "purchaseUpdated",
+            "handler": on_purchase_updated,
+        }),
+    )?;
+
+    // Only Google Play Billing reports dropped connections
+    let listener_app = app.clone();
+    let on_connection_changed = Channel::new(move |_| {
+        crate::connection::disconnected(&listener_app);
+        Ok(())
+    });
+    app.iap().0.run_mobile_plugin::<serde_json::Value>(
+        "registerListener",
+        serde_json::json!({
+            "event": "billingConnectionChanged",
+            "handler": on_connection_changed,
+        }),
+    )?;
+    Ok(())
+}
+
 /// Access to the iap APIs.
 pub struct Iap<R: Runtime>(PluginHandle<R>);
 
@@ -39,6 +77,22 @@ impl<R: Runtime> Iap<R> {
         &self,
         product_ids: Vec<String>,
         product_type: String,
+    ) -> crate::Result<GetProductsResponse> {
+        let mut response = self.query_products(product_ids.clone(), product_type.clone())?;
+        crate::products::report_invalid_products(
+            &product_ids,
+            &product_type,
+            &mut response,
+            |ids, other_type| self.query_products(ids, other_type),
+        );
+        crate::offers::annotate(&mut response.products);
+        Ok(response)
+    }
+
+    fn query_products(
+        &self,
+        product_ids: Vec<String>,
+        product_type: String,
     ) -> crate::Result<GetProductsResponse> {
         self.0
             .run_mobile_plugin(
@@ -46,6 +100,7 @@ impl<R: Runtime> Iap<R> {
                 GetProductsRequest {
                     product_ids,
                     product_type,
+                    force_refresh: false,
                 },
             )
             .map_err(Into::into)
@@ -57,6 +112,10 @@ impl<R: Runtime> Iap<R> {
         product_type: String,
         options: Option<PurchaseOptions>,
     ) -> crate::Result<Purchase> {
+        // Google Play needs an offer token for every subscription purchase
+        #[cfg(target_os = "android")]
+        let options = self.with_default_offer_token(&product_id, &product_type, options)?;
+
         self.0
             .run_mobile_plugin(
                 "purchase",
@@ -64,11 +123,41 @@ impl<R: Runtime> Iap<R> {
                     product_id,
                     product_type,
                     options,
+                    request_id: None,
+                    timeout_ms: None,
                 },
             )
             .map_err(Into::into)
     }
 
+    /// Fill in the best eligible offer when the caller did not pick one.
+    #[cfg(target_os = "android")]
+    fn with_default_offer_token(
+        &self,
+        product_id: &str,
+        product_type: &str,
+        options: Option<PurchaseOptions>,
+    ) -> crate::Result<Option<PurchaseOptions>> {
+        let has_offer_token = options
+            .as_ref()
+            .is_some_and(|opts| opts.offer_token.is_some());
+        if product_type != "subs" || has_offer_token {
+            return Ok(options);
+        }
+
+        let response = self.get_products(vec![product_id.to_string()], product_type.to_string())?;
+        let offer_token = response
+            .products
+            .iter()
+            .find(|product| product.product_id == product_id)
+            .and_then(crate::offers::best_offer_token);
+
+        Ok(Some(PurchaseOptions {
+            offer_token,
+            ..options.unwrap_or_default()
+        }))
+    }
+
     pub fn restore_purchases(
         &self,
         product_type: String,
@@ -78,6 +167,51 @@ impl<R: Runtime> Iap<R> {
             .map_err(Into::into)
     }
 
+    pub fn get_unfinished_purchases(&self) -> crate::Result<GetUnfinishedPurchasesResponse> {
+        self.0
+            .run_mobile_plugin("getUnfinishedPurchases", ())
+            .map_err(Into::into)
+    }
+
+    pub fn get_store_capabilities(&self) -> crate::Result<StoreCapabilities> {
+        self.0
+            .run_mobile_plugin("getStoreCapabilities", ())
+            .map_err(Into::into)
+    }
+
+    pub fn redeem_offer_code(
+        &self,
+        code: Option<String>,
+    ) -> crate::Result<RedeemOfferCodeResponse> {
+        self.0
+            .run_mobile_plugin("redeemOfferCode", RedeemOfferCodeRequest { code })
+            .map_err(Into::into)
+    }
+
+    pub fn begin_refund_request(
+        &self,
+        transaction_id: String,
+    ) -> crate::Result<BeginRefundRequestResponse> {
+        self.0
+            .run_mobile_plugin(
+                "beginRefundRequest",
+                BeginRefundRequestRequest { transaction_id },
+            )
+            .map_err(Into::into)
+    }
+
+    pub fn show_manage_subscriptions(
+        &self,
+        product_id: Option<String>,
+    ) -> crate::Result<ShowManageSubscriptionsResponse> {
+        self.0
+            .run_mobile_plugin(
+                "showManageSubscriptions",
+                ShowManageSubscriptionsRequest { product_id },
+            )
+            .map_err(Into::into)
+    }
+
     pub fn get_purchase_history(&self) -> crate::Result<GetPurchaseHistoryResponse> {
         self.0
             .run_mobile_plugin("getPurchaseHistory", ())
@@ -96,6 +230,15 @@ impl<R: Runtime> Iap<R> {
             .map_err(Into::into)
     }
 
+    pub fn consume_purchase(
+        &self,
+        purchase_token: String,
+    ) -> crate::Result<ConsumePurchaseResponse> {
+        self.0
+            .run_mobile_plugin("consumePurchase", ConsumePurchaseRequest { purchase_token })
+            .map_err(Into::into)
+    }
+
     pub fn get_product_status(
         &self,
         product_id: String,
@@ -111,4 +254,20 @@ impl<R: Runtime> Iap<R> {
             )
             .map_err(Into::into)
     }
+
+    pub fn get_products_status(
+        &self,
+        product_ids: Vec<String>,
+        product_type: String,
+    ) -> crate::Result<GetProductsStatusResponse> {
+        self.0
+            .run_mobile_plugin(
+                "getProductsStatus",
+                GetProductsStatusRequest {
+                    product_ids: Some(product_ids),
+                    product_type,
+                },
+            )
+            .map_err(Into::into)
+    }
 }
@@ -0,0 +1,888 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+
+use tauri::{AppHandle, Runtime};
+
+use crate::models::*;
+use crate::{now_millis, MockConfig, MockProduct};
+
+pub fn init<R: Runtime>(app: &AppHandle<R>, config: Option<MockConfig>) -> crate::Result<Iap<R>> {
+    Ok(Iap::new(app, config.unwrap_or_default()))
+}
+
+#[derive(Default)]
+struct MockState {
+    /// Like Google Play Billing, the store is connected by `initialize` and can drop.
+    connected: bool,
+    /// Error codes the next `initialize` calls fail with, first one first.
+    connect_errors: VecDeque<String>,
+    purchases: Vec<Purchase>,
+    next_purchase: u64,
+    /// Offer codes already redeemed. Each code is redeemable once.
+    redeemed_codes: HashSet<String>,
+    /// The `product_id` of every `show_manage_subscriptions` call, first one first.
+    manage_subscriptions_calls: Vec<Option<String>>,
+}
+
+/// Access to the iap APIs, backed by an in-memory store for development and tests.
+///
+/// The products come from the `mock` config. Purchases complete right away and are kept
+/// until the app quits. Store outages are simulated with [`Iap::disconnect`] and
+/// [`Iap::fail_connects`].
+pub struct Iap<R: Runtime> {
+    app: AppHandle<R>,
+    config: MockConfig,
+    state: Mutex<MockState>,
+}
+
+impl<R: Runtime> Iap<R> {
+    pub(crate) fn new(app: &AppHandle<R>, config: MockConfig) -> Self {
+        Self {
+            app: app.clone(),
+            config,
+            state: Mutex::new(MockState::default()),
+        }
+    }
+
+    /// Drop the connection, so store calls fail with `notConnected` until the next
+    /// `initialize`.
+    pub fn disconnect(&self) {
+        self.state().connected = false;
+    }
+
+    /// Fail the next `times` calls of `initialize` with the error `code`.
+    pub fn fail_connects(&self, code: &str, times: usize) {
+        self.state()
+            .connect_errors
+            .extend(std::iter::repeat(code.to_string()).take(times));
+    }
+
+    /// The `product_id` of every `show_manage_subscriptions` call so far, first one first.
+    pub fn manage_subscriptions_calls(&self) -> Vec<Option<String>> {
+        self.state().manage_subscriptions_calls.clone()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn connected(&self) -> crate::Result<std::sync::MutexGuard<'_, MockState>> {
+        let state = self.state();
+        if !state.connected {
+            return Err(crate::Error::rejected(
+                "notConnected",
+                "The mock store is not connected",
+            ));
+        }
+        Ok(state)
+    }
+
+    /// A listed product of the type that is sold in the storefront.
+    fn product(&self, product_id: &str, product_type: &str) -> Option<&MockProduct> {
+        self.config
+            .products
+            .get(product_id)
+            .filter(|product| product.product_type == product_type)
+            .filter(|_| {
+                !self
+                    .config
+                    .invalid_product_ids
+                    .iter()
+                    .any(|id| id == product_id)
+            })
+            .filter(|_| {
+                !self
+                    .config
+                    .region_unavailable_ids
+                    .iter()
+                    .any(|id| id == product_id)
+            })
+    }
+
+    fn product_type_of(&self, product_id: &str) -> Option<&str> {
+        self.config
+            .products
+            .get(product_id)
+            .map(|product| product.product_type.as_str())
+    }
+
+    pub fn initialize(&self) -> crate::Result<InitializeResponse> {
+        let mut state = self.state();
+        if let Some(code) = state.connect_errors.pop_front() {
+            state.connected = false;
+            return Err(crate::Error::rejected(
+                &code,
+                "The mock store failed to connect",
+            ));
+        }
+        state.connected = true;
+        Ok(InitializeResponse { success: true })
+    }
+
+    pub fn get_products(
+        &self,
+        product_ids: Vec<String>,
+        product_type: String,
+    ) -> crate::Result<GetProductsResponse> {
+        let mut response = self.query_products(&product_ids, &product_type)?;
+        crate::products::report_invalid_products(
+            &product_ids,
+            &product_type,
+            &mut response,
+            |ids, other_type| self.query_products(&ids, &other_type),
+        );
+        Ok(response)
+    }
+
+    fn query_products(
+        &self,
+        product_ids: &[String],
+        product_type: &str,
+    ) -> crate::Result<GetProductsResponse> {
+        drop(self.connected()?);
+
+        let mut products = Vec::new();
+        let mut failure_reasons = HashMap::new();
+        for product_id in product_ids {
+            if self.product_type_of(product_id) != Some(product_type) {
+                continue;
+            }
+            if self.config.region_unavailable_ids.contains(product_id) {
+                failure_reasons.insert(product_id.clone(), ProductFailureReason::RegionUnavailable);
+            }
+            if let Some(product) = self.product(product_id, product_type) {
+                products.push(to_product(product_id, product));
+            }
+        }
+
+        Ok(GetProductsResponse {
+            products,
+            invalid_product_ids: Vec::new(),
+            failure_reasons,
+            storefront: self.config.storefront.clone(),
+        })
+    }
+
+    pub fn purchase(
+        &self,
+        product_id: String,
+        product_type: String,
+        options: Option<PurchaseOptions>,
+    ) -> crate::Result<Purchase> {
+        let mut state = self.connected()?;
+        if self.product(&product_id, &product_type).is_none() {
+            return Err(crate::Error::rejected(
+                "productNotFound",
+                "Product not found",
+            ));
+        }
+
+        Ok(self.complete_purchase(
+            &mut state,
+            product_id,
+            &product_type,
+            options.and_then(|options| options.obfuscated_account_id),
+        ))
+    }
+
+    /// Record a completed, unacknowledged purchase of the product.
+    fn complete_purchase(
+        &self,
+        state: &mut MockState,
+        product_id: String,
+        product_type: &str,
+        account_id: Option<String>,
+    ) -> Purchase {
+        state.next_purchase += 1;
+        let purchase_time = now_millis();
+        let order_id = format!("mock-order-{purchase_time}-{}", state.next_purchase);
+        let purchase_token = format!("mock-token-{purchase_time}-{}", state.next_purchase);
+        let original_json = serde_json::json!({
+            "orderId": order_id,
+            "productId": product_id,
+            "purchaseToken": purchase_token,
+            "purchaseTime": purchase_time,
+            "obfuscatedAccountId": account_id,
+        });
+        let purchase = Purchase {
+            order_id: Some(order_id),
+            package_name: self.app.package_info().name.clone(),
+            is_auto_renewing: product_type == "subs",
+            product_id,
+            purchase_time,
+            purchase_token,
+            purchase_state: PurchaseStateValue::Purchased as i32,
+            is_acknowledged: false,
+            original_json: original_json.to_string(),
+            signature: String::new(),
+            revocation_date: None,
+            revocation_reason: None,
+            delivery: None,
+            fulfilled: false,
+        };
+        state.purchases.push(purchase.clone());
+        purchase
+    }
+
+    pub fn restore_purchases(
+        &self,
+        product_type: String,
+    ) -> crate::Result<RestorePurchasesResponse> {
+        let state = self.connected()?;
+        Ok(RestorePurchasesResponse {
+            purchases: state
+                .purchases
+                .iter()
+                .filter(|purchase| {
+                    self.product_type_of(&purchase.product_id) == Some(&product_type)
+                })
+                .cloned()
+                .collect(),
+        })
+    }
+
+    pub fn get_purchase_history(&self) -> crate::Result<GetPurchaseHistoryResponse> {
+        let state = self.connected()?;
+        Ok(GetPurchaseHistoryResponse {
+            history: state
+                .purchases
+                .iter()
+                .map(|purchase| PurchaseHistoryRecord {
+                    product_id: purchase.product_id.clone(),
+                    purchase_time: purchase.purchase_time,
+                    purchase_token: purchase.purchase_token.clone(),
+                    quantity: 1,
+                    original_json: purchase.original_json.clone(),
+                    signature: purchase.signature.clone(),
+                })
+                .collect(),
+        })
+    }
+
+    pub fn get_unfinished_purchases(&self) -> crate::Result<GetUnfinishedPurchasesResponse> {
+        let state = self.connected()?;
+        Ok(GetUnfinishedPurchasesResponse {
+            purchases: state
+                .purchases
+                .iter()
+                .filter(|purchase| !purchase.is_acknowledged)
+                .cloned()
+                .collect(),
+        })
+    }
+
+    pub fn get_store_capabilities(&self) -> crate::Result<StoreCapabilities> {
+        drop(self.connected()?);
+        let sells = |product_type: &str| {
+            self.config
+                .products
+                .values()
+                .any(|product| product.product_type == product_type)
+        };
+
+        let mut features = Vec::new();
+        if sells("subs") {
+            features.push(StoreFeature::Subscriptions);
+        }
+        if sells("inapp") {
+            features.push(StoreFeature::Consumables);
+        }
+        if !self.config.offer_codes.is_empty() {
+            features.push(StoreFeature::OfferCodes);
+        }
+        Ok(StoreCapabilities {
+            store: StoreKind::Mock,
+            can_make_payments: true,
+            storefront: self.config.storefront.clone(),
+            features,
+        })
+    }
+
+    /// Redeem one of the `offerCodes` of the config. There is no redemption sheet to type a
+    /// code into, so the code is required. The redeemed purchase arrives as `purchaseUpdated`.
+    pub fn redeem_offer_code(
+        &self,
+        code: Option<String>,
+    ) -> crate::Result<RedeemOfferCodeResponse> {
+        let (purchase, product_type) = {
+            let mut state = self.connected()?;
+            let redeemable = code.filter(|code| !state.redeemed_codes.contains(code));
+            let Some((code, product_id)) = redeemable.and_then(|code| {
+                let product_id = self.config.offer_codes.get(&code)?.clone();
+                Some((code, product_id))
+            }) else {
+                return Err(crate::Error::rejected(
+                    "invalidOfferCode",
+                    "The offer code is unknown or was already redeemed",
+                ));
+            };
+            let Some(product_type) = self.product_type_of(&product_id).map(str::to_string) else {
+                return Err(crate::Error::rejected(
+                    "productNotFound",
+                    "The offer code redeems a product the mock store does not sell",
+                ));
+            };
+
+            state.redeemed_codes.insert(code);
+            let purchase = self.complete_purchase(&mut state, product_id, &product_type, None);
+            (purchase, product_type)
+        };
+
+        crate::updates::observe(
+            &self.app,
+            Some(&product_type),
+            std::slice::from_ref(&purchase),
+        );
+        crate::delivery::emit(&self.app, purchase);
+        Ok(RedeemOfferCodeResponse { presented: true })
+    }
+
+    pub fn begin_refund_request(
+        &self,
+        _transaction_id: String,
+    ) -> crate::Result<BeginRefundRequestResponse> {
+        Err(crate::Error::rejected(
+            "notSupported",
+            "The mock store has no refunds",
+        ))
+    }
+
+    /// Record the call instead of showing a subscription page.
+    pub fn show_manage_subscriptions(
+        &self,
+        product_id: Option<String>,
+    ) -> crate::Result<ShowManageSubscriptionsResponse> {
+        self.state().manage_subscriptions_calls.push(product_id);
+        Ok(ShowManageSubscriptionsResponse {
+            presented: true,
+            url: None,
+        })
+    }
+
+    pub fn acknowledge_purchase(
+        &self,
+        purchase_token: String,
+    ) -> crate::Result<AcknowledgePurchaseResponse> {
+        let mut state = self.connected()?;
+        let purchase = state
+            .purchases
+            .iter_mut()
+            .find(|purchase| purchase.purchase_token == purchase_token)
+            .ok_or_else(unknown_token)?;
+        purchase.is_acknowledged = true;
+        Ok(AcknowledgePurchaseResponse {
+            success: true,
+            queued: false,
+        })
+    }
+
+    pub fn consume_purchase(
+        &self,
+        purchase_token: String,
+    ) -> crate::Result<ConsumePurchaseResponse> {
+        let mut state = self.connected()?;
+        let index = state
+            .purchases
+            .iter()
+            .position(|purchase| purchase.purchase_token == purchase_token)
+            .ok_or_else(unknown_token)?;
+        state.purchases.remove(index);
+        Ok(ConsumePurchaseResponse {
+            success: true,
+            queued: false,
+        })
+    }
+
+    pub fn get_product_status(
+        &self,
+        product_id: String,
+        product_type: String,
+    ) -> crate::Result<ProductStatus> {
+        let purchases = self.restore_purchases(product_type)?.purchases;
+        Ok(product_status(&purchases, product_id))
+    }
+
+    pub fn get_products_status(
+        &self,
+        product_ids: Vec<String>,
+        product_type: String,
+    ) -> crate::Result<GetProductsStatusResponse> {
+        let purchases = self.restore_purchases(product_type)?.purchases;
+        let statuses = product_ids
+            .into_iter()
+            .map(|product_id| (product_id.clone(), product_status(&purchases, product_id)))
+            .collect();
+        Ok(GetProductsStatusResponse { statuses })
+    }
+}
+
+fn unknown_token() -> crate::Error {
+    crate::Error::rejected(
+        "invalidPurchaseToken",
+        "The mock store has no purchase with this token",
+    )
+}
+
+fn to_product(product_id: &str, product: &MockProduct) -> Product {
+    let micros = product.price_amount_micros;
+    let formatted_price = format!(
+        "{}.{:02} {}",
+        micros / 1_000_000,
+        micros % 1_000_000 / 10_000,
+        product.currency
+    );
+    let subscription_offer_details = (product.product_type == "subs").then(|| {
+        let pricing_phases = vec![PricingPhase {
+            formatted_price: formatted_price.clone(),
+            price_currency_code: product.currency.clone(),
+            price_amount_micros: micros,
+            billing_period: "P1M".to_string(),
+            billing_cycle_count: 0,
+            recurrence_mode: crate::offers::INFINITE_RECURRING,
+        }];
+        vec![SubscriptionOffer {
+            offer_token: format!("{product_id}-monthly"),
+            base_plan_id: "monthly".to_string(),
+            offer_id: None,
+            offer_kind: crate::offers::classify(&pricing_phases),
+            is_eligible: true,
+            pricing_phases,
+        }]
+    });
+
+    Product {
+        product_id: product_id.to_string(),
+        title: product.title.clone(),
+        description: String::new(),
+        product_type: product.product_type.clone(),
+        formatted_price: Some(formatted_price),
+        price_currency_code: Some(product.currency.clone()),
+        price_amount_micros: Some(micros),
+        subscription_offer_details,
+    }
+}
+
+/// The status of the latest purchase of the product.
+fn product_status(purchases: &[Purchase], product_id: String) -> ProductStatus {
+    let Some(purchase) = purchases
+        .iter()
+        .filter(|purchase| purchase.product_id == product_id)
+        .max_by_key(|purchase| purchase.purchase_time)
+    else {
+        return ProductStatus {
+            product_id,
+            is_owned: false,
+            purchase_state: None,
+            purchase_time: None,
+            expiration_time: None,
+            is_auto_renewing: None,
+            is_acknowledged: None,
+            purchase_token: None,
+        };
+    };
+
+    ProductStatus {
+        product_id,
+        is_owned: true,
+        purchase_state: Some(PurchaseStateValue::Purchased),
+        purchase_time: Some(purchase.purchase_time),
+        expiration_time: None,
+        is_auto_renewing: Some(purchase.is_auto_renewing),
+        is_acknowledged: Some(purchase.is_acknowledged),
+        purchase_token: Some(purchase.purchase_token.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    use serde_json::json;
+    use tauri::test::{mock_app, MockRuntime};
+    use tauri::{App, Listener, Manager};
+
+    use super::*;
+    use crate::connection::{self, Connection};
+    use crate::IapExt;
+
+    fn store(app: &App<MockRuntime>) -> Iap<MockRuntime> {
+        let config: MockConfig = serde_json::from_value(json!({
+            "products": {
+                "premium": { "title": "Premium", "priceAmountMicros": 4_990_000 },
+                "coins": { "productType": "inapp", "priceAmountMicros": 990_000 },
+                "retired": { "productType": "inapp" },
+                "regional": { "productType": "inapp" },
+            },
+            "invalidProductIds": ["retired"],
+            "regionUnavailableIds": ["regional"],
+            "storefront": "USA",
+            "offerCodes": { "SPRING": "premium", "LEGACY": "discontinued" },
+        }))
+        .unwrap();
+        let store = Iap::new(app.handle(), config);
+        store.initialize().unwrap();
+        store
+    }
+
+    /// An app with the mock as its store, and the connection states it emits.
+    fn app_with_store() -> (App<MockRuntime>, Arc<Mutex<Vec<ConnectionState>>>) {
+        let app = mock_app();
+        app.manage(store(&app));
+        app.manage(Connection::default());
+        app.manage(crate::events::EventBuffer::default());
+        app.manage(crate::retry_queue::RetryQueue::with_path(None));
+
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let listener = states.clone();
+        app.listen_any("billingConnectionChanged", move |event| {
+            let status: ConnectionStatus = serde_json::from_str(event.payload()).unwrap();
+            listener.lock().unwrap().push(status.state);
+        });
+        (app, states)
+    }
+
+    /// An app with the mock as its store and the bookkeeping that sees redeemed purchases, and
+    /// the products of the `purchaseUpdated` events it emits.
+    fn app_with_bookkeeping() -> (App<MockRuntime>, Arc<Mutex<Vec<String>>>) {
+        let (app, _) = app_with_store();
+        app.manage(crate::Config::default());
+        app.manage(crate::ledger::Ledger::with_path(None));
+        app.manage(crate::pending::PendingPurchases::with_path(None));
+        app.manage(crate::acknowledgement::AcknowledgementTracker::default());
+        app.manage(crate::currency::Wallet::with_paths(
+            None,
+            None,
+            &Default::default(),
+        ));
+        app.manage(crate::hooks::Hooks::new(None));
+        app.manage(crate::flow::PurchaseFlows::with_path(None, None));
+        app.manage(crate::delivery::Deliveries::with_path(None));
+        #[cfg(feature = "remote-validator")]
+        app.manage(crate::validator::Validation(None));
+
+        let updated = Arc::new(Mutex::new(Vec::new()));
+        let listener = updated.clone();
+        app.listen_any("purchaseUpdated", move |event| {
+            let purchase: Purchase = serde_json::from_str(event.payload()).unwrap();
+            listener.lock().unwrap().push(purchase.product_id);
+        });
+        (app, updated)
+    }
+
+    fn redeem(
+        app: &App<MockRuntime>,
+        code: Option<&str>,
+    ) -> crate::Result<RedeemOfferCodeResponse> {
+        let request = RedeemOfferCodeRequest {
+            code: code.map(str::to_string),
+        };
+        tauri::async_runtime::block_on(crate::commands::redeem_offer_code(
+            app.handle().clone(),
+            request,
+        ))
+    }
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn reports_invalid_and_region_unavailable_ids() {
+        let app = mock_app();
+
+        let response = store(&app)
+            .get_products(
+                ids(&["coins", "retired", "regional", "typo", "premium"]),
+                "inapp".into(),
+            )
+            .unwrap();
+
+        assert_eq!(response.products.len(), 1);
+        assert_eq!(response.products[0].product_id, "coins");
+        assert_eq!(
+            response.products[0].formatted_price.as_deref(),
+            Some("0.99 USD")
+        );
+        assert_eq!(response.storefront.as_deref(), Some("USA"));
+        assert_eq!(
+            response.invalid_product_ids,
+            ids(&["retired", "regional", "typo", "premium"])
+        );
+        assert_eq!(
+            response.failure_reasons.get("retired"),
+            Some(&ProductFailureReason::NotFound)
+        );
+        assert_eq!(
+            response.failure_reasons.get("regional"),
+            Some(&ProductFailureReason::RegionUnavailable)
+        );
+        assert_eq!(
+            response.failure_reasons.get("typo"),
+            Some(&ProductFailureReason::NotFound)
+        );
+        assert_eq!(
+            response.failure_reasons.get("premium"),
+            Some(&ProductFailureReason::WrongType)
+        );
+    }
+
+    #[test]
+    fn unavailable_products_cannot_be_purchased() {
+        let app = mock_app();
+        let store = store(&app);
+
+        for product_id in ["retired", "regional", "typo"] {
+            let err = store
+                .purchase(product_id.into(), "inapp".into(), None)
+                .unwrap_err();
+            assert_eq!(err.code(), Some("productNotFound"));
+        }
+    }
+
+    #[test]
+    fn purchases_are_restored_until_consumed() {
+        let app = mock_app();
+        let store = store(&app);
+
+        let purchase = store
+            .purchase("coins".into(), "inapp".into(), None)
+            .unwrap();
+        assert!(!purchase.is_acknowledged);
+        assert_eq!(store.get_unfinished_purchases().unwrap().purchases.len(), 1);
+        store
+            .acknowledge_purchase(purchase.purchase_token.clone())
+            .unwrap();
+        assert!(store
+            .get_unfinished_purchases()
+            .unwrap()
+            .purchases
+            .is_empty());
+        assert!(store
+            .restore_purchases("subs".into())
+            .unwrap()
+            .purchases
+            .is_empty());
+        let restored = store.restore_purchases("inapp".into()).unwrap().purchases;
+        assert_eq!(restored.len(), 1);
+        assert!(restored[0].is_acknowledged);
+
+        store
+            .consume_purchase(purchase.purchase_token.clone())
+            .unwrap();
+        assert!(store
+            .restore_purchases("inapp".into())
+            .unwrap()
+            .purchases
+            .is_empty());
+        let err = store.consume_purchase(purchase.purchase_token).unwrap_err();
+        assert_eq!(err.code(), Some("invalidPurchaseToken"));
+    }
+
+    #[test]
+    fn capabilities_report_the_mock_store() {
+        let app = mock_app();
+
+        let capabilities = store(&app).get_store_capabilities().unwrap();
+
+        assert_eq!(capabilities.store, StoreKind::Mock);
+        assert!(capabilities.can_make_payments);
+        assert_eq!(capabilities.storefront.as_deref(), Some("USA"));
+        assert_eq!(
+            capabilities.features,
+            vec![
+                StoreFeature::Subscriptions,
+                StoreFeature::Consumables,
+                StoreFeature::OfferCodes
+            ]
+        );
+
+        let empty = Iap::new(app.handle(), MockConfig::default());
+        empty.initialize().unwrap();
+        assert!(empty.get_store_capabilities().unwrap().features.is_empty());
+    }
+
+    #[test]
+    fn store_calls_need_a_connection() {
+        let app = mock_app();
+        let store = store(&app);
+        store.disconnect();
+
+        let err = store.restore_purchases("subs".into()).unwrap_err();
+        assert_eq!(err.code(), Some("notConnected"));
+
+        store.fail_connects("serviceUnavailable", 1);
+        let err = store.initialize().unwrap_err();
+        assert_eq!(err.code(), Some("serviceUnavailable"));
+        store.initialize().unwrap();
+        assert!(store.restore_purchases("subs".into()).is_ok());
+    }
+
+    #[test]
+    fn connect_retries_while_the_store_is_unavailable() {
+        let (app, states) = app_with_store();
+        app.iap().disconnect();
+        app.iap().fail_connects("serviceUnavailable", 1);
+
+        connection::connect(app.handle()).unwrap();
+
+        assert!(app.iap().state().connected);
+        assert!(app.iap().state().connect_errors.is_empty());
+        assert_eq!(
+            *states.lock().unwrap(),
+            vec![ConnectionState::Connecting, ConnectionState::Connected]
+        );
+    }
+
+    #[test]
+    fn connect_gives_up_at_once_when_billing_is_unavailable() {
+        let (app, states) = app_with_store();
+        app.iap().disconnect();
+        app.iap().fail_connects("billingUnavailable", 2);
+        let started = Instant::now();
+
+        let err = connection::connect(app.handle()).unwrap_err();
+
+        assert_eq!(err.code(), Some("billingUnavailable"));
+        assert!(started.elapsed().as_millis() < 1_000);
+        // One attempt used one of the two failures
+        assert_eq!(app.iap().state().connect_errors.len(), 1);
+        assert_eq!(
+            *states.lock().unwrap(),
+            vec![ConnectionState::Connecting, ConnectionState::Failed]
+        );
+    }
+
+    #[test]
+    fn store_calls_reconnect_a_dropped_connection() {
+        let (app, _) = app_with_store();
+        connection::connect(app.handle()).unwrap();
+        app.iap()
+            .purchase("coins".into(), "inapp".into(), None)
+            .unwrap();
+        app.iap().disconnect();
+
+        let restored = connection::with_reconnect(app.handle(), || {
+            app.iap().restore_purchases("inapp".into())
+        })
+        .unwrap();
+
+        assert_eq!(restored.purchases.len(), 1);
+        assert_eq!(
+            app.state::<Connection>().status().state,
+            ConnectionState::Connected
+        );
+    }
+
+    #[test]
+    fn concurrent_store_calls_share_one_reconnect() {
+        let (app, states) = app_with_store();
+        connection::connect(app.handle()).unwrap();
+        app.iap().disconnect();
+        // The reconnect takes a second, long enough for every call to wait for it
+        app.iap().fail_connects("serviceUnavailable", 1);
+        states.lock().unwrap().clear();
+
+        let calls: Vec<_> = (0..4)
+            .map(|_| {
+                let app = app.handle().clone();
+                std::thread::spawn(move || {
+                    connection::with_reconnect(&app, || app.iap().restore_purchases("inapp".into()))
+                })
+            })
+            .collect();
+        for call in calls {
+            assert!(call.join().unwrap().is_ok());
+        }
+
+        let states = states.lock().unwrap();
+        assert_eq!(
+            states
+                .iter()
+                .filter(|state| **state == ConnectionState::Connecting)
+                .count(),
+            1
+        );
+        assert_eq!(states.last(), Some(&ConnectionState::Connected));
+    }
+
+    #[test]
+    fn a_valid_offer_code_redeems_its_product_once() {
+        let (app, updated) = app_with_bookkeeping();
+
+        let response = redeem(&app, Some("SPRING")).unwrap();
+
+        assert!(response.presented);
+        assert_eq!(*updated.lock().unwrap(), vec!["premium"]);
+        let restored = app
+            .iap()
+            .restore_purchases("subs".into())
+            .unwrap()
+            .purchases;
+        assert_eq!(restored.len(), 1);
+        assert_eq!(restored[0].product_id, "premium");
+
+        let err = redeem(&app, Some("SPRING")).unwrap_err();
+        assert_eq!(err.code(), Some("invalidOfferCode"));
+        assert_eq!(updated.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn unknown_offer_codes_are_rejected() {
+        let (app, updated) = app_with_bookkeeping();
+
+        for code in [Some("WINTER"), Some("spring"), None] {
+            let err = redeem(&app, code).unwrap_err();
+            assert_eq!(err.code(), Some("invalidOfferCode"));
+        }
+        // A code of a product the store does not sell is not used up
+        for _ in 0..2 {
+            let err = redeem(&app, Some("LEGACY")).unwrap_err();
+            assert_eq!(err.code(), Some("productNotFound"));
+        }
+
+        assert!(updated.lock().unwrap().is_empty());
+        assert!(app
+            .iap()
+            .restore_purchases("subs".into())
+            .unwrap()
+            .purchases
+            .is_empty());
+    }
+
+    #[test]
+    fn redeeming_reconnects_a_dropped_connection() {
+        let (app, updated) = app_with_bookkeeping();
+        app.iap().disconnect();
+
+        redeem(&app, Some("SPRING")).unwrap();
+
+        assert_eq!(*updated.lock().unwrap(), vec!["premium"]);
+        assert_eq!(
+            app.state::<Connection>().status().state,
+            ConnectionState::Connected
+        );
+    }
+
+    #[test]
+    fn managing_subscriptions_passes_the_product_through() {
+        let (app, _) = app_with_store();
+
+        for product_id in [Some("premium"), None] {
+            let request = ShowManageSubscriptionsRequest {
+                product_id: product_id.map(str::to_string),
+            };
+            let response = tauri::async_runtime::block_on(
+                crate::commands::show_manage_subscriptions(app.handle().clone(), request),
+            )
+            .unwrap();
+            assert!(response.presented);
+            assert_eq!(response.url, None);
+        }
+
+        assert_eq!(
+            app.iap().manage_subscriptions_calls(),
+            vec![Some("premium".to_string()), None]
+        );
+    }
+}
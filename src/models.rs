@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -16,6 +18,9 @@ pub struct GetProductsRequest {
     pub product_ids: Vec<String>,
     #[serde(default = "default_product_type")]
     pub product_type: String,
+    /// Skip the product cache and query the store.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub force_refresh: bool,
 }
 
 fn default_product_type() -> String {
@@ -33,6 +38,17 @@ pub struct PricingPhase {
     pub recurrence_mode: i32,
 }
 
+/// What the user pays during the first pricing phase of an offer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OfferKind {
+    FreeTrial,
+    PayAsYouGo,
+    PayUpFront,
+    #[default]
+    Recurring,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SubscriptionOffer {
@@ -40,6 +56,14 @@ pub struct SubscriptionOffer {
     pub base_plan_id: String,
     pub offer_id: Option<String>,
     pub pricing_phases: Vec<PricingPhase>,
+    #[serde(default)]
+    pub offer_kind: OfferKind,
+    #[serde(default = "default_is_eligible")]
+    pub is_eligible: bool,
+}
+
+fn default_is_eligible() -> bool {
+    true
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -59,13 +83,31 @@ pub struct Product {
     pub subscription_offer_details: Option<Vec<SubscriptionOffer>>,
 }
 
+/// Why a requested product id is missing from [`GetProductsResponse::products`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ProductFailureReason {
+    NotFound,
+    WrongType,
+    RegionUnavailable,
+    /// The product exists, but has no offer the user is eligible for.
+    NotEligible,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct GetProductsResponse {
     pub products: Vec<Product>,
+    #[serde(default)]
+    pub invalid_product_ids: Vec<String>,
+    #[serde(default)]
+    pub failure_reasons: HashMap<String, ProductFailureReason>,
+    /// Storefront the prices belong to, when the store reports it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storefront: Option<String>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PurchaseOptions {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -78,7 +120,7 @@ pub struct PurchaseOptions {
     pub app_account_token: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PurchaseRequest {
     pub product_id: String,
@@ -86,6 +128,12 @@ pub struct PurchaseRequest {
     pub product_type: String,
     #[serde(flatten)]
     pub options: Option<PurchaseOptions>,
+    /// Identifies the flow for `cancel_purchase`. Generated when omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Stop waiting for the store after this many milliseconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -101,6 +149,82 @@ pub struct Purchase {
     pub is_acknowledged: bool,
     pub original_json: String,
     pub signature: String,
+    /// When the store revoked the purchase, e.g. after a refund (StoreKit 2).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revocation_date: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revocation_reason: Option<RevocationReason>,
+    /// Whether the app received this purchase before. Unset for purchases without a token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delivery: Option<Delivery>,
+    /// Whether the app confirmed it processed the purchase with `mark_fulfilled`.
+    #[serde(default)]
+    pub fulfilled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Delivery {
+    /// The first time the app receives this purchase in this state.
+    FirstSeen,
+    /// Delivered before, through another path or in an earlier session.
+    Redelivery,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MarkFulfilledRequest {
+    pub purchase_token: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MarkFulfilledResponse {
+    /// `false` when the plugin never delivered a purchase with this token.
+    pub success: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RevocationReason {
+    /// Refunded because of an issue with the app.
+    DeveloperIssue,
+    /// Refunded or revoked for another reason, e.g. Family Sharing was turned off.
+    Other,
+    /// No longer listed by the store, which is how Google Play reports refunds.
+    NoLongerOwned,
+}
+
+/// An entitlement that was owned at the previous restore and has been revoked since.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PurchaseRevocation {
+    pub product_id: String,
+    pub product_type: String,
+    pub purchase_token: String,
+    pub reason: RevocationReason,
+    /// Revocation date from the store, or when the plugin noticed the purchase was gone.
+    pub revoked_at: i64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BeginRefundRequestRequest {
+    pub transaction_id: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RefundRequestStatus {
+    /// The request was submitted. Whether the store refunds is reported later as a revocation.
+    Success,
+    UserCancelled,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BeginRefundRequestResponse {
+    pub status: RefundRequestStatus,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -143,6 +267,24 @@ pub struct AcknowledgePurchaseRequest {
 #[serde(rename_all = "camelCase")]
 pub struct AcknowledgePurchaseResponse {
     pub success: bool,
+    /// The store call failed and will be retried from the pending operation queue.
+    #[serde(default)]
+    pub queued: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConsumePurchaseRequest {
+    pub purchase_token: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConsumePurchaseResponse {
+    pub success: bool,
+    /// The store call failed and will be retried from the pending operation queue.
+    #[serde(default)]
+    pub queued: bool,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -204,3 +346,561 @@ pub struct ProductStatus {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub purchase_token: Option<String>,
 }
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetProductsStatusRequest {
+    /// Defaults to every catalog product of `product_type`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub product_ids: Option<Vec<String>>,
+    #[serde(default = "default_product_type")]
+    pub product_type: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetProductsStatusResponse {
+    pub statuses: HashMap<String, ProductStatus>,
+}
+
+/// A purchase flow that has started and not yet returned.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InFlightPurchase {
+    pub request_id: String,
+    pub product_id: String,
+    pub product_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account_id: Option<String>,
+    pub started_at: i64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetInFlightPurchasesResponse {
+    pub purchases: Vec<InFlightPurchase>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CancelPurchaseRequest {
+    pub request_id: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CancelPurchaseResponse {
+    /// `false` when no running purchase has this request id.
+    pub success: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PendingOperationKind {
+    Acknowledge,
+    Consume,
+}
+
+/// An acknowledge or consume call that failed and is waiting to be retried.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingOperation {
+    pub kind: PendingOperationKind,
+    pub purchase_token: String,
+    /// Failed store calls so far.
+    pub attempts: u32,
+    pub created_at: i64,
+    pub next_attempt_at: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetPendingOperationsResponse {
+    pub operations: Vec<PendingOperation>,
+}
+
+/// An unacknowledged purchase and when the store refunds it.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AcknowledgementDeadline {
+    pub product_id: String,
+    pub purchase_token: String,
+    pub purchase_time: i64,
+    pub deadline: i64,
+}
+
+/// A purchase the store accepted but has not completed, e.g. a cash payment or Ask to Buy.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingPurchase {
+    pub product_id: String,
+    pub product_type: String,
+    /// Empty when the store only assigns a token once the purchase completes (App Store).
+    pub purchase_token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub order_id: Option<String>,
+    pub purchase_time: i64,
+}
+
+/// How a pending purchase ended, the payload of `pendingPurchaseResolved` and
+/// `pendingPurchaseFailed`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingPurchaseOutcome {
+    pub pending: PendingPurchase,
+    /// The completed or canceled purchase, if the store reported one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub purchase: Option<Purchase>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetPendingPurchasesResponse {
+    pub purchases: Vec<PendingPurchase>,
+}
+
+/// Purchases the store still considers unfinished: unacknowledged, pending or never finished.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetUnfinishedPurchasesResponse {
+    pub purchases: Vec<Purchase>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscribeEventsRequest {
+    /// Event names the frontend now listens to.
+    pub events: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ConnectionState {
+    #[default]
+    Disconnected,
+    Connecting,
+    Connected,
+    /// Connecting gave up after several attempts. The next command tries again.
+    Failed,
+}
+
+/// State of the connection to the store, the payload of `billingConnectionChanged`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionStatus {
+    pub state: ConnectionState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RedeemOfferCodeRequest {
+    /// Code to prefill, where the store supports it (Google Play).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RedeemOfferCodeResponse {
+    /// Whether the redemption UI was shown. Redeemed purchases arrive as `purchaseUpdated`.
+    pub presented: bool,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShowManageSubscriptionsRequest {
+    /// Subscription to open directly, where the store supports it (Google Play).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub product_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShowManageSubscriptionsResponse {
+    pub presented: bool,
+    /// The page that was opened, when the store has no in-app management UI.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum StoreKind {
+    Play,
+    AppStore,
+    MicrosoftStore,
+    Steam,
+    Stripe,
+    /// The in-memory store of the `mock` feature.
+    Mock,
+    /// No store backend on this platform.
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum StoreFeature {
+    Subscriptions,
+    /// Upgrading or downgrading a subscription from within the app.
+    Replacement,
+    PriceChangeConfirmation,
+    Consumables,
+    OfferCodes,
+}
+
+/// What the active store supports, so the app can hide actions instead of handling errors.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoreCapabilities {
+    pub store: StoreKind,
+    /// `false` when payments are disabled, e.g. by parental controls.
+    pub can_make_payments: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storefront: Option<String>,
+    #[serde(default)]
+    pub features: Vec<StoreFeature>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum LedgerEventKind {
+    Purchase,
+    Restore,
+    Acknowledgement,
+    Consumption,
+    Fulfillment,
+    RefundRequest,
+    Revocation,
+}
+
+/// One line of the purchase ledger.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LedgerEntry {
+    /// Schema version the entry was written with.
+    pub version: u32,
+    pub recorded_at: i64,
+    pub kind: LedgerEventKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub product_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub product_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub purchase_token: Option<String>,
+    /// The purchase, restored purchases, revocation or refund status the entry is about.
+    #[serde(default, skip_serializing_if = "serde_json::Value::is_null")]
+    pub data: serde_json::Value,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryLedgerRequest {
+    #[serde(default)]
+    pub product_id: Option<String>,
+    /// Inclusive lower bound on `recorded_at`, in milliseconds since the epoch.
+    #[serde(default)]
+    pub from: Option<i64>,
+    /// Exclusive upper bound on `recorded_at`.
+    #[serde(default)]
+    pub to: Option<i64>,
+    /// Only entries of these kinds. All kinds when empty.
+    #[serde(default)]
+    pub kinds: Vec<LedgerEventKind>,
+    /// Keep only the most recent entries.
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryLedgerResponse {
+    /// Matching entries, oldest first.
+    pub entries: Vec<LedgerEntry>,
+}
+
+/// A change of a virtual currency balance.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CurrencyTransaction {
+    pub currency: String,
+    /// Positive for credits, negative for spending.
+    pub amount: i64,
+    pub balance_after: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Purchase a credit came from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub purchase_token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub product_id: Option<String>,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetCurrencyBalanceResponse {
+    /// Balance of every currency that was ever credited.
+    pub balances: std::collections::BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpendCurrencyRequest {
+    pub currency: String,
+    pub amount: u64,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetCurrencyHistoryRequest {
+    #[serde(default)]
+    pub currency: Option<String>,
+    /// Keep only the most recent transactions.
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetCurrencyHistoryResponse {
+    /// Transactions, oldest first.
+    pub transactions: Vec<CurrencyTransaction>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PurchaseAndFulfillRequest {
+    #[serde(flatten)]
+    pub purchase: PurchaseRequest,
+    /// Consume the purchase once granted instead of acknowledging it, for consumables.
+    #[serde(default)]
+    pub consume: bool,
+}
+
+/// Step of a purchase flow, in the order they run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PurchaseFlowStage {
+    /// Waiting for the store to complete the purchase.
+    Purchase,
+    Verify,
+    Grant,
+    /// Acknowledging or consuming the purchase.
+    Finish,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum PurchaseFlowOutcome {
+    /// The purchase was verified, granted and finished.
+    Granted {
+        purchase: Purchase,
+    },
+    /// Payment is still pending. The flow continues when the store completes the purchase
+    /// and reports the outcome as a `purchaseFlowFinished` event.
+    Pending {
+        purchase: Purchase,
+    },
+    Canceled,
+    Failed {
+        stage: PurchaseFlowStage,
+        error: String,
+        /// The purchase, if the store completed it before the failing stage.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        purchase: Option<Purchase>,
+    },
+}
+
+/// Outcome of a flow that finished outside of its `purchase_and_fulfill` call, after a
+/// pending payment or a restart.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PurchaseFlowFinished {
+    /// The `request_id` of the flow.
+    pub flow_id: String,
+    pub outcome: PurchaseFlowOutcome,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ValidatePurchaseRequest {
+    pub purchase: Purchase,
+}
+
+/// The body POSTed to the validation server.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ValidationRequest {
+    pub purchase_token: String,
+    pub product_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order_id: Option<String>,
+    pub package_name: String,
+    pub original_json: String,
+    pub signature: String,
+    /// `android`, `ios`, `macos`, `windows` or `linux`.
+    pub platform: String,
+    /// The app's user the purchase is claimed for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account_id: Option<String>,
+}
+
+impl ValidationRequest {
+    pub fn new(purchase: &Purchase) -> Self {
+        Self {
+            purchase_token: purchase.purchase_token.clone(),
+            product_id: purchase.product_id.clone(),
+            order_id: purchase.order_id.clone(),
+            package_name: purchase.package_name.clone(),
+            original_json: purchase.original_json.clone(),
+            signature: purchase.signature.clone(),
+            platform: std::env::consts::OS.to_string(),
+            account_id: None,
+        }
+    }
+}
+
+/// What the validation server answers.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ValidationVerdict {
+    /// Whether the purchase is genuine and may be granted.
+    pub valid: bool,
+    /// Why the purchase was rejected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// When the entitlement ends, for subscriptions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+    /// Signed token with the entitlements of the account, when the server issues them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entitlement_token: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyEntitlementTokenRequest {
+    pub token: String,
+}
+
+/// What an entitlement token proves. Times are in seconds, as JWTs have them.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct EntitlementClaims {
+    #[serde(rename = "sub")]
+    pub account_id: String,
+    /// Product ids the account owns.
+    pub entitlements: Vec<String>,
+    #[serde(rename = "iat")]
+    pub issued_at: i64,
+    #[serde(rename = "exp")]
+    pub expires_at: i64,
+    #[serde(rename = "iss", default, skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn store_capabilities_read_the_native_payloads() {
+        let play: StoreCapabilities = serde_json::from_value(json!({
+            "store": "play",
+            "canMakePayments": true,
+            "features": ["consumables", "offerCodes", "subscriptions", "replacement"],
+            "storefront": "US",
+        }))
+        .unwrap();
+        assert_eq!(play.store, StoreKind::Play);
+        assert!(play.can_make_payments);
+        assert_eq!(play.storefront.as_deref(), Some("US"));
+        assert_eq!(
+            play.features,
+            vec![
+                StoreFeature::Consumables,
+                StoreFeature::OfferCodes,
+                StoreFeature::Subscriptions,
+                StoreFeature::Replacement,
+            ]
+        );
+
+        let app_store: StoreCapabilities = serde_json::from_value(json!({
+            "store": "appStore",
+            "canMakePayments": false,
+            "features": ["subscriptions", "priceChangeConfirmation"],
+        }))
+        .unwrap();
+        assert_eq!(app_store.store, StoreKind::AppStore);
+        assert_eq!(app_store.storefront, None);
+        assert_eq!(app_store.features[1], StoreFeature::PriceChangeConfirmation);
+    }
+
+    #[test]
+    fn the_mock_store_kind_serializes_for_the_frontend() {
+        assert_eq!(
+            serde_json::to_value(StoreKind::Mock).unwrap(),
+            json!("mock")
+        );
+    }
+
+    #[test]
+    fn store_capabilities_serialize_for_the_frontend() {
+        let capabilities = StoreCapabilities {
+            store: StoreKind::MicrosoftStore,
+            can_make_payments: true,
+            storefront: None,
+            features: vec![StoreFeature::Subscriptions],
+        };
+        assert_eq!(
+            serde_json::to_value(capabilities).unwrap(),
+            json!({
+                "store": "microsoftStore",
+                "canMakePayments": true,
+                "features": ["subscriptions"],
+            })
+        );
+    }
+
+    #[test]
+    fn redeem_offer_code_takes_an_optional_code() {
+        let request: RedeemOfferCodeRequest = serde_json::from_value(json!({})).unwrap();
+        assert_eq!(request.code, None);
+
+        let request: RedeemOfferCodeRequest =
+            serde_json::from_value(json!({ "code": "SPRING" })).unwrap();
+        assert_eq!(request.code.as_deref(), Some("SPRING"));
+
+        let response: RedeemOfferCodeResponse =
+            serde_json::from_value(json!({ "presented": true })).unwrap();
+        assert!(response.presented);
+    }
+
+    #[test]
+    fn manage_subscriptions_reports_the_opened_page() {
+        let request: ShowManageSubscriptionsRequest =
+            serde_json::from_value(json!({ "productId": "premium" })).unwrap();
+        assert_eq!(request.product_id.as_deref(), Some("premium"));
+
+        let in_app: ShowManageSubscriptionsResponse =
+            serde_json::from_value(json!({ "presented": true })).unwrap();
+        assert_eq!(in_app.url, None);
+        assert_eq!(
+            serde_json::to_value(in_app).unwrap(),
+            json!({ "presented": true })
+        );
+
+        let page = "https://play.google.com/store/account/subscriptions?sku=premium";
+        let browser: ShowManageSubscriptionsResponse =
+            serde_json::from_value(json!({ "presented": true, "url": page })).unwrap();
+        assert_eq!(browser.url.as_deref(), Some(page));
+    }
+}
@@ -0,0 +1,266 @@
+use crate::models::*;
+
+/// Google Play `RecurrenceMode` values, which every backend reuses for pricing phases.
+pub const INFINITE_RECURRING: i32 = 1;
+pub const FINITE_RECURRING: i32 = 2;
+pub const NON_RECURRING: i32 = 3;
+
+/// Classify an offer by its first pricing phase.
+///
+/// An offer whose only phase recurs forever is the plain base plan. Anything else is an
+/// introductory offer, and the first phase tells which kind.
+pub fn classify(pricing_phases: &[PricingPhase]) -> OfferKind {
+    let Some(first) = pricing_phases.first() else {
+        return OfferKind::Recurring;
+    };
+
+    if first.recurrence_mode == INFINITE_RECURRING {
+        OfferKind::Recurring
+    } else if first.price_amount_micros == 0 {
+        OfferKind::FreeTrial
+    } else if first.recurrence_mode == NON_RECURRING {
+        OfferKind::PayUpFront
+    } else {
+        OfferKind::PayAsYouGo
+    }
+}
+
+/// Fill in `offer_kind` for every subscription offer of the given products.
+pub fn annotate(products: &mut [Product]) {
+    for offer in products
+        .iter_mut()
+        .flat_map(|product| product.subscription_offer_details.iter_mut().flatten())
+    {
+        offer.offer_kind = classify(&offer.pricing_phases);
+    }
+}
+
+/// Pick the offer the user should be shown by default.
+///
+/// Only offers the user is eligible for are considered. Free trials win over paid
+/// introductory offers, which win over the base plan; ties go to the cheaper first phase.
+pub fn best_offer(product: &Product) -> Option<&SubscriptionOffer> {
+    product
+        .subscription_offer_details
+        .as_deref()?
+        .iter()
+        .filter(|offer| offer.is_eligible)
+        .min_by_key(|offer| {
+            let rank = match offer.offer_kind {
+                OfferKind::FreeTrial => 0,
+                OfferKind::PayAsYouGo | OfferKind::PayUpFront => 1,
+                OfferKind::Recurring => 2,
+            };
+            let first_price = offer
+                .pricing_phases
+                .first()
+                .map_or(i64::MAX, |phase| phase.price_amount_micros);
+            (rank, first_price)
+        })
+}
+
+/// Token of [`best_offer`], skipping backends that leave offer tokens empty.
+///
+/// A subscription without any eligible offer falls back to its base plan, the offer without
+/// an `offer_id`, or else its first offer, so the purchase still goes through.
+pub fn best_offer_token(product: &Product) -> Option<String> {
+    best_offer(product)
+        .or_else(|| {
+            let offers = product.subscription_offer_details.as_deref()?;
+            offers
+                .iter()
+                .find(|offer| offer.offer_id.is_none())
+                .or_else(|| offers.first())
+        })
+        .map(|offer| offer.offer_token.clone())
+        .filter(|token| !token.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn phase(price_amount_micros: i64, recurrence_mode: i32) -> PricingPhase {
+        PricingPhase {
+            formatted_price: String::new(),
+            price_currency_code: "USD".to_string(),
+            price_amount_micros,
+            billing_period: "P1M".to_string(),
+            billing_cycle_count: 0,
+            recurrence_mode,
+        }
+    }
+
+    fn offer(token: &str, offer_id: Option<&str>, phases: Vec<PricingPhase>) -> SubscriptionOffer {
+        SubscriptionOffer {
+            offer_token: token.to_string(),
+            base_plan_id: "monthly".to_string(),
+            offer_id: offer_id.map(str::to_string),
+            offer_kind: classify(&phases),
+            is_eligible: true,
+            pricing_phases: phases,
+        }
+    }
+
+    fn base_plan() -> SubscriptionOffer {
+        offer("base", None, vec![phase(9_990_000, INFINITE_RECURRING)])
+    }
+
+    fn product(offers: Vec<SubscriptionOffer>) -> Product {
+        Product {
+            product_id: "premium".to_string(),
+            title: String::new(),
+            description: String::new(),
+            product_type: "subs".to_string(),
+            formatted_price: None,
+            price_currency_code: None,
+            price_amount_micros: None,
+            subscription_offer_details: Some(offers),
+        }
+    }
+
+    #[test]
+    fn classify_by_first_phase() {
+        assert_eq!(classify(&[]), OfferKind::Recurring);
+        assert_eq!(
+            classify(&[phase(9_990_000, INFINITE_RECURRING)]),
+            OfferKind::Recurring
+        );
+        assert_eq!(
+            classify(&[
+                phase(0, FINITE_RECURRING),
+                phase(9_990_000, INFINITE_RECURRING)
+            ]),
+            OfferKind::FreeTrial
+        );
+        assert_eq!(
+            classify(&[
+                phase(990_000, FINITE_RECURRING),
+                phase(9_990_000, INFINITE_RECURRING)
+            ]),
+            OfferKind::PayAsYouGo
+        );
+        assert_eq!(
+            classify(&[
+                phase(19_990_000, NON_RECURRING),
+                phase(9_990_000, INFINITE_RECURRING)
+            ]),
+            OfferKind::PayUpFront
+        );
+    }
+
+    #[test]
+    fn annotate_fills_in_offer_kinds() {
+        let mut trial = offer(
+            "trial",
+            Some("trial"),
+            vec![
+                phase(0, FINITE_RECURRING),
+                phase(9_990_000, INFINITE_RECURRING),
+            ],
+        );
+        trial.offer_kind = OfferKind::Recurring;
+        let mut products = [product(vec![trial])];
+
+        annotate(&mut products);
+
+        let offers = products[0].subscription_offer_details.as_ref().unwrap();
+        assert_eq!(offers[0].offer_kind, OfferKind::FreeTrial);
+    }
+
+    #[test]
+    fn best_offer_prefers_trials_then_intro_offers_then_the_base_plan() {
+        let intro = offer(
+            "intro",
+            Some("intro"),
+            vec![
+                phase(990_000, FINITE_RECURRING),
+                phase(9_990_000, INFINITE_RECURRING),
+            ],
+        );
+        let trial = offer(
+            "trial",
+            Some("trial"),
+            vec![
+                phase(0, FINITE_RECURRING),
+                phase(9_990_000, INFINITE_RECURRING),
+            ],
+        );
+
+        let all = product(vec![base_plan(), intro.clone(), trial]);
+        assert_eq!(best_offer(&all).unwrap().offer_token, "trial");
+
+        let no_trial = product(vec![base_plan(), intro]);
+        assert_eq!(best_offer(&no_trial).unwrap().offer_token, "intro");
+
+        let base_only = product(vec![base_plan()]);
+        assert_eq!(best_offer(&base_only).unwrap().offer_token, "base");
+    }
+
+    #[test]
+    fn best_offer_breaks_ties_by_first_phase_price() {
+        let cheap = offer(
+            "cheap",
+            Some("cheap"),
+            vec![
+                phase(490_000, FINITE_RECURRING),
+                phase(9_990_000, INFINITE_RECURRING),
+            ],
+        );
+        let dear = offer(
+            "dear",
+            Some("dear"),
+            vec![
+                phase(990_000, FINITE_RECURRING),
+                phase(9_990_000, INFINITE_RECURRING),
+            ],
+        );
+
+        let product = product(vec![dear, cheap]);
+        assert_eq!(best_offer(&product).unwrap().offer_token, "cheap");
+    }
+
+    #[test]
+    fn best_offer_skips_ineligible_offers() {
+        let mut trial = offer(
+            "trial",
+            Some("trial"),
+            vec![
+                phase(0, FINITE_RECURRING),
+                phase(9_990_000, INFINITE_RECURRING),
+            ],
+        );
+        trial.is_eligible = false;
+
+        let product = product(vec![base_plan(), trial]);
+        assert_eq!(best_offer(&product).unwrap().offer_token, "base");
+    }
+
+    #[test]
+    fn best_offer_token_falls_back_to_the_base_plan() {
+        let mut base = base_plan();
+        base.is_eligible = false;
+        let mut trial = offer(
+            "trial",
+            Some("trial"),
+            vec![
+                phase(0, FINITE_RECURRING),
+                phase(9_990_000, INFINITE_RECURRING),
+            ],
+        );
+        trial.is_eligible = false;
+
+        let product = product(vec![trial, base]);
+        assert!(best_offer(&product).is_none());
+        assert_eq!(best_offer_token(&product).as_deref(), Some("base"));
+    }
+
+    #[test]
+    fn best_offer_token_skips_empty_tokens() {
+        let mut base = base_plan();
+        base.offer_token = String::new();
+
+        assert_eq!(best_offer_token(&product(vec![base])), None);
+        assert_eq!(best_offer_token(&product(Vec::new())), None);
+    }
+}
@@ -0,0 +1,362 @@
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use tauri::{AppHandle, Manager, Runtime};
+
+use crate::events::{self, IapEvent};
+use crate::models::*;
+
+const PENDING_FILE: &str = "iap-pending-purchases.json";
+
+/// Google Play keeps listing a pending purchase until it completes or is canceled, so one
+/// missing from `restore_purchases` has failed. The App Store never lists deferred purchases.
+const RESTORE_LISTS_PENDING: bool = cfg!(target_os = "android");
+
+/// Pending purchases, kept in the app data directory until the store completes or cancels them.
+pub(crate) struct PendingPurchases {
+    path: Option<PathBuf>,
+    purchases: Mutex<Vec<PendingPurchase>>,
+}
+
+impl PendingPurchases {
+    pub fn new<R: Runtime>(app: &AppHandle<R>) -> Self {
+        let path = app
+            .path()
+            .app_data_dir()
+            .ok()
+            .map(|dir| dir.join(PENDING_FILE));
+        Self::with_path(path)
+    }
+
+    pub(crate) fn with_path(path: Option<PathBuf>) -> Self {
+        let purchases = path
+            .as_ref()
+            .and_then(|path| std::fs::read(path).ok())
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+
+        Self {
+            path,
+            purchases: Mutex::new(purchases),
+        }
+    }
+
+    /// The pending purchases, oldest first.
+    pub fn list(&self) -> Vec<PendingPurchase> {
+        self.purchases
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    fn record(&self, purchase: &Purchase, product_type: &str) {
+        let mut purchases = self.purchases.lock().unwrap_or_else(|e| e.into_inner());
+        if purchases.iter().any(|pending| is_match(pending, purchase)) {
+            return;
+        }
+
+        purchases.push(PendingPurchase {
+            product_id: purchase.product_id.clone(),
+            product_type: product_type.to_string(),
+            purchase_token: purchase.purchase_token.clone(),
+            order_id: purchase.order_id.clone(),
+            purchase_time: purchase.purchase_time,
+        });
+        self.persist(&purchases);
+    }
+
+    /// Remove the pending purchase the given purchase completes or cancels.
+    fn take(&self, purchase: &Purchase) -> Option<PendingPurchase> {
+        let mut purchases = self.purchases.lock().unwrap_or_else(|e| e.into_inner());
+        let index = purchases
+            .iter()
+            .position(|pending| is_match(pending, purchase))?;
+        let pending = purchases.remove(index);
+        self.persist(&purchases);
+        Some(pending)
+    }
+
+    /// Remove the pending purchases of this type that none of the purchases match.
+    fn take_missing(&self, product_type: &str, purchases: &[Purchase]) -> Vec<PendingPurchase> {
+        let mut pending = self.purchases.lock().unwrap_or_else(|e| e.into_inner());
+        let (missing, kept): (Vec<_>, Vec<_>) = pending.drain(..).partition(|pending| {
+            pending.product_type == product_type
+                && !purchases.iter().any(|purchase| is_match(pending, purchase))
+        });
+        *pending = kept;
+        if !missing.is_empty() {
+            self.persist(&pending);
+        }
+        missing
+    }
+
+    fn persist(&self, purchases: &[PendingPurchase]) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        if let Ok(json) = serde_json::to_vec(purchases) {
+            if let Some(dir) = path.parent() {
+                let _ = std::fs::create_dir_all(dir);
+            }
+            let _ = std::fs::write(path, json);
+        }
+    }
+}
+
+/// A purchase continues a pending one if it has the same token. Stores that only assign a
+/// token on completion are matched by product instead.
+fn is_match(pending: &PendingPurchase, purchase: &Purchase) -> bool {
+    if pending.purchase_token.is_empty() || purchase.purchase_token.is_empty() {
+        pending.product_id == purchase.product_id
+    } else {
+        pending.purchase_token == purchase.purchase_token
+    }
+}
+
+/// Record pending purchases and resolve the pending purchases that the given ones complete
+/// or cancel.
+///
+/// `product_type` is `None` for purchases reported by a native event, which are recorded as
+/// `inapp`: Google Play only allows pending payments for one-time products.
+pub(crate) fn observe<R: Runtime>(
+    app: &AppHandle<R>,
+    product_type: Option<&str>,
+    purchases: &[Purchase],
+) {
+    let pending = app.state::<PendingPurchases>();
+    for purchase in purchases {
+        if purchase.purchase_state == PurchaseStateValue::Pending as i32 {
+            pending.record(purchase, product_type.unwrap_or("inapp"));
+            continue;
+        }
+
+        let Some(resolved) = pending.take(purchase) else {
+            continue;
+        };
+        let outcome = PendingPurchaseOutcome {
+            pending: resolved,
+            purchase: Some(purchase.clone()),
+        };
+        if purchase.purchase_state == PurchaseStateValue::Purchased as i32 {
+            events::emit(app, IapEvent::PendingPurchaseResolved(outcome));
+        } else {
+            events::emit(app, IapEvent::PendingPurchaseFailed(outcome));
+        }
+    }
+}
+
+/// Check the pending purchases of a type against a full `restore_purchases` listing.
+pub(crate) fn reconcile<R: Runtime>(
+    app: &AppHandle<R>,
+    product_type: &str,
+    purchases: &[Purchase],
+) {
+    reconcile_listing(app, product_type, purchases, RESTORE_LISTS_PENDING);
+}
+
+/// [`reconcile`] with whether the store lists pending purchases passed in.
+fn reconcile_listing<R: Runtime>(
+    app: &AppHandle<R>,
+    product_type: &str,
+    purchases: &[Purchase],
+    lists_pending: bool,
+) {
+    observe(app, Some(product_type), purchases);
+
+    if !lists_pending {
+        return;
+    }
+    for pending in app
+        .state::<PendingPurchases>()
+        .take_missing(product_type, purchases)
+    {
+        events::emit(
+            app,
+            IapEvent::PendingPurchaseFailed(PendingPurchaseOutcome {
+                pending,
+                purchase: None,
+            }),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tauri::test::{mock_app, MockRuntime};
+    use tauri::{App, Listener};
+
+    use super::*;
+    use crate::events::EventBuffer;
+    use crate::now_millis;
+
+    /// Outcome events as `{name}:{product_id}:{whether a purchase is attached}`.
+    type Emitted = Arc<Mutex<Vec<String>>>;
+
+    fn app(pending: PendingPurchases) -> (App<MockRuntime>, Emitted) {
+        let app = mock_app();
+        app.manage(pending);
+        app.manage(EventBuffer::default());
+
+        let emitted = Emitted::default();
+        for name in ["pendingPurchaseResolved", "pendingPurchaseFailed"] {
+            let emitted = emitted.clone();
+            app.listen_any(name, move |event| {
+                let outcome: PendingPurchaseOutcome =
+                    serde_json::from_str(event.payload()).unwrap();
+                emitted.lock().unwrap().push(format!(
+                    "{name}:{}:{}",
+                    outcome.pending.product_id,
+                    outcome.purchase.is_some()
+                ));
+            });
+        }
+        (app, emitted)
+    }
+
+    fn purchase(product_id: &str, token: &str, state: PurchaseStateValue) -> Purchase {
+        serde_json::from_value(serde_json::json!({
+            "orderId": null,
+            "packageName": "app",
+            "productId": product_id,
+            "purchaseTime": 1_000,
+            "purchaseToken": token,
+            "purchaseState": state as i32,
+            "isAutoRenewing": false,
+            "isAcknowledged": false,
+            "originalJson": "",
+            "signature": "",
+        }))
+        .unwrap()
+    }
+
+    fn pending_products(app: &App<MockRuntime>) -> Vec<String> {
+        app.state::<PendingPurchases>()
+            .list()
+            .into_iter()
+            .map(|pending| pending.product_id)
+            .collect()
+    }
+
+    #[test]
+    fn a_pending_purchase_resolves_when_it_is_purchased() {
+        let (app, emitted) = app(PendingPurchases::with_path(None));
+        reconcile(
+            app.handle(),
+            "inapp",
+            &[purchase("coins", "t1", PurchaseStateValue::Pending)],
+        );
+        assert_eq!(pending_products(&app), vec!["coins"]);
+        assert!(emitted.lock().unwrap().is_empty());
+
+        reconcile(
+            app.handle(),
+            "inapp",
+            &[purchase("coins", "t1", PurchaseStateValue::Purchased)],
+        );
+
+        assert!(pending_products(&app).is_empty());
+        assert_eq!(
+            *emitted.lock().unwrap(),
+            vec!["pendingPurchaseResolved:coins:true"]
+        );
+    }
+
+    #[test]
+    fn a_pending_purchase_fails_when_it_is_canceled() {
+        let (app, emitted) = app(PendingPurchases::with_path(None));
+        observe(
+            app.handle(),
+            None,
+            &[purchase("coins", "t1", PurchaseStateValue::Pending)],
+        );
+
+        reconcile(
+            app.handle(),
+            "inapp",
+            &[purchase("coins", "t1", PurchaseStateValue::Canceled)],
+        );
+
+        assert!(pending_products(&app).is_empty());
+        assert_eq!(
+            *emitted.lock().unwrap(),
+            vec!["pendingPurchaseFailed:coins:true"]
+        );
+    }
+
+    #[test]
+    fn a_pending_purchase_missing_from_the_listing_fails() {
+        let (app, emitted) = app(PendingPurchases::with_path(None));
+        observe(
+            app.handle(),
+            Some("inapp"),
+            &[
+                purchase("coins", "t1", PurchaseStateValue::Pending),
+                purchase("gems", "t2", PurchaseStateValue::Pending),
+            ],
+        );
+        observe(
+            app.handle(),
+            Some("subs"),
+            &[purchase("premium", "t3", PurchaseStateValue::Pending)],
+        );
+
+        // A store that does not list pending purchases cannot tell missing ones apart
+        reconcile_listing(app.handle(), "inapp", &[], false);
+        assert_eq!(pending_products(&app), vec!["coins", "gems", "premium"]);
+
+        reconcile_listing(
+            app.handle(),
+            "inapp",
+            &[purchase("gems", "t2", PurchaseStateValue::Pending)],
+            true,
+        );
+
+        // Pending purchases of the other type were not part of the listing
+        assert_eq!(pending_products(&app), vec!["gems", "premium"]);
+        assert_eq!(
+            *emitted.lock().unwrap(),
+            vec!["pendingPurchaseFailed:coins:false"]
+        );
+    }
+
+    #[test]
+    fn pending_purchases_survive_a_restart() {
+        let path = std::env::temp_dir().join(format!("iap-pending-{}.json", now_millis()));
+        let (first, _) = app(PendingPurchases::with_path(Some(path.clone())));
+        observe(
+            first.handle(),
+            Some("inapp"),
+            &[purchase("coins", "t1", PurchaseStateValue::Pending)],
+        );
+        // The same purchase reported again is recorded once
+        observe(
+            first.handle(),
+            None,
+            &[purchase("coins", "t1", PurchaseStateValue::Pending)],
+        );
+
+        let (restarted, emitted) = app(PendingPurchases::with_path(Some(path.clone())));
+        assert_eq!(pending_products(&restarted), vec!["coins"]);
+        assert_eq!(
+            restarted.state::<PendingPurchases>().list()[0].product_type,
+            "inapp"
+        );
+
+        reconcile(
+            restarted.handle(),
+            "inapp",
+            &[purchase("coins", "t1", PurchaseStateValue::Purchased)],
+        );
+        assert_eq!(
+            *emitted.lock().unwrap(),
+            vec!["pendingPurchaseResolved:coins:true"]
+        );
+        assert!(PendingPurchases::with_path(Some(path.clone()))
+            .list()
+            .is_empty());
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::HashSet;
+
+use crate::models::*;
+
+/// Record every requested id the store did not return, keeping any reason the backend already gave.
+///
+/// Ids that are simply missing are looked up again as the other product type through `query`,
+/// so a product requested with the wrong type is reported as such instead of silently dropped.
+pub(crate) fn report_invalid_products(
+    requested: &[String],
+    product_type: &str,
+    response: &mut GetProductsResponse,
+    query: impl FnOnce(Vec<String>, String) -> crate::Result<GetProductsResponse>,
+) {
+    let found: HashSet<&str> = response
+        .products
+        .iter()
+        .map(|product| product.product_id.as_str())
+        .collect();
+
+    for id in requested {
+        if !found.contains(id.as_str()) {
+            response
+                .failure_reasons
+                .entry(id.clone())
+                .or_insert(ProductFailureReason::NotFound);
+        }
+    }
+
+    let other_type = match product_type {
+        "subs" => Some("inapp"),
+        "inapp" => Some("subs"),
+        _ => None,
+    };
+    let not_found: Vec<String> = response
+        .failure_reasons
+        .iter()
+        .filter(|(_, reason)| **reason == ProductFailureReason::NotFound)
+        .map(|(id, _)| id.clone())
+        .collect();
+
+    if let Some(other_type) = other_type.filter(|_| !not_found.is_empty()) {
+        // A failed second lookup only costs us the more precise reason
+        if let Ok(other) = query(not_found, other_type.to_string()) {
+            for product in other.products {
+                response
+                    .failure_reasons
+                    .insert(product.product_id, ProductFailureReason::WrongType);
+            }
+        }
+    }
+
+    let mut seen = HashSet::new();
+    response.invalid_product_ids = requested
+        .iter()
+        .filter(|id| response.failure_reasons.contains_key(id.as_str()))
+        .filter(|id| seen.insert(id.as_str()))
+        .cloned()
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn product(product_id: &str, product_type: &str) -> Product {
+        Product {
+            product_id: product_id.to_string(),
+            title: String::new(),
+            description: String::new(),
+            product_type: product_type.to_string(),
+            formatted_price: None,
+            price_currency_code: None,
+            price_amount_micros: None,
+            subscription_offer_details: None,
+        }
+    }
+
+    fn response(products: Vec<Product>) -> GetProductsResponse {
+        GetProductsResponse {
+            products,
+            invalid_product_ids: Vec::new(),
+            failure_reasons: Default::default(),
+            storefront: None,
+        }
+    }
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn missing_ids_are_not_found() {
+        let mut found = response(vec![product("monthly", "subs")]);
+
+        report_invalid_products(&ids(&["monthly", "yearly"]), "subs", &mut found, |_, _| {
+            Ok(response(Vec::new()))
+        });
+
+        assert_eq!(found.invalid_product_ids, ids(&["yearly"]));
+        assert_eq!(
+            found.failure_reasons.get("yearly"),
+            Some(&ProductFailureReason::NotFound)
+        );
+        assert!(!found.failure_reasons.contains_key("monthly"));
+    }
+
+    #[test]
+    fn ids_of_the_other_type_are_wrong_type() {
+        let mut found = response(Vec::new());
+        let mut queried = None;
+
+        report_invalid_products(
+            &ids(&["coins", "typo"]),
+            "subs",
+            &mut found,
+            |ids, product_type| {
+                queried = Some((ids.clone(), product_type));
+                Ok(response(vec![product("coins", "inapp")]))
+            },
+        );
+
+        let (mut queried_ids, queried_type) = queried.unwrap();
+        queried_ids.sort();
+        assert_eq!(queried_ids, ids(&["coins", "typo"]));
+        assert_eq!(queried_type, "inapp");
+        assert_eq!(
+            found.failure_reasons.get("coins"),
+            Some(&ProductFailureReason::WrongType)
+        );
+        assert_eq!(
+            found.failure_reasons.get("typo"),
+            Some(&ProductFailureReason::NotFound)
+        );
+        assert_eq!(found.invalid_product_ids, ids(&["coins", "typo"]));
+    }
+
+    #[test]
+    fn reasons_from_the_backend_are_kept() {
+        let mut found = response(Vec::new());
+        found
+            .failure_reasons
+            .insert("intro".to_string(), ProductFailureReason::NotEligible);
+
+        report_invalid_products(&ids(&["intro"]), "subs", &mut found, |_, _| {
+            panic!("only not found ids are looked up again")
+        });
+
+        assert_eq!(
+            found.failure_reasons.get("intro"),
+            Some(&ProductFailureReason::NotEligible)
+        );
+        assert_eq!(found.invalid_product_ids, ids(&["intro"]));
+    }
+
+    #[test]
+    fn a_failed_second_lookup_keeps_not_found() {
+        let mut found = response(Vec::new());
+
+        report_invalid_products(&ids(&["coins"]), "inapp", &mut found, |_, _| {
+            Err(crate::Error::rejected("networkError", "offline"))
+        });
+
+        assert_eq!(
+            found.failure_reasons.get("coins"),
+            Some(&ProductFailureReason::NotFound)
+        );
+    }
+
+    #[test]
+    fn invalid_ids_follow_the_request_order_without_duplicates() {
+        let mut found = response(Vec::new());
+
+        report_invalid_products(&ids(&["b", "a", "b"]), "other", &mut found, |_, _| {
+            panic!("unknown product types have no other type")
+        });
+
+        assert_eq!(found.invalid_product_ids, ids(&["b", "a"]));
+    }
+}
@@ -0,0 +1,509 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tauri::{AppHandle, Manager, Runtime};
+
+use crate::events::{self, IapEvent};
+use crate::models::*;
+use crate::{now_millis, IapExt};
+
+const QUEUE_FILE: &str = "iap-pending-operations.json";
+
+/// Failed store calls after which an operation is dropped with a `pendingOperationFailed` event.
+/// With the backoff below this keeps retrying for a bit over a day.
+const MAX_ATTEMPTS: u32 = 30;
+const BASE_DELAY_MS: i64 = 5_000;
+const MAX_DELAY_MS: i64 = 60 * 60 * 1000;
+
+/// Error codes of store calls that fail the same way however often they are retried, e.g.
+/// for a token the store does not know.
+const PERMANENT_ERROR_CODES: &[&str] = &[
+    "invalidPurchaseToken",
+    "insufficientQuantity",
+    "consumeFailed",
+    "notSupported",
+    "notConfigured",
+    "invalidPurchaseState",
+];
+
+/// Only the store backends have anything to retry, the desktop stub always fails.
+const HAS_STORE: bool = cfg!(any(
+    mobile,
+    target_os = "windows",
+    all(target_os = "macos", feature = "unstable"),
+    all(target_os = "linux", feature = "steam"),
+    all(target_os = "linux", feature = "stripe"),
+    feature = "mock"
+));
+
+/// Acknowledge and consume calls that failed, kept in the app data directory until the store
+/// accepts them.
+///
+/// Google Play refunds purchases that are not acknowledged within three days, so a call that
+/// failed on a flaky network must not depend on the app remembering to retry it.
+pub(crate) struct RetryQueue {
+    path: Option<PathBuf>,
+    operations: Mutex<Vec<PendingOperation>>,
+    /// Held while operations are sent to the store, so two retries never race.
+    retrying: Mutex<()>,
+    worker_running: AtomicBool,
+}
+
+impl RetryQueue {
+    pub fn new<R: Runtime>(app: &AppHandle<R>) -> Self {
+        let path = app
+            .path()
+            .app_data_dir()
+            .ok()
+            .map(|dir| dir.join(QUEUE_FILE));
+        Self::with_path(path)
+    }
+
+    pub(crate) fn with_path(path: Option<PathBuf>) -> Self {
+        let operations = path
+            .as_ref()
+            .and_then(|path| std::fs::read(path).ok())
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+
+        Self {
+            path,
+            operations: Mutex::new(operations),
+            retrying: Mutex::new(()),
+            worker_running: AtomicBool::new(false),
+        }
+    }
+
+    /// The queued operations, oldest first.
+    pub fn list(&self) -> Vec<PendingOperation> {
+        self.operations
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    fn enqueue(&self, kind: PendingOperationKind, purchase_token: String, error: String) {
+        let mut operations = self.operations.lock().unwrap_or_else(|e| e.into_inner());
+        if operations
+            .iter()
+            .any(|op| op.kind == kind && op.purchase_token == purchase_token)
+        {
+            return;
+        }
+
+        let now = now_millis();
+        operations.push(PendingOperation {
+            kind,
+            purchase_token,
+            attempts: 1,
+            created_at: now,
+            next_attempt_at: now + backoff(1),
+            last_error: Some(error),
+        });
+        self.persist(&operations);
+    }
+
+    /// Remove an operation, returning it if it was queued.
+    fn remove(&self, kind: PendingOperationKind, purchase_token: &str) -> Option<PendingOperation> {
+        let mut operations = self.operations.lock().unwrap_or_else(|e| e.into_inner());
+        let index = operations
+            .iter()
+            .position(|op| op.kind == kind && op.purchase_token == purchase_token)?;
+        let operation = operations.remove(index);
+        self.persist(&operations);
+        Some(operation)
+    }
+
+    /// Push back a failed operation, or drop it once it ran out of attempts.
+    fn record_failure(
+        &self,
+        kind: PendingOperationKind,
+        purchase_token: &str,
+        error: String,
+    ) -> Option<PendingOperation> {
+        let mut operations = self.operations.lock().unwrap_or_else(|e| e.into_inner());
+        let index = operations
+            .iter()
+            .position(|op| op.kind == kind && op.purchase_token == purchase_token)?;
+
+        let operation = &mut operations[index];
+        operation.attempts += 1;
+        operation.next_attempt_at = now_millis() + backoff(operation.attempts);
+        operation.last_error = Some(error);
+
+        let dropped = (operation.attempts >= MAX_ATTEMPTS).then(|| operations.remove(index));
+        self.persist(&operations);
+        dropped
+    }
+
+    fn next_attempt_at(&self) -> Option<i64> {
+        self.operations
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|op| op.next_attempt_at)
+            .min()
+    }
+
+    fn persist(&self, operations: &[PendingOperation]) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        // Losing the file only loses the retries, the store calls themselves are not affected
+        if let Ok(json) = serde_json::to_vec(operations) {
+            if let Some(dir) = path.parent() {
+                let _ = std::fs::create_dir_all(dir);
+            }
+            let _ = std::fs::write(path, json);
+        }
+    }
+}
+
+/// Delay before the given attempt: doubling from five seconds, capped at an hour.
+fn backoff(attempts: u32) -> i64 {
+    let exponent = attempts.saturating_sub(1).min(20);
+    (BASE_DELAY_MS << exponent).min(MAX_DELAY_MS)
+}
+
+fn is_permanent(err: &crate::Error) -> bool {
+    err.code()
+        .is_some_and(|code| PERMANENT_ERROR_CODES.contains(&code))
+}
+
+fn call_store<R: Runtime>(
+    app: &AppHandle<R>,
+    kind: PendingOperationKind,
+    purchase_token: String,
+) -> crate::Result<bool> {
+    match kind {
+        PendingOperationKind::Acknowledge => app
+            .iap()
+            .acknowledge_purchase(purchase_token)
+            .map(|response| response.success),
+        PendingOperationKind::Consume => app
+            .iap()
+            .consume_purchase(purchase_token)
+            .map(|response| response.success),
+    }
+}
+
+/// Bookkeeping for a purchase the store acknowledged or consumed.
+fn completed<R: Runtime>(app: &AppHandle<R>, kind: PendingOperationKind, purchase_token: &str) {
+    crate::ledger::record_completed(app, kind, purchase_token);
+    crate::acknowledgement::forget(app, purchase_token);
+    if kind == PendingOperationKind::Consume {
+        // Consumed purchases leave the restore listing without being revoked
+        crate::revocation::forget(app, purchase_token);
+    }
+}
+
+/// Run the operation now, queueing it for retry if the store call fails for a reason a retry
+/// can fix.
+///
+/// Returns whether the store accepted it; `false` means it was queued.
+fn run<R: Runtime>(
+    app: &AppHandle<R>,
+    kind: PendingOperationKind,
+    purchase_token: String,
+) -> crate::Result<bool> {
+    let queue = app.state::<RetryQueue>();
+    match call_store(app, kind, purchase_token.clone()) {
+        Ok(true) => {
+            queue.remove(kind, &purchase_token);
+            completed(app, kind, &purchase_token);
+            Ok(true)
+        }
+        Ok(false) if HAS_STORE => {
+            queue.enqueue(
+                kind,
+                purchase_token,
+                "The store did not confirm the call".to_string(),
+            );
+            schedule(app);
+            Ok(false)
+        }
+        Err(err) if HAS_STORE && !is_permanent(&err) => {
+            queue.enqueue(kind, purchase_token, err.to_string());
+            schedule(app);
+            Ok(false)
+        }
+        result => result,
+    }
+}
+
+/// `acknowledge_purchase` that queues the acknowledgement for retry when it fails.
+pub(crate) fn acknowledge<R: Runtime>(
+    app: &AppHandle<R>,
+    purchase_token: String,
+) -> crate::Result<AcknowledgePurchaseResponse> {
+    let success = run(app, PendingOperationKind::Acknowledge, purchase_token)?;
+    Ok(AcknowledgePurchaseResponse {
+        success,
+        queued: !success,
+    })
+}
+
+/// `consume_purchase` that queues the consumption for retry when it fails.
+pub(crate) fn consume<R: Runtime>(
+    app: &AppHandle<R>,
+    purchase_token: String,
+) -> crate::Result<ConsumePurchaseResponse> {
+    let success = run(app, PendingOperationKind::Consume, purchase_token)?;
+    Ok(ConsumePurchaseResponse {
+        success,
+        queued: !success,
+    })
+}
+
+/// Retry queued operations, e.g. on `initialize` or when the connection comes back.
+///
+/// Operations still in their backoff are retried as well unless `due_only` is set.
+pub(crate) fn retry<R: Runtime>(app: &AppHandle<R>, due_only: bool) {
+    retry_operations(app, due_only);
+    schedule(app);
+}
+
+fn retry_operations<R: Runtime>(app: &AppHandle<R>, due_only: bool) {
+    retry_operations_with(app, due_only, |kind, token| call_store(app, kind, token));
+}
+
+/// [`retry_operations`] with the store call passed in.
+fn retry_operations_with<R: Runtime>(
+    app: &AppHandle<R>,
+    due_only: bool,
+    call_store: impl Fn(PendingOperationKind, String) -> crate::Result<bool>,
+) {
+    let queue = app.state::<RetryQueue>();
+    let _retrying = queue.retrying.lock().unwrap_or_else(|e| e.into_inner());
+
+    let now = now_millis();
+    let operations: Vec<PendingOperation> = queue
+        .list()
+        .into_iter()
+        .filter(|op| !due_only || op.next_attempt_at <= now)
+        .collect();
+
+    for operation in operations {
+        let kind = operation.kind;
+        let token = operation.purchase_token;
+        let error = match call_store(kind, token.clone()) {
+            Ok(true) => {
+                completed(app, kind, &token);
+                if let Some(mut operation) = queue.remove(kind, &token) {
+                    operation.last_error = None;
+                    events::emit(app, IapEvent::PendingOperationSucceeded(operation));
+                }
+                continue;
+            }
+            Ok(false) => "The store did not confirm the call".to_string(),
+            Err(err) if is_permanent(&err) => {
+                if let Some(mut operation) = queue.remove(kind, &token) {
+                    operation.last_error = Some(err.to_string());
+                    events::emit(app, IapEvent::PendingOperationFailed(operation));
+                }
+                continue;
+            }
+            Err(err) => err.to_string(),
+        };
+
+        if let Some(operation) = queue.record_failure(kind, &token, error) {
+            events::emit(app, IapEvent::PendingOperationFailed(operation));
+        }
+    }
+}
+
+/// Make sure a background worker retries the queue once the next backoff passes.
+fn schedule<R: Runtime>(app: &AppHandle<R>) {
+    let queue = app.state::<RetryQueue>();
+    if queue.next_attempt_at().is_none() || queue.worker_running.swap(true, Ordering::AcqRel) {
+        return;
+    }
+
+    let app = app.clone();
+    std::thread::spawn(move || loop {
+        let queue = app.state::<RetryQueue>();
+        let Some(next_attempt_at) = queue.next_attempt_at() else {
+            queue.worker_running.store(false, Ordering::Release);
+            // An operation queued while stopping would otherwise wait for the next trigger
+            if queue.next_attempt_at().is_some()
+                && !queue.worker_running.swap(true, Ordering::AcqRel)
+            {
+                continue;
+            }
+            return;
+        };
+
+        let wait = (next_attempt_at - now_millis()).max(0) as u64;
+        std::thread::sleep(Duration::from_millis(wait));
+        retry_operations(&app, true);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tauri::test::{mock_app, MockRuntime};
+    use tauri::{App, Listener};
+
+    use super::*;
+    use crate::acknowledgement::AcknowledgementTracker;
+    use crate::events::EventBuffer;
+    use crate::ledger::Ledger;
+    use crate::revocation::Entitlements;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("iap-queue-{name}-{}.json", now_millis()))
+    }
+
+    /// An app with a queue holding an acknowledgement and a consumption, and the events it
+    /// emits by name.
+    fn app() -> (App<MockRuntime>, Arc<Mutex<Vec<String>>>) {
+        let app = mock_app();
+        let queue = RetryQueue::with_path(None);
+        queue.enqueue(
+            PendingOperationKind::Acknowledge,
+            "a".into(),
+            "Offline".into(),
+        );
+        queue.enqueue(PendingOperationKind::Consume, "c".into(), "Offline".into());
+        app.manage(queue);
+        app.manage(EventBuffer::default());
+        app.manage(Ledger::with_path(None));
+        app.manage(AcknowledgementTracker::default());
+        app.manage(Entitlements::with_path(None));
+
+        let emitted = Arc::new(Mutex::new(Vec::new()));
+        for name in ["pendingOperationSucceeded", "pendingOperationFailed"] {
+            let emitted = emitted.clone();
+            app.listen_any(name, move |event| {
+                let operation: PendingOperation = serde_json::from_str(event.payload()).unwrap();
+                emitted
+                    .lock()
+                    .unwrap()
+                    .push(format!("{name}:{}", operation.purchase_token));
+            });
+        }
+        (app, emitted)
+    }
+
+    fn tokens(app: &App<MockRuntime>) -> Vec<String> {
+        app.state::<RetryQueue>()
+            .list()
+            .into_iter()
+            .map(|op| op.purchase_token)
+            .collect()
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_an_hour() {
+        assert_eq!(backoff(1), 5_000);
+        assert_eq!(backoff(2), 10_000);
+        assert_eq!(backoff(3), 20_000);
+        assert_eq!(backoff(10), 2_560_000);
+        assert_eq!(backoff(11), MAX_DELAY_MS);
+        assert_eq!(backoff(MAX_ATTEMPTS), MAX_DELAY_MS);
+    }
+
+    #[test]
+    fn only_transient_errors_are_retried() {
+        assert!(is_permanent(&crate::Error::rejected(
+            "invalidPurchaseToken",
+            "Not a token"
+        )));
+        assert!(!is_permanent(&crate::Error::rejected(
+            "networkError",
+            "Offline"
+        )));
+        assert!(!is_permanent(&std::io::Error::other("Offline").into()));
+    }
+
+    #[test]
+    fn the_queue_survives_a_restart() {
+        let path = temp_path("restart");
+        let queue = RetryQueue::with_path(Some(path.clone()));
+        queue.enqueue(
+            PendingOperationKind::Acknowledge,
+            "a".into(),
+            "Offline".into(),
+        );
+        queue.enqueue(PendingOperationKind::Consume, "c".into(), "Offline".into());
+        // A second failure of a queued operation does not queue it twice
+        queue.enqueue(PendingOperationKind::Consume, "c".into(), "Offline".into());
+        queue.remove(PendingOperationKind::Acknowledge, "a");
+
+        let restarted = RetryQueue::with_path(Some(path.clone()));
+        let operations = restarted.list();
+        assert_eq!(operations.len(), 1);
+        assert_eq!(operations[0].kind, PendingOperationKind::Consume);
+        assert_eq!(operations[0].purchase_token, "c");
+        assert_eq!(operations[0].attempts, 1);
+        assert_eq!(operations[0].last_error.as_deref(), Some("Offline"));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn accepted_retries_leave_the_queue() {
+        let (app, emitted) = app();
+
+        retry_operations_with(app.handle(), false, |_, _| Ok(true));
+
+        assert!(tokens(&app).is_empty());
+        assert_eq!(
+            *emitted.lock().unwrap(),
+            vec!["pendingOperationSucceeded:a", "pendingOperationSucceeded:c"]
+        );
+    }
+
+    #[test]
+    fn permanent_failures_are_dropped() {
+        let (app, emitted) = app();
+
+        retry_operations_with(app.handle(), false, |kind, _| match kind {
+            PendingOperationKind::Acknowledge => Err(crate::Error::rejected(
+                "invalidPurchaseToken",
+                "Not a token",
+            )),
+            PendingOperationKind::Consume => Ok(true),
+        });
+
+        assert!(tokens(&app).is_empty());
+        assert_eq!(
+            *emitted.lock().unwrap(),
+            vec!["pendingOperationFailed:a", "pendingOperationSucceeded:c"]
+        );
+    }
+
+    #[test]
+    fn transient_failures_stay_queued_until_they_run_out_of_attempts() {
+        let (app, emitted) = app();
+        let offline = |_, _| Err(crate::Error::rejected("networkError", "Offline"));
+
+        retry_operations_with(app.handle(), false, offline);
+
+        let operations = app.state::<RetryQueue>().list();
+        assert_eq!(operations.len(), 2);
+        assert!(operations.iter().all(|op| op.attempts == 2));
+        assert!(operations
+            .iter()
+            .all(|op| op.next_attempt_at > now_millis() + backoff(1)));
+        assert!(emitted.lock().unwrap().is_empty());
+
+        // Due only: nothing is due during the backoff
+        retry_operations_with(app.handle(), true, |_, _| {
+            panic!("retried before it was due")
+        });
+
+        for _ in 2..MAX_ATTEMPTS {
+            retry_operations_with(app.handle(), false, offline);
+        }
+        assert!(tokens(&app).is_empty());
+        assert_eq!(
+            *emitted.lock().unwrap(),
+            vec!["pendingOperationFailed:a", "pendingOperationFailed:c"]
+        );
+    }
+}
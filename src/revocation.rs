@@ -0,0 +1,318 @@
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Manager, Runtime};
+
+use crate::events::{self, IapEvent};
+use crate::models::*;
+use crate::now_millis;
+
+const ENTITLEMENTS_FILE: &str = "iap-entitlements.json";
+
+/// Whether `restore_purchases` lists every entitlement, so that a missing one was revoked.
+/// The StoreKit 1 restore on iOS does not; there only revocation dates count.
+const RESTORE_IS_COMPLETE: bool = !cfg!(target_os = "ios");
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Entitlement {
+    product_id: String,
+    purchase_token: String,
+    /// Entitlements recorded before this was kept count as renewing.
+    #[serde(default = "renewing")]
+    is_auto_renewing: bool,
+}
+
+fn renewing() -> bool {
+    true
+}
+
+/// Entitlements listed by the last `restore_purchases` of each product type, kept in the app
+/// data directory so revocations are noticed across launches.
+pub(crate) struct Entitlements {
+    path: Option<PathBuf>,
+    by_type: Mutex<HashMap<String, Vec<Entitlement>>>,
+}
+
+impl Entitlements {
+    pub fn new<R: Runtime>(app: &AppHandle<R>) -> Self {
+        let path = app
+            .path()
+            .app_data_dir()
+            .ok()
+            .map(|dir| dir.join(ENTITLEMENTS_FILE));
+        Self::with_path(path)
+    }
+
+    pub(crate) fn with_path(path: Option<PathBuf>) -> Self {
+        let by_type = path
+            .as_ref()
+            .and_then(|path| std::fs::read(path).ok())
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+
+        Self {
+            path,
+            by_type: Mutex::new(by_type),
+        }
+    }
+
+    /// Replace the entitlements of a type, returning the previous ones. `None` if this type
+    /// was never restored before.
+    fn replace(&self, product_type: &str, current: Vec<Entitlement>) -> Option<Vec<Entitlement>> {
+        let mut by_type = self.by_type.lock().unwrap_or_else(|e| e.into_inner());
+        let previous = by_type.insert(product_type.to_string(), current);
+        self.persist(&by_type);
+        previous
+    }
+
+    fn remove(&self, purchase_token: &str) {
+        let mut by_type = self.by_type.lock().unwrap_or_else(|e| e.into_inner());
+        let mut removed = false;
+        for entitlements in by_type.values_mut() {
+            let before = entitlements.len();
+            entitlements.retain(|entitlement| entitlement.purchase_token != purchase_token);
+            removed |= entitlements.len() != before;
+        }
+        if removed {
+            self.persist(&by_type);
+        }
+    }
+
+    fn persist(&self, by_type: &HashMap<String, Vec<Entitlement>>) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        if let Ok(json) = serde_json::to_vec(by_type) {
+            if let Some(dir) = path.parent() {
+                let _ = std::fs::create_dir_all(dir);
+            }
+            let _ = std::fs::write(path, json);
+        }
+    }
+}
+
+fn is_owned(purchase: &Purchase) -> bool {
+    purchase.purchase_state == PurchaseStateValue::Purchased as i32
+        && purchase.revocation_date.is_none()
+}
+
+/// Compare a full `restore_purchases` listing with the previous one and emit a
+/// `purchaseRevoked` event for every entitlement that is gone or carries a revocation date.
+///
+/// Entitlements are matched by purchase token, then by product, since a renewed subscription
+/// gets a new token on some stores. A missing entitlement is how Google Play reports a refund.
+/// Purchases consumed through the plugin were dropped by [`forget`] and are not missed, and a
+/// subscription that was no longer auto-renewing at the previous restore is assumed to have
+/// expired. On iOS only revocation dates count, see [`RESTORE_IS_COMPLETE`].
+///
+/// The `on_entitlement_changed` hook is called when the set of owned products differs from
+/// the previous restore, or on the first restore of the type.
+pub(crate) fn check<R: Runtime>(app: &AppHandle<R>, product_type: &str, purchases: &[Purchase]) {
+    let owned: Vec<Purchase> = purchases
+        .iter()
+        .filter(|purchase| is_owned(purchase))
+        .cloned()
+        .collect();
+    let owned_ids: BTreeSet<&str> = owned
+        .iter()
+        .map(|purchase| purchase.product_id.as_str())
+        .collect();
+    let current = owned
+        .iter()
+        .map(|purchase| Entitlement {
+            product_id: purchase.product_id.clone(),
+            purchase_token: purchase.purchase_token.clone(),
+            is_auto_renewing: purchase.is_auto_renewing,
+        })
+        .collect();
+
+    let previous = app.state::<Entitlements>().replace(product_type, current);
+    let changed = previous.as_ref().map_or(true, |previous| {
+        previous
+            .iter()
+            .map(|entitlement| entitlement.product_id.as_str())
+            .collect::<BTreeSet<_>>()
+            != owned_ids
+    });
+    if changed {
+        crate::hooks::entitlement_changed(app, product_type, &owned);
+    }
+
+    for entitlement in previous.unwrap_or_default() {
+        let listed = purchases
+            .iter()
+            .find(|purchase| purchase.purchase_token == entitlement.purchase_token)
+            .or_else(|| {
+                purchases
+                    .iter()
+                    .find(|purchase| purchase.product_id == entitlement.product_id)
+            });
+
+        let (reason, revoked_at) = match listed {
+            Some(purchase)
+                if purchase.revocation_date.is_some() || purchase.revocation_reason.is_some() =>
+            {
+                (
+                    purchase
+                        .revocation_reason
+                        .unwrap_or(RevocationReason::Other),
+                    purchase.revocation_date.unwrap_or_else(now_millis),
+                )
+            }
+            Some(_) => continue,
+            None if !RESTORE_IS_COMPLETE => continue,
+            None if product_type == "subs" && !entitlement.is_auto_renewing => continue,
+            None => (RevocationReason::NoLongerOwned, now_millis()),
+        };
+
+        let revocation = PurchaseRevocation {
+            product_id: entitlement.product_id,
+            product_type: product_type.to_string(),
+            purchase_token: entitlement.purchase_token,
+            reason,
+            revoked_at,
+        };
+        crate::ledger::record_revocation(app, &revocation);
+        crate::hooks::refund(app, &revocation);
+        events::emit(app, IapEvent::PurchaseRevoked(revocation));
+    }
+}
+
+/// Stop tracking a consumed purchase.
+pub(crate) fn forget<R: Runtime>(app: &AppHandle<R>, purchase_token: &str) {
+    app.state::<Entitlements>().remove(purchase_token);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tauri::test::{mock_app, MockRuntime};
+    use tauri::{App, Listener};
+
+    use super::*;
+    use crate::events::EventBuffer;
+    use crate::hooks::Hooks;
+    use crate::ledger::Ledger;
+
+    fn app() -> (App<MockRuntime>, Arc<Mutex<Vec<PurchaseRevocation>>>) {
+        let app = mock_app();
+        app.manage(Entitlements::with_path(None));
+        app.manage(Ledger::with_path(None));
+        app.manage(Hooks::new(None));
+        app.manage(EventBuffer::default());
+        let revoked = Arc::new(Mutex::new(Vec::new()));
+        let listener = revoked.clone();
+        app.listen_any("purchaseRevoked", move |event| {
+            listener
+                .lock()
+                .unwrap()
+                .push(serde_json::from_str(event.payload()).unwrap());
+        });
+        (app, revoked)
+    }
+
+    fn purchase(product_id: &str, token: &str) -> Purchase {
+        serde_json::from_value(serde_json::json!({
+            "orderId": "order",
+            "packageName": "app",
+            "productId": product_id,
+            "purchaseTime": 0,
+            "purchaseToken": token,
+            "purchaseState": 0,
+            "isAutoRenewing": true,
+            "isAcknowledged": true,
+            "originalJson": "",
+            "signature": "",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn a_listed_revocation_is_reported() {
+        let (app, revoked) = app();
+        check(app.handle(), "inapp", &[purchase("premium", "token")]);
+
+        let mut refunded = purchase("premium", "token");
+        refunded.purchase_state = PurchaseStateValue::Canceled as i32;
+        refunded.revocation_date = Some(1_000);
+        refunded.revocation_reason = Some(RevocationReason::DeveloperIssue);
+        check(app.handle(), "inapp", &[refunded]);
+
+        let revoked = revoked.lock().unwrap();
+        assert_eq!(revoked.len(), 1);
+        assert_eq!(revoked[0].purchase_token, "token");
+        assert_eq!(revoked[0].reason, RevocationReason::DeveloperIssue);
+        assert_eq!(revoked[0].revoked_at, 1_000);
+    }
+
+    #[test]
+    fn a_non_consumable_missing_from_a_restore_is_reported() {
+        let (app, revoked) = app();
+        check(
+            app.handle(),
+            "inapp",
+            &[purchase("lifetime", "token"), purchase("coins", "other")],
+        );
+        // Google Play drops refunded purchases from the restore
+        check(app.handle(), "inapp", &[purchase("coins", "other")]);
+
+        let revoked = revoked.lock().unwrap();
+        assert_eq!(revoked.len(), 1);
+        assert_eq!(revoked[0].product_id, "lifetime");
+        assert_eq!(revoked[0].product_type, "inapp");
+        assert_eq!(revoked[0].purchase_token, "token");
+        assert_eq!(revoked[0].reason, RevocationReason::NoLongerOwned);
+    }
+
+    #[test]
+    fn a_consumed_purchase_is_not_reported() {
+        let (app, revoked) = app();
+        check(app.handle(), "inapp", &[purchase("coins", "consumable")]);
+        forget(app.handle(), "consumable");
+        check(app.handle(), "inapp", &[]);
+
+        assert!(revoked.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn an_expired_subscription_is_not_reported() {
+        let (app, revoked) = app();
+        let mut canceled = purchase("monthly", "canceled");
+        canceled.is_auto_renewing = false;
+        check(
+            app.handle(),
+            "subs",
+            &[canceled, purchase("yearly", "renewing")],
+        );
+        check(app.handle(), "subs", &[]);
+
+        // Only the subscription that was still renewing was revoked
+        let revoked = revoked.lock().unwrap();
+        assert_eq!(revoked.len(), 1);
+        assert_eq!(revoked[0].product_id, "yearly");
+    }
+
+    #[test]
+    fn a_renewed_subscription_is_not_reported() {
+        let (app, revoked) = app();
+        check(app.handle(), "subs", &[purchase("monthly", "first")]);
+        check(app.handle(), "subs", &[purchase("monthly", "renewed")]);
+
+        assert!(revoked.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_revocation_on_the_first_restore_is_not_reported() {
+        let (app, revoked) = app();
+        let mut refunded = purchase("premium", "token");
+        refunded.revocation_date = Some(1_000);
+        check(app.handle(), "inapp", &[refunded]);
+
+        assert!(revoked.lock().unwrap().is_empty());
+    }
+}
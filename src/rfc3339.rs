@@ -0,0 +1,24 @@
+//! RFC 3339 UTC timestamps, the format of the Google Play Developer and Steam Web APIs.
+
+/// Milliseconds since the Unix epoch of a timestamp such as `2024-05-01T12:30:00.250Z`.
+pub(crate) fn parse(timestamp: &str) -> Option<i64> {
+    let timestamp = timestamp.strip_suffix('Z')?;
+    let (date, time) = timestamp.split_once('T')?;
+
+    let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let mut time = time.splitn(3, ':').map(str::parse::<i64>);
+    let (hour, minute, second) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+    let millis = format!("{fraction:0<3}").get(..3)?.parse::<i64>().ok()?;
+
+    // Days since the epoch of a proleptic Gregorian date (Howard Hinnant's days_from_civil)
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    Some(((days * 24 + hour) * 60 + minute) * 60_000 + second * 1000 + millis)
+}
@@ -0,0 +1,581 @@
+//! Verification server for the app's backend, the counterpart of [`RemoteValidator`].
+//!
+//! [`Server`] builds an axum [`Router`] with two routes:
+//! - `POST /purchases` takes a [`ValidationRequest`], verifies the purchase with the store of
+//!   its platform, saves it and answers with a [`ValidationVerdict`]
+//! - `GET /entitlements/:account_id` answers with the purchases the account owns now
+//! - `POST /entitlements/:account_id/token` issues an entitlement token for the account, when
+//!   the server has an [`EntitlementTokenIssuer`]
+//!
+//! Authentication is left to the surrounding app, e.g. as a layer on the router. The layer
+//! must make sure the `accountId` of a request and the `account_id` in the path are the
+//! signed-in user's, since tokens are only issued for those.
+//!
+//! [`RemoteValidator`]: crate::RemoteValidator
+//! [`EntitlementTokenIssuer`]: crate::EntitlementTokenIssuer
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use crate::models::*;
+use crate::{now_millis, EntitlementTokenIssuer};
+
+mod apple;
+mod google;
+mod store;
+
+pub use apple::{AppStoreEnvironment, AppStoreVerifier};
+pub use google::GooglePlayVerifier;
+pub use store::{MemoryStore, PurchaseStore};
+
+/// A purchase as the store reported it to the server.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifiedPurchase {
+    pub platform: String,
+    pub product_id: String,
+    pub purchase_token: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order_id: Option<String>,
+    /// The account that claimed the purchase.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account_id: Option<String>,
+    /// A [`PurchaseStateValue`].
+    pub purchase_state: i32,
+    pub purchased_at: i64,
+    /// When a subscription ends.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revoked_at: Option<i64>,
+    pub verified_at: i64,
+}
+
+impl VerifiedPurchase {
+    /// Why the purchase does not grant its product at `now`, or `None` if it does.
+    pub fn inactive_reason(&self, now: i64) -> Option<&'static str> {
+        if self.revoked_at.is_some() {
+            Some("revoked")
+        } else if self.purchase_state == PurchaseStateValue::Pending as i32 {
+            Some("pending")
+        } else if self.purchase_state != PurchaseStateValue::Purchased as i32 {
+            Some("canceled")
+        } else if self.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            Some("expired")
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetEntitlementsResponse {
+    pub entitlements: Vec<VerifiedPurchase>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EntitlementTokenResponse {
+    pub token: String,
+    pub expires_at: i64,
+}
+
+/// Checks purchases with a store.
+///
+/// Calls block, the router runs them on a blocking thread. Errors with the code
+/// `purchaseNotFound` make the purchase invalid, `storeUnavailable` is answered with `503` so
+/// the client retries, and any other error with `502`.
+pub trait PurchaseVerifier: Send + Sync + 'static {
+    fn verify(&self, request: &ValidationRequest) -> crate::Result<VerifiedPurchase>;
+}
+
+/// Verifiers by platform and the store purchases are saved to.
+pub struct Server {
+    verifiers: HashMap<String, Arc<dyn PurchaseVerifier>>,
+    store: Arc<dyn PurchaseStore>,
+    token_issuer: Option<EntitlementTokenIssuer>,
+}
+
+impl Server {
+    pub fn new(store: impl PurchaseStore) -> Self {
+        Self {
+            verifiers: HashMap::new(),
+            store: Arc::new(store),
+            token_issuer: None,
+        }
+    }
+
+    /// Verify purchases made on the given platforms, e.g. `["ios", "macos"]`, with `verifier`.
+    pub fn verifier(mut self, platforms: &[&str], verifier: impl PurchaseVerifier) -> Self {
+        let verifier: Arc<dyn PurchaseVerifier> = Arc::new(verifier);
+        for platform in platforms {
+            self.verifiers
+                .insert(platform.to_string(), Arc::clone(&verifier));
+        }
+        self
+    }
+
+    /// Issue entitlement tokens, also with every verdict for a request with an `accountId`.
+    pub fn entitlement_tokens(mut self, issuer: EntitlementTokenIssuer) -> Self {
+        self.token_issuer = Some(issuer);
+        self
+    }
+
+    pub fn router(self) -> Router {
+        Router::new()
+            .route("/purchases", post(submit_purchase))
+            .route("/entitlements/:account_id", get(get_entitlements))
+            .route("/entitlements/:account_id/token", post(issue_token))
+            .with_state(Arc::new(self))
+    }
+
+    /// Verify and save a purchase. Blocks.
+    pub fn validate(&self, request: &ValidationRequest) -> crate::Result<ValidationVerdict> {
+        let Some(verifier) = self.verifiers.get(&request.platform) else {
+            return Ok(invalid(format!(
+                "Purchases from {} cannot be verified",
+                request.platform
+            )));
+        };
+
+        let mut purchase = match verifier.verify(request) {
+            Ok(purchase) => purchase,
+            Err(err) if err.code() == Some("purchaseNotFound") => {
+                return Ok(invalid("The store does not know this purchase"));
+            }
+            Err(err) => return Err(err),
+        };
+        if purchase.product_id != request.product_id {
+            return Ok(invalid(format!(
+                "The purchase is for {}, not {}",
+                purchase.product_id, request.product_id
+            )));
+        }
+
+        let claimed_by = self
+            .store
+            .find(&purchase.purchase_token)?
+            .and_then(|saved| saved.account_id);
+        match (&claimed_by, &request.account_id) {
+            (Some(owner), Some(account_id)) if owner != account_id => {
+                return Ok(invalid("The purchase belongs to another account"));
+            }
+            _ => purchase.account_id = request.account_id.clone().or(claimed_by),
+        }
+        self.store.save(&purchase)?;
+
+        // Only for the account of the caller, never for the one that claimed the purchase before
+        let entitlement_token = match (&self.token_issuer, &request.account_id) {
+            (Some(_), Some(account_id)) => Some(self.entitlement_token(account_id)?.token),
+            _ => None,
+        };
+        let reason = purchase.inactive_reason(now_millis());
+        Ok(ValidationVerdict {
+            valid: reason.is_none(),
+            reason: reason.map(str::to_string),
+            expires_at: purchase.expires_at,
+            entitlement_token,
+        })
+    }
+
+    /// Purchases of the account that grant their product now. Blocks.
+    pub fn entitlements(&self, account_id: &str) -> crate::Result<Vec<VerifiedPurchase>> {
+        let now = now_millis();
+        let mut purchases = self.store.list(account_id)?;
+        purchases.retain(|purchase| purchase.inactive_reason(now).is_none());
+        Ok(purchases)
+    }
+
+    /// A signed token with the product ids the account owns now. It expires no later than
+    /// the first of its subscriptions. Fails with `notConfigured` without an issuer. Blocks.
+    pub fn entitlement_token(&self, account_id: &str) -> crate::Result<EntitlementTokenResponse> {
+        let issuer = self.token_issuer.as_ref().ok_or_else(|| {
+            crate::Error::rejected("notConfigured", "No entitlement token issuer is configured")
+        })?;
+
+        let entitlements = self.entitlements(account_id)?;
+        let not_after = entitlements
+            .iter()
+            .filter_map(|purchase| purchase.expires_at)
+            .min();
+        let mut product_ids: Vec<String> = entitlements
+            .into_iter()
+            .map(|purchase| purchase.product_id)
+            .collect();
+        product_ids.sort();
+        product_ids.dedup();
+
+        let (token, claims) = issuer.issue(account_id, product_ids, not_after)?;
+        Ok(EntitlementTokenResponse {
+            token,
+            expires_at: claims.expires_at * 1000,
+        })
+    }
+}
+
+fn invalid(reason: impl Into<String>) -> ValidationVerdict {
+    ValidationVerdict {
+        valid: false,
+        reason: Some(reason.into()),
+        expires_at: None,
+        entitlement_token: None,
+    }
+}
+
+type HandlerResult<T> = std::result::Result<Json<T>, (StatusCode, String)>;
+
+async fn submit_purchase(
+    State(server): State<Arc<Server>>,
+    Json(request): Json<ValidationRequest>,
+) -> HandlerResult<ValidationVerdict> {
+    blocking(move || server.validate(&request)).await
+}
+
+async fn get_entitlements(
+    State(server): State<Arc<Server>>,
+    Path(account_id): Path<String>,
+) -> HandlerResult<GetEntitlementsResponse> {
+    blocking(move || {
+        server
+            .entitlements(&account_id)
+            .map(|entitlements| GetEntitlementsResponse { entitlements })
+    })
+    .await
+}
+
+async fn issue_token(
+    State(server): State<Arc<Server>>,
+    Path(account_id): Path<String>,
+) -> HandlerResult<EntitlementTokenResponse> {
+    blocking(move || server.entitlement_token(&account_id)).await
+}
+
+async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> crate::Result<T> + Send + 'static,
+) -> HandlerResult<T> {
+    let result = tokio::task::spawn_blocking(work)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+
+    result.map(Json).map_err(|err| {
+        let status = match err.code() {
+            Some("storeUnavailable") => StatusCode::SERVICE_UNAVAILABLE,
+            Some("storeError") => StatusCode::BAD_GATEWAY,
+            Some("notConfigured") => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, err.to_string())
+    })
+}
+
+/// The payload of a JWS, without checking its signature.
+fn jws_payload<T: serde::de::DeserializeOwned>(jws: &str) -> crate::Result<T> {
+    crate::jwt::Jws::parse(jws)
+        .and_then(|jws| jws.payload())
+        .ok_or_else(|| crate::Error::rejected("storeError", "The store sent a malformed JWS"))
+}
+
+/// The DER bytes of a PEM private key.
+fn pem_to_der(pem: &str) -> crate::Result<Vec<u8>> {
+    let body: String = pem
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .map(str::trim)
+        .collect();
+    base64::engine::general_purpose::STANDARD
+        .decode(body)
+        .map_err(|err| crate::Error::rejected("invalidKey", format!("Invalid PEM key: {err}")))
+}
+
+/// The error of a store API call. `404`, `410` and `400` mean the store does not know the
+/// purchase.
+fn store_error(err: ureq::Error) -> crate::Error {
+    match err {
+        ureq::Error::Status(400 | 404 | 410, _) => {
+            crate::Error::rejected("purchaseNotFound", "The store does not know this purchase")
+        }
+        ureq::Error::Status(status, _) if status == 429 || status >= 500 => {
+            crate::Error::rejected("storeUnavailable", format!("The store answered {status}"))
+        }
+        ureq::Error::Status(status, response) => crate::Error::rejected(
+            "storeError",
+            format!(
+                "The store answered {status}: {}",
+                response.into_string().unwrap_or_default()
+            ),
+        ),
+        ureq::Error::Transport(err) => crate::Error::rejected("storeUnavailable", err.to_string()),
+    }
+}
+
+fn response_error(err: std::io::Error) -> crate::Error {
+    crate::Error::rejected("storeError", format!("Unexpected store response: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::stand_in::StandIn;
+    use crate::{generate_signing_key, path_segment, EntitlementTokenConfig};
+
+    /// Knows every purchase except the tokens `unknown` and `down`.
+    struct StubVerifier;
+
+    impl PurchaseVerifier for StubVerifier {
+        fn verify(&self, request: &ValidationRequest) -> crate::Result<VerifiedPurchase> {
+            match request.purchase_token.as_str() {
+                "unknown" => Err(crate::Error::rejected("purchaseNotFound", "unknown")),
+                "down" => Err(crate::Error::rejected("storeUnavailable", "down")),
+                token => Ok(VerifiedPurchase {
+                    platform: request.platform.clone(),
+                    product_id: if token == "other" {
+                        "other".into()
+                    } else {
+                        request.product_id.clone()
+                    },
+                    purchase_token: token.into(),
+                    order_id: request.order_id.clone(),
+                    account_id: None,
+                    purchase_state: if token == "pending" {
+                        PurchaseStateValue::Pending as i32
+                    } else {
+                        PurchaseStateValue::Purchased as i32
+                    },
+                    purchased_at: now_millis(),
+                    expires_at: None,
+                    revoked_at: None,
+                    verified_at: now_millis(),
+                }),
+            }
+        }
+    }
+
+    fn router() -> Router {
+        let (signing_key, _) = generate_signing_key("k1").unwrap();
+        let issuer = EntitlementTokenIssuer::from_config(&EntitlementTokenConfig {
+            signing_key: Some(signing_key),
+            keys: Vec::new(),
+            issuer: None,
+            ttl_secs: 60,
+        })
+        .unwrap();
+        Server::new(MemoryStore::new())
+            .verifier(&["android"], StubVerifier)
+            .entitlement_tokens(issuer)
+            .router()
+    }
+
+    fn purchase(token: &str, account_id: Option<&str>) -> serde_json::Value {
+        serde_json::json!({
+            "purchaseToken": token,
+            "productId": "premium",
+            "packageName": "com.example.app",
+            "originalJson": "{}",
+            "signature": "",
+            "platform": "android",
+            "accountId": account_id,
+        })
+    }
+
+    /// Status and JSON body of the router's answer.
+    fn call(
+        router: &Router,
+        method: &str,
+        uri: &str,
+        body: Option<serde_json::Value>,
+    ) -> (StatusCode, serde_json::Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Content-Type", "application/json")
+            .body(body.map_or_else(Body::empty, |body| Body::from(body.to_string())))
+            .unwrap();
+
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let response = router.clone().oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                (
+                    status,
+                    serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null),
+                )
+            })
+    }
+
+    #[test]
+    fn verifies_and_saves_purchases() {
+        let router = router();
+
+        let (status, verdict) = call(
+            &router,
+            "POST",
+            "/purchases",
+            Some(purchase("token", Some("user-1"))),
+        );
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(verdict["valid"], true);
+        assert!(verdict["entitlementToken"].is_string());
+
+        let (_, body) = call(&router, "GET", "/entitlements/user-1", None);
+        assert_eq!(body["entitlements"][0]["purchaseToken"], "token");
+        assert_eq!(body["entitlements"][0]["accountId"], "user-1");
+    }
+
+    #[test]
+    fn rejects_what_the_store_does_not_confirm() {
+        let router = router();
+        let mut unknown_platform = purchase("token", None);
+        unknown_platform["platform"] = "windows".into();
+
+        for request in [
+            unknown_platform,
+            purchase("unknown", None),
+            purchase("other", None),
+        ] {
+            let (status, verdict) = call(&router, "POST", "/purchases", Some(request));
+
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(verdict["valid"], false);
+            assert!(verdict["reason"].is_string());
+        }
+    }
+
+    #[test]
+    fn inactive_purchases_are_saved_but_not_entitled() {
+        let router = router();
+
+        let (_, verdict) = call(
+            &router,
+            "POST",
+            "/purchases",
+            Some(purchase("pending", Some("user-1"))),
+        );
+
+        assert_eq!(verdict["valid"], false);
+        assert_eq!(verdict["reason"], "pending");
+        let (_, body) = call(&router, "GET", "/entitlements/user-1", None);
+        assert_eq!(body["entitlements"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn an_unavailable_store_is_retried() {
+        let (status, _) = call(
+            &router(),
+            "POST",
+            "/purchases",
+            Some(purchase("down", None)),
+        );
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn purchases_of_another_account_are_invalid() {
+        let router = router();
+        call(
+            &router,
+            "POST",
+            "/purchases",
+            Some(purchase("token", Some("user-1"))),
+        );
+
+        let (_, verdict) = call(
+            &router,
+            "POST",
+            "/purchases",
+            Some(purchase("token", Some("user-2"))),
+        );
+
+        assert_eq!(verdict["valid"], false);
+        assert!(verdict.get("entitlementToken").is_none());
+        let (_, body) = call(&router, "GET", "/entitlements/user-2", None);
+        assert_eq!(body["entitlements"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn no_token_is_issued_for_the_account_that_claimed_the_purchase() {
+        let router = router();
+        call(
+            &router,
+            "POST",
+            "/purchases",
+            Some(purchase("token", Some("user-1"))),
+        );
+
+        let (_, verdict) = call(&router, "POST", "/purchases", Some(purchase("token", None)));
+
+        assert_eq!(verdict["valid"], true);
+        assert!(verdict.get("entitlementToken").is_none());
+        let (_, body) = call(&router, "GET", "/entitlements/user-1", None);
+        assert_eq!(body["entitlements"][0]["accountId"], "user-1");
+    }
+
+    #[test]
+    fn issues_tokens_for_an_account() {
+        let (status, body) = call(&router(), "POST", "/entitlements/user-1/token", None);
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["token"].is_string());
+
+        let without_issuer = Server::new(MemoryStore::new()).router();
+        let (status, _) = call(&without_issuer, "POST", "/entitlements/user-1/token", None);
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn store_answers_map_to_error_codes() {
+        let store = StandIn::start(|request| {
+            let status = request.path().trim_start_matches('/').parse().unwrap();
+            (status, r#"{"error":"details"}"#.to_string())
+        });
+        let code = |status: u16| {
+            let err = ureq::get(&format!("{}/{status}", store.url))
+                .call()
+                .unwrap_err();
+            store_error(err).code().map(str::to_string)
+        };
+
+        for status in [400, 404, 410] {
+            assert_eq!(
+                code(status).as_deref(),
+                Some("purchaseNotFound"),
+                "{status}"
+            );
+        }
+        for status in [429, 500, 503] {
+            assert_eq!(
+                code(status).as_deref(),
+                Some("storeUnavailable"),
+                "{status}"
+            );
+        }
+        assert_eq!(code(401).as_deref(), Some("storeError"));
+
+        let unreachable = ureq::get("http://127.0.0.1:1/").call().unwrap_err();
+        assert_eq!(store_error(unreachable).code(), Some("storeUnavailable"));
+    }
+
+    #[test]
+    fn escapes_path_segments() {
+        assert_eq!(path_segment("com.example_app-1~"), "com.example_app-1~");
+        assert_eq!(path_segment("a/../b?c=d#e f"), "a%2F..%2Fb%3Fc%3Dd%23e%20f");
+    }
+}
@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use super::VerifiedPurchase;
+
+/// Where the server keeps verified purchases, e.g. the backend's database.
+///
+/// Calls block, the router runs them on a blocking thread.
+pub trait PurchaseStore: Send + Sync + 'static {
+    /// Insert a purchase or replace the one with the same purchase token.
+    fn save(&self, purchase: &VerifiedPurchase) -> crate::Result<()>;
+
+    fn find(&self, purchase_token: &str) -> crate::Result<Option<VerifiedPurchase>>;
+
+    /// Every purchase the account claimed, including expired and revoked ones.
+    fn list(&self, account_id: &str) -> crate::Result<Vec<VerifiedPurchase>>;
+}
+
+/// A [`PurchaseStore`] that keeps purchases in memory, for tests and prototypes.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    purchases: Mutex<HashMap<String, VerifiedPurchase>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl PurchaseStore for MemoryStore {
+    fn save(&self, purchase: &VerifiedPurchase) -> crate::Result<()> {
+        self.purchases
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(purchase.purchase_token.clone(), purchase.clone());
+        Ok(())
+    }
+
+    fn find(&self, purchase_token: &str) -> crate::Result<Option<VerifiedPurchase>> {
+        Ok(self
+            .purchases
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(purchase_token)
+            .cloned())
+    }
+
+    fn list(&self, account_id: &str) -> crate::Result<Vec<VerifiedPurchase>> {
+        let mut purchases: Vec<VerifiedPurchase> = self
+            .purchases
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .filter(|purchase| purchase.account_id.as_deref() == Some(account_id))
+            .cloned()
+            .collect();
+        purchases.sort_by_key(|purchase| purchase.purchased_at);
+        Ok(purchases)
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Deduplicates concurrent calls that share a key.
+///
+/// The first caller for a key runs the work. Callers that arrive while it is running wait
+/// for it and receive a copy of its result instead of starting the work again.
+pub(crate) struct SingleFlight<K, V> {
+    calls: Mutex<HashMap<K, Arc<Call<V>>>>,
+}
+
+struct Call<V> {
+    result: Mutex<Option<crate::Result<V>>>,
+    done: Condvar,
+}
+
+impl<K, V> Default for SingleFlight<K, V> {
+    fn default() -> Self {
+        Self {
+            calls: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> SingleFlight<K, V> {
+    /// Run `work` for `key`, or wait for the call already in flight for it.
+    pub fn run(&self, key: K, work: impl FnOnce() -> crate::Result<V>) -> crate::Result<V> {
+        match self.join_or_lead(&key) {
+            Ok(call) => Self::wait(&call),
+            Err(call) => self.lead(key, call, work),
+        }
+    }
+
+    /// `Ok` with the running call to join, or `Err` with a freshly registered call to lead.
+    fn join_or_lead(&self, key: &K) -> Result<Arc<Call<V>>, Arc<Call<V>>> {
+        let mut calls = self.calls.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(call) = calls.get(key) {
+            return Ok(call.clone());
+        }
+
+        let call = Arc::new(Call {
+            result: Mutex::new(None),
+            done: Condvar::new(),
+        });
+        calls.insert(key.clone(), call.clone());
+        Err(call)
+    }
+
+    fn lead(
+        &self,
+        key: K,
+        call: Arc<Call<V>>,
+        work: impl FnOnce() -> crate::Result<V>,
+    ) -> crate::Result<V> {
+        // Lands the call even when `work` panics, so waiters and later callers are not stuck
+        let landing = Landing {
+            flights: self,
+            key,
+            call,
+        };
+        let result = work();
+
+        *landing
+            .call
+            .result
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(
+            result
+                .as_ref()
+                .map(Clone::clone)
+                .map_err(crate::Error::share),
+        );
+        drop(landing);
+
+        result
+    }
+
+    fn wait(call: &Call<V>) -> crate::Result<V> {
+        let mut result = call.result.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            match result.as_ref() {
+                Some(Ok(value)) => return Ok(value.clone()),
+                Some(Err(error)) => return Err(error.share()),
+                None => result = call.done.wait(result).unwrap_or_else(|e| e.into_inner()),
+            }
+        }
+    }
+}
+
+/// Ends the leader's call: records a failure if the leader panicked before storing its result,
+/// unregisters the call and wakes the waiters.
+struct Landing<'a, K: Eq + Hash, V> {
+    flights: &'a SingleFlight<K, V>,
+    key: K,
+    call: Arc<Call<V>>,
+}
+
+impl<K: Eq + Hash, V> Drop for Landing<'_, K, V> {
+    fn drop(&mut self) {
+        self.call
+            .result
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_or_insert_with(|| Err(std::io::Error::other("The call panicked").into()));
+
+        let mut calls = self.flights.calls.lock().unwrap_or_else(|e| e.into_inner());
+        if calls
+            .get(&self.key)
+            .is_some_and(|call| Arc::ptr_eq(call, &self.call))
+        {
+            calls.remove(&self.key);
+        }
+        drop(calls);
+
+        self.call.done.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Wait until a second caller holds the leader's call, next to the map and the leader.
+    fn wait_for_follower(flights: &SingleFlight<&str, u32>, key: &str) {
+        while flights
+            .calls
+            .lock()
+            .unwrap()
+            .get(key)
+            .map_or(0, Arc::strong_count)
+            < 3
+        {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn concurrent_callers_share_one_call() {
+        let flights = Arc::new(SingleFlight::<&str, u32>::default());
+        let runs = Arc::new(AtomicUsize::new(0));
+        let (started, leader_started) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+
+        let leader = {
+            let flights = flights.clone();
+            let runs = runs.clone();
+            thread::spawn(move || {
+                flights.run("key", || {
+                    runs.fetch_add(1, Ordering::SeqCst);
+                    started.send(()).unwrap();
+                    released.recv().unwrap();
+                    Ok(7)
+                })
+            })
+        };
+        leader_started.recv().unwrap();
+
+        let follower = {
+            let flights = flights.clone();
+            let runs = runs.clone();
+            thread::spawn(move || {
+                flights.run("key", || {
+                    runs.fetch_add(1, Ordering::SeqCst);
+                    Ok(8)
+                })
+            })
+        };
+        wait_for_follower(&flights, "key");
+        release.send(()).unwrap();
+
+        assert_eq!(leader.join().unwrap().unwrap(), 7);
+        assert_eq!(follower.join().unwrap().unwrap(), 7);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn finished_calls_run_again() {
+        let flights = SingleFlight::<&str, u32>::default();
+
+        assert_eq!(flights.run("key", || Ok(1)).unwrap(), 1);
+        assert_eq!(flights.run("key", || Ok(2)).unwrap(), 2);
+    }
+
+    #[test]
+    fn a_panicking_leader_releases_waiters_and_the_key() {
+        let flights = Arc::new(SingleFlight::<&str, u32>::default());
+        let (started, leader_started) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+
+        let leader = {
+            let flights = flights.clone();
+            thread::spawn(move || {
+                catch_unwind(AssertUnwindSafe(|| {
+                    flights.run("key", || {
+                        started.send(()).unwrap();
+                        released.recv().unwrap();
+                        panic!("the work panicked");
+                    })
+                }))
+            })
+        };
+        leader_started.recv().unwrap();
+
+        let follower = {
+            let flights = flights.clone();
+            thread::spawn(move || flights.run("key", || Ok(8)))
+        };
+        wait_for_follower(&flights, "key");
+        release.send(()).unwrap();
+
+        assert!(leader.join().unwrap().is_err());
+        assert!(follower.join().unwrap().is_err());
+        assert_eq!(flights.run("key", || Ok(9)).unwrap(), 9);
+    }
+
+    #[test]
+    fn waiters_keep_the_error_code_of_a_rejection() {
+        let flights = Arc::new(SingleFlight::<&str, u32>::default());
+        let (started, leader_started) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+
+        let leader = {
+            let flights = flights.clone();
+            thread::spawn(move || {
+                flights.run("key", || {
+                    started.send(()).unwrap();
+                    released.recv().unwrap();
+                    Err(crate::Error::rejected(
+                        "notConnected",
+                        "The store is unreachable",
+                    ))
+                })
+            })
+        };
+        leader_started.recv().unwrap();
+
+        let follower = {
+            let flights = flights.clone();
+            thread::spawn(move || flights.run("key", || Ok(8)))
+        };
+        wait_for_follower(&flights, "key");
+        release.send(()).unwrap();
+
+        let leader = leader.join().unwrap().unwrap_err();
+        let follower = follower.join().unwrap().unwrap_err();
+        assert_eq!(leader.code(), Some("notConnected"));
+        assert_eq!(follower.code(), Some("notConnected"));
+    }
+}
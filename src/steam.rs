@@ -0,0 +1,934 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+use tauri::{AppHandle, Manager, Runtime};
+
+use crate::models::*;
+use crate::{now_millis, rfc3339, SteamConfig, SteamItem};
+
+const ORDERS_FILE: &str = "iap-steam-orders.json";
+
+/// Delay between two QueryTxn calls while the user authorizes a purchase in the overlay.
+const AUTHORIZATION_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Order statuses after Steam gave the money back.
+const REFUNDED_STATUSES: &[&str] = &[
+    "Refunded",
+    "PartialRefund",
+    "Chargedback",
+    "RefundedSuspectedFraud",
+    "RefundedFriendlyFraud",
+];
+
+pub fn init<R: Runtime>(app: &AppHandle<R>, config: Option<SteamConfig>) -> crate::Result<Iap<R>> {
+    let orders_path = app
+        .path()
+        .app_data_dir()
+        .ok()
+        .map(|dir| dir.join(ORDERS_FILE));
+    Ok(Iap::with_path(app, config, orders_path))
+}
+
+/// The `response` object every ISteamMicroTxn method answers with.
+#[derive(Deserialize)]
+struct Envelope<T> {
+    response: WebApiResponse<T>,
+}
+
+#[derive(Deserialize)]
+struct WebApiResponse<T> {
+    result: String,
+    params: Option<T>,
+    #[serde(default)]
+    error: Option<WebApiError>,
+}
+
+#[derive(Deserialize)]
+struct WebApiError {
+    #[serde(deserialize_with = "string_or_number")]
+    errorcode: String,
+    errordesc: String,
+}
+
+#[derive(Deserialize)]
+struct UserInfo {
+    #[serde(default)]
+    country: Option<String>,
+    #[serde(default)]
+    currency: Option<String>,
+    /// `Active`, `Trusted` or `LockedFromPurchasing`.
+    #[serde(default)]
+    status: Option<String>,
+}
+
+/// An order as QueryTxn returns it.
+#[derive(Clone, Deserialize, Serialize)]
+struct Order {
+    #[serde(deserialize_with = "string_or_number")]
+    orderid: String,
+    #[serde(default, deserialize_with = "optional_string_or_number")]
+    transid: Option<String>,
+    #[serde(default, deserialize_with = "optional_string_or_number")]
+    steamid: Option<String>,
+    /// `Init`, `Approved`, `Succeeded`, `Failed` or one of [`REFUNDED_STATUSES`].
+    status: String,
+    #[serde(default)]
+    currency: Option<String>,
+    /// When the status last changed.
+    #[serde(default)]
+    time: Option<String>,
+    #[serde(default)]
+    timecreated: Option<String>,
+    #[serde(default)]
+    items: Vec<OrderItem>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct OrderItem {
+    #[serde(deserialize_with = "string_or_number")]
+    itemid: String,
+    #[serde(default)]
+    qty: u32,
+    #[serde(default)]
+    amount: u64,
+}
+
+/// An order started by `purchase`. Kept after it was finalized, so the purchases of the user
+/// can be restored with QueryTxn.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StartedOrder {
+    order_id: String,
+    product_id: String,
+    started_at: i64,
+    /// The user the order was started for.
+    #[serde(default)]
+    steam_id: String,
+    /// Whether FinalizeTxn charged the order.
+    #[serde(default)]
+    finalized: bool,
+}
+
+/// Access to the iap APIs, backed by Steam microtransactions.
+///
+/// Purchases are started with InitTxn and authorized by the user in the Steam overlay, which
+/// the plugin notices by polling QueryTxn. An authorized order is charged by FinalizeTxn when
+/// the purchase is acknowledged or consumed, so the app grants before the money moves.
+pub struct Iap<R: Runtime> {
+    app: AppHandle<R>,
+    config: Option<SteamConfig>,
+    agent: ureq::Agent,
+    steam_id: Mutex<Option<String>>,
+    orders_path: Option<PathBuf>,
+    orders: Mutex<Vec<StartedOrder>>,
+}
+
+impl<R: Runtime> Iap<R> {
+    pub(crate) fn with_path(
+        app: &AppHandle<R>,
+        config: Option<SteamConfig>,
+        orders_path: Option<PathBuf>,
+    ) -> Self {
+        let orders = orders_path
+            .as_ref()
+            .and_then(|path| std::fs::read(path).ok())
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+
+        Self {
+            app: app.clone(),
+            config,
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(10))
+                .build(),
+            steam_id: Mutex::new(None),
+            orders_path,
+            orders: Mutex::new(orders),
+        }
+    }
+
+    /// Set the SteamID64 of the signed-in user, e.g. from the Steamworks SDK. A purchase with
+    /// an `obfuscated_account_id` sets it as well.
+    pub fn set_steam_id(&self, steam_id: u64) {
+        *self.steam_id.lock().unwrap_or_else(|e| e.into_inner()) = Some(steam_id.to_string());
+    }
+
+    fn config(&self) -> crate::Result<&SteamConfig> {
+        self.config
+            .as_ref()
+            .ok_or_else(|| crate::Error::rejected("notConfigured", "The `steam` config is missing"))
+    }
+
+    fn steam_id(&self) -> crate::Result<String> {
+        self.steam_id
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+            .ok_or_else(|| crate::Error::rejected("notSignedIn", "The Steam user is not known"))
+    }
+
+    /// Call a method of ISteamMicroTxn, or of its sandbox. The publisher key is added by the
+    /// proxy at `base_url`.
+    fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        version: u32,
+        post: bool,
+        params: &[(&str, String)],
+    ) -> crate::Result<T> {
+        let config = self.config()?;
+        let interface = if config.sandbox {
+            "ISteamMicroTxnSandbox"
+        } else {
+            "ISteamMicroTxn"
+        };
+        let url = format!("{}/{interface}/{method}/v{version}/", config.base_url);
+
+        let app_id = config.app_id.to_string();
+        let mut pairs = vec![("appid", app_id.as_str())];
+        pairs.extend(params.iter().map(|(name, value)| (*name, value.as_str())));
+
+        let response = if post {
+            self.agent.post(&url).send_form(&pairs)
+        } else {
+            self.agent.get(&url).query_pairs(pairs).call()
+        };
+        let envelope: Envelope<T> = response
+            .map_err(|err| match err {
+                ureq::Error::Status(status, _) => crate::Error::rejected(
+                    "steamError",
+                    format!("{method} was answered with {status}"),
+                ),
+                ureq::Error::Transport(err) => {
+                    crate::Error::rejected("networkError", err.to_string())
+                }
+            })?
+            .into_json()
+            .map_err(|err| {
+                crate::Error::rejected("steamError", format!("Unexpected {method} response: {err}"))
+            })?;
+
+        let response = envelope.response;
+        if response.result != "OK" {
+            let message = response.error.map_or_else(
+                || format!("{method} failed"),
+                |error| format!("{} ({})", error.errordesc, error.errorcode),
+            );
+            return Err(crate::Error::rejected("steamError", message));
+        }
+        response.params.ok_or_else(|| {
+            crate::Error::rejected("steamError", format!("{method} returned no params"))
+        })
+    }
+
+    fn user_info(&self) -> crate::Result<UserInfo> {
+        self.call("GetUserInfo", 2, false, &[("steamid", self.steam_id()?)])
+    }
+
+    fn query_order(&self, order_id: &str) -> crate::Result<Order> {
+        self.call("QueryTxn", 3, false, &[("orderid", order_id.to_string())])
+    }
+
+    /// The wallet currency of the user, or the configured one.
+    fn currency(&self) -> crate::Result<String> {
+        let config = self.config()?;
+        Ok(self
+            .user_info()
+            .ok()
+            .and_then(|info| info.currency)
+            .unwrap_or_else(|| config.currency.clone()))
+    }
+
+    pub fn initialize(&self) -> crate::Result<InitializeResponse> {
+        self.config()?;
+        Ok(InitializeResponse { success: true })
+    }
+
+    pub fn get_products(
+        &self,
+        product_ids: Vec<String>,
+        product_type: String,
+    ) -> crate::Result<GetProductsResponse> {
+        let mut response = self.query_products(&product_ids, &product_type)?;
+        crate::products::report_invalid_products(
+            &product_ids,
+            &product_type,
+            &mut response,
+            |ids, other_type| self.query_products(&ids, &other_type),
+        );
+        Ok(response)
+    }
+
+    fn query_products(
+        &self,
+        product_ids: &[String],
+        product_type: &str,
+    ) -> crate::Result<GetProductsResponse> {
+        let config = self.config()?;
+
+        // Steam microtransactions are one-time charges, subscriptions do not exist here
+        let mut products = Vec::new();
+        if product_type != "subs" {
+            let currency = self.currency()?;
+            for product_id in product_ids {
+                if let Some(item) = config.items.get(product_id) {
+                    products.extend(to_product(product_id, item, &currency, &config.currency));
+                }
+            }
+        }
+
+        Ok(GetProductsResponse {
+            products,
+            invalid_product_ids: Vec::new(),
+            failure_reasons: Default::default(),
+            storefront: None,
+        })
+    }
+
+    pub fn purchase(
+        &self,
+        product_id: String,
+        product_type: String,
+        options: Option<PurchaseOptions>,
+    ) -> crate::Result<Purchase> {
+        let config = self.config()?;
+        let item = config
+            .items
+            .get(&product_id)
+            .filter(|_| product_type != "subs")
+            .ok_or_else(|| crate::Error::rejected("productNotFound", "Product not found"))?;
+
+        if let Some(account_id) = options.and_then(|options| options.obfuscated_account_id) {
+            let steam_id = account_id.parse().map_err(|_| {
+                crate::Error::rejected("invalidAccountId", format!("Not a SteamID64: {account_id}"))
+            })?;
+            self.set_steam_id(steam_id);
+        }
+        let steam_id = self.steam_id()?;
+        let product = to_product(&product_id, item, &self.currency()?, &config.currency)
+            .ok_or_else(|| crate::Error::rejected("productNotFound", "The product has no price"))?;
+        let (currency, amount) = (
+            product.price_currency_code.unwrap_or_default(),
+            product.price_amount_micros.unwrap_or_default() / 10_000,
+        );
+
+        let order_id = new_order_id();
+        self.start_order(StartedOrder {
+            order_id: order_id.clone(),
+            product_id: product_id.clone(),
+            started_at: now_millis(),
+            steam_id: steam_id.clone(),
+            finalized: false,
+        });
+
+        let mut params = vec![
+            ("orderid", order_id.clone()),
+            ("steamid", steam_id),
+            ("itemcount", "1".to_string()),
+            ("language", config.language.clone()),
+            ("currency", currency),
+            ("usersession", "client".to_string()),
+            ("itemid[0]", item.item_id.to_string()),
+            ("qty[0]", "1".to_string()),
+            ("amount[0]", amount.to_string()),
+            ("description[0]", item.description.clone()),
+        ];
+        if let Some(category) = &item.category {
+            params.push(("category[0]", category.clone()));
+        }
+        if let Err(err) = self.call::<serde_json::Value>("InitTxn", 3, true, &params) {
+            self.finish_order(&order_id);
+            return Err(err);
+        }
+
+        let order = self.wait_for_authorization(&order_id)?;
+        self.to_purchases(&order)
+            .into_iter()
+            .next()
+            .ok_or_else(|| crate::Error::rejected("purchaseFailed", "The order has no items"))
+    }
+
+    /// Poll the order until the user authorized or declined it in the overlay.
+    fn wait_for_authorization(&self, order_id: &str) -> crate::Result<Order> {
+        let deadline = now_millis() + self.config()?.authorization_timeout_secs as i64 * 1000;
+        loop {
+            let order = self.query_order(order_id)?;
+            match order.status.as_str() {
+                "Approved" | "Succeeded" => return Ok(order),
+                "Init" if now_millis() < deadline => {
+                    std::thread::sleep(AUTHORIZATION_POLL_INTERVAL)
+                }
+                "Init" | "Failed" => {
+                    self.finish_order(order_id);
+                    return Err(crate::Error::rejected(
+                        "purchaseNotCompleted",
+                        "The purchase was not authorized",
+                    ));
+                }
+                status => {
+                    self.finish_order(order_id);
+                    return Err(crate::Error::rejected(
+                        "purchaseFailed",
+                        format!("The order is {status}"),
+                    ));
+                }
+            }
+        }
+    }
+
+    /// A purchase for each item of the order the app sells.
+    fn to_purchases(&self, order: &Order) -> Vec<Purchase> {
+        let Ok(config) = self.config() else {
+            return Vec::new();
+        };
+        let order_time = |time: &Option<String>| time.as_deref().and_then(rfc3339::parse);
+        let refunded = REFUNDED_STATUSES.contains(&order.status.as_str());
+        let purchase_state = match order.status.as_str() {
+            "Init" => PurchaseStateValue::Pending,
+            "Failed" => PurchaseStateValue::Canceled,
+            _ => PurchaseStateValue::Purchased,
+        };
+
+        order
+            .items
+            .iter()
+            .filter_map(|order_item| {
+                let (product_id, _) = config
+                    .items
+                    .iter()
+                    .find(|(_, item)| item.item_id.to_string() == order_item.itemid)?;
+                // Orders of this plugin have one item, others get a token per item
+                let purchase_token = if order.items.len() == 1 {
+                    order.orderid.clone()
+                } else {
+                    format!("{}-{}", order.orderid, order_item.itemid)
+                };
+
+                Some(Purchase {
+                    order_id: order.transid.clone(),
+                    package_name: self.app.package_info().name.clone(),
+                    product_id: product_id.clone(),
+                    purchase_time: order_time(&order.timecreated)
+                        .or_else(|| order_time(&order.time))
+                        .unwrap_or_else(now_millis),
+                    purchase_token,
+                    purchase_state: purchase_state as i32,
+                    is_auto_renewing: false,
+                    is_acknowledged: order.status != "Approved",
+                    original_json: serde_json::to_string(order).unwrap_or_default(),
+                    signature: String::new(),
+                    revocation_date: if refunded {
+                        order_time(&order.time).or_else(|| Some(now_millis()))
+                    } else {
+                        None
+                    },
+                    revocation_reason: refunded.then_some(RevocationReason::Other),
+                    delivery: None,
+                    fulfilled: false,
+                })
+            })
+            .collect()
+    }
+
+    /// The orders this device started for the user, as QueryTxn reports them now.
+    fn user_orders(&self) -> crate::Result<Vec<Order>> {
+        let steam_id = self.steam_id()?;
+        let order_ids: Vec<String> = self
+            .orders
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .filter(|order| order.steam_id == steam_id)
+            .map(|order| order.order_id.clone())
+            .collect();
+
+        let mut orders = Vec::new();
+        for order_id in order_ids {
+            let order = self.query_order(&order_id)?;
+            if order.steamid.as_deref().unwrap_or(&steam_id) == steam_id {
+                orders.push(order);
+            }
+        }
+        Ok(orders)
+    }
+
+    pub fn restore_purchases(
+        &self,
+        product_type: String,
+    ) -> crate::Result<RestorePurchasesResponse> {
+        if product_type == "subs" {
+            return Ok(RestorePurchasesResponse {
+                purchases: Vec::new(),
+            });
+        }
+
+        // Refunded orders stay listed, their revocation date reports the refund
+        let purchases = self
+            .user_orders()?
+            .iter()
+            .filter(|order| order.status != "Init" && order.status != "Failed")
+            .flat_map(|order| self.to_purchases(order))
+            .collect();
+        Ok(RestorePurchasesResponse { purchases })
+    }
+
+    pub fn get_unfinished_purchases(&self) -> crate::Result<GetUnfinishedPurchasesResponse> {
+        let started: Vec<StartedOrder> = self
+            .orders
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .filter(|order| !order.finalized)
+            .cloned()
+            .collect();
+        let timeout_ms = self.config()?.authorization_timeout_secs as i64 * 1000;
+
+        let mut purchases = Vec::new();
+        for started in started {
+            // Unreachable orders are looked at again on the next launch
+            let Ok(order) = self.query_order(&started.order_id) else {
+                continue;
+            };
+            match order.status.as_str() {
+                "Approved" => purchases.extend(self.to_purchases(&order)),
+                "Init" if now_millis() - started.started_at < timeout_ms => {}
+                "Init" | "Failed" => self.finish_order(&started.order_id),
+                _ => self.finalized_order(&started.order_id),
+            }
+        }
+        Ok(GetUnfinishedPurchasesResponse { purchases })
+    }
+
+    pub fn get_store_capabilities(&self) -> crate::Result<StoreCapabilities> {
+        let user_info = self.user_info().ok();
+        Ok(StoreCapabilities {
+            store: StoreKind::Steam,
+            can_make_payments: user_info
+                .as_ref()
+                .is_some_and(|info| info.status.as_deref() != Some("LockedFromPurchasing")),
+            storefront: user_info.and_then(|info| info.country),
+            features: vec![StoreFeature::Consumables],
+        })
+    }
+
+    pub fn redeem_offer_code(
+        &self,
+        _code: Option<String>,
+    ) -> crate::Result<RedeemOfferCodeResponse> {
+        Err(crate::Error::rejected(
+            "notSupported",
+            "Steam microtransactions have no offer codes",
+        ))
+    }
+
+    pub fn begin_refund_request(
+        &self,
+        _transaction_id: String,
+    ) -> crate::Result<BeginRefundRequestResponse> {
+        Err(crate::Error::rejected(
+            "notSupported",
+            "Steam refunds are requested on help.steampowered.com",
+        ))
+    }
+
+    pub fn show_manage_subscriptions(
+        &self,
+        _product_id: Option<String>,
+    ) -> crate::Result<ShowManageSubscriptionsResponse> {
+        Err(crate::Error::rejected(
+            "notSupported",
+            "Subscriptions are not supported on Steam",
+        ))
+    }
+
+    pub fn acknowledge_purchase(
+        &self,
+        purchase_token: String,
+    ) -> crate::Result<AcknowledgePurchaseResponse> {
+        self.finalize(&purchase_token)?;
+        Ok(AcknowledgePurchaseResponse {
+            success: true,
+            queued: false,
+        })
+    }
+
+    pub fn consume_purchase(
+        &self,
+        purchase_token: String,
+    ) -> crate::Result<ConsumePurchaseResponse> {
+        // Steam has no consumption, the app keeps track of what it granted
+        self.finalize(&purchase_token)?;
+        Ok(ConsumePurchaseResponse {
+            success: true,
+            queued: false,
+        })
+    }
+
+    /// Charge an authorized order with FinalizeTxn. Finalized orders are left alone.
+    fn finalize(&self, purchase_token: &str) -> crate::Result<()> {
+        // Tokens of orders with several items are `{orderid}-{itemid}`, see `to_purchases`
+        let order_id = purchase_token
+            .split_once('-')
+            .map_or(purchase_token, |(order_id, _)| order_id);
+
+        let order = self.query_order(order_id)?;
+        match order.status.as_str() {
+            "Approved" => {
+                self.call::<serde_json::Value>(
+                    "FinalizeTxn",
+                    2,
+                    true,
+                    &[("orderid", order_id.to_string())],
+                )?;
+            }
+            "Succeeded" => {}
+            status => {
+                return Err(crate::Error::rejected(
+                    "invalidPurchaseState",
+                    format!("The order is {status}"),
+                ))
+            }
+        }
+        self.finalized_order(order_id);
+        Ok(())
+    }
+
+    pub fn get_product_status(
+        &self,
+        product_id: String,
+        product_type: String,
+    ) -> crate::Result<ProductStatus> {
+        let purchases = self.restore_purchases(product_type)?.purchases;
+        Ok(product_status(&purchases, product_id))
+    }
+
+    pub fn get_products_status(
+        &self,
+        product_ids: Vec<String>,
+        product_type: String,
+    ) -> crate::Result<GetProductsStatusResponse> {
+        // One round of QueryTxn calls serves every requested product
+        let purchases = self.restore_purchases(product_type)?.purchases;
+
+        let statuses = product_ids
+            .into_iter()
+            .map(|product_id| (product_id.clone(), product_status(&purchases, product_id)))
+            .collect();
+        Ok(GetProductsStatusResponse { statuses })
+    }
+
+    fn start_order(&self, order: StartedOrder) {
+        let mut orders = self.orders.lock().unwrap_or_else(|e| e.into_inner());
+        orders.push(order);
+        self.persist(&orders);
+    }
+
+    /// Keep a charged order for restoring.
+    fn finalized_order(&self, order_id: &str) {
+        let mut orders = self.orders.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(order) = orders
+            .iter_mut()
+            .find(|order| order.order_id == order_id && !order.finalized)
+        else {
+            return;
+        };
+        order.finalized = true;
+        self.persist(&orders);
+    }
+
+    /// Forget an order that was never charged.
+    fn finish_order(&self, order_id: &str) {
+        let mut orders = self.orders.lock().unwrap_or_else(|e| e.into_inner());
+        let before = orders.len();
+        orders.retain(|order| order.order_id != order_id);
+        if orders.len() != before {
+            self.persist(&orders);
+        }
+    }
+
+    fn persist(&self, orders: &[StartedOrder]) {
+        let Some(path) = &self.orders_path else {
+            return;
+        };
+
+        if let Ok(json) = serde_json::to_vec(orders) {
+            if let Some(dir) = path.parent() {
+                let _ = std::fs::create_dir_all(dir);
+            }
+            let _ = std::fs::write(path, json);
+        }
+    }
+}
+
+/// The product of an item definition, priced in `currency` or else in `fallback_currency`.
+fn to_product(
+    product_id: &str,
+    item: &SteamItem,
+    currency: &str,
+    fallback_currency: &str,
+) -> Option<Product> {
+    let (currency, cents) = item
+        .prices
+        .get_key_value(currency)
+        .or_else(|| item.prices.get_key_value(fallback_currency))?;
+
+    Some(Product {
+        product_id: product_id.to_string(),
+        title: item.description.clone(),
+        description: item.description.clone(),
+        product_type: "inapp".to_string(),
+        formatted_price: Some(format!("{}.{:02} {currency}", cents / 100, cents % 100)),
+        price_currency_code: Some(currency.clone()),
+        price_amount_micros: Some(*cents as i64 * 10_000),
+        subscription_offer_details: None,
+    })
+}
+
+/// The status of the latest purchase of the product.
+fn product_status(purchases: &[Purchase], product_id: String) -> ProductStatus {
+    let Some(purchase) = purchases
+        .iter()
+        .filter(|purchase| purchase.product_id == product_id)
+        .max_by_key(|purchase| purchase.purchase_time)
+    else {
+        return ProductStatus {
+            product_id,
+            is_owned: false,
+            purchase_state: None,
+            purchase_time: None,
+            expiration_time: None,
+            is_auto_renewing: None,
+            is_acknowledged: None,
+            purchase_token: None,
+        };
+    };
+
+    let is_owned = purchase.purchase_state == PurchaseStateValue::Purchased as i32
+        && purchase.revocation_date.is_none();
+    ProductStatus {
+        product_id,
+        is_owned,
+        purchase_state: Some(if is_owned {
+            PurchaseStateValue::Purchased
+        } else {
+            PurchaseStateValue::Canceled
+        }),
+        purchase_time: Some(purchase.purchase_time),
+        expiration_time: None,
+        is_auto_renewing: Some(false),
+        is_acknowledged: Some(purchase.is_acknowledged),
+        purchase_token: Some(purchase.purchase_token.clone()),
+    }
+}
+
+/// A random order id. Steam needs them unique per app across all users.
+fn new_order_id() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_i64(now_millis());
+    // Positive as a signed 64-bit integer, which some Steam tooling expects
+    (hasher.finish() >> 1).to_string()
+}
+
+/// Ids the Web API sends as numbers in some responses and as strings in others.
+fn string_or_number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(value) => Ok(value),
+        value => Ok(value.to_string()),
+    }
+}
+
+fn optional_string_or_number<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Null => Ok(None),
+        serde_json::Value::String(value) => Ok(Some(value)),
+        value => Ok(Some(value.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+    use tauri::test::{mock_app, MockRuntime};
+
+    use super::*;
+    use crate::stand_in::{Request, StandIn};
+
+    const STEAM_ID: u64 = 76561197960287930;
+
+    fn iap(base_url: &str, name: &str) -> Iap<MockRuntime> {
+        let config = serde_json::from_value(json!({
+            "appId": 480,
+            "baseUrl": base_url,
+            "items": {
+                "gems": { "itemId": 1, "description": "Gems", "prices": { "USD": 199 } },
+            },
+        }))
+        .unwrap();
+        let path = std::env::temp_dir().join(format!("iap-steam-{name}-{}.json", now_millis()));
+        let iap = Iap::with_path(mock_app().handle(), Some(config), Some(path));
+        iap.set_steam_id(STEAM_ID);
+        iap
+    }
+
+    fn ok(params: serde_json::Value) -> (u16, String) {
+        (
+            200,
+            json!({ "response": { "result": "OK", "params": params } }).to_string(),
+        )
+    }
+
+    /// Answers like Steam for a single order, whose status FinalizeTxn moves to `Succeeded`.
+    fn steam(status: &'static str) -> StandIn {
+        let order = Arc::new(Mutex::new((String::new(), status)));
+        StandIn::start(move |request: &Request| {
+            let mut order = order.lock().unwrap();
+            match request.path() {
+                "/ISteamMicroTxn/GetUserInfo/v2/" => ok(json!({ "currency": "USD" })),
+                "/ISteamMicroTxn/InitTxn/v3/" => {
+                    order.0 = request.param("orderid").unwrap_or_default();
+                    ok(json!({ "orderid": order.0, "transid": "9" }))
+                }
+                "/ISteamMicroTxn/QueryTxn/v3/" => ok(json!({
+                    "orderid": order.0,
+                    "transid": 9,
+                    "steamid": STEAM_ID.to_string(),
+                    "status": order.1,
+                    "time": "2026-10-18T10:00:00Z",
+                    "items": [{ "itemid": 1, "qty": 1, "amount": 199 }],
+                })),
+                "/ISteamMicroTxn/FinalizeTxn/v2/" => {
+                    order.1 = "Succeeded";
+                    ok(json!({ "orderid": order.0 }))
+                }
+                _ => (404, String::new()),
+            }
+        })
+    }
+
+    fn methods(steam: &StandIn) -> Vec<String> {
+        steam
+            .requests()
+            .iter()
+            .map(|request| {
+                request
+                    .path()
+                    .split('/')
+                    .nth(2)
+                    .unwrap_or_default()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn purchases_finalizes_and_restores_an_order() {
+        let steam = steam("Approved");
+        let iap = iap(&steam.url, "round-trip");
+
+        let purchase = iap.purchase("gems".into(), "inapp".into(), None).unwrap();
+        assert_eq!(purchase.product_id, "gems");
+        assert!(!purchase.is_acknowledged);
+        assert_eq!(
+            iap.get_unfinished_purchases().unwrap().purchases[0].purchase_token,
+            purchase.purchase_token
+        );
+
+        iap.acknowledge_purchase(purchase.purchase_token.clone())
+            .unwrap();
+        assert!(iap.get_unfinished_purchases().unwrap().purchases.is_empty());
+        let restored = iap.restore_purchases("inapp".into()).unwrap().purchases;
+        assert_eq!(restored.len(), 1);
+        assert_eq!(restored[0].purchase_token, purchase.purchase_token);
+        assert!(restored[0].is_acknowledged);
+
+        assert_eq!(
+            methods(&steam),
+            [
+                "GetUserInfo",
+                "InitTxn",
+                "QueryTxn",
+                "QueryTxn",
+                "QueryTxn",
+                "FinalizeTxn",
+                "QueryTxn",
+            ]
+        );
+        let requests = steam.requests();
+        let init = &requests[1];
+        assert_eq!(init.method, "POST");
+        assert_eq!(init.param("steamid"), Some(STEAM_ID.to_string()));
+        assert_eq!(init.param("itemid[0]").as_deref(), Some("1"));
+        assert_eq!(init.param("amount[0]").as_deref(), Some("199"));
+        assert_eq!(init.param("currency").as_deref(), Some("USD"));
+        assert_eq!(
+            requests[5].param("orderid"),
+            Some(purchase.purchase_token.clone())
+        );
+        assert!(requests
+            .iter()
+            .all(|request| request.param("key").is_none()));
+    }
+
+    #[test]
+    fn a_declined_order_is_forgotten() {
+        let steam = steam("Failed");
+        let iap = iap(&steam.url, "declined");
+
+        let err = iap
+            .purchase("gems".into(), "inapp".into(), None)
+            .unwrap_err();
+
+        assert_eq!(err.code(), Some("purchaseNotCompleted"));
+        assert!(iap
+            .restore_purchases("inapp".into())
+            .unwrap()
+            .purchases
+            .is_empty());
+        assert!(!methods(&steam).iter().any(|method| method == "FinalizeTxn"));
+    }
+
+    #[test]
+    fn restores_only_orders_of_the_signed_in_user() {
+        let steam = steam("Approved");
+        let iap = iap(&steam.url, "other-user");
+        iap.purchase("gems".into(), "inapp".into(), None).unwrap();
+        let queried = methods(&steam).len();
+
+        iap.set_steam_id(STEAM_ID + 1);
+        let restored = iap.restore_purchases("inapp".into()).unwrap().purchases;
+
+        assert!(restored.is_empty());
+        assert_eq!(methods(&steam).len(), queried);
+    }
+
+    #[test]
+    fn finalized_orders_are_restored_after_a_restart() {
+        let steam = steam("Approved");
+        let iap = iap(&steam.url, "restart");
+        let purchase = iap.purchase("gems".into(), "inapp".into(), None).unwrap();
+        iap.consume_purchase(purchase.purchase_token.clone())
+            .unwrap();
+
+        let restarted = Iap::with_path(
+            mock_app().handle(),
+            iap.config.clone(),
+            iap.orders_path.clone(),
+        );
+        restarted.set_steam_id(STEAM_ID);
+
+        let status = restarted
+            .get_product_status("gems".into(), "inapp".into())
+            .unwrap();
+        assert!(status.is_owned);
+        assert_eq!(status.purchase_token, Some(purchase.purchase_token));
+    }
+}
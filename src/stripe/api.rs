@@ -0,0 +1,653 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::models::*;
+
+/// Currencies Stripe amounts are given in whole units of, not cents.
+const ZERO_DECIMAL_CURRENCIES: &[&str] = &[
+    "bif", "clp", "djf", "gnf", "jpy", "kmf", "krw", "mga", "pyg", "rwf", "ugx", "vnd", "vuv",
+    "xaf", "xof", "xpf",
+];
+
+/// Subscription statuses that still grant the product.
+const ACTIVE_SUBSCRIPTION_STATUSES: &[&str] = &["active", "trialing", "past_due"];
+
+/// A related object, which Stripe sends as its id unless it was expanded.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub(crate) enum Expandable<T> {
+    Id(String),
+    Object(Box<T>),
+}
+
+impl<T> Expandable<T> {
+    fn object(&self) -> Option<&T> {
+        match self {
+            Self::Id(_) => None,
+            Self::Object(object) => Some(object),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct CheckoutSession {
+    pub id: String,
+    #[serde(default)]
+    pub url: Option<String>,
+    /// `open`, `complete` or `expired`.
+    #[serde(default)]
+    pub status: Option<String>,
+    /// `paid`, `unpaid` or `no_payment_required`.
+    pub payment_status: String,
+    /// `payment` or `subscription`.
+    pub mode: String,
+    pub created: i64,
+    #[serde(default)]
+    pub customer: Option<String>,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    #[serde(default)]
+    pub subscription: Option<Expandable<Subscription>>,
+    #[serde(default)]
+    pub payment_intent: Option<Expandable<PaymentIntent>>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct PaymentIntent {
+    pub id: String,
+    #[serde(default)]
+    pub latest_charge: Option<Expandable<Charge>>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct Charge {
+    pub id: String,
+    #[serde(default)]
+    pub payment_intent: Option<String>,
+    #[serde(default)]
+    pub refunded: bool,
+    #[serde(default)]
+    pub amount_refunded: i64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct Subscription {
+    pub id: String,
+    pub status: String,
+    pub customer: String,
+    pub created: i64,
+    #[serde(default)]
+    pub cancel_at_period_end: bool,
+    /// Moved to the subscription items in newer API versions.
+    #[serde(default)]
+    pub current_period_end: Option<i64>,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    pub items: List<SubscriptionItem>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct SubscriptionItem {
+    pub price: ItemPrice,
+    #[serde(default)]
+    pub current_period_end: Option<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct ItemPrice {
+    pub id: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct List<T> {
+    pub data: Vec<T>,
+    #[serde(default)]
+    pub has_more: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct Price {
+    pub id: String,
+    #[serde(default)]
+    pub unit_amount: Option<i64>,
+    pub currency: String,
+    #[serde(default)]
+    pub recurring: Option<Recurring>,
+    pub product: Expandable<PriceProduct>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct Recurring {
+    /// `day`, `week`, `month` or `year`.
+    pub interval: String,
+    pub interval_count: u32,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct PriceProduct {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BillingPortalSession {
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct Customer {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: ApiError,
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+    #[serde(default)]
+    message: Option<String>,
+}
+
+/// Calls to the Stripe API, or to whatever stands in for it at `api_base`.
+pub(crate) struct StripeApi {
+    api_base: String,
+    secret_key: String,
+    agent: ureq::Agent,
+}
+
+impl StripeApi {
+    pub fn new(api_base: &str, secret_key: String) -> Self {
+        Self {
+            api_base: api_base.trim_end_matches('/').to_string(),
+            secret_key,
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(10))
+                .build(),
+        }
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        self.agent
+            .request(method, &format!("{}/v1/{path}", self.api_base))
+            .set("Authorization", &format!("Bearer {}", self.secret_key))
+    }
+
+    fn get<T: DeserializeOwned>(&self, path: &str, query: &[(&str, &str)]) -> crate::Result<T> {
+        let mut request = self.request("GET", path);
+        for (name, value) in query {
+            request = request.query(name, value);
+        }
+        parse(request.call())
+    }
+
+    fn post<T: DeserializeOwned>(&self, path: &str, form: &[(&str, &str)]) -> crate::Result<T> {
+        parse(self.request("POST", path).send_form(form))
+    }
+
+    /// A new customer for an account of the app.
+    pub fn create_customer(&self, account_id: &str) -> crate::Result<String> {
+        self.post::<Customer>("customers", &[("metadata[account_id]", account_id)])
+            .map(|customer| customer.id)
+    }
+
+    pub fn create_checkout_session(
+        &self,
+        params: &[(&str, String)],
+    ) -> crate::Result<CheckoutSession> {
+        let form: Vec<(&str, &str)> = params
+            .iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .collect();
+        self.post("checkout/sessions", &form)
+    }
+
+    /// The session with its subscription or the charge of its payment.
+    pub fn checkout_session(&self, id: &str) -> crate::Result<CheckoutSession> {
+        self.get(
+            &format!("checkout/sessions/{}", crate::path_segment(id)),
+            &[
+                ("expand[]", "subscription"),
+                ("expand[]", "payment_intent.latest_charge"),
+            ],
+        )
+    }
+
+    /// The completed sessions of a customer, newest first.
+    pub fn checkout_sessions(&self, customer: &str) -> crate::Result<Vec<CheckoutSession>> {
+        let mut sessions: Vec<CheckoutSession> = Vec::new();
+        loop {
+            let starting_after = sessions.last().map(|session| session.id.clone());
+            let mut query = vec![
+                ("customer", customer),
+                ("status", "complete"),
+                ("limit", "100"),
+                ("expand[]", "data.subscription"),
+                ("expand[]", "data.payment_intent.latest_charge"),
+            ];
+            if let Some(starting_after) = &starting_after {
+                query.push(("starting_after", starting_after));
+            }
+
+            let page: List<CheckoutSession> = self.get("checkout/sessions", &query)?;
+            let has_more = page.has_more && !page.data.is_empty();
+            sessions.extend(page.data);
+            if !has_more {
+                return Ok(sessions);
+            }
+        }
+    }
+
+    pub fn expire_checkout_session(&self, id: &str) -> crate::Result<()> {
+        self.post::<serde_json::Value>(
+            &format!("checkout/sessions/{}/expire", crate::path_segment(id)),
+            &[],
+        )
+        .map(|_| ())
+    }
+
+    pub fn price(&self, id: &str) -> crate::Result<Price> {
+        self.get(
+            &format!("prices/{}", crate::path_segment(id)),
+            &[("expand[]", "product")],
+        )
+    }
+
+    /// URL of a customer portal session, where the customer manages subscriptions.
+    pub fn billing_portal_url(&self, customer: &str) -> crate::Result<String> {
+        self.post::<BillingPortalSession>("billing_portal/sessions", &[("customer", customer)])
+            .map(|session| session.url)
+    }
+}
+
+fn parse<T: DeserializeOwned>(
+    response: std::result::Result<ureq::Response, ureq::Error>,
+) -> crate::Result<T> {
+    match response {
+        Ok(response) => response.into_json().map_err(|err| {
+            crate::Error::rejected("stripeError", format!("Unexpected Stripe response: {err}"))
+        }),
+        Err(ureq::Error::Status(status, response)) => {
+            let message = response
+                .into_json::<ErrorBody>()
+                .ok()
+                .and_then(|body| body.error.message)
+                .unwrap_or_else(|| format!("Stripe answered {status}"));
+            Err(crate::Error::rejected("stripeError", message))
+        }
+        Err(ureq::Error::Transport(err)) => {
+            Err(crate::Error::rejected("networkError", err.to_string()))
+        }
+    }
+}
+
+/// Amounts per major unit of the currency.
+fn minor_units(currency: &str) -> i64 {
+    if ZERO_DECIMAL_CURRENCIES.contains(&currency.to_lowercase().as_str()) {
+        1
+    } else {
+        100
+    }
+}
+
+/// The product of a price, with the price as its only offer if it recurs.
+pub(crate) fn to_product(product_id: &str, product_type: &str, price: &Price) -> Product {
+    let currency = price.currency.to_uppercase();
+    let amount = price.unit_amount.unwrap_or_default();
+    let units = minor_units(&currency);
+    let formatted_price = if units == 1 {
+        format!("{amount} {currency}")
+    } else {
+        format!("{}.{:02} {currency}", amount / units, amount % units)
+    };
+    let price_amount_micros = amount * (1_000_000 / units);
+    let (title, description) = price.product.object().map_or_else(
+        || (product_id.to_string(), String::new()),
+        |product| {
+            (
+                product.name.clone(),
+                product.description.clone().unwrap_or_default(),
+            )
+        },
+    );
+
+    let subscription_offer_details = price.recurring.as_ref().map(|recurring| {
+        let pricing_phases = vec![PricingPhase {
+            formatted_price: formatted_price.clone(),
+            price_currency_code: currency.clone(),
+            price_amount_micros,
+            billing_period: format!(
+                "P{}{}",
+                recurring.interval_count,
+                match recurring.interval.as_str() {
+                    "day" => "D",
+                    "week" => "W",
+                    "year" => "Y",
+                    _ => "M",
+                }
+            ),
+            billing_cycle_count: 0,
+            recurrence_mode: crate::offers::INFINITE_RECURRING,
+        }];
+        vec![SubscriptionOffer {
+            offer_token: price.id.clone(),
+            base_plan_id: price.id.clone(),
+            offer_id: None,
+            offer_kind: crate::offers::classify(&pricing_phases),
+            is_eligible: true,
+            pricing_phases,
+        }]
+    });
+
+    Product {
+        product_id: product_id.to_string(),
+        title,
+        description,
+        product_type: product_type.to_string(),
+        formatted_price: Some(formatted_price),
+        price_currency_code: Some(currency),
+        price_amount_micros: Some(price_amount_micros),
+        subscription_offer_details,
+    }
+}
+
+/// The product id the plugin put into the metadata, or the first price of a subscription.
+fn subscription_product_id(subscription: &Subscription) -> Option<String> {
+    subscription
+        .metadata
+        .get("product_id")
+        .cloned()
+        .or_else(|| {
+            subscription
+                .items
+                .data
+                .first()
+                .map(|item| item.price.id.clone())
+        })
+}
+
+fn period_end(subscription: &Subscription) -> Option<i64> {
+    subscription
+        .current_period_end
+        .or_else(|| {
+            subscription
+                .items
+                .data
+                .iter()
+                .filter_map(|item| item.current_period_end)
+                .min()
+        })
+        .map(|end| end * 1000)
+}
+
+fn is_active(subscription: &Subscription) -> bool {
+    ACTIVE_SUBSCRIPTION_STATUSES.contains(&subscription.status.as_str())
+}
+
+/// The purchase of a completed session. `None` for sessions without the product id the
+/// plugin puts into the metadata.
+pub(crate) fn session_purchase(session: &CheckoutSession, package_name: &str) -> Option<Purchase> {
+    let product_id = session.metadata.get("product_id")?.clone();
+    let subscription = session.subscription.as_ref().and_then(Expandable::object);
+    let charge = session
+        .payment_intent
+        .as_ref()
+        .and_then(Expandable::object)
+        .and_then(|intent| intent.latest_charge.as_ref())
+        .and_then(Expandable::object);
+
+    let purchase_state = match (session.status.as_deref(), session.payment_status.as_str()) {
+        (Some("expired"), _) => PurchaseStateValue::Canceled,
+        (_, "paid" | "no_payment_required") => PurchaseStateValue::Purchased,
+        _ => PurchaseStateValue::Pending,
+    };
+    let refunded = charge.is_some_and(|charge| charge.refunded);
+    let order_id = match (&session.subscription, &session.payment_intent) {
+        (Some(Expandable::Id(id)), _) | (None, Some(Expandable::Id(id))) => Some(id.clone()),
+        (Some(Expandable::Object(subscription)), _) => Some(subscription.id.clone()),
+        (None, Some(Expandable::Object(intent))) => Some(intent.id.clone()),
+        (None, None) => None,
+    };
+
+    Some(Purchase {
+        order_id,
+        package_name: package_name.to_string(),
+        product_id,
+        purchase_time: session.created * 1000,
+        purchase_token: session.id.clone(),
+        purchase_state: purchase_state as i32,
+        is_auto_renewing: subscription.is_some_and(|subscription| {
+            is_active(subscription) && !subscription.cancel_at_period_end
+        }),
+        // Checkout charges right away, there is nothing to acknowledge
+        is_acknowledged: true,
+        original_json: serde_json::to_string(session).unwrap_or_default(),
+        signature: String::new(),
+        // Stripe has no refund date on the charge, this is when the plugin noticed
+        revocation_date: refunded.then(crate::now_millis),
+        revocation_reason: refunded.then_some(RevocationReason::Other),
+        delivery: None,
+        fulfilled: false,
+    })
+}
+
+/// Whether the session still grants its product: paid, not refunded and, for subscriptions,
+/// not ended.
+pub(crate) fn session_is_current(session: &CheckoutSession) -> bool {
+    match session.subscription.as_ref().and_then(Expandable::object) {
+        Some(subscription) => is_active(subscription),
+        None => true,
+    }
+}
+
+/// The status of a subscription as the product it was bought as.
+pub(crate) fn subscription_status(subscription: &Subscription) -> Option<ProductStatus> {
+    let is_owned = is_active(subscription);
+    Some(ProductStatus {
+        product_id: subscription_product_id(subscription)?,
+        is_owned,
+        purchase_state: Some(if is_owned {
+            PurchaseStateValue::Purchased
+        } else {
+            PurchaseStateValue::Canceled
+        }),
+        purchase_time: Some(subscription.created * 1000),
+        expiration_time: period_end(subscription),
+        is_auto_renewing: Some(is_owned && !subscription.cancel_at_period_end),
+        is_acknowledged: Some(true),
+        purchase_token: Some(subscription.id.clone()),
+    })
+}
+
+/// The status of the product of a session, from its subscription if it has one.
+pub(crate) fn session_status(session: &CheckoutSession) -> Option<ProductStatus> {
+    if let Some(subscription) = session.subscription.as_ref().and_then(Expandable::object) {
+        return subscription_status(subscription).map(|status| ProductStatus {
+            product_id: session
+                .metadata
+                .get("product_id")
+                .cloned()
+                .unwrap_or(status.product_id),
+            purchase_token: Some(session.id.clone()),
+            ..status
+        });
+    }
+
+    let purchase = session_purchase(session, "")?;
+    let is_owned = purchase.purchase_state == PurchaseStateValue::Purchased as i32
+        && purchase.revocation_date.is_none();
+    Some(ProductStatus {
+        product_id: purchase.product_id,
+        is_owned,
+        purchase_state: Some(if is_owned {
+            PurchaseStateValue::Purchased
+        } else {
+            PurchaseStateValue::Canceled
+        }),
+        purchase_time: Some(purchase.purchase_time),
+        expiration_time: None,
+        is_auto_renewing: Some(false),
+        is_acknowledged: Some(true),
+        purchase_token: Some(purchase.purchase_token),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::stand_in::StandIn;
+
+    fn price(unit_amount: i64, currency: &str, recurring: Option<serde_json::Value>) -> Price {
+        serde_json::from_value(json!({
+            "id": "price_1",
+            "unit_amount": unit_amount,
+            "currency": currency,
+            "recurring": recurring,
+            "product": { "name": "Premium", "description": "Everything" },
+        }))
+        .unwrap()
+    }
+
+    fn session(extra: serde_json::Value) -> CheckoutSession {
+        let mut session = json!({
+            "id": "cs_1",
+            "status": "complete",
+            "payment_status": "paid",
+            "mode": "payment",
+            "created": 1_700_000_000,
+            "metadata": { "product_id": "coins" },
+        });
+        session
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(session).unwrap()
+    }
+
+    fn subscription(status: &str) -> serde_json::Value {
+        json!({
+            "id": "sub_1",
+            "status": status,
+            "customer": "cus_1",
+            "created": 1_700_000_000,
+            "items": { "data": [
+                { "price": { "id": "price_monthly" }, "current_period_end": 1_702_592_000 },
+            ]},
+        })
+    }
+
+    #[test]
+    fn products_are_priced_in_the_minor_units_of_their_currency() {
+        let product = to_product(
+            "premium",
+            "subs",
+            &price(
+                999,
+                "usd",
+                Some(json!({ "interval": "month", "interval_count": 1 })),
+            ),
+        );
+        assert_eq!(product.title, "Premium");
+        assert_eq!(product.formatted_price.as_deref(), Some("9.99 USD"));
+        assert_eq!(product.price_amount_micros, Some(9_990_000));
+        let offers = product.subscription_offer_details.unwrap();
+        assert_eq!(offers[0].offer_token, "price_1");
+        assert_eq!(offers[0].pricing_phases[0].billing_period, "P1M");
+
+        let product = to_product("coins", "inapp", &price(500, "jpy", None));
+        assert_eq!(product.formatted_price.as_deref(), Some("500 JPY"));
+        assert_eq!(product.price_amount_micros, Some(500_000_000));
+        assert!(product.subscription_offer_details.is_none());
+    }
+
+    #[test]
+    fn sessions_become_purchases_in_their_payment_state() {
+        let purchase = session_purchase(
+            &session(json!({ "payment_intent": "pi_1" })),
+            "com.example.app",
+        )
+        .unwrap();
+        assert_eq!(purchase.purchase_token, "cs_1");
+        assert_eq!(purchase.order_id.as_deref(), Some("pi_1"));
+        assert_eq!(purchase.purchase_time, 1_700_000_000_000);
+        assert_eq!(
+            purchase.purchase_state,
+            PurchaseStateValue::Purchased as i32
+        );
+        assert!(purchase.revocation_date.is_none());
+
+        let unpaid = session(json!({ "payment_status": "unpaid" }));
+        assert_eq!(
+            session_purchase(&unpaid, "").unwrap().purchase_state,
+            PurchaseStateValue::Pending as i32
+        );
+        let expired = session(json!({ "status": "expired", "payment_status": "unpaid" }));
+        assert_eq!(
+            session_purchase(&expired, "").unwrap().purchase_state,
+            PurchaseStateValue::Canceled as i32
+        );
+        assert!(session_purchase(&session(json!({ "metadata": {} })), "").is_none());
+    }
+
+    #[test]
+    fn refunded_charges_revoke_their_purchase() {
+        let refunded = session(json!({ "payment_intent": {
+            "id": "pi_1",
+            "latest_charge": { "id": "ch_1", "refunded": true, "amount_refunded": 500 },
+        }}));
+
+        let purchase = session_purchase(&refunded, "").unwrap();
+        assert_eq!(purchase.revocation_reason, Some(RevocationReason::Other));
+        let status = session_status(&refunded).unwrap();
+        assert!(!status.is_owned);
+        assert_eq!(status.purchase_state, Some(PurchaseStateValue::Canceled));
+    }
+
+    #[test]
+    fn subscriptions_are_owned_while_active() {
+        let active: Subscription = serde_json::from_value(subscription("trialing")).unwrap();
+        let status = subscription_status(&active).unwrap();
+        assert_eq!(status.product_id, "price_monthly");
+        assert!(status.is_owned);
+        assert_eq!(status.is_auto_renewing, Some(true));
+        assert_eq!(status.expiration_time, Some(1_702_592_000_000));
+
+        let ended: Subscription = serde_json::from_value(subscription("canceled")).unwrap();
+        let status = subscription_status(&ended).unwrap();
+        assert!(!status.is_owned);
+        assert_eq!(status.is_auto_renewing, Some(false));
+
+        let bought = session(json!({
+            "mode": "subscription",
+            "metadata": { "product_id": "premium" },
+            "subscription": subscription("active"),
+        }));
+        let status = session_status(&bought).unwrap();
+        assert_eq!(status.product_id, "premium");
+        assert_eq!(status.purchase_token.as_deref(), Some("cs_1"));
+        assert!(session_is_current(&bought));
+    }
+
+    #[test]
+    fn errors_carry_stripe_messages() {
+        let stripe = StandIn::answering(vec![(
+            400,
+            json!({ "error": { "message": "No such price: 'price_gone'" } }).to_string(),
+        )]);
+
+        let err = StripeApi::new(&stripe.url, "sk_test_1".into())
+            .price("price_gone")
+            .unwrap_err();
+
+        assert_eq!(err.code(), Some("stripeError"));
+        assert!(err.to_string().contains("No such price"));
+        assert_eq!(stripe.requests()[0].path(), "/v1/prices/price_gone");
+    }
+}
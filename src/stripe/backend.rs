@@ -0,0 +1,783 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Manager, Runtime};
+
+use super::checkout::{
+    BillingPortalResponse, CreateCheckoutSessionRequest, CreateCheckoutSessionResponse,
+    StripePurchasesResponse,
+};
+use crate::models::*;
+use crate::{now_millis, path_segment, StripeConfig};
+
+const STATE_FILE: &str = "iap-stripe.json";
+
+/// Delay between two looks for a connection to the loopback server.
+const LOOPBACK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a delayed payment, e.g. a bank debit, may stay unpaid before its session is
+/// given up. A failed payment leaves the session unpaid for good.
+const DELAYED_PAYMENT_WINDOW_MS: i64 = 14 * 24 * 60 * 60 * 1000;
+
+/// Stripe replaces this in the success URL with the id of the session.
+const SESSION_ID_TEMPLATE: &str = "{CHECKOUT_SESSION_ID}";
+
+pub fn init<R: Runtime>(app: &AppHandle<R>, config: Option<StripeConfig>) -> crate::Result<Iap<R>> {
+    let state_path = app
+        .path()
+        .app_data_dir()
+        .ok()
+        .map(|dir| dir.join(STATE_FILE));
+    Ok(Iap::with_path(app, config, state_path))
+}
+
+/// What the plugin keeps across launches.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StripeState {
+    /// Sessions started by `purchase` that did not end in a paid purchase yet.
+    open_sessions: Vec<OpenSession>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OpenSession {
+    session_id: String,
+    product_id: String,
+    created_at: i64,
+}
+
+/// How Checkout returned.
+enum Redirect {
+    Completed,
+    Canceled,
+}
+
+/// Access to the iap APIs, backed by Stripe Checkout.
+///
+/// A purchase asks the app's backend for a Checkout Session, opens it in the system browser
+/// and waits until Checkout returns through the `deep_link` or to a loopback HTTP server.
+/// The backend decides the price and the customer, see
+/// [`StripeCheckout`](super::StripeCheckout).
+pub struct Iap<R: Runtime> {
+    app: AppHandle<R>,
+    config: Option<StripeConfig>,
+    agent: ureq::Agent,
+    /// `Authorization` header of the requests to the backend.
+    authorization: Mutex<Option<String>>,
+    state_path: Option<PathBuf>,
+    state: Mutex<StripeState>,
+    /// Purchases waiting for a deep link, by the `state` parameter of their return URLs.
+    redirects: Mutex<HashMap<String, mpsc::Sender<Redirect>>>,
+}
+
+impl<R: Runtime> Iap<R> {
+    pub(crate) fn with_path(
+        app: &AppHandle<R>,
+        config: Option<StripeConfig>,
+        state_path: Option<PathBuf>,
+    ) -> Self {
+        let state = state_path
+            .as_ref()
+            .and_then(|path| std::fs::read(path).ok())
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+
+        Self {
+            app: app.clone(),
+            config,
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(10))
+                .build(),
+            authorization: Mutex::new(None),
+            state_path,
+            state: Mutex::new(state),
+            redirects: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Set the `Authorization` header the backend knows the signed-in user by, e.g.
+    /// `Bearer <session token>`.
+    pub fn set_authorization(&self, authorization: impl Into<String>) {
+        *self.authorization.lock().unwrap_or_else(|e| e.into_inner()) = Some(authorization.into());
+    }
+
+    /// Hand a deep link to the plugin, from the app's deep link handler. Returns whether it
+    /// was the return URL of a running purchase.
+    pub fn handle_checkout_redirect(&self, url: &str) -> bool {
+        let Some(state) = query_param(url, "state") else {
+            return false;
+        };
+        let redirect = if query_param(url, "canceled").is_some() {
+            Redirect::Canceled
+        } else {
+            Redirect::Completed
+        };
+
+        let sender = self
+            .redirects
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(state);
+        sender.is_some_and(|sender| sender.send(redirect).is_ok())
+    }
+
+    fn config(&self) -> crate::Result<&StripeConfig> {
+        self.config
+            .as_ref()
+            .filter(|config| config.backend_url.is_some())
+            .ok_or_else(|| {
+                crate::Error::rejected("notConfigured", "The `stripe.backendUrl` config is missing")
+            })
+    }
+
+    /// Call a checkout route of the backend.
+    fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> crate::Result<T> {
+        let config = self.config()?;
+        let backend_url = config.backend_url.as_deref().unwrap_or_default();
+        let mut request = self.agent.request(
+            method,
+            &format!("{}/{path}", backend_url.trim_end_matches('/')),
+        );
+        for (name, value) in &config.headers {
+            request = request.set(name, value);
+        }
+        if let Some(authorization) = &*self.authorization.lock().unwrap_or_else(|e| e.into_inner())
+        {
+            request = request.set("Authorization", authorization);
+        }
+
+        let response = match body {
+            Some(body) => request.send_json(body),
+            None => request.call(),
+        };
+        response
+            .map_err(|err| match err {
+                ureq::Error::Status(401 | 403, _) => {
+                    crate::Error::rejected("notSignedIn", "The backend does not know the user")
+                }
+                ureq::Error::Status(404, _) => {
+                    crate::Error::rejected("notFound", format!("{path} was not found"))
+                }
+                ureq::Error::Status(status, _) => crate::Error::rejected(
+                    "stripeError",
+                    format!("{path} was answered with {status}"),
+                ),
+                ureq::Error::Transport(err) => {
+                    crate::Error::rejected("networkError", err.to_string())
+                }
+            })?
+            .into_json()
+            .map_err(|err| {
+                crate::Error::rejected("stripeError", format!("Unexpected {path} response: {err}"))
+            })
+    }
+
+    /// A session the plugin started, as a purchase of this app.
+    fn session(&self, session_id: &str) -> crate::Result<Purchase> {
+        let mut purchase: Purchase = self.call(
+            "GET",
+            &format!("checkout-sessions/{}", path_segment(session_id)),
+            None,
+        )?;
+        purchase.package_name = self.app.package_info().name.clone();
+        Ok(purchase)
+    }
+
+    fn customer_purchases(&self, product_type: &str) -> crate::Result<StripePurchasesResponse> {
+        self.call(
+            "GET",
+            &format!("purchases?productType={}", path_segment(product_type)),
+            None,
+        )
+    }
+
+    pub fn initialize(&self) -> crate::Result<InitializeResponse> {
+        self.config()?;
+        Ok(InitializeResponse { success: true })
+    }
+
+    pub fn get_products(
+        &self,
+        product_ids: Vec<String>,
+        product_type: String,
+    ) -> crate::Result<GetProductsResponse> {
+        let mut response = self.query_products(&product_ids, &product_type)?;
+        crate::products::report_invalid_products(
+            &product_ids,
+            &product_type,
+            &mut response,
+            |ids, other_type| self.query_products(&ids, &other_type),
+        );
+        Ok(response)
+    }
+
+    fn query_products(
+        &self,
+        product_ids: &[String],
+        product_type: &str,
+    ) -> crate::Result<GetProductsResponse> {
+        let config = self.config()?;
+
+        let mut products = Vec::new();
+        for product_id in product_ids {
+            let listed = config
+                .products
+                .get(product_id)
+                .is_some_and(|product| product.product_type == product_type);
+            if listed {
+                products.push(self.call(
+                    "GET",
+                    &format!("products/{}", path_segment(product_id)),
+                    None,
+                )?);
+            }
+        }
+
+        Ok(GetProductsResponse {
+            products,
+            invalid_product_ids: Vec::new(),
+            failure_reasons: Default::default(),
+            storefront: None,
+        })
+    }
+
+    pub fn purchase(
+        &self,
+        product_id: String,
+        product_type: String,
+        _options: Option<PurchaseOptions>,
+    ) -> crate::Result<Purchase> {
+        let config = self.config()?;
+        config
+            .products
+            .get(&product_id)
+            .filter(|product| product.product_type == product_type)
+            .ok_or_else(|| crate::Error::rejected("productNotFound", "Product not found"))?;
+
+        // Checkout comes back to a deep link, or else to a loopback server on a free port
+        let state = random_hex();
+        let (return_url, listener) = match &config.deep_link {
+            Some(deep_link) => (deep_link.clone(), None),
+            None => {
+                let listener = TcpListener::bind("127.0.0.1:0")?;
+                let url = format!(
+                    "http://127.0.0.1:{}/checkout",
+                    listener.local_addr()?.port()
+                );
+                (url, Some(listener))
+            }
+        };
+        let separator = if return_url.contains('?') { '&' } else { '?' };
+
+        // The backend picks the price and the customer of the signed-in user
+        let session: CreateCheckoutSessionResponse = self.call(
+            "POST",
+            "checkout-sessions",
+            Some(
+                serde_json::to_value(CreateCheckoutSessionRequest {
+                    product_id: product_id.clone(),
+                    success_url: format!(
+                        "{return_url}{separator}state={state}&session_id={SESSION_ID_TEMPLATE}"
+                    ),
+                    cancel_url: format!("{return_url}{separator}state={state}&canceled=true"),
+                })
+                .unwrap_or_default(),
+            ),
+        )?;
+        self.update_state(|stripe_state| {
+            stripe_state.open_sessions.push(OpenSession {
+                session_id: session.session_id.clone(),
+                product_id: product_id.clone(),
+                created_at: now_millis(),
+            })
+        });
+
+        let receiver = listener.is_none().then(|| {
+            let (sender, receiver) = mpsc::channel();
+            self.redirects
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(state.clone(), sender);
+            receiver
+        });
+        if let Err(err) = open_browser(&session.url) {
+            self.abandon(&state, &session.session_id);
+            return Err(err);
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(config.checkout_timeout_secs);
+        let redirect = match (listener, receiver) {
+            (Some(listener), _) => wait_for_loopback(&listener, &state, deadline),
+            (None, Some(receiver)) => receiver
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .ok(),
+            (None, None) => None,
+        };
+
+        match redirect {
+            Some(Redirect::Completed) => {
+                let purchase = self.session(&session.session_id)?;
+                self.settle(&purchase);
+                Ok(purchase)
+            }
+            Some(Redirect::Canceled) => {
+                self.abandon(&state, &session.session_id);
+                Err(crate::Error::rejected(
+                    "userCanceled",
+                    "Checkout was canceled",
+                ))
+            }
+            None => {
+                self.abandon(&state, &session.session_id);
+                Err(crate::Error::rejected(
+                    "purchaseNotCompleted",
+                    "Checkout did not return in time",
+                ))
+            }
+        }
+    }
+
+    /// Expire a session that will not be paid and stop waiting for it.
+    fn abandon(&self, state: &str, session_id: &str) {
+        self.redirects
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(state);
+        // A session the user paid in the meantime cannot be expired, which is fine
+        let _ = self.call::<serde_json::Value>(
+            "POST",
+            &format!("checkout-sessions/{}/expire", path_segment(session_id)),
+            Some(serde_json::json!({})),
+        );
+        self.forget(session_id);
+    }
+
+    /// Forget a session once its payment went through or it expired.
+    fn settle(&self, purchase: &Purchase) {
+        if purchase.purchase_state != PurchaseStateValue::Pending as i32 {
+            self.forget(&purchase.purchase_token);
+        }
+    }
+
+    fn forget(&self, session_id: &str) {
+        self.update_state(|stripe_state| {
+            stripe_state
+                .open_sessions
+                .retain(|open| open.session_id != session_id)
+        });
+    }
+
+    pub fn restore_purchases(
+        &self,
+        product_type: String,
+    ) -> crate::Result<RestorePurchasesResponse> {
+        let package_name = &self.app.package_info().name;
+        let mut purchases = self.customer_purchases(&product_type)?.purchases;
+        for purchase in &mut purchases {
+            purchase.package_name = package_name.clone();
+        }
+        Ok(RestorePurchasesResponse { purchases })
+    }
+
+    pub fn get_unfinished_purchases(&self) -> crate::Result<GetUnfinishedPurchasesResponse> {
+        self.config()?;
+        let open_sessions = self
+            .state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .open_sessions
+            .clone();
+
+        let mut purchases = Vec::new();
+        for open in open_sessions {
+            let purchase = match self.session(&open.session_id) {
+                Ok(purchase) => purchase,
+                Err(err) if err.code() == Some("notFound") => {
+                    self.forget(&open.session_id);
+                    continue;
+                }
+                // Unreachable sessions are looked at again on the next launch
+                Err(_) => continue,
+            };
+            // Open sessions are expired by Stripe after a day at most, delayed payments
+            // are waited for a while longer
+            if purchase.purchase_state == PurchaseStateValue::Pending as i32 {
+                if now_millis() - open.created_at >= DELAYED_PAYMENT_WINDOW_MS {
+                    self.forget(&open.session_id);
+                }
+                continue;
+            }
+            self.settle(&purchase);
+            if purchase.purchase_state == PurchaseStateValue::Purchased as i32 {
+                purchases.push(purchase);
+            }
+        }
+        Ok(GetUnfinishedPurchasesResponse { purchases })
+    }
+
+    pub fn get_store_capabilities(&self) -> crate::Result<StoreCapabilities> {
+        Ok(StoreCapabilities {
+            store: StoreKind::Stripe,
+            can_make_payments: self.config().is_ok(),
+            storefront: None,
+            features: vec![StoreFeature::Subscriptions, StoreFeature::Consumables],
+        })
+    }
+
+    pub fn redeem_offer_code(
+        &self,
+        _code: Option<String>,
+    ) -> crate::Result<RedeemOfferCodeResponse> {
+        Err(crate::Error::rejected(
+            "notSupported",
+            "Promotion codes are entered in Checkout, see `allowPromotionCodes`",
+        ))
+    }
+
+    pub fn begin_refund_request(
+        &self,
+        _transaction_id: String,
+    ) -> crate::Result<BeginRefundRequestResponse> {
+        Err(crate::Error::rejected(
+            "notSupported",
+            "Stripe refunds are issued by the merchant",
+        ))
+    }
+
+    pub fn show_manage_subscriptions(
+        &self,
+        _product_id: Option<String>,
+    ) -> crate::Result<ShowManageSubscriptionsResponse> {
+        let portal: BillingPortalResponse =
+            self.call("POST", "billing-portal", Some(serde_json::json!({})))?;
+        Ok(ShowManageSubscriptionsResponse {
+            presented: open_browser(&portal.url).is_ok(),
+            url: Some(portal.url),
+        })
+    }
+
+    pub fn acknowledge_purchase(
+        &self,
+        _purchase_token: String,
+    ) -> crate::Result<AcknowledgePurchaseResponse> {
+        // Checkout charges right away, this method exists for API compatibility
+        Ok(AcknowledgePurchaseResponse {
+            success: true,
+            queued: false,
+        })
+    }
+
+    pub fn consume_purchase(
+        &self,
+        _purchase_token: String,
+    ) -> crate::Result<ConsumePurchaseResponse> {
+        // A one-time price can be bought again at any time, the app keeps track of grants
+        Ok(ConsumePurchaseResponse {
+            success: true,
+            queued: false,
+        })
+    }
+
+    pub fn get_product_status(
+        &self,
+        product_id: String,
+        product_type: String,
+    ) -> crate::Result<ProductStatus> {
+        let statuses = self.customer_purchases(&product_type)?.statuses;
+        Ok(product_status(&statuses, product_id))
+    }
+
+    pub fn get_products_status(
+        &self,
+        product_ids: Vec<String>,
+        product_type: String,
+    ) -> crate::Result<GetProductsStatusResponse> {
+        // One listing of the customer's purchases serves every requested product
+        let purchases = self.customer_purchases(&product_type)?;
+
+        let statuses = product_ids
+            .into_iter()
+            .map(|product_id| {
+                (
+                    product_id.clone(),
+                    product_status(&purchases.statuses, product_id),
+                )
+            })
+            .collect();
+        Ok(GetProductsStatusResponse { statuses })
+    }
+
+    fn update_state(&self, update: impl FnOnce(&mut StripeState)) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        update(&mut state);
+
+        let Some(path) = &self.state_path else {
+            return;
+        };
+        if let Ok(json) = serde_json::to_vec(&*state) {
+            if let Some(dir) = path.parent() {
+                let _ = std::fs::create_dir_all(dir);
+            }
+            let _ = std::fs::write(path, json);
+        }
+    }
+}
+
+/// The status of the newest session of the product, the one that owns it if any does.
+fn product_status(statuses: &[ProductStatus], product_id: String) -> ProductStatus {
+    statuses
+        .iter()
+        .filter(|status| status.product_id == product_id)
+        .max_by_key(|status| (status.is_owned, status.purchase_time))
+        .cloned()
+        .unwrap_or(ProductStatus {
+            product_id,
+            is_owned: false,
+            purchase_state: None,
+            purchase_time: None,
+            expiration_time: None,
+            is_auto_renewing: None,
+            is_acknowledged: None,
+            purchase_token: None,
+        })
+}
+
+fn open_browser(url: &str) -> crate::Result<()> {
+    std::process::Command::new("xdg-open")
+        .arg(url)
+        .spawn()
+        .map(|_| ())
+        .map_err(|err| {
+            crate::Error::rejected("browserUnavailable", format!("Opening {url} failed: {err}"))
+        })
+}
+
+/// Serve the loopback return URL until Checkout comes back with `state`.
+fn wait_for_loopback(listener: &TcpListener, state: &str, deadline: Instant) -> Option<Redirect> {
+    listener.set_nonblocking(true).ok()?;
+    while Instant::now() < deadline {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Some(redirect) = answer_loopback(stream, state) {
+                    return Some(redirect);
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                std::thread::sleep(LOOPBACK_POLL_INTERVAL)
+            }
+            Err(_) => return None,
+        }
+    }
+    None
+}
+
+/// Read the request line of a browser request and answer with a page to close.
+fn answer_loopback(stream: TcpStream, state: &str) -> Option<Redirect> {
+    let _ = stream.set_nonblocking(false);
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line).ok()?;
+
+    // `GET /checkout?state=...&session_id=... HTTP/1.1`
+    let target = request_line.split_whitespace().nth(1).unwrap_or_default();
+    let redirect = (query_param(target, "state") == Some(state)).then(|| {
+        if query_param(target, "canceled").is_some() {
+            Redirect::Canceled
+        } else {
+            Redirect::Completed
+        }
+    });
+
+    let (status, body) = match redirect {
+        Some(Redirect::Completed) => ("200 OK", "Payment complete. You can return to the app."),
+        Some(Redirect::Canceled) => ("200 OK", "Checkout canceled. You can return to the app."),
+        None => ("404 Not Found", "Not found."),
+    };
+    let _ = write!(
+        &stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    redirect
+}
+
+/// The value of a query parameter of a URL or request target. Values the plugin generates
+/// need no decoding.
+fn query_param<'a>(url: &'a str, name: &str) -> Option<&'a str> {
+    let (_, query) = url.split_once('?')?;
+    query
+        .split(['&', '#'])
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// A random value for the `state` parameter of the return URLs.
+fn random_hex() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_i64(now_millis());
+    format!("{:016x}", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tauri::test::{mock_app, MockRuntime};
+
+    use super::*;
+    use crate::stand_in::StandIn;
+
+    fn iap(backend_url: &str, name: &str) -> Iap<MockRuntime> {
+        let config = serde_json::from_value(json!({
+            "backendUrl": backend_url,
+            "headers": { "X-App-Key": "app" },
+            "products": {
+                "premium": {},
+                "coins": { "productType": "inapp" },
+            },
+        }))
+        .unwrap();
+        let path = std::env::temp_dir().join(format!("iap-stripe-{name}-{}.json", now_millis()));
+        Iap::with_path(mock_app().handle(), Some(config), Some(path))
+    }
+
+    fn purchase(session_id: &str, purchase_state: PurchaseStateValue) -> serde_json::Value {
+        json!({
+            "orderId": "pi_1",
+            "packageName": "",
+            "productId": "coins",
+            "purchaseTime": 1_700_000_000_000_i64,
+            "purchaseToken": session_id,
+            "purchaseState": purchase_state as i32,
+            "isAutoRenewing": false,
+            "isAcknowledged": true,
+            "originalJson": "{}",
+            "signature": "",
+        })
+    }
+
+    #[test]
+    fn asks_the_backend_as_the_signed_in_user() {
+        let backend = StandIn::start(|request| match request.path() {
+            "/products/coins" => (
+                200,
+                json!({
+                    "productId": "coins",
+                    "title": "Coins",
+                    "description": "",
+                    "productType": "inapp",
+                    "formattedPrice": "1.99 USD",
+                })
+                .to_string(),
+            ),
+            "/purchases" => (
+                200,
+                json!({
+                    "purchases": [purchase("cs_1", PurchaseStateValue::Purchased)],
+                    "statuses": [{
+                        "productId": "coins",
+                        "isOwned": true,
+                        "purchaseToken": "cs_1",
+                    }],
+                })
+                .to_string(),
+            ),
+            _ => (404, String::new()),
+        });
+        let iap = iap(&backend.url, "routes");
+        iap.set_authorization("Bearer user-1");
+
+        let products = iap
+            .get_products(vec!["coins".into()], "inapp".into())
+            .unwrap()
+            .products;
+        let restored = iap.restore_purchases("inapp".into()).unwrap().purchases;
+        let status = iap
+            .get_product_status("coins".into(), "inapp".into())
+            .unwrap();
+
+        assert_eq!(products[0].formatted_price.as_deref(), Some("1.99 USD"));
+        assert_eq!(restored[0].purchase_token, "cs_1");
+        assert_eq!(restored[0].package_name, iap.app.package_info().name);
+        assert!(status.is_owned);
+
+        let requests = backend.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[1].param("productType").as_deref(), Some("inapp"));
+        for request in requests.iter() {
+            assert_eq!(request.header("authorization"), Some("Bearer user-1"));
+            assert_eq!(request.header("x-app-key"), Some("app"));
+        }
+    }
+
+    #[test]
+    fn needs_the_backend_url() {
+        let iap = Iap::with_path(
+            mock_app().handle(),
+            Some(serde_json::from_value(json!({ "products": {} })).unwrap()),
+            None,
+        );
+
+        let err = iap.restore_purchases("subs".into()).unwrap_err();
+
+        assert_eq!(err.code(), Some("notConfigured"));
+    }
+
+    #[test]
+    fn settles_open_sessions() {
+        let backend = StandIn::start(|request| match request.path() {
+            "/checkout-sessions/cs_paid" => (
+                200,
+                purchase("cs_paid", PurchaseStateValue::Purchased).to_string(),
+            ),
+            "/checkout-sessions/cs_open" => (
+                200,
+                purchase("cs_open", PurchaseStateValue::Pending).to_string(),
+            ),
+            "/checkout-sessions/cs_expired" => (
+                200,
+                purchase("cs_expired", PurchaseStateValue::Canceled).to_string(),
+            ),
+            _ => (404, String::new()),
+        });
+        let iap = iap(&backend.url, "settle");
+        iap.update_state(|state| {
+            for session_id in ["cs_paid", "cs_open", "cs_expired", "cs_unknown"] {
+                state.open_sessions.push(OpenSession {
+                    session_id: session_id.into(),
+                    product_id: "coins".into(),
+                    created_at: now_millis(),
+                });
+            }
+        });
+
+        let purchases = iap.get_unfinished_purchases().unwrap().purchases;
+
+        assert_eq!(purchases.len(), 1);
+        assert_eq!(purchases[0].purchase_token, "cs_paid");
+        let open: Vec<String> = iap
+            .state
+            .lock()
+            .unwrap()
+            .open_sessions
+            .iter()
+            .map(|open| open.session_id.clone())
+            .collect();
+        assert_eq!(open, ["cs_open"]);
+    }
+}
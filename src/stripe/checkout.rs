@@ -0,0 +1,372 @@
+use serde::{Deserialize, Serialize};
+
+use super::api::{self, CheckoutSession, StripeApi};
+use crate::models::*;
+use crate::StripeConfig;
+
+/// The body of `POST /checkout-sessions`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateCheckoutSessionRequest {
+    pub product_id: String,
+    /// Where Checkout returns after the payment, with `{CHECKOUT_SESSION_ID}` in it.
+    pub success_url: String,
+    /// Where Checkout returns when the user cancels.
+    pub cancel_url: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateCheckoutSessionResponse {
+    pub session_id: String,
+    /// The Checkout page the app opens in the browser.
+    pub url: String,
+}
+
+/// The answer of `GET /purchases`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StripePurchasesResponse {
+    /// Paid purchases that still grant their product. Refunded payments stay with a
+    /// revocation date.
+    pub purchases: Vec<Purchase>,
+    /// The status of the product of every completed session, ended subscriptions included.
+    pub statuses: Vec<ProductStatus>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BillingPortalResponse {
+    pub url: String,
+}
+
+/// The backend side of Stripe Checkout, which holds the secret key.
+///
+/// The app never talks to Stripe. It calls these routes of the backend at `backendUrl`,
+/// which serves each of them for the Stripe customer of the user it signed in, and answers
+/// errors with the codes `productNotFound` and `purchaseNotFound` with `404`:
+/// - `GET /products/:product_id`: [`StripeCheckout::product`]
+/// - `POST /checkout-sessions`: [`StripeCheckout::create_session`]
+/// - `GET /checkout-sessions/:session_id`: [`StripeCheckout::session`]
+/// - `POST /checkout-sessions/:session_id/expire`: [`StripeCheckout::expire_session`]
+/// - `GET /purchases?productType=`: [`StripeCheckout::purchases`]
+/// - `POST /billing-portal`: [`StripeCheckout::billing_portal`]
+///
+/// Calls block.
+pub struct StripeCheckout {
+    config: StripeConfig,
+    api: StripeApi,
+}
+
+impl StripeCheckout {
+    /// Fails with `invalidKey` when the config has no `secret_key`.
+    pub fn from_config(config: &StripeConfig) -> crate::Result<Self> {
+        let secret_key = config.secret_key.clone().ok_or_else(|| {
+            crate::Error::rejected("invalidKey", "No Stripe secret key is configured")
+        })?;
+
+        Ok(Self {
+            config: config.clone(),
+            api: StripeApi::new(&config.api_base, secret_key),
+        })
+    }
+
+    /// A new Stripe customer for an account of the app, to keep with the account.
+    pub fn create_customer(&self, account_id: &str) -> crate::Result<String> {
+        self.api.create_customer(account_id)
+    }
+
+    /// A product of the config with its price.
+    pub fn product(&self, product_id: &str) -> crate::Result<Product> {
+        let product = self.config_product(product_id)?;
+        let price = self.api.price(&product.price_id)?;
+        Ok(api::to_product(product_id, &product.product_type, &price))
+    }
+
+    /// A Checkout Session of the customer for a product of the config.
+    pub fn create_session(
+        &self,
+        customer: &str,
+        request: &CreateCheckoutSessionRequest,
+    ) -> crate::Result<CreateCheckoutSessionResponse> {
+        let product = self.config_product(&request.product_id)?;
+        let subscription = product.product_type == "subs";
+        let mut params = vec![
+            (
+                "mode",
+                if subscription {
+                    "subscription"
+                } else {
+                    "payment"
+                }
+                .to_string(),
+            ),
+            ("customer", customer.to_string()),
+            ("line_items[0][price]", product.price_id.clone()),
+            ("line_items[0][quantity]", "1".to_string()),
+            ("success_url", request.success_url.clone()),
+            ("cancel_url", request.cancel_url.clone()),
+            ("metadata[product_id]", request.product_id.clone()),
+        ];
+        if subscription {
+            params.push((
+                "subscription_data[metadata][product_id]",
+                request.product_id.clone(),
+            ));
+        } else {
+            params.push((
+                "payment_intent_data[metadata][product_id]",
+                request.product_id.clone(),
+            ));
+        }
+        if self.config.allow_promotion_codes {
+            params.push(("allow_promotion_codes", "true".to_string()));
+        }
+
+        let session = self.api.create_checkout_session(&params)?;
+        let url = session.url.ok_or_else(|| {
+            crate::Error::rejected("stripeError", "The Checkout Session has no URL")
+        })?;
+        Ok(CreateCheckoutSessionResponse {
+            session_id: session.id,
+            url,
+        })
+    }
+
+    /// The purchase of a session of the customer, pending until it is paid and canceled once
+    /// it expired.
+    pub fn session(&self, customer: &str, session_id: &str) -> crate::Result<Purchase> {
+        let session = self.customer_session(customer, session_id)?;
+        api::session_purchase(&session, "").ok_or_else(|| {
+            crate::Error::rejected("purchaseNotFound", "The Checkout Session has no product")
+        })
+    }
+
+    /// Expire an open session of the customer. A session that was paid in the meantime
+    /// cannot be expired.
+    pub fn expire_session(&self, customer: &str, session_id: &str) -> crate::Result<()> {
+        self.customer_session(customer, session_id)?;
+        self.api.expire_checkout_session(session_id)
+    }
+
+    /// The purchases of the customer whose product is of the given type.
+    pub fn purchases(
+        &self,
+        customer: &str,
+        product_type: &str,
+    ) -> crate::Result<StripePurchasesResponse> {
+        let mut sessions = self.api.checkout_sessions(customer)?;
+        sessions.retain(|session| {
+            session
+                .metadata
+                .get("product_id")
+                .and_then(|product_id| self.config.products.get(product_id))
+                .is_some_and(|product| product.product_type == product_type)
+        });
+
+        Ok(StripePurchasesResponse {
+            purchases: sessions
+                .iter()
+                .filter(|session| api::session_is_current(session))
+                .filter_map(|session| api::session_purchase(session, ""))
+                .collect(),
+            statuses: sessions.iter().filter_map(api::session_status).collect(),
+        })
+    }
+
+    /// A customer portal session, where the customer manages subscriptions.
+    pub fn billing_portal(&self, customer: &str) -> crate::Result<BillingPortalResponse> {
+        let url = self.api.billing_portal_url(customer)?;
+        Ok(BillingPortalResponse { url })
+    }
+
+    fn config_product(&self, product_id: &str) -> crate::Result<&crate::StripeProduct> {
+        self.config
+            .products
+            .get(product_id)
+            .ok_or_else(|| crate::Error::rejected("productNotFound", "Product not found"))
+    }
+
+    /// A session, if it belongs to the customer.
+    fn customer_session(&self, customer: &str, session_id: &str) -> crate::Result<CheckoutSession> {
+        let session = self.api.checkout_session(session_id)?;
+        if session.customer.as_deref() != Some(customer) {
+            return Err(crate::Error::rejected(
+                "purchaseNotFound",
+                "The Checkout Session belongs to another customer",
+            ));
+        }
+        Ok(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::stand_in::StandIn;
+
+    fn checkout(api_base: &str) -> StripeCheckout {
+        let config: StripeConfig = serde_json::from_value(json!({
+            "apiBase": api_base,
+            "secretKey": "sk_test_1",
+            "allowPromotionCodes": true,
+            "products": {
+                "premium": { "priceId": "price_monthly" },
+                "coins": { "priceId": "price_coins", "productType": "inapp" },
+            },
+        }))
+        .unwrap();
+        StripeCheckout::from_config(&config).unwrap()
+    }
+
+    fn session(id: &str, customer: &str, product_id: &str) -> serde_json::Value {
+        json!({
+            "id": id,
+            "status": "complete",
+            "payment_status": "paid",
+            "mode": "payment",
+            "created": 1_700_000_000,
+            "customer": customer,
+            "metadata": { "product_id": product_id },
+        })
+    }
+
+    #[test]
+    fn needs_the_secret_key() {
+        let config: StripeConfig =
+            serde_json::from_value(json!({ "backendUrl": "https://api.example.com" })).unwrap();
+
+        let err = StripeCheckout::from_config(&config).err().unwrap();
+
+        assert_eq!(err.code(), Some("invalidKey"));
+    }
+
+    #[test]
+    fn creates_sessions_with_the_configured_price_for_the_customer() {
+        let stripe = StandIn::answering(vec![(
+            200,
+            json!({
+                "id": "cs_1",
+                "url": "https://checkout.stripe.com/c/cs_1",
+                "payment_status": "unpaid",
+                "mode": "subscription",
+                "created": 1_700_000_000,
+            })
+            .to_string(),
+        )]);
+
+        let session = checkout(&stripe.url)
+            .create_session(
+                "cus_1",
+                &CreateCheckoutSessionRequest {
+                    product_id: "premium".into(),
+                    success_url: "myapp://checkout?session_id={CHECKOUT_SESSION_ID}".into(),
+                    cancel_url: "myapp://checkout?canceled=true".into(),
+                },
+            )
+            .unwrap();
+
+        assert_eq!(session.session_id, "cs_1");
+        assert_eq!(session.url, "https://checkout.stripe.com/c/cs_1");
+        let requests = stripe.requests();
+        let request = &requests[0];
+        assert_eq!(request.path(), "/v1/checkout/sessions");
+        assert_eq!(request.header("authorization"), Some("Bearer sk_test_1"));
+        assert_eq!(request.param("customer").as_deref(), Some("cus_1"));
+        assert_eq!(request.param("mode").as_deref(), Some("subscription"));
+        assert_eq!(
+            request.param("line_items[0][price]").as_deref(),
+            Some("price_monthly")
+        );
+        assert_eq!(
+            request
+                .param("subscription_data[metadata][product_id]")
+                .as_deref(),
+            Some("premium")
+        );
+        assert_eq!(
+            request.param("allow_promotion_codes").as_deref(),
+            Some("true")
+        );
+    }
+
+    #[test]
+    fn unknown_products_are_not_sold() {
+        let stripe = StandIn::answering(Vec::new());
+
+        let err = checkout(&stripe.url)
+            .create_session(
+                "cus_1",
+                &CreateCheckoutSessionRequest {
+                    product_id: "price_cheap".into(),
+                    success_url: String::new(),
+                    cancel_url: String::new(),
+                },
+            )
+            .unwrap_err();
+
+        assert_eq!(err.code(), Some("productNotFound"));
+        assert!(stripe.requests().is_empty());
+    }
+
+    #[test]
+    fn sessions_of_other_customers_are_not_found() {
+        let stripe = StandIn::answering(vec![
+            (200, session("cs_1", "cus_2", "coins").to_string()),
+            (200, session("cs_1", "cus_2", "coins").to_string()),
+        ]);
+        let checkout = checkout(&stripe.url);
+
+        let err = checkout.session("cus_1", "cs_1").unwrap_err();
+        assert_eq!(err.code(), Some("purchaseNotFound"));
+        let err = checkout.expire_session("cus_1", "cs_1").unwrap_err();
+        assert_eq!(err.code(), Some("purchaseNotFound"));
+
+        let requests = stripe.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|request| request.method == "GET"));
+    }
+
+    #[test]
+    fn returns_the_session_of_the_customer_as_a_purchase() {
+        let stripe = StandIn::answering(vec![(200, session("cs_1", "cus_1", "coins").to_string())]);
+
+        let purchase = checkout(&stripe.url).session("cus_1", "cs_1").unwrap();
+
+        assert_eq!(purchase.purchase_token, "cs_1");
+        assert_eq!(purchase.product_id, "coins");
+        assert_eq!(
+            purchase.purchase_state,
+            PurchaseStateValue::Purchased as i32
+        );
+    }
+
+    #[test]
+    fn lists_the_purchases_of_the_customer() {
+        let stripe = StandIn::answering(vec![(
+            200,
+            json!({
+                "data": [
+                    session("cs_1", "cus_1", "coins"),
+                    session("cs_2", "cus_1", "premium"),
+                    session("cs_3", "cus_1", "retired"),
+                ],
+                "has_more": false,
+            })
+            .to_string(),
+        )]);
+
+        let response = checkout(&stripe.url).purchases("cus_1", "inapp").unwrap();
+
+        assert_eq!(response.purchases.len(), 1);
+        assert_eq!(response.purchases[0].purchase_token, "cs_1");
+        assert_eq!(response.statuses.len(), 1);
+        assert!(response.statuses[0].is_owned);
+        assert_eq!(
+            stripe.requests()[0].param("customer").as_deref(),
+            Some("cus_1")
+        );
+    }
+}
@@ -0,0 +1,23 @@
+//! Stripe Checkout for builds distributed outside the app stores.
+//!
+//! On Linux without the `steam` feature, purchases go through Checkout Sessions opened in
+//! the system browser. The app never holds the secret key: it calls the app's backend, which
+//! serves the routes of [`StripeCheckout`] for the signed-in user. [`parse_event`] turns
+//! webhook requests into purchases and subscription statuses for the backend that fulfills
+//! them.
+
+mod api;
+#[cfg(all(target_os = "linux", feature = "plugin", not(feature = "steam")))]
+mod backend;
+mod checkout;
+mod webhook;
+
+#[cfg(all(target_os = "linux", feature = "plugin", not(feature = "steam")))]
+pub(crate) use backend::init;
+#[cfg(all(target_os = "linux", feature = "plugin", not(feature = "steam")))]
+pub use backend::Iap;
+pub use checkout::{
+    BillingPortalResponse, CreateCheckoutSessionRequest, CreateCheckoutSessionResponse,
+    StripeCheckout, StripePurchasesResponse,
+};
+pub use webhook::{parse_event, WebhookEvent, WebhookUpdate};
@@ -0,0 +1,344 @@
+use serde::Deserialize;
+
+use super::api::{self, Charge, CheckoutSession, Subscription};
+use crate::hmac_sha256;
+use crate::models::*;
+use crate::now_millis;
+
+/// Seconds a signature may be old, the tolerance of Stripe's own libraries.
+const TOLERANCE_SECS: i64 = 300;
+
+#[derive(Deserialize)]
+struct RawEvent {
+    id: String,
+    #[serde(rename = "type")]
+    event_type: String,
+    created: i64,
+    data: RawEventData,
+}
+
+#[derive(Deserialize)]
+struct RawEventData {
+    object: serde_json::Value,
+}
+
+/// A verified webhook event.
+#[derive(Debug, Clone)]
+pub struct WebhookEvent {
+    pub id: String,
+    /// The Stripe event type, e.g. `checkout.session.completed`.
+    pub event_type: String,
+    /// Milliseconds since the Unix epoch.
+    pub created: i64,
+    pub update: WebhookUpdate,
+}
+
+/// What an event changes.
+#[derive(Debug, Clone)]
+pub enum WebhookUpdate {
+    /// A Checkout Session completed, or its delayed payment succeeded or failed.
+    Purchase(Purchase),
+    /// A subscription was created, changed or ended.
+    Subscription {
+        customer: String,
+        status: ProductStatus,
+    },
+    /// A charge was refunded, in full or in part.
+    Refund {
+        payment_intent: Option<String>,
+        charge: String,
+        /// In the smallest unit of the currency.
+        amount_refunded: i64,
+        /// Whether the whole charge was refunded.
+        refunded: bool,
+    },
+    /// An event type without a mapping.
+    Other,
+}
+
+/// Verify the `Stripe-Signature` header of a webhook request against the endpoint secret
+/// and parse its body.
+///
+/// `payload` is the raw request body. Fails with `invalidSignature` when the signature does
+/// not match or is older than five minutes, and with `invalidEvent` when the body is not an
+/// event.
+pub fn parse_event(
+    payload: &str,
+    signature_header: &str,
+    secret: &str,
+) -> crate::Result<WebhookEvent> {
+    verify_signature(payload, signature_header, secret, now_millis() / 1000)?;
+
+    let invalid = |err: serde_json::Error| crate::Error::rejected("invalidEvent", err.to_string());
+    let event: RawEvent = serde_json::from_str(payload).map_err(invalid)?;
+    let object = event.data.object;
+
+    let update = match event.event_type.as_str() {
+        "checkout.session.completed"
+        | "checkout.session.async_payment_succeeded"
+        | "checkout.session.async_payment_failed" => {
+            let session: CheckoutSession = serde_json::from_value(object).map_err(invalid)?;
+            match api::session_purchase(&session, "") {
+                Some(mut purchase) => {
+                    if event.event_type == "checkout.session.async_payment_failed" {
+                        purchase.purchase_state = PurchaseStateValue::Canceled as i32;
+                    }
+                    WebhookUpdate::Purchase(purchase)
+                }
+                None => WebhookUpdate::Other,
+            }
+        }
+        "customer.subscription.created"
+        | "customer.subscription.updated"
+        | "customer.subscription.deleted" => {
+            let subscription: Subscription = serde_json::from_value(object).map_err(invalid)?;
+            match api::subscription_status(&subscription) {
+                Some(status) => WebhookUpdate::Subscription {
+                    customer: subscription.customer,
+                    status,
+                },
+                None => WebhookUpdate::Other,
+            }
+        }
+        "charge.refunded" => {
+            let charge: Charge = serde_json::from_value(object).map_err(invalid)?;
+            WebhookUpdate::Refund {
+                payment_intent: charge.payment_intent,
+                charge: charge.id,
+                amount_refunded: charge.amount_refunded,
+                refunded: charge.refunded,
+            }
+        }
+        _ => WebhookUpdate::Other,
+    };
+
+    Ok(WebhookEvent {
+        id: event.id,
+        event_type: event.event_type,
+        created: event.created * 1000,
+        update,
+    })
+}
+
+/// The header is `t=<timestamp>,v1=<signature>`, with one `v1` per active endpoint secret.
+fn verify_signature(payload: &str, header: &str, secret: &str, now_secs: i64) -> crate::Result<()> {
+    let invalid = |reason: &str| crate::Error::rejected("invalidSignature", reason.to_string());
+
+    let mut timestamp = None;
+    let mut signatures = Vec::new();
+    for part in header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", value)) => timestamp = value.parse::<i64>().ok(),
+            Some(("v1", value)) => signatures.push(value),
+            _ => {}
+        }
+    }
+    let timestamp = timestamp.ok_or_else(|| invalid("The signature has no timestamp"))?;
+
+    let signed = format!("{timestamp}.{payload}");
+    if !signatures
+        .iter()
+        .any(|signature| hmac_sha256::verify(secret.as_bytes(), signed.as_bytes(), signature))
+    {
+        return Err(invalid("The signature does not match"));
+    }
+    if (now_secs - timestamp).abs() > TOLERANCE_SECS {
+        return Err(invalid("The signature is too old"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const SECRET: &str = "whsec_test_secret";
+    const TIMESTAMP: i64 = 1_492_774_577;
+    const PAYLOAD: &str = r#"{"id":"evt_1","type":"checkout.session.completed","created":1492774577,"data":{"object":{"id":"cs_1","status":"complete","payment_status":"paid","mode":"payment","created":1492774500,"metadata":{"product_id":"coins"}}}}"#;
+    /// HMAC-SHA256 of `{TIMESTAMP}.{PAYLOAD}` under [`SECRET`], as Stripe signs webhooks.
+    const SIGNATURE: &str = "8649254fb4e85e362120085968d3b3ef2135067be3f2de320d73392359ba6e19";
+    /// The same under a rolled endpoint secret.
+    const OLD_SIGNATURE: &str = "e5999b5b205675bdf5ab6d1f98c9903532a7ee7955032874fab56822a396dc87";
+
+    fn header(signatures: &[&str]) -> String {
+        let mut header = format!("t={TIMESTAMP}");
+        for signature in signatures {
+            header.push_str(&format!(",v1={signature}"));
+        }
+        header
+    }
+
+    fn verify(payload: &str, header: &str, now_secs: i64) -> Option<String> {
+        verify_signature(payload, header, SECRET, now_secs)
+            .err()
+            .map(|err| err.code().unwrap_or_default().to_string())
+    }
+
+    #[test]
+    fn accepts_a_valid_signature() {
+        assert_eq!(verify(PAYLOAD, &header(&[SIGNATURE]), TIMESTAMP), None);
+        assert_eq!(
+            verify(
+                PAYLOAD,
+                &format!("t={TIMESTAMP}, v1={SIGNATURE}, v0=6ffbb59b2300aae63f272406069a9788598b792a944a07aba816edb039989a39"),
+                TIMESTAMP + 10,
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_a_tampered_payload() {
+        let tampered = PAYLOAD.replace("coins", "gems");
+
+        assert_eq!(
+            verify(&tampered, &header(&[SIGNATURE]), TIMESTAMP).as_deref(),
+            Some("invalidSignature")
+        );
+        assert_eq!(
+            verify(
+                PAYLOAD,
+                &format!("t={},v1={SIGNATURE}", TIMESTAMP + 1),
+                TIMESTAMP
+            )
+            .as_deref(),
+            Some("invalidSignature")
+        );
+        assert_eq!(
+            verify(PAYLOAD, &format!("v1={SIGNATURE}"), TIMESTAMP).as_deref(),
+            Some("invalidSignature")
+        );
+    }
+
+    #[test]
+    fn rejects_a_timestamp_outside_the_tolerance() {
+        let header = header(&[SIGNATURE]);
+
+        assert_eq!(verify(PAYLOAD, &header, TIMESTAMP + TOLERANCE_SECS), None);
+        assert_eq!(
+            verify(PAYLOAD, &header, TIMESTAMP + TOLERANCE_SECS + 1).as_deref(),
+            Some("invalidSignature")
+        );
+        assert_eq!(
+            verify(PAYLOAD, &header, TIMESTAMP - TOLERANCE_SECS - 1).as_deref(),
+            Some("invalidSignature")
+        );
+    }
+
+    #[test]
+    fn accepts_any_of_several_signatures() {
+        let unrelated = "0".repeat(64);
+
+        assert_eq!(
+            verify(PAYLOAD, &header(&[OLD_SIGNATURE, SIGNATURE]), TIMESTAMP),
+            None
+        );
+        assert_eq!(
+            verify(PAYLOAD, &header(&[SIGNATURE, &unrelated]), TIMESTAMP),
+            None
+        );
+        assert_eq!(
+            verify(PAYLOAD, &header(&[OLD_SIGNATURE, &unrelated]), TIMESTAMP).as_deref(),
+            Some("invalidSignature")
+        );
+    }
+
+    /// Sign `payload` the way Stripe does, timestamped now.
+    fn signed(payload: &serde_json::Value) -> (String, String) {
+        let payload = payload.to_string();
+        let timestamp = now_millis() / 1000;
+        let signature = hmac_sha256::hex(
+            SECRET.as_bytes(),
+            format!("{timestamp}.{payload}").as_bytes(),
+        );
+        (payload, format!("t={timestamp},v1={signature}"))
+    }
+
+    #[test]
+    fn parses_completed_sessions_and_failed_payments() {
+        let (payload, header) = signed(&serde_json::from_str(PAYLOAD).unwrap());
+        let event = parse_event(&payload, &header, SECRET).unwrap();
+
+        assert_eq!(event.id, "evt_1");
+        assert_eq!(event.created, TIMESTAMP * 1000);
+        let WebhookUpdate::Purchase(purchase) = event.update else {
+            panic!("expected a purchase, got {:?}", event.update);
+        };
+        assert_eq!(purchase.product_id, "coins");
+        assert_eq!(purchase.purchase_token, "cs_1");
+        assert_eq!(
+            purchase.purchase_state,
+            PurchaseStateValue::Purchased as i32
+        );
+
+        let mut failed: serde_json::Value = serde_json::from_str(PAYLOAD).unwrap();
+        failed["type"] = json!("checkout.session.async_payment_failed");
+        let (payload, header) = signed(&failed);
+        let WebhookUpdate::Purchase(purchase) =
+            parse_event(&payload, &header, SECRET).unwrap().update
+        else {
+            panic!("expected a purchase");
+        };
+        assert_eq!(purchase.purchase_state, PurchaseStateValue::Canceled as i32);
+    }
+
+    #[test]
+    fn parses_subscription_changes_and_refunds() {
+        let (payload, header) = signed(&json!({
+            "id": "evt_2",
+            "type": "customer.subscription.deleted",
+            "created": TIMESTAMP,
+            "data": { "object": {
+                "id": "sub_1",
+                "status": "canceled",
+                "customer": "cus_1",
+                "created": TIMESTAMP,
+                "metadata": { "product_id": "premium" },
+                "items": { "data": [{ "price": { "id": "price_monthly" } }] },
+            }},
+        }));
+        let WebhookUpdate::Subscription { customer, status } =
+            parse_event(&payload, &header, SECRET).unwrap().update
+        else {
+            panic!("expected a subscription");
+        };
+        assert_eq!(customer, "cus_1");
+        assert_eq!(status.product_id, "premium");
+        assert!(!status.is_owned);
+
+        let (payload, header) = signed(&json!({
+            "id": "evt_3",
+            "type": "charge.refunded",
+            "created": TIMESTAMP,
+            "data": { "object": {
+                "id": "ch_1",
+                "payment_intent": "pi_1",
+                "refunded": false,
+                "amount_refunded": 500,
+            }},
+        }));
+        let WebhookUpdate::Refund {
+            payment_intent,
+            amount_refunded,
+            refunded,
+            ..
+        } = parse_event(&payload, &header, SECRET).unwrap().update
+        else {
+            panic!("expected a refund");
+        };
+        assert_eq!(payment_intent.as_deref(), Some("pi_1"));
+        assert_eq!(amount_refunded, 500);
+        assert!(!refunded);
+    }
+
+    #[test]
+    fn rejects_bodies_that_are_not_events() {
+        let (payload, header) = signed(&json!({ "id": "evt_4" }));
+
+        let err = parse_event(&payload, &header, SECRET).unwrap_err();
+
+        assert_eq!(err.code(), Some("invalidEvent"));
+    }
+}
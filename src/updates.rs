@@ -0,0 +1,65 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tauri::{AppHandle, Manager, Runtime};
+
+use crate::models::*;
+use crate::IapExt;
+
+/// Whether the native plugin emits `purchaseUpdated` for every purchase it completes. Only the
+/// mobile plugins do; elsewhere the plugin emits the purchases that arrive after their
+/// `purchase` call stopped waiting.
+pub(crate) const NATIVE_PURCHASE_EVENTS: bool = cfg!(mobile);
+
+/// Let the plugin's bookkeeping see purchases reported by the store.
+///
+/// `product_type` is known for purchases returned by a command and `None` for native events.
+pub(crate) fn observe<R: Runtime>(
+    app: &AppHandle<R>,
+    product_type: Option<&str>,
+    purchases: &[Purchase],
+) {
+    crate::ledger::record_purchases(app, product_type, purchases);
+    crate::pending::observe(app, product_type, purchases);
+    crate::acknowledgement::observe(app, purchases);
+    crate::currency::observe(app, purchases);
+    crate::hooks::observe(app, purchases);
+    crate::flow::observe(app, purchases);
+}
+
+/// A purchase that was not returned by a command, forwarded to the frontend.
+#[cfg(mobile)]
+pub(crate) fn purchase_updated<R: Runtime>(app: &AppHandle<R>, purchase: Purchase) {
+    observe(app, None, std::slice::from_ref(&purchase));
+    crate::delivery::emit(app, purchase);
+}
+
+/// Whether this launch already replayed the unfinished purchases.
+#[derive(Default)]
+pub(crate) struct Replay(AtomicBool);
+
+/// Emit a `purchaseUpdated` event for every purchase the store still considers unfinished,
+/// once per launch, then resume the purchase flows of earlier sessions.
+///
+/// Purchases that completed while the app was closed or whose flow was interrupted are
+/// otherwise only noticed when the app calls `restore_purchases`. The events of the replay are
+/// held until the frontend listens for them.
+pub(crate) fn replay_unfinished<R: Runtime>(app: &AppHandle<R>) {
+    if app.state::<Replay>().0.swap(true, Ordering::AcqRel) {
+        return;
+    }
+
+    let Ok(response) = app.iap().get_unfinished_purchases() else {
+        // Try again on the next `initialize`
+        app.state::<Replay>().0.store(false, Ordering::Release);
+        return;
+    };
+
+    crate::events::replay(app, || {
+        observe(app, None, &response.purchases);
+        for purchase in response.purchases {
+            crate::delivery::emit(app, purchase);
+        }
+        // Flows of earlier sessions only know their outcome once the replay attached purchases
+        crate::flow::resume(app);
+    });
+}
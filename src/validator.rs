@@ -0,0 +1,272 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tauri::{AppHandle, Manager, Runtime};
+
+use crate::models::*;
+use crate::ValidationConfig;
+
+/// Delay before the first retry, doubled for every further one.
+const RETRY_DELAY_MS: u64 = 500;
+
+/// Validates purchases on the app's own server.
+///
+/// Each purchase is POSTed as a [`ValidationRequest`] and the server answers with a
+/// [`ValidationVerdict`]. Network errors, `429` and `5xx` responses are retried with a
+/// growing delay. Plain `http://` URLs work, so a local stand-in can take the place of the
+/// server in tests.
+#[derive(Debug, Clone)]
+pub struct RemoteValidator {
+    url: String,
+    headers: Vec<(String, String)>,
+    account_id: Option<String>,
+    retries: u32,
+    agent: ureq::Agent,
+}
+
+impl RemoteValidator {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            headers: Vec::new(),
+            account_id: None,
+            retries: 2,
+            agent: agent(Duration::from_secs(10)),
+        }
+    }
+
+    pub fn from_config(config: &ValidationConfig) -> Self {
+        let mut validator = Self::new(config.url.clone())
+            .retries(config.retries)
+            .timeout(Duration::from_secs(config.timeout_secs));
+        validator.headers.extend(
+            config
+                .headers
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone())),
+        );
+        validator
+    }
+
+    /// Send a header with every request, e.g. `Authorization`.
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Claim purchases for this user of the app.
+    pub fn account_id(mut self, account_id: impl Into<String>) -> Self {
+        self.account_id = Some(account_id.into());
+        self
+    }
+
+    /// Attempts after the first one.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Timeout of each attempt.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.agent = agent(timeout);
+        self
+    }
+
+    /// Ask the server for its verdict on a purchase. Fails with `validationUnavailable` when
+    /// the server cannot be reached or keeps failing, and with `validationFailed` when it
+    /// answers with another error or a body that is not a verdict.
+    pub fn validate(&self, purchase: &Purchase) -> crate::Result<ValidationVerdict> {
+        let body = ValidationRequest {
+            account_id: self.account_id.clone(),
+            ..ValidationRequest::new(purchase)
+        };
+        let mut attempt = 0;
+        loop {
+            let mut request = self.agent.post(&self.url);
+            for (name, value) in &self.headers {
+                request = request.set(name, value);
+            }
+
+            let error = match request.send_json(&body) {
+                Ok(response) => {
+                    return response.into_json().map_err(|err| {
+                        crate::Error::rejected(
+                            "validationFailed",
+                            format!("The validation server sent no verdict: {err}"),
+                        )
+                    });
+                }
+                Err(ureq::Error::Status(status, _)) if status == 429 || status >= 500 => {
+                    format!("The validation server answered {status}")
+                }
+                Err(ureq::Error::Status(status, _)) => {
+                    return Err(crate::Error::rejected(
+                        "validationFailed",
+                        format!("The validation server answered {status}"),
+                    ));
+                }
+                Err(ureq::Error::Transport(err)) => err.to_string(),
+            };
+
+            if attempt == self.retries {
+                return Err(crate::Error::rejected("validationUnavailable", error));
+            }
+            std::thread::sleep(Duration::from_millis(RETRY_DELAY_MS << attempt.min(10)));
+            attempt += 1;
+        }
+    }
+}
+
+fn agent(timeout: Duration) -> ureq::Agent {
+    ureq::AgentBuilder::new().timeout(timeout).build()
+}
+
+/// The validator purchases are checked with, from the builder or the `validation` config.
+pub(crate) struct Validation(pub Option<Arc<RemoteValidator>>);
+
+/// The verdict on a purchase, or `None` without a validator.
+pub(crate) fn validate<R: Runtime>(
+    app: &AppHandle<R>,
+    purchase: &Purchase,
+) -> Option<crate::Result<ValidationVerdict>> {
+    let validator = app.state::<Validation>().0.clone()?;
+    Some(validator.validate(purchase))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stand_in::StandIn;
+
+    fn purchase() -> Purchase {
+        serde_json::from_value(serde_json::json!({
+            "orderId": "order",
+            "packageName": "app",
+            "productId": "coins",
+            "purchaseTime": 0,
+            "purchaseToken": "token",
+            "purchaseState": 0,
+            "isAutoRenewing": false,
+            "isAcknowledged": false,
+            "originalJson": "{}",
+            "signature": "signature",
+        }))
+        .unwrap()
+    }
+
+    fn answer(status: u16, body: serde_json::Value) -> (u16, String) {
+        (status, body.to_string())
+    }
+
+    #[test]
+    fn returns_the_verdict_of_the_server() {
+        let server = StandIn::answering(vec![answer(
+            200,
+            serde_json::json!({ "valid": false, "reason": "refunded", "expiresAt": 7 }),
+        )]);
+
+        let verdict = RemoteValidator::new(format!("{}/validate", server.url))
+            .validate(&purchase())
+            .unwrap();
+
+        assert!(!verdict.valid);
+        assert_eq!(verdict.reason.as_deref(), Some("refunded"));
+        assert_eq!(verdict.expires_at, Some(7));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].path(), "/validate");
+        let body: ValidationRequest = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(body.purchase_token, "token");
+        assert_eq!(body.product_id, "coins");
+        assert_eq!(body.signature, "signature");
+    }
+
+    #[test]
+    fn client_errors_fail_without_retrying() {
+        let server = StandIn::answering(vec![
+            answer(400, serde_json::json!({})),
+            answer(200, serde_json::json!({ "valid": true })),
+        ]);
+
+        let err = RemoteValidator::new(server.url.clone())
+            .validate(&purchase())
+            .unwrap_err();
+
+        assert_eq!(err.code(), Some("validationFailed"));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn a_body_that_is_not_a_verdict_fails() {
+        let server = StandIn::answering(vec![answer(200, serde_json::json!({ "ok": 1 }))]);
+
+        let err = RemoteValidator::new(server.url.clone())
+            .validate(&purchase())
+            .unwrap_err();
+
+        assert_eq!(err.code(), Some("validationFailed"));
+    }
+
+    #[test]
+    fn server_errors_and_throttling_are_retried_until_unavailable() {
+        let server = StandIn::answering(vec![
+            answer(503, serde_json::json!({})),
+            answer(429, serde_json::json!({})),
+            answer(200, serde_json::json!({ "valid": true })),
+        ]);
+
+        let err = RemoteValidator::new(server.url.clone())
+            .retries(1)
+            .validate(&purchase())
+            .unwrap_err();
+
+        assert_eq!(err.code(), Some("validationUnavailable"));
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[test]
+    fn a_retry_can_succeed() {
+        let server = StandIn::answering(vec![
+            answer(500, serde_json::json!({})),
+            answer(200, serde_json::json!({ "valid": true })),
+        ]);
+
+        let verdict = RemoteValidator::new(server.url.clone())
+            .retries(1)
+            .validate(&purchase())
+            .unwrap();
+
+        assert!(verdict.valid);
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[test]
+    fn forwards_headers_and_the_account() {
+        let server = StandIn::answering(vec![
+            answer(503, serde_json::json!({})),
+            answer(200, serde_json::json!({ "valid": true })),
+        ]);
+
+        RemoteValidator::from_config(&ValidationConfig {
+            url: server.url.clone(),
+            headers: [("Authorization".to_string(), "Bearer app".to_string())].into(),
+            retries: 1,
+            timeout_secs: 5,
+        })
+        .header("X-App-Version", "1.2.0")
+        .account_id("user-1")
+        .validate(&purchase())
+        .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        for request in requests.iter() {
+            assert_eq!(request.header("authorization"), Some("Bearer app"));
+            assert_eq!(request.header("x-app-version"), Some("1.2.0"));
+            let body: ValidationRequest = serde_json::from_str(&request.body).unwrap();
+            assert_eq!(body.account_id.as_deref(), Some("user-1"));
+        }
+    }
+}
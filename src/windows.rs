@@ -1,34 +1,62 @@
 use serde::de::DeserializeOwned;
 use tauri::Manager;
 use tauri::{plugin::PluginApi, AppHandle, Runtime};
-use windows::core::{Interface, HSTRING};
+use windows::core::{Interface, GUID, HSTRING};
 use windows::{
-    Foundation::DateTime,
+    Foundation::{DateTime, Uri},
     Services::Store::{
-        StoreContext, StoreLicense, StoreProduct, StorePurchaseProperties, StorePurchaseStatus,
+        StoreConsumableStatus, StoreContext, StoreDurationUnit, StoreLicense, StoreProduct,
+        StorePurchaseProperties, StorePurchaseStatus,
     },
+    System::Launcher,
     Win32::UI::Shell::IInitializeWithWindow,
 };
-use windows_collections::IIterable;
+use windows_collections::{IIterable, IMapView};
 
 use crate::error::{ErrorResponse, PluginInvokeError};
 use crate::models::*;
-use std::sync::{Arc, RwLock};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+
+const CONSUMABLES_FILE: &str = "iap-windows-consumables.json";
 
 pub fn init<R: Runtime, C: DeserializeOwned>(
     app: &AppHandle<R>,
     _api: PluginApi<R, C>,
 ) -> crate::Result<Iap<R>> {
+    let consumables_path = app
+        .path()
+        .app_data_dir()
+        .ok()
+        .map(|dir| dir.join(CONSUMABLES_FILE));
+    let consumables = consumables_path
+        .as_ref()
+        .and_then(|path| std::fs::read(path).ok())
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default();
+
     Ok(Iap {
         app_handle: app.clone(),
         store_context: Arc::new(RwLock::new(None)),
+        consumables_path,
+        consumables: Mutex::new(consumables),
     })
 }
 
 /// Access to the iap APIs.
+///
+/// The Store has no purchase id. Purchases of add-ons with a license (durables and
+/// subscriptions) use the license's SKU Store ID as their token, in `purchase` as in
+/// `restore_purchases`. Consumables are not restored, so each unfulfilled one gets a token
+/// of its own that is kept until its fulfillment is reported.
 pub struct Iap<R: Runtime> {
     app_handle: AppHandle<R>,
     store_context: Arc<RwLock<Option<StoreContext>>>,
+    consumables_path: Option<PathBuf>,
+    /// Tokens of unfulfilled consumables, by Store ID.
+    consumables: Mutex<HashMap<String, String>>,
 }
 
 impl<R: Runtime> Iap<R> {
@@ -104,6 +132,21 @@ impl<R: Runtime> Iap<R> {
         &self,
         product_ids: Vec<String>,
         product_type: String,
+    ) -> crate::Result<GetProductsResponse> {
+        let mut response = self.query_products(&product_ids, &product_type)?;
+        crate::products::report_invalid_products(
+            &product_ids,
+            &product_type,
+            &mut response,
+            |ids, other_type| self.query_products(&ids, &other_type),
+        );
+        Ok(response)
+    }
+
+    fn query_products(
+        &self,
+        product_ids: &[String],
+        product_type: &str,
     ) -> crate::Result<GetProductsResponse> {
         let context = self.get_store_context()?;
 
@@ -114,7 +157,7 @@ impl<R: Runtime> Iap<R> {
             .collect();
 
         // Determine product kinds based on type
-        let product_kinds: Vec<HSTRING> = match product_type.as_str() {
+        let product_kinds: Vec<HSTRING> = match product_type {
             "inapp" => vec![
                 HSTRING::from("Consumable"),
                 HSTRING::from("UnmanagedConsumable"),
@@ -160,13 +203,18 @@ impl<R: Runtime> Iap<R> {
             let item = iterator.Current()?;
             let store_product = item.Value()?;
 
-            let product = self.convert_store_product_to_product(&store_product, &product_type)?;
+            let product = self.convert_store_product_to_product(&store_product, product_type)?;
             products.push(product);
 
             iterator.MoveNext()?;
         }
 
-        Ok(GetProductsResponse { products })
+        Ok(GetProductsResponse {
+            products,
+            invalid_product_ids: Vec::new(),
+            failure_reasons: Default::default(),
+            storefront: None,
+        })
     }
 
     fn convert_store_product_to_product(
@@ -219,35 +267,42 @@ impl<R: Runtime> Iap<R> {
                 let subscription_info = sku.SubscriptionInfo();
 
                 if let Ok(info) = subscription_info {
-                    let billing_period = info.BillingPeriod()?;
-                    let billing_period_unit = info.BillingPeriodUnit()?;
-
-                    let billing_period_str = format!(
-                        "P{}{}",
-                        billing_period,
-                        match billing_period_unit.0 {
-                            0 => "D", // Day
-                            1 => "W", // Week
-                            2 => "M", // Month
-                            3 => "Y", // Year
-                            _ => "M",
-                        }
-                    );
-
-                    let pricing_phase = PricingPhase {
+                    let mut pricing_phases = Vec::new();
+
+                    // A trial is a free, non-renewing phase in front of the regular price
+                    if info.HasTrialPeriod()? {
+                        pricing_phases.push(PricingPhase {
+                            formatted_price: String::new(),
+                            price_currency_code: currency_code.clone(),
+                            price_amount_micros: 0,
+                            billing_period: Self::format_billing_period(
+                                info.TrialPeriod()?,
+                                info.TrialPeriodUnit()?,
+                            ),
+                            billing_cycle_count: 1,
+                            recurrence_mode: crate::offers::FINITE_RECURRING,
+                        });
+                    }
+
+                    pricing_phases.push(PricingPhase {
                         formatted_price: sku_price.FormattedPrice()?.to_string(),
                         price_currency_code: currency_code.clone(),
                         price_amount_micros,
-                        billing_period: billing_period_str,
+                        billing_period: Self::format_billing_period(
+                            info.BillingPeriod()?,
+                            info.BillingPeriodUnit()?,
+                        ),
                         billing_cycle_count: 0, // Windows doesn't provide this directly
-                        recurrence_mode: 1,     // Infinite recurring
-                    };
+                        recurrence_mode: crate::offers::INFINITE_RECURRING,
+                    });
 
                     let offer = SubscriptionOffer {
                         offer_token: sku_id.clone(),
                         base_plan_id: sku_id,
                         offer_id: None,
-                        pricing_phases: vec![pricing_phase],
+                        offer_kind: crate::offers::classify(&pricing_phases),
+                        is_eligible: true,
+                        pricing_phases,
                     };
 
                     offers.push(offer);
@@ -275,6 +330,18 @@ impl<R: Runtime> Iap<R> {
         })
     }
 
+    /// Format a Store duration as an ISO 8601 period, e.g. `P1M`
+    fn format_billing_period(value: u32, unit: StoreDurationUnit) -> String {
+        match unit {
+            StoreDurationUnit::Minute => format!("PT{value}M"),
+            StoreDurationUnit::Hour => format!("PT{value}H"),
+            StoreDurationUnit::Day => format!("P{value}D"),
+            StoreDurationUnit::Week => format!("P{value}W"),
+            StoreDurationUnit::Year => format!("P{value}Y"),
+            _ => format!("P{value}M"),
+        }
+    }
+
     pub fn purchase(
         &self,
         product_id: String,
@@ -385,7 +452,14 @@ impl<R: Runtime> Iap<R> {
             })?
             .as_millis() as i64;
 
-        let purchase_token = format!("win_{}_{}", product_id, purchase_time);
+        // `inapp` products are the consumable kinds, see `query_products`. A consumable's own
+        // token keeps a purchase after a fulfilled one from looking like a redelivery
+        let purchase_token = if product_type == "inapp" {
+            self.consumable_token(&product_id)?
+        } else {
+            self.license_sku_store_id(&context, &product_id)?
+                .unwrap_or_else(|| product_id.clone())
+        };
 
         Ok(Purchase {
             order_id: Some(purchase_token.clone()),
@@ -401,9 +475,65 @@ impl<R: Runtime> Iap<R> {
                 status.0, error_message, product_id
             ),
             signature: String::new(), // Windows doesn't provide signatures like Android
+            revocation_date: None,
+            revocation_reason: None,
+            delivery: None,
+            fulfilled: false,
         })
     }
 
+    /// SKU Store ID of the user's license for the add-on, if it has one.
+    fn license_sku_store_id(
+        &self,
+        context: &StoreContext,
+        store_id: &str,
+    ) -> crate::Result<Option<String>> {
+        let app_license = context
+            .GetAppLicenseAsync()
+            .and_then(|async_op| async_op.get())?;
+
+        let sku_prefix = format!("{store_id}/");
+        let iterator = app_license.AddOnLicenses()?.First()?;
+        while iterator.HasCurrent()? {
+            let sku_store_id = iterator.Current()?.Value()?.SkuStoreId()?.to_string();
+            if sku_store_id == store_id || sku_store_id.starts_with(&sku_prefix) {
+                return Ok(Some(sku_store_id));
+            }
+            iterator.MoveNext()?;
+        }
+        Ok(None)
+    }
+
+    /// Token of the unfulfilled consumable, `win_{store_id}_{tracking_id}`.
+    ///
+    /// The Store refuses a second purchase until the first is fulfilled and answers with
+    /// `AlreadyPurchased`, which returns the same token. The tracking id is the one the
+    /// fulfillment is reported with, so a retried report is not counted twice.
+    fn consumable_token(&self, store_id: &str) -> crate::Result<String> {
+        let mut consumables = self.consumables.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(token) = consumables.get(store_id) {
+            return Ok(token.clone());
+        }
+
+        let token = format!("win_{store_id}_{:032x}", GUID::new()?.to_u128());
+        consumables.insert(store_id.to_string(), token.clone());
+        self.persist_consumables(&consumables);
+        Ok(token)
+    }
+
+    fn persist_consumables(&self, consumables: &HashMap<String, String>) {
+        let Some(path) = &self.consumables_path else {
+            return;
+        };
+
+        if let Ok(json) = serde_json::to_vec(consumables) {
+            if let Some(dir) = path.parent() {
+                let _ = std::fs::create_dir_all(dir);
+            }
+            let _ = std::fs::write(path, json);
+        }
+    }
+
     pub fn restore_purchases(
         &self,
         product_type: String,
@@ -487,6 +617,62 @@ impl<R: Runtime> Iap<R> {
                 is_active, expiration_millis
             ),
             signature: String::new(),
+            revocation_date: None,
+            revocation_reason: None,
+            delivery: None,
+            fulfilled: false,
+        })
+    }
+
+    pub fn get_unfinished_purchases(&self) -> crate::Result<GetUnfinishedPurchasesResponse> {
+        // Store purchases complete within the purchase call, there is nothing to replay
+        Ok(GetUnfinishedPurchasesResponse::default())
+    }
+
+    pub fn get_store_capabilities(&self) -> crate::Result<StoreCapabilities> {
+        // The Store API does not expose the market or a payment restriction, a usable store
+        // context is as close as it gets
+        Ok(StoreCapabilities {
+            store: StoreKind::MicrosoftStore,
+            can_make_payments: self.get_store_context().is_ok(),
+            storefront: None,
+            features: vec![StoreFeature::Subscriptions, StoreFeature::Consumables],
+        })
+    }
+
+    pub fn redeem_offer_code(
+        &self,
+        _code: Option<String>,
+    ) -> crate::Result<RedeemOfferCodeResponse> {
+        Err(crate::Error::rejected(
+            "notSupported",
+            "The Microsoft Store has no offer code redemption",
+        ))
+    }
+
+    pub fn begin_refund_request(
+        &self,
+        _transaction_id: String,
+    ) -> crate::Result<BeginRefundRequestResponse> {
+        Err(crate::Error::rejected(
+            "notSupported",
+            "The Microsoft Store has no in-app refund requests",
+        ))
+    }
+
+    pub fn show_manage_subscriptions(
+        &self,
+        _product_id: Option<String>,
+    ) -> crate::Result<ShowManageSubscriptionsResponse> {
+        // The Store app has no subscription page, subscriptions are managed on the account site
+        let url = "https://account.microsoft.com/services";
+        let presented = Uri::CreateUri(&HSTRING::from(url))
+            .and_then(|uri| Launcher::LaunchUriAsync(&uri))
+            .and_then(|async_op| async_op.get())?;
+
+        Ok(ShowManageSubscriptionsResponse {
+            presented,
+            url: Some(url.to_string()),
         })
     }
 
@@ -496,7 +682,78 @@ impl<R: Runtime> Iap<R> {
     ) -> crate::Result<AcknowledgePurchaseResponse> {
         // Windows Store handles acknowledgment automatically
         // This method exists for API compatibility
-        Ok(AcknowledgePurchaseResponse { success: true })
+        Ok(AcknowledgePurchaseResponse {
+            success: true,
+            queued: false,
+        })
+    }
+
+    pub fn consume_purchase(
+        &self,
+        purchase_token: String,
+    ) -> crate::Result<ConsumePurchaseResponse> {
+        // Consumable tokens carry their tracking id, see `consumable_token`. Restored add-ons
+        // use their SKU Store ID, `{store_id}/{sku_id}`, and get a tracking id derived from it
+        let (store_id, tracking_id) = match purchase_token
+            .strip_prefix("win_")
+            .and_then(|rest| rest.rsplit_once('_'))
+        {
+            Some((store_id, tracking_id)) => (
+                store_id,
+                u128::from_str_radix(tracking_id, 16)
+                    .map(GUID::from_u128)
+                    .unwrap_or_else(|_| derived_tracking_id(&purchase_token)),
+            ),
+            None => (
+                purchase_token.split('/').next().unwrap_or_default(),
+                derived_tracking_id(&purchase_token),
+            ),
+        };
+        if store_id.is_empty() {
+            return Err(crate::Error::rejected(
+                "invalidPurchaseToken",
+                format!("Not a Microsoft Store purchase token: {purchase_token}"),
+            ));
+        }
+
+        let context = self.get_store_context()?;
+        let result = context
+            .ReportConsumableFulfillmentAsync(&HSTRING::from(store_id), 1, tracking_id)
+            .and_then(|async_op| async_op.get())?;
+
+        match result.Status()? {
+            StoreConsumableStatus::Succeeded => {
+                let mut consumables = self.consumables.lock().unwrap_or_else(|e| e.into_inner());
+                if consumables.get(store_id) == Some(&purchase_token) {
+                    consumables.remove(store_id);
+                    self.persist_consumables(&consumables);
+                }
+                Ok(ConsumePurchaseResponse {
+                    success: true,
+                    queued: false,
+                })
+            }
+            StoreConsumableStatus::NetworkError => Err(crate::Error::rejected(
+                "networkError",
+                "Network error while reporting the fulfillment",
+            )),
+            StoreConsumableStatus::ServerError => Err(crate::Error::rejected(
+                "serverError",
+                "Server error while reporting the fulfillment",
+            )),
+            // Nothing left to fulfill, e.g. a durable add-on or a consumable fulfilled before
+            StoreConsumableStatus::InsufficentQuantity => Err(crate::Error::rejected(
+                "insufficientQuantity",
+                format!("{store_id} has no unfulfilled quantity"),
+            )),
+            status => Err(crate::Error::rejected(
+                "consumeFailed",
+                format!(
+                    "Failed to report consumable fulfillment: status {}",
+                    status.0
+                ),
+            )),
+        }
     }
 
     pub fn get_product_status(
@@ -504,6 +761,29 @@ impl<R: Runtime> Iap<R> {
         product_id: String,
         product_type: String,
     ) -> crate::Result<ProductStatus> {
+        let addon_licenses = self.get_addon_licenses()?;
+        self.license_status(&addon_licenses, product_id, &product_type)
+    }
+
+    pub fn get_products_status(
+        &self,
+        product_ids: Vec<String>,
+        product_type: String,
+    ) -> crate::Result<GetProductsStatusResponse> {
+        // One license query serves every requested product
+        let addon_licenses = self.get_addon_licenses()?;
+
+        let mut statuses = HashMap::new();
+        for product_id in product_ids {
+            let status = self.license_status(&addon_licenses, product_id.clone(), &product_type)?;
+            statuses.insert(product_id, status);
+        }
+
+        Ok(GetProductsStatusResponse { statuses })
+    }
+
+    /// Fetch the add-on licenses of the current user
+    fn get_addon_licenses(&self) -> crate::Result<IMapView<HSTRING, StoreLicense>> {
         let context = self.get_store_context()?;
 
         // Get app license to check ownership
@@ -511,8 +791,15 @@ impl<R: Runtime> Iap<R> {
             .GetAppLicenseAsync()
             .and_then(|async_op| async_op.get())?;
 
-        let addon_licenses = app_license.AddOnLicenses()?;
+        Ok(app_license.AddOnLicenses()?)
+    }
 
+    fn license_status(
+        &self,
+        addon_licenses: &IMapView<HSTRING, StoreLicense>,
+        product_id: String,
+        product_type: &str,
+    ) -> crate::Result<ProductStatus> {
         // Look for the specific product license
         let product_key = HSTRING::from(&product_id);
         let has_license = addon_licenses.HasKey(&product_key)?;
@@ -566,3 +853,11 @@ impl<R: Runtime> Iap<R> {
         }
     }
 }
+
+/// A tracking id that is the same for every report of the same token.
+fn derived_tracking_id(purchase_token: &str) -> GUID {
+    let digest = Sha256::digest(purchase_token.as_bytes());
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    GUID::from_u128(u128::from_be_bytes(bytes))
+}